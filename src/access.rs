@@ -0,0 +1,446 @@
+use crate::auth::Principal;
+use crate::geoip::GeoFilter;
+use crate::glob;
+use rshttp::request::HeaderMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses `ADDR/PREFIX`, or a bare address as a single-host network
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| format!("invalid IP address '{}'", addr))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?,
+            None => max,
+        };
+        Ok(Cidr { network, prefix })
+    }
+
+    /// Whether the address lies within this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Treat IPv4-mapped IPv6 peers (from dual-stack sockets) as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Networks allowed and denied to connect, from --allow and --deny
+///
+/// The most specific network containing the peer decides, and a tie goes to
+/// --deny. Peers in no listed network are refused when some networks are
+/// allowed, and accepted otherwise. Unix socket peers have no address and are
+/// always accepted. Peers the networks accept are then held to the --geoip-db
+/// country rules, if any.
+#[derive(Debug)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub geo: Option<GeoFilter>,
+}
+
+impl IpFilter {
+    /// Whether a peer at `ip` may connect
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let longest = |networks: &[Cidr]| {
+            networks.iter().filter(|cidr| cidr.contains(ip)).map(|cidr| cidr.prefix).max()
+        };
+        let permitted = match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allowed), Some(denied)) => allowed > denied,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.allow.is_empty(),
+        };
+        permitted && self.geo.as_ref().is_none_or(|geo| geo.permits(ip))
+    }
+}
+
+/// Proxies whose X-Forwarded-For and Forwarded headers name the client, from --trusted-proxy
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<Cidr>) -> TrustedProxies {
+        TrustedProxies { networks }
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client a request from `peer` was made by
+    ///
+    /// The addresses the proxies added are followed from the nearest one back,
+    /// for as long as the address they were received from is a trusted proxy
+    /// too, so a client can't pass for another by sending the headers itself.
+    /// `Forwarded` is preferred over `X-Forwarded-For` when both are sent.
+    pub fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let hops: Vec<&str> = match headers.get("Forwarded") {
+            Some(_) => (headers.get_all("Forwarded"))
+                .flat_map(|value| value.split(','))
+                .map(|element| {
+                    let pairs = element.split(';').filter_map(|pair| pair.trim().split_once('='));
+                    pairs.filter(|(name, _)| name.eq_ignore_ascii_case("for")).map(|(_, node)| node).next().unwrap_or("")
+                })
+                .collect(),
+            None => headers.get_all("X-Forwarded-For").flat_map(|value| value.split(',')).collect(),
+        };
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            if !self.trusts(client) {
+                break;
+            }
+            // An obfuscated or unknown node ends the chain that can be followed
+            match node_address(hop) {
+                Some(ip) => client = ip,
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// The address of a forwarded node, e.g. `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `"[2001:db8::1]:4711"`
+fn node_address(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.rsplit_once(':')?.0.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4))
+}
+
+/// The facts about a request that rules can match on
+pub struct AccessRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub ip: Option<IpAddr>,
+    pub principal: Option<&'a Principal>,
+}
+
+/// The result of evaluating the rules for a request
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// Serve the request, with or without credentials
+    Allow,
+    /// Refuse the request outright
+    Deny,
+    /// The request may be allowed once the client authenticates
+    Unauthenticated,
+    /// No rule matched; fall back to the server defaults
+    NoMatch,
+}
+
+#[derive(Debug)]
+enum Condition {
+    IpIn(Cidr),
+    Group(String),
+    User(String),
+    Authenticated,
+}
+
+impl Condition {
+    fn holds(&self, request: &AccessRequest) -> bool {
+        match self {
+            Condition::IpIn(cidr) => request.ip.is_some_and(|ip| cidr.contains(ip)),
+            Condition::Group(group) => request.principal.is_some_and(|p| p.groups.contains(group)),
+            Condition::User(user) => request.principal.is_some_and(|p| p.name == *user),
+            Condition::Authenticated => request.principal.is_some(),
+        }
+    }
+
+    /// Whether a missing principal is the reason this condition fails
+    fn needs_principal(&self) -> bool {
+        !matches!(self, Condition::IpIn(_))
+    }
+}
+
+#[derive(Debug)]
+enum Action {
+    Allow,
+    Deny,
+    Require(Condition),
+}
+
+/// One statement of the policy, e.g. `deny PUT unless ip in 10.0.0.0/8`
+#[derive(Debug)]
+struct Rule {
+    action: Action,
+    methods: Vec<String>,
+    path: Option<String>,
+    guard: Option<(bool, Condition)>,
+}
+
+impl Rule {
+    fn applies_to(&self, request: &AccessRequest) -> bool {
+        // A rule for GET covers HEAD too, as both read the same thing
+        let method_matches = self.methods.is_empty()
+            || self.methods.iter().any(|m| m == request.method || (m == "GET" && request.method == "HEAD"));
+        let path_matches = self.path.as_ref().is_none_or(|pattern| glob::covers(pattern, request.path));
+        let guard_matches = match &self.guard {
+            Some((expected, condition)) => condition.holds(request) == *expected,
+            None => true,
+        };
+        method_matches && path_matches && guard_matches
+    }
+}
+
+/// An ordered list of access rules; the first rule that applies decides
+#[derive(Debug)]
+pub struct AccessPolicy {
+    rules: Vec<Rule>,
+}
+
+impl AccessPolicy {
+    /// Loads a policy file, reporting the first syntax error with its line number
+    pub fn load(path: &Path) -> Result<AccessPolicy, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        AccessPolicy::parse(&source).map_err(|e| format!("{}:{}", path.display(), e))
+    }
+
+    /// Parses statements separated by newlines or `;`, with `#` comments
+    pub fn parse(source: &str) -> Result<AccessPolicy, String> {
+        let mut rules = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            for statement in line.split(';') {
+                let tokens: Vec<&str> = statement.split_whitespace().collect();
+                if !tokens.is_empty() {
+                    let rule = parse_rule(&tokens).map_err(|e| format!("{}: {}", number + 1, e))?;
+                    rules.push(rule);
+                }
+            }
+        }
+        Ok(AccessPolicy { rules })
+    }
+
+    pub fn evaluate(&self, request: &AccessRequest) -> Decision {
+        let Some(rule) = self.rules.iter().find(|rule| rule.applies_to(request)) else {
+            return Decision::NoMatch;
+        };
+        match &rule.action {
+            Action::Allow => Decision::Allow,
+            Action::Deny => Decision::Deny,
+            Action::Require(condition) if condition.holds(request) => Decision::Allow,
+            Action::Require(condition) if condition.needs_principal() && request.principal.is_none() => {
+                Decision::Unauthenticated
+            }
+            Action::Require(_) => Decision::Deny,
+        }
+    }
+}
+
+fn parse_rule(tokens: &[&str]) -> Result<Rule, String> {
+    let mut rest = tokens;
+    let action = match rest {
+        ["allow", tail @ ..] => {
+            rest = tail;
+            Action::Allow
+        }
+        ["deny", tail @ ..] => {
+            rest = tail;
+            Action::Deny
+        }
+        ["require", tail @ ..] => {
+            let (condition, tail) = parse_condition(tail)?;
+            rest = match tail {
+                ["for", tail @ ..] => tail,
+                _ => tail,
+            };
+            Action::Require(condition)
+        }
+        [other, ..] => return Err(format!("expected allow, deny or require, got '{}'", other)),
+        [] => return Err("empty rule".to_string()),
+    };
+
+    let mut methods = Vec::new();
+    while let [token, tail @ ..] = rest {
+        if token.starts_with('/') || *token == "if" || *token == "unless" {
+            break;
+        }
+        // `*` stands for every method, as does leaving them out
+        for method in token.split(',').filter(|m| !m.is_empty() && *m != "*") {
+            if !method.chars().all(|c| c.is_ascii_uppercase()) {
+                return Err(format!("expected an HTTP method, got '{}'", method));
+            }
+            methods.push(method.to_string());
+        }
+        rest = tail;
+    }
+
+    let mut path = None;
+    if let [token, tail @ ..] = rest {
+        if token.starts_with('/') {
+            path = Some(token.to_string());
+            rest = tail;
+        }
+    }
+
+    let guard = match rest {
+        [] => None,
+        [keyword @ ("if" | "unless"), tail @ ..] => {
+            let (condition, tail) = parse_condition(tail)?;
+            if let [extra, ..] = tail {
+                return Err(format!("unexpected '{}' after condition", extra));
+            }
+            Some((*keyword == "if", condition))
+        }
+        [extra, ..] => return Err(format!("unexpected '{}'", extra)),
+    };
+
+    Ok(Rule {
+        action,
+        methods,
+        path,
+        guard,
+    })
+}
+
+fn parse_condition<'a, 'b>(tokens: &'a [&'b str]) -> Result<(Condition, &'a [&'b str]), String> {
+    match tokens {
+        ["ip", "in", cidr, tail @ ..] => Ok((Condition::IpIn(Cidr::parse(cidr)?), tail)),
+        ["authenticated", tail @ ..] => Ok((Condition::Authenticated, tail)),
+        [token, tail @ ..] => match token.split_once('=') {
+            Some(("group", group)) if !group.is_empty() => Ok((Condition::Group(group.to_string()), tail)),
+            Some(("user", user)) if !user.is_empty() => Ok((Condition::User(user.to_string()), tail)),
+            _ => Err(format!("unknown condition '{}'", token)),
+        },
+        [] => Err("missing condition".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decide(policy: &str, method: &str, path: &str) -> Decision {
+        let policy = AccessPolicy::parse(policy).unwrap();
+        policy.evaluate(&AccessRequest {
+            method,
+            path,
+            ip: None,
+            principal: None,
+        })
+    }
+
+    #[test]
+    fn directory_rules_cover_the_directory_itself() {
+        let policy = "deny /internal/**";
+        assert_eq!(decide(policy, "GET", "/internal"), Decision::Deny);
+        assert_eq!(decide(policy, "GET", "/internal/"), Decision::Deny);
+        assert_eq!(decide(policy, "GET", "/internal/index.html"), Decision::Deny);
+        assert_eq!(decide(policy, "GET", "/internals"), Decision::NoMatch);
+    }
+
+    fn decide_as(policy: &str, ip: &str, principal: Option<(&str, &[&str])>) -> Decision {
+        let principal = principal.map(|(name, groups)| Principal {
+            name: name.to_string(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        });
+        AccessPolicy::parse(policy).unwrap().evaluate(&AccessRequest {
+            method: "GET",
+            path: "/admin/users",
+            ip: Some(ip.parse().unwrap()),
+            principal: principal.as_ref(),
+        })
+    }
+
+    #[test]
+    fn the_first_rule_that_applies_decides() {
+        let policy = "allow /admin/public/**; deny /admin/**; allow";
+        assert_eq!(decide(policy, "GET", "/admin/public/logo.png"), Decision::Allow);
+        assert_eq!(decide(policy, "GET", "/admin/users"), Decision::Deny);
+        assert_eq!(decide(policy, "GET", "/index.html"), Decision::Allow);
+    }
+
+    #[test]
+    fn get_rules_cover_head() {
+        let policy = "allow GET /docs/**; deny * /**";
+        assert_eq!(decide(policy, "GET", "/docs/x"), Decision::Allow);
+        assert_eq!(decide(policy, "HEAD", "/docs/x"), Decision::Allow);
+        assert_eq!(decide(policy, "PUT", "/docs/x"), Decision::Deny);
+        assert_eq!(decide("deny HEAD /a", "GET", "/a"), Decision::NoMatch);
+    }
+
+    #[test]
+    fn rules_match_methods() {
+        let policy = "deny PUT,DELETE /files/**\nallow GET";
+        assert_eq!(decide(policy, "PUT", "/files/a"), Decision::Deny);
+        assert_eq!(decide(policy, "DELETE", "/files/a"), Decision::Deny);
+        assert_eq!(decide(policy, "GET", "/files/a"), Decision::Allow);
+        assert_eq!(decide(policy, "POST", "/files/a"), Decision::NoMatch);
+    }
+
+    #[test]
+    fn guards_match_addresses() {
+        let policy = "deny /admin/** unless ip in 10.0.0.0/8";
+        assert_eq!(decide_as(policy, "10.1.2.3", None), Decision::NoMatch);
+        assert_eq!(decide_as(policy, "192.0.2.1", None), Decision::Deny);
+        let policy = "allow /admin/** if ip in 2001:db8::/32";
+        assert_eq!(decide_as(policy, "2001:db8::1", None), Decision::Allow);
+        assert_eq!(decide_as(policy, "2001:db9::1", None), Decision::NoMatch);
+    }
+
+    #[test]
+    fn requirements_ask_for_credentials_only_when_they_could_help() {
+        let policy = "require group=admins for /admin/**";
+        assert_eq!(decide_as(policy, "192.0.2.1", None), Decision::Unauthenticated);
+        assert_eq!(decide_as(policy, "192.0.2.1", Some(("ann", &["staff"]))), Decision::Deny);
+        assert_eq!(decide_as(policy, "192.0.2.1", Some(("ann", &["staff", "admins"]))), Decision::Allow);
+        let policy = "require user=ann /admin/**";
+        assert_eq!(decide_as(policy, "192.0.2.1", Some(("ann", &[]))), Decision::Allow);
+        assert_eq!(decide_as(policy, "192.0.2.1", Some(("bob", &[]))), Decision::Deny);
+        let policy = "require ip in 10.0.0.0/8 for /admin/**";
+        assert_eq!(decide_as(policy, "192.0.2.1", None), Decision::Deny);
+        let policy = "require authenticated";
+        assert_eq!(decide_as(policy, "10.0.0.1", None), Decision::Unauthenticated);
+        assert_eq!(decide_as(policy, "10.0.0.1", Some(("bob", &[]))), Decision::Allow);
+    }
+
+    #[test]
+    fn syntax_errors_name_their_line() {
+        let error = |policy| AccessPolicy::parse(policy).unwrap_err();
+        assert_eq!(error("allow /a\n# comment\npermit /b"), "3: expected allow, deny or require, got 'permit'");
+        assert_eq!(error("deny get /a"), "1: expected an HTTP method, got 'get'");
+        assert_eq!(error("deny /a if ip in 10.0.0.0/8 extra"), "1: unexpected 'extra' after condition");
+        assert_eq!(error("require group= /a"), "1: unknown condition 'group='");
+        assert!(AccessPolicy::parse("allow GET /a # trailing comment; deny").unwrap().rules.len() == 1);
+    }
+}
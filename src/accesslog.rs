@@ -0,0 +1,254 @@
+use crate::metrics::RequestRecord;
+#[cfg(unix)]
+use crate::syslog::{self, Syslog};
+use crate::units;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::Arc;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+/// Layout of the access log lines, chosen with --log-format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Apache's Common Log Format: `%h %l %u %t "%r" %>s %b`, with `%b` counting the response headers too
+    Common,
+    /// Apache's Combined Log Format, followed by the time taken in microseconds (Apache's `%D`)
+    Combined,
+    /// One JSON object per line, with the fields listed at [`json_line`]
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Result<LogFormat, String> {
+        match value {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected common, combined or json".to_string()),
+        }
+    }
+}
+
+/// When the access log file is rotated, and how many rotated files are kept
+///
+/// The current file is renamed to `<file>.1`, older ones move up by one, and
+/// the one past `keep` is deleted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+    /// Rotate once the file would grow beyond this many bytes
+    pub max_size: Option<u64>,
+    /// Rotate once this long has passed since the first line went into the file
+    pub interval: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Rotation {
+    fn is_due(&self, file: &LogFile, next_line: usize) -> bool {
+        file.size > 0
+            && (self.max_size.is_some_and(|max_size| file.size + next_line as u64 > max_size)
+                || self.interval.is_some_and(|interval| file.started.elapsed() >= interval))
+    }
+}
+
+/// One line per request, on stdout, appended to --access-log or sent to syslog
+pub struct AccessLog {
+    format: LogFormat,
+    rotation: Rotation,
+    out: Mutex<Sink>,
+}
+
+enum Sink {
+    Stdout,
+    File(LogFile),
+    #[cfg(unix)]
+    Syslog(Arc<Syslog>),
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// When the first line went into the file
+    started: Instant,
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    /// Moves the file aside and starts a new one, keeping the current file if that fails
+    fn rotate(&mut self, keep: usize) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(keep));
+            for n in (1..keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        *self = LogFile::open(self.path.clone())?;
+        Ok(())
+    }
+}
+
+/// A finished request as the access log sees it
+pub struct Entry<'a> {
+    pub record: &'a RequestRecord,
+    pub peer_ip: Option<IpAddr>,
+    pub received: SystemTime,
+    pub elapsed: Duration,
+    pub status: Option<u16>,
+    pub bytes_sent: u64,
+}
+
+impl AccessLog {
+    /// Logs to `path`, opened for appending now so it can be written to after privileges are dropped, or to stdout
+    ///
+    /// Rotating needs the file's directory to stay writable; when it isn't,
+    /// the server keeps appending to the current file.
+    pub fn open(format: LogFormat, path: Option<&Path>, rotation: Rotation) -> io::Result<AccessLog> {
+        let out = match path {
+            Some(path) => Sink::File(LogFile::open(std::path::absolute(path)?)?),
+            None => Sink::Stdout,
+        };
+        Ok(AccessLog {
+            format,
+            rotation,
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Sends every line to syslog under the local7 facility
+    #[cfg(unix)]
+    pub fn to_syslog(format: LogFormat, syslog: Arc<Syslog>) -> AccessLog {
+        AccessLog {
+            format,
+            rotation: Rotation::default(),
+            out: Mutex::new(Sink::Syslog(syslog)),
+        }
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let record = entry.record;
+        if record.method.is_empty() {
+            return; // The client closed the connection without sending a request
+        }
+        let mut line = match self.format {
+            LogFormat::Json => json_line(entry),
+            LogFormat::Common | LogFormat::Combined => clf_line(entry, self.format),
+        };
+        line.push('\n');
+
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let written = match &mut *out {
+            Sink::File(file) => {
+                if self.rotation.is_due(file, line.len()) {
+                    if let Err(e) = file.rotate(self.rotation.keep) {
+                        warn!("Failed to rotate the access log {}, appending to it: {}", file.path.display(), e);
+                        // Not tried again before the next interval, or before the file grows by another max size
+                        file.size = 0;
+                    }
+                }
+                if file.size == 0 {
+                    file.started = Instant::now();
+                }
+                file.size += line.len() as u64;
+                file.file.write_all(line.as_bytes())
+            }
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            #[cfg(unix)]
+            Sink::Syslog(syslog) => {
+                syslog.send(syslog::LOCAL7, syslog::INFO, &line);
+                Ok(())
+            }
+        };
+        if let Err(e) = written {
+            warn!("Failed to write the access log: {}", e);
+        }
+    }
+}
+
+/// A line in Common or Combined Log Format
+fn clf_line(entry: &Entry, format: LogFormat) -> String {
+    let record = entry.record;
+    let mut line = format!(
+        "{} - {} [{}] \"{}\" {} {}",
+        entry.peer_ip.map_or("-".to_string(), |ip| ip.to_string()),
+        record.user.as_deref().map_or("-".to_string(), escape),
+        units::clf_timestamp(entry.received),
+        escape(&record.request_line),
+        entry.status.map_or("-".to_string(), |status| status.to_string()),
+        match entry.bytes_sent {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        },
+    );
+    if format == LogFormat::Combined {
+        line.push_str(&format!(
+            " \"{}\" \"{}\" {}",
+            record.referer.as_deref().map_or("-".to_string(), escape),
+            record.user_agent.as_deref().map_or("-".to_string(), escape),
+            entry.elapsed.as_micros()
+        ));
+    }
+    line
+}
+
+/// A JSON object with stable field names, which are always present and `null` when unknown
+///
+/// `time` (ISO 8601 in UTC), `remote_addr`, `user`, `method`, `path` (without
+/// the query), `query`, `protocol`, `status`, `bytes` (sent, headers
+/// included), `duration_us`, `referer`, `user_agent` and `cache_hit`.
+fn json_line(entry: &Entry) -> String {
+    let record = entry.record;
+    let mut request_line = record.request_line.split_whitespace().skip(1);
+    let query = request_line.next().and_then(|target| target.split_once('?')).map(|(_, query)| query);
+    serde_json::json!({
+        "time": units::iso_timestamp(entry.received),
+        "remote_addr": entry.peer_ip.map(|ip| ip.to_string()),
+        "user": record.user,
+        "method": record.method,
+        "path": record.path,
+        "query": query,
+        "protocol": request_line.next(),
+        "status": entry.status,
+        "bytes": entry.bytes_sent,
+        "duration_us": entry.elapsed.as_micros() as u64,
+        "referer": record.referer,
+        "user_agent": record.user_agent,
+        "cache_hit": record.cache_hit,
+    })
+    .to_string()
+}
+
+/// Escapes quotes, backslashes and control characters as Apache does, so a client can't forge log lines
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,62 @@
+#[cfg(target_os = "linux")]
+use std::io;
+use tracing::warn;
+
+/// The CPUs given with `--cpus`
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug)]
+pub struct CpuList(pub Vec<usize>);
+
+/// Parses a CPU list such as `0-3,8,10-11`
+#[cfg(target_os = "linux")]
+pub fn parse_cpus(value: &str) -> Result<CpuList, String> {
+    let invalid = || format!("invalid CPU list '{}' (expected e.g. 0-3,8)", value);
+    let mut cpus = Vec::new();
+    for part in value.split(',') {
+        let (first, last) = match part.trim().split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => (part.trim(), part.trim()),
+        };
+        let (first, last): (usize, usize) = (first.parse().map_err(|_| invalid())?, last.parse().map_err(|_| invalid())?);
+        if last < first || last >= libc::CPU_SETSIZE as usize {
+            return Err(invalid());
+        }
+        for cpu in first..=last {
+            if !cpus.contains(&cpu) {
+                cpus.push(cpu);
+            }
+        }
+    }
+    Ok(CpuList(cpus))
+}
+
+/// The CPUs this process may run on
+#[cfg(target_os = "linux")]
+pub fn available() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect())
+    }
+}
+
+/// Pins the calling thread to one of `cpus`, picked round-robin by `index`; does nothing when `cpus` is empty
+#[cfg(target_os = "linux")]
+pub fn pin(cpus: &[usize], index: usize) {
+    let Some(&cpu) = cpus.get(index % cpus.len().max(1)) else {
+        return;
+    };
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        warn!("Failed to pin thread to CPU {}: {}", cpu, io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cpus: &[usize], _index: usize) {}
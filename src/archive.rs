@@ -0,0 +1,198 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The query that asks for a directory as an archive
+pub const QUERY: &str = "download=tar.gz";
+
+const BLOCK: usize = 512;
+
+/// Largest size the octal size field holds; bigger files get the binary form
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// A file to put in an archive, with the size and time it had when the directory was listed
+pub struct Entry {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// The `files` that can be read, each under its name in the archive
+pub fn entries<'a>(files: impl Iterator<Item = (&'a Path, String)>) -> Vec<Entry> {
+    files
+        .filter_map(|(path, name)| {
+            let metadata = path.metadata().ok()?;
+            Some(Entry {
+                path: path.to_path_buf(),
+                name,
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })
+        })
+        .collect()
+}
+
+/// An ETag for the archive of `entries`, which changes when any file is added, removed, resized or touched
+pub fn etag(entries: &[Entry]) -> String {
+    let mut hasher = DefaultHasher::new();
+    for entry in entries {
+        (&entry.name, entry.size, entry.modified).hash(&mut hasher);
+    }
+    format!("\"{:x}-{:x}\"", hasher.finish(), entries.len())
+}
+
+/// When the most recently changed of `entries` was modified
+pub fn modified(entries: &[Entry]) -> Option<SystemTime> {
+    entries.iter().filter_map(|entry| entry.modified).max()
+}
+
+/// Writes `entries` as a gzip-compressed tar archive
+///
+/// The archive is written as the files are read, so it never has to fit in
+/// memory. Names longer than the tar header holds get a GNU long name entry,
+/// which GNU tar, bsdtar and 7-Zip all understand. Each file takes the size
+/// it was listed with, so the same unchanged files always make the same
+/// bytes and a download can be resumed by writing the archive again.
+pub fn write_tar_gz(out: impl Write, entries: &[Entry]) -> io::Result<()> {
+    let mut gzip = GzEncoder::new(out, Compression::default());
+    for entry in entries {
+        if entry.name.len() > 100 {
+            let mut long_name = entry.name.clone().into_bytes();
+            long_name.push(0);
+            gzip.write_all(&header("././@LongLink", long_name.len() as u64, 0, b'L'))?;
+            write_padded(&mut gzip, &mut long_name.as_slice(), long_name.len() as u64)?;
+        }
+        let mtime = entry.modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        gzip.write_all(&header(&entry.name, entry.size, mtime.map_or(0, |mtime| mtime.as_secs()), b'0'))?;
+        match File::open(&entry.path) {
+            Ok(mut source) => write_padded(&mut gzip, &mut source, entry.size)?,
+            // Gone or unreadable since the directory was listed
+            Err(_) => write_padded(&mut gzip, &mut io::empty(), entry.size)?,
+        }
+    }
+    // The end of the archive
+    gzip.write_all(&[0; BLOCK * 2])?;
+    gzip.finish()?.flush()
+}
+
+/// Length in bytes of the archive of `entries`, found by writing it and throwing it away
+pub fn len(entries: &[Entry]) -> io::Result<u64> {
+    let mut counter = Window {
+        out: io::sink(),
+        skip: 0,
+        left: u64::MAX,
+    };
+    write_tar_gz(&mut counter, entries)?;
+    Ok(u64::MAX - counter.left)
+}
+
+/// Writes the bytes in `range` of the archive of `entries`, stopping once they have been written
+pub fn write_tar_gz_range(out: impl Write, entries: &[Entry], range: Range<u64>) -> io::Result<()> {
+    let mut window = Window {
+        out,
+        skip: range.start,
+        left: range.end - range.start,
+    };
+    match write_tar_gz(&mut window, entries) {
+        Err(_) if window.left == 0 => window.out.flush(),
+        result => result,
+    }
+}
+
+/// Passes on `left` bytes after skipping `skip`, then fails the write to stop the archive being made
+struct Window<W: Write> {
+    out: W,
+    skip: u64,
+    left: u64,
+}
+
+impl<W: Write> Write for Window<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = buf.len().min(self.skip.try_into().unwrap_or(usize::MAX));
+        self.skip -= skipped as u64;
+        let kept = (buf.len() - skipped).min(self.left.try_into().unwrap_or(usize::MAX));
+        if skipped == 0 && kept == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.out.write_all(&buf[skipped..skipped + kept])?;
+        self.left -= kept as u64;
+        Ok(skipped + kept)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// A ustar header for a regular file, or for the long name of the next one
+fn header(name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let name = name.as_bytes();
+    header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    match size <= MAX_OCTAL_SIZE {
+        true => octal(&mut header[124..136], size),
+        false => {
+            header[124] = 0x80;
+            header[128..136].copy_from_slice(&size.to_be_bytes());
+        }
+    }
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is taken with its own field counted as spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    header
+}
+
+/// Zero-padded octal digits ending in a NUL, filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// Copies `size` bytes and pads them to a whole block, with zeros if the source ran short
+fn write_padded(out: &mut impl Write, source: &mut impl Read, size: u64) -> io::Result<()> {
+    let copied = io::copy(&mut source.take(size), out)?;
+    let padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+    io::copy(&mut io::repeat(0).take(size - copied + padding), out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_of_a_regenerated_archive_match_the_whole() {
+        let dir = std::env::temp_dir().join(format!("rshttp-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: Vec<_> = (0..3).map(|n| dir.join(format!("file{}.txt", n))).collect();
+        for (n, file) in files.iter().enumerate() {
+            std::fs::write(file, format!("contents {}\n", n).repeat(2000)).unwrap();
+        }
+        let entries = entries(files.iter().map(|file| (file.as_path(), file.file_name().unwrap().to_string_lossy().into_owned())));
+        let mut whole = Vec::new();
+        write_tar_gz(&mut whole, &entries).unwrap();
+        assert_eq!(len(&entries).unwrap(), whole.len() as u64);
+        let mut part = Vec::new();
+        write_tar_gz_range(&mut part, &entries, 100..200).unwrap();
+        let mut tail = Vec::new();
+        write_tar_gz_range(&mut tail, &entries, 150..whole.len() as u64).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(part, whole[100..200]);
+        assert_eq!(tail, whole[150..]);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::affinity;
+use crate::listener::{BufferedConnection, FileBody, Listener};
+use crate::throttle::{Pacer, Shaping};
+use crate::{admit, handle_connection, Context, FileCache, ACCEPT_POLL_INTERVAL};
+use rshttp::request;
+use rshttp::response::Response;
+use std::io::{self, SeekFrom};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+/// Bytes of a file body read and written at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serves every listener on a tokio runtime until shutdown begins
+///
+/// Waiting for a request and sending the response happen asynchronously, so
+/// idle and slow clients don't hold a thread. The handler itself stays
+/// synchronous and runs on one of at most `threads` blocking threads.
+///
+/// Returns the runtime once the acceptors stop; connections still in flight
+/// keep running on it until it is dropped.
+pub fn run(
+    listeners: Vec<Listener>,
+    context: Arc<Context>,
+    cache: FileCache,
+    threads: usize,
+    cpus: Vec<usize>,
+) -> io::Result<Runtime> {
+    // Runtime and blocking threads alike are pinned round-robin to `cpus`, in the order they start
+    let started = AtomicUsize::new(0);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(threads.max(1))
+        .on_thread_start(move || affinity::pin(&cpus, started.fetch_add(1, Ordering::Relaxed)))
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let mut acceptors = Vec::new();
+        for listener in listeners {
+            acceptors.push(tokio::spawn(accept(listener, Arc::clone(&context), Arc::clone(&cache))));
+        }
+        for acceptor in acceptors {
+            acceptor.await.expect("Acceptor task panicked")?;
+        }
+        Ok::<_, io::Error>(())
+    })?;
+
+    Ok(runtime)
+}
+
+async fn accept(listener: Listener, context: Arc<Context>, cache: FileCache) -> io::Result<()> {
+    match listener {
+        Listener::Tcp(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            while !context.shutdown.is_draining() {
+                let Ok(accepted) = tokio::time::timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await else {
+                    continue;
+                };
+                let (stream, peer) = accepted?;
+                if context.tcp_nodelay {
+                    let _ = stream.set_nodelay(true);
+                }
+                tokio::spawn(handle(stream, Some(peer.ip()), Arc::clone(&context), Arc::clone(&cache)));
+            }
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            while !context.shutdown.is_draining() {
+                let Ok(accepted) = tokio::time::timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await else {
+                    continue;
+                };
+                let (stream, _) = accepted?;
+                tokio::spawn(handle(stream, None, Arc::clone(&context), Arc::clone(&cache)));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle<S>(mut stream: S, peer_ip: Option<IpAddr>, context: Arc<Context>, cache: FileCache)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _admission = match admit(&context, peer_ip) {
+        Ok(admission) => admission,
+        Err(refusal) => {
+            let mut response = Vec::new();
+            let _ = refusal.send(&mut response, false);
+            let _ = write_within(&mut stream, &response, context.timeouts.write).await;
+            let _ = stream.shutdown().await;
+            return;
+        }
+    };
+    let head = match context.timeouts.header {
+        Some(timeout) => tokio::time::timeout(timeout, read_head(&mut stream)).await,
+        None => Ok(read_head(&mut stream).await),
+    };
+    let head = match head {
+        Ok(Ok(head)) => head,
+        Ok(Err(_)) => return,
+        Err(_) => {
+            let mut response = Vec::new();
+            let _ = Response::error(408).header("Connection", "close").send(&mut response, false);
+            let _ = write_within(&mut stream, &response, context.timeouts.write).await;
+            let _ = stream.shutdown().await;
+            return;
+        }
+    };
+
+    let handler_context = Arc::clone(&context);
+    let response = tokio::task::spawn_blocking(move || {
+        let mut buffered = BufferedConnection::new(head, peer_ip);
+        handle_connection(&mut buffered, &handler_context, cache);
+        buffered.into_output()
+    })
+    .await;
+
+    if let Ok((response, body)) = response {
+        if write_response(&mut stream, &response, body, context.timeouts.write, context.shaping).await.is_ok() {
+            let _ = stream.shutdown().await;
+        }
+    }
+}
+
+/// Writes the buffered response, then streams the file body from disk if there is one
+///
+/// Each chunk has to be taken by the client within `timeout`, and is held back as `shaping` says.
+async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: &[u8],
+    body: Option<FileBody>,
+    timeout: Option<Duration>,
+    shaping: Shaping,
+) -> io::Result<()> {
+    let mut pacer = shaping.is_active().then(|| Pacer::new(shaping));
+    write_paced(stream, response, &mut pacer, timeout).await?;
+    if let Some(body) = body {
+        let mut file = tokio::fs::File::from_std(body.file);
+        file.seek(SeekFrom::Start(body.offset)).await?;
+        let mut file = file.take(body.len);
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            write_paced(stream, &chunk[..read], &mut pacer, timeout).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `bytes` in chunks small enough for the pacer, waiting before each as it says
+async fn write_paced(
+    stream: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+    pacer: &mut Option<Pacer>,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let Some(pacer) = pacer else {
+        return write_within(stream, bytes, timeout).await;
+    };
+    for chunk in bytes.chunks(pacer.chunk_size()) {
+        tokio::time::sleep(pacer.wait(chunk.len())).await;
+        write_within(stream, chunk, timeout).await?;
+    }
+    Ok(())
+}
+
+/// Writes all of `bytes`, failing if the client doesn't take them within `timeout`
+async fn write_within(
+    stream: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, stream.write_all(bytes)).await {
+            Ok(written) => written,
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        },
+        None => stream.write_all(bytes).await,
+    }
+}
+
+/// Reads the request head like [`rshttp::request::read_head`], without blocking a thread
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut temp_buffer = [0; 1024];
+    loop {
+        let bytes_read = stream.read(&mut temp_buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&temp_buffer[..bytes_read]);
+        if buffer.windows(4).any(|window| window == b"\r\n\r\n") || buffer.len() > request::MAX_HEAD {
+            break;
+        }
+    }
+    Ok(buffer)
+}
@@ -0,0 +1,226 @@
+//! Credentials and the providers that check them
+//!
+//! The rshttp binary and [`Authenticate`](crate::middleware::Authenticate)
+//! run the same [`AuthProvider`]s; implement it to check credentials against
+//! anything else, such as an LDAP directory:
+//!
+//! ```
+//! use rshttp::auth::{AuthProvider, Credentials, Principal};
+//!
+//! struct Directory;
+//!
+//! impl AuthProvider for Directory {
+//!     fn authenticate(&self, credentials: &Credentials) -> Option<Principal> {
+//!         match credentials {
+//!             Credentials::Basic { user, password } if user == "ada" && password == "analytical" => Some(Principal {
+//!                 name: user.clone(),
+//!                 groups: vec!["engineers".to_string()],
+//!             }),
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//! ```
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Credentials extracted from the Authorization header
+pub enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Parses a `Basic` or `Bearer` Authorization header value
+    pub fn from_header(value: &str) -> Option<Credentials> {
+        let (scheme, rest) = value.trim().split_once(' ')?;
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = STANDARD.decode(rest.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            Some(Credentials::Basic {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            Some(Credentials::Bearer(rest.trim().to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+/// The authenticated identity behind a request
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+/// Checks credentials and resolves them to a principal
+pub trait AuthProvider: Send + Sync {
+    /// Returns the principal when the credentials are valid for this provider
+    fn authenticate(&self, credentials: &Credentials) -> Option<Principal>;
+}
+
+/// Runs the providers in order and returns the first principal that matches
+pub fn authenticate(providers: &[Box<dyn AuthProvider>], header: Option<&str>) -> Option<Principal> {
+    let credentials = Credentials::from_header(header?)?;
+    providers
+        .iter()
+        .find_map(|provider| provider.authenticate(&credentials))
+}
+
+/// A single user/password pair given on the command line
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    user: String,
+    password: String,
+}
+
+impl BasicAuth {
+    pub fn new(user: &str, password: &str) -> BasicAuth {
+        BasicAuth {
+            user: user.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// Parses a `USER:PASSWORD` pair
+    pub fn parse(value: &str) -> Result<BasicAuth, String> {
+        match value.split_once(':') {
+            Some((user, password)) if !user.is_empty() => Ok(BasicAuth::new(user, password)),
+            _ => Err(format!("expected USER:PASSWORD, got '{}'", value)),
+        }
+    }
+}
+
+impl AuthProvider for BasicAuth {
+    fn authenticate(&self, credentials: &Credentials) -> Option<Principal> {
+        match credentials {
+            Credentials::Basic { user, password }
+                if *user == self.user && constant_time_eq(password.as_bytes(), self.password.as_bytes()) =>
+            {
+                Some(Principal {
+                    name: user.clone(),
+                    groups: Vec::new(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Bearer tokens signed as HS256 JWTs with a shared secret
+pub struct JwtAuth {
+    secret: Vec<u8>,
+}
+
+impl JwtAuth {
+    pub fn new(secret: &str) -> JwtAuth {
+        JwtAuth {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    /// Verifies the signature and validity period of a token and returns its claims
+    ///
+    /// Tokens have to say when they expire; one that says when it starts to
+    /// count (`nbf`) is refused before then.
+    fn verify(&self, token: &str) -> Option<serde_json::Value> {
+        let mut parts = token.split('.');
+        let (header, payload, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        let signed = &token[..header.len() + 1 + payload.len()];
+
+        let header: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        if header["alg"] != "HS256" {
+            return None;
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).ok()?;
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+
+        let claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        if now >= claims["exp"].as_u64()? {
+            return None;
+        }
+        if !claims["nbf"].is_null() && claims["nbf"].as_u64().is_none_or(|nbf| now < nbf) {
+            return None;
+        }
+        Some(claims)
+    }
+}
+
+impl AuthProvider for JwtAuth {
+    fn authenticate(&self, credentials: &Credentials) -> Option<Principal> {
+        let Credentials::Bearer(token) = credentials else {
+            return None;
+        };
+        let claims = self.verify(token)?;
+        // Without a subject there is no one to grant anything to
+        let name = claims["sub"].as_str().filter(|sub| !sub.is_empty())?;
+        Some(Principal {
+            name: name.to_string(),
+            groups: claims["groups"]
+                .as_array()
+                .map(|groups| groups.iter().filter_map(|g| g.as_str().map(String::from)).collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(claims: serde_json::Value) -> Credentials {
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let signed = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(claims.to_string()));
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(signed.as_bytes());
+        Credentials::Bearer(format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())))
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn jwts_need_a_subject_and_a_current_validity_period() {
+        let jwt = JwtAuth::new("secret");
+        let valid = jwt.authenticate(&token(serde_json::json!({"sub": "ada", "exp": now() + 60, "groups": ["ops"]})));
+        let principal = valid.unwrap();
+        assert_eq!((principal.name.as_str(), principal.groups), ("ada", vec!["ops".to_string()]));
+        assert!(jwt.authenticate(&token(serde_json::json!({"sub": "ada", "exp": now() + 60, "nbf": now() - 1}))).is_some());
+
+        assert!(jwt.authenticate(&token(serde_json::json!({"sub": "ada"}))).is_none());
+        assert!(jwt.authenticate(&token(serde_json::json!({"sub": "ada", "exp": now() - 1}))).is_none());
+        assert!(jwt.authenticate(&token(serde_json::json!({"sub": "ada", "exp": now() + 60, "nbf": now() + 60}))).is_none());
+        assert!(jwt.authenticate(&token(serde_json::json!({"sub": "ada", "exp": now() + 60, "nbf": "soon"}))).is_none());
+        assert!(jwt.authenticate(&token(serde_json::json!({"exp": now() + 60}))).is_none());
+        assert!(jwt.authenticate(&token(serde_json::json!({"sub": "", "exp": now() + 60}))).is_none());
+        assert!(JwtAuth::new("other").authenticate(&token(serde_json::json!({"sub": "ada", "exp": now() + 60}))).is_none());
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let basic = BasicAuth::parse("ada:pass:word").unwrap();
+        assert!(authenticate(&[Box::new(basic.clone())], Some("Basic YWRhOnBhc3M6d29yZA==")).is_some());
+        assert!(authenticate(&[Box::new(basic)], Some("Basic YWRhOnBhc3M=")).is_none());
+        assert!(BasicAuth::parse(":password").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::lan;
+use crate::listener::Listener;
+use std::path::Path;
+
+/// What the server ended up doing, printed once it is ready to serve
+#[derive(Default)]
+pub struct Banner {
+    roots: Vec<String>,
+    features: Vec<(&'static str, String)>,
+    /// Path of a link to print for each HTTP endpoint
+    share: Option<String>,
+    /// Whether to draw a QR code of the first address reachable from the network
+    qr_code: bool,
+}
+
+impl Banner {
+    /// Adds a served directory, for `host` or the default one
+    pub fn root(&mut self, host: Option<&str>, dir: &Path) {
+        self.roots.push(match host {
+            Some(host) => format!("{} -> {}", host, dir.display()),
+            None => dir.display().to_string(),
+        });
+    }
+
+    /// Adds a line such as `Cache  memory`
+    pub fn feature(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.features.push((name, detail.into()));
+    }
+
+    /// Adds a `Share` line with a link to `path` on every HTTP endpoint
+    pub fn share(&mut self, path: String) {
+        self.share = Some(path);
+    }
+
+    /// Ends the summary with a QR code of the first address reachable from the network, if any
+    pub fn qr_code(&mut self) {
+        self.qr_code = true;
+    }
+
+    pub fn print(&self, listeners: &[Listener]) {
+        let build = if cfg!(debug_assertions) { "debug" } else { "release" };
+        println!("rshttp {} ({} build)", env!("CARGO_PKG_VERSION"), build);
+        for root in &self.roots {
+            println!("  {:<10} {}", "Root", root);
+        }
+
+        // Sockets of one SO_REUSEPORT group share an endpoint
+        let mut endpoints: Vec<(String, usize)> = Vec::new();
+        for listener in listeners {
+            let endpoint = listener.endpoint();
+            match endpoints.last_mut() {
+                Some((last, count)) if *last == endpoint => *count += 1,
+                _ => endpoints.push((endpoint, 1)),
+            }
+        }
+        for (endpoint, count) in endpoints {
+            match count {
+                1 => println!("  {:<10} {}", "Listening", endpoint),
+                _ => println!("  {:<10} {} ({} sockets, SO_REUSEPORT)", "Listening", endpoint, count),
+            }
+            if let Some(path) = self.share.as_ref().filter(|_| endpoint.starts_with("http://")) {
+                println!("  {:<10} {}{}", "Share", endpoint.trim_end_matches('/'), path);
+            }
+        }
+
+        let lan_urls = lan::urls(listeners);
+        for url in &lan_urls {
+            println!("  {:<10} {}", "Network", url);
+        }
+        println!("  {:<10} off, plain HTTP", "TLS");
+
+        for (name, detail) in &self.features {
+            println!("  {:<10} {}", name, detail);
+        }
+        println!("  {:<10} {}", "Built with", built_features().join(", "));
+        if let Some(code) = lan_urls.first().filter(|_| self.qr_code).and_then(|url| lan::qr_code(url)) {
+            println!();
+            println!("{}", code);
+        }
+    }
+}
+
+/// The optional cargo features compiled in, or `none`
+fn built_features() -> Vec<&'static str> {
+    let features = [
+        ("watch", cfg!(feature = "watch")),
+        ("webhook", cfg!(feature = "webhook")),
+        ("fallback", cfg!(feature = "fallback")),
+        ("oidc", cfg!(feature = "oidc")),
+        ("sqlite", cfg!(feature = "sqlite")),
+        ("git", cfg!(feature = "git")),
+        ("s3", cfg!(feature = "s3")),
+        ("geoip", cfg!(feature = "geoip")),
+        ("async", cfg!(feature = "async")),
+        ("io-uring", cfg!(feature = "io-uring")),
+    ];
+    let built: Vec<&str> = features.iter().filter(|(_, built)| *built).map(|(name, _)| *name).collect();
+    match built.is_empty() {
+        true => vec!["none"],
+        false => built,
+    }
+}
@@ -0,0 +1,153 @@
+use crate::metrics::Metrics;
+use crate::{units, walk};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long one request may take before it counts as failed
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Arguments of the `bench` subcommand
+#[derive(clap::Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// URL paths to request in turn, by default every file below the served directory
+    /// whose name can go into a URL as it is
+    paths: Vec<String>,
+    /// Requests in flight at the same time, each on its own connection
+    #[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+    /// Requests to send in all
+    #[arg(long, value_name = "N", default_value_t = 10_000, conflicts_with = "duration")]
+    requests: u64,
+    /// Send requests for this long (e.g. 30s) instead of a number of them
+    #[arg(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    duration: Option<Duration>,
+}
+
+/// What one client thread saw
+#[derive(Default)]
+struct Tally {
+    latencies: Vec<Duration>,
+    bytes: u64,
+    errors: u64,
+    unsuccessful: u64,
+}
+
+/// Requests files from the server started in this process, at `address`, and prints a report
+///
+/// Returns the process exit code: 1 if any request failed or was answered
+/// with an error status, 0 otherwise. The cache hit rate is read from the
+/// server's own counters.
+pub fn run(args: &BenchArgs, address: SocketAddr, root: &Path, metrics: &Metrics) -> i32 {
+    let paths: Vec<String> = match args.paths.is_empty() {
+        true => (walk::files(root).iter())
+            .filter_map(|file| file.strip_prefix(root).ok())
+            .map(|relative| format!("/{}", relative.to_string_lossy().replace('\\', "/")))
+            // Request paths are not percent-decoded, so these names can't be requested
+            .filter(|path| !path.contains(|c: char| c.is_whitespace() || c.is_control() || "?#%".contains(c)))
+            .collect(),
+        false => (args.paths.iter()).map(|path| format!("/{}", path.trim_start_matches('/'))).collect(),
+    };
+    if paths.is_empty() {
+        eprintln!("Error: {} holds no files to request", root.display());
+        return 1;
+    }
+    let before = metrics.snapshot(0);
+    let next = Arc::new(AtomicU64::new(0));
+    let paths = Arc::new(paths);
+    let started = Instant::now();
+    let deadline = args.duration.map(|duration| started + duration);
+    let clients: Vec<_> = (0..args.concurrency)
+        .map(|_| {
+            let (next, paths, total) = (Arc::clone(&next), Arc::clone(&paths), args.requests);
+            thread::spawn(move || {
+                let mut tally = Tally::default();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let done = match deadline {
+                        Some(deadline) => Instant::now() >= deadline,
+                        None => index >= total,
+                    };
+                    if done {
+                        return tally;
+                    }
+                    let sent = Instant::now();
+                    match fetch(address, &paths[index as usize % paths.len()]) {
+                        Ok((status, len)) => {
+                            tally.latencies.push(sent.elapsed());
+                            tally.bytes += len;
+                            if !(200..400).contains(&status) {
+                                tally.unsuccessful += 1;
+                            }
+                        }
+                        Err(_) => tally.errors += 1,
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut total = Tally::default();
+    for client in clients {
+        let tally = client.join().unwrap_or_default();
+        total.latencies.extend(tally.latencies);
+        total.bytes += tally.bytes;
+        total.errors += tally.errors;
+        total.unsuccessful += tally.unsuccessful;
+    }
+    let elapsed = started.elapsed();
+    let after = metrics.snapshot(0);
+
+    let answered = total.latencies.len() as u64;
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    total.latencies.sort();
+    let percentile = |p: usize| match total.latencies.is_empty() {
+        true => Duration::ZERO,
+        false => total.latencies[(total.latencies.len() - 1) * p / 100],
+    };
+    println!("=== rshttp bench ===");
+    println!("Requests:       {} to {} path(s), {} at a time", answered, paths.len(), args.concurrency);
+    println!("Duration:       {}", units::duration(elapsed));
+    println!(
+        "Throughput:     {:.1} requests/s, {}/s",
+        answered as f64 / seconds,
+        units::size((total.bytes as f64 / seconds) as u64)
+    );
+    println!(
+        "Latency:        p50 {}, p90 {}, p99 {}, max {}",
+        millis(percentile(50)),
+        millis(percentile(90)),
+        millis(percentile(99)),
+        millis(percentile(100))
+    );
+    let (hits, misses) = (after.cache_hits - before.cache_hits, after.cache_misses - before.cache_misses);
+    if hits + misses > 0 {
+        let rate = hits as f64 * 100.0 / (hits + misses) as f64;
+        println!("Cache hits:     {} / {} ({:.1}%)", hits, hits + misses, rate);
+    }
+    println!("Error statuses: {}", total.unsuccessful);
+    println!("Failed:         {}", total.errors);
+    i32::from(total.errors + total.unsuccessful > 0)
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// GETs `path` on a new connection, returning the status and the bytes received
+fn fetch(address: SocketAddr, path: &str) -> io::Result<(u16, u64)> {
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let status = (response.get(9..12))
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response"))?;
+    Ok((status, response.len() as u64))
+}
@@ -0,0 +1,46 @@
+use crate::listener::Listener;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::{Command, Stdio};
+use tracing::{info, warn};
+
+/// The URL of `path` on the first TCP listener, as a browser on this machine reaches it
+pub fn local_url(listeners: &[Listener], path: &str) -> Option<String> {
+    let address = local_address(listeners)?;
+    Some(format!("http://{}/{}", address, path.trim_start_matches('/')))
+}
+
+/// The address of the first TCP listener, as a client on this machine reaches it
+///
+/// A listener on all interfaces (0.0.0.0 or ::) is reached through loopback.
+pub fn local_address(listeners: &[Listener]) -> Option<SocketAddr> {
+    let mut address = listeners.iter().find_map(Listener::tcp_address)?;
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    Some(address)
+}
+
+/// Opens `url` in the default browser, for --open
+///
+/// Uses `open` on macOS, `start` on Windows and `xdg-open` elsewhere. The
+/// browser runs on its own; a failure to start it is only logged.
+pub fn open(url: &str) {
+    #[cfg(target_os = "macos")]
+    let mut command = Command::new("open");
+    #[cfg(windows)]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    };
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let mut command = Command::new("xdg-open");
+    let spawned = command.arg(url).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn();
+    match spawned {
+        Ok(_) => info!("Opened {} in the browser", url),
+        Err(e) => warn!("Failed to open {} in the browser: {}", url, e),
+    }
+}
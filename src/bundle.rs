@@ -0,0 +1,124 @@
+use crate::sitearchive::SiteArchive;
+use crate::{archive, units, walk};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Arguments of the `bundle` subcommand
+#[derive(clap::Args, Debug)]
+pub struct BundleArgs {
+    /// Directory whose files are built into the executable
+    directory: PathBuf,
+    /// The executable to write
+    #[arg(short, long, value_name = "FILE")]
+    output: PathBuf,
+}
+
+/// Ends an executable with a site built in, after the length of the site
+const MAGIC: &[u8; 8] = b"rshttp\x00S";
+
+/// Length of the site's length and [`MAGIC`]
+const TRAILER_LEN: u64 = 16;
+
+/// Writes a copy of this executable with the files of a directory built in, returning the process exit code
+///
+/// The files are appended to the executable as a gzip-compressed tar,
+/// followed by its length and [`MAGIC`]. The copy finds them at startup, see
+/// [`embedded`], and serves them instead of a directory, so it runs anywhere
+/// without the site next to it.
+pub fn run(args: &BundleArgs) -> i32 {
+    if !args.directory.is_dir() {
+        eprintln!("Error: {} is not a directory", args.directory.display());
+        return 2;
+    }
+    let exe = std::env::current_exe().and_then(fs::canonicalize).ok();
+    if exe.is_some() && args.output.canonicalize().ok() == exe {
+        eprintln!("Error: {} is the running executable", args.output.display());
+        return 2;
+    }
+    match bundle(&args.directory, &args.output) {
+        Ok((files, site_len)) => {
+            println!("Bundled {} files ({}) into {}", files, units::size(site_len), args.output.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: cannot write {}: {}", args.output.display(), e);
+            let _ = fs::remove_file(&args.output);
+            1
+        }
+    }
+}
+
+/// The site built into the running executable, if it was written by `rshttp bundle`
+pub fn embedded() -> Option<Result<SiteArchive, String>> {
+    let exe = std::env::current_exe().ok()?;
+    let mut file = File::open(&exe).ok()?;
+    let (server_len, site_len) = split(&mut file).ok()?;
+    if site_len == 0 {
+        return None;
+    }
+    file.seek(SeekFrom::Start(server_len)).ok()?;
+    Some(SiteArchive::unpack(&exe, file.take(site_len)))
+}
+
+/// Writes the executable and returns how many files went into it and how large they were compressed
+fn bundle(dir: &Path, output: &Path) -> io::Result<(usize, u64)> {
+    let mut exe = File::open(std::env::current_exe()?)?;
+    // A bundled executable bundles again without the site it carries
+    let (server_len, _) = split(&mut exe)?;
+    exe.seek(SeekFrom::Start(0))?;
+    let mut out = BufWriter::new(File::create(output)?);
+    io::copy(&mut exe.take(server_len), &mut out)?;
+
+    let files = walk::files(dir);
+    let entries = files.iter().filter_map(|file| {
+        let relative = file.strip_prefix(dir).ok()?.to_string_lossy().replace('\\', "/");
+        Some((file.as_path(), relative))
+    });
+    let mut counted = Counted { out: &mut out, len: 0 };
+    archive::write_tar_gz(&mut counted, &archive::entries(entries))?;
+    let site_len = counted.len;
+    out.write_all(&site_len.to_le_bytes())?;
+    out.write_all(MAGIC)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(output, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok((files.len(), site_len))
+}
+
+/// Lengths of the server in an executable and of the site built into it, 0 without one
+fn split(exe: &mut File) -> io::Result<(u64, u64)> {
+    let len = exe.seek(SeekFrom::End(0))?;
+    if len < TRAILER_LEN {
+        return Ok((len, 0));
+    }
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    exe.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    exe.read_exact(&mut trailer)?;
+    let site_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    match &trailer[8..] == MAGIC && site_len <= len - TRAILER_LEN {
+        true => Ok((len - TRAILER_LEN - site_len, site_len)),
+        false => Ok((len, 0)),
+    }
+}
+
+/// Counts the bytes written through it
+struct Counted<W> {
+    out: W,
+    len: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,901 @@
+use crate::units;
+use rshttp::middleware::Encoding;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// A file body kept around so it doesn't have to be read again
+pub struct CachedFile {
+    pub contents: Vec<u8>,
+    pub mime_type: String,
+    /// When the file was last modified as it was read, if known
+    pub modified: Option<SystemTime>,
+    /// When the entry stops being served, for files whose origin limits how long they may be kept
+    pub expires: Option<SystemTime>,
+    /// When the entry was last found to match the file
+    checked: Mutex<Instant>,
+    /// Requests served from this entry
+    pub hits: AtomicU64,
+    /// The contents compressed with each coding asked for so far, dropped with the entry
+    encoded: Mutex<HashMap<Encoding, Arc<Vec<u8>>>>,
+}
+
+impl CachedFile {
+    pub fn new(contents: Vec<u8>, mime_type: String, modified: Option<SystemTime>) -> CachedFile {
+        CachedFile {
+            contents,
+            mime_type,
+            modified,
+            expires: None,
+            checked: Mutex::new(Instant::now()),
+            hits: AtomicU64::new(0),
+            encoded: Mutex::default(),
+        }
+    }
+
+    /// The contents compressed with `encoding`, and whether they were compressed just now
+    ///
+    /// Variants go when the entry is replaced or removed, e.g. once the
+    /// watcher sees the file change. A new one has to be reported to the
+    /// cache with [`CacheBackend::grown`] to count against its budget.
+    pub fn encoded(&self, encoding: Encoding) -> (Arc<Vec<u8>>, bool) {
+        if let Some(encoded) = lock(&self.encoded).get(&encoding) {
+            return (Arc::clone(encoded), false);
+        }
+        let encoded = Arc::new(encoding.encode(&self.contents));
+        lock(&self.encoded).insert(encoding, Arc::clone(&encoded));
+        (encoded, true)
+    }
+
+    /// The bytes the entry holds, compressed variants included
+    pub fn size(&self) -> u64 {
+        let variants: usize = lock(&self.encoded).values().map(|encoded| encoded.len()).sum();
+        (self.contents.len() + variants) as u64
+    }
+
+    /// Marks the entry to be dropped once `ttl` has passed
+    pub fn expiring_after(mut self, ttl: Duration) -> CachedFile {
+        self.expires = Some(SystemTime::now() + ttl);
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= SystemTime::now())
+    }
+
+    /// How much longer the entry may be served, if it expires
+    pub fn time_to_live(&self) -> Option<Duration> {
+        self.expires.map(|expires| expires.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    /// Whether the file at `path` still has the cached size and modification time
+    ///
+    /// The file is looked at no more than once per `ttl`; until then the entry
+    /// is taken to be fresh. Entries whose modification time is unknown, or
+    /// whose file is gone, are stale once `ttl` has passed.
+    pub fn is_fresh(&self, path: &Path, ttl: Duration) -> bool {
+        let mut checked = lock(&self.checked);
+        if checked.elapsed() < ttl {
+            return true;
+        }
+        let fresh = match fs::metadata(path) {
+            Ok(metadata) => {
+                metadata.len() == self.contents.len() as u64
+                    && self.modified.is_some()
+                    && metadata.modified().ok() == self.modified
+            }
+            Err(_) => false,
+        };
+        if fresh {
+            *checked = Instant::now();
+        }
+        fresh
+    }
+}
+
+/// Where served files are cached, keyed by their path on disk
+pub trait CacheBackend: Send + Sync {
+    fn get(&self, path: &Path) -> Option<Arc<CachedFile>>;
+    fn insert(&self, path: PathBuf, file: Arc<CachedFile>);
+    /// Drops the entry, e.g. because the file changed
+    fn remove(&self, path: &Path);
+    /// Puts a new version of a changed file in place of the old one
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    fn replace(&self, path: PathBuf, file: Arc<CachedFile>) {
+        self.insert(path, file);
+    }
+    /// Drops every entry below `dir`, e.g. because the directory was replaced as a whole
+    fn remove_under(&self, dir: &Path);
+    /// Counts a variant added to `file` against the budget, if it is still the entry for `path`
+    fn grown(&self, _path: &Path, _file: &CachedFile) {}
+    /// How much of the memory budget is in use, for caches that have one
+    fn usage(&self) -> Option<Usage> {
+        None
+    }
+    /// Every entry held in this process, for inspection
+    fn entries(&self) -> Vec<(PathBuf, Arc<CachedFile>)> {
+        Vec::new()
+    }
+}
+
+/// What [`CacheBackend::usage`] reports
+pub struct Usage {
+    pub entries: usize,
+    pub bytes: u64,
+    pub budget: u64,
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} file(s), {} of {} ({:.1}%)",
+            self.entries,
+            units::size(self.bytes),
+            units::size(self.budget),
+            self.bytes as f64 * 100.0 / self.budget.max(1) as f64
+        )
+    }
+}
+
+/// A cache several instances use together
+pub trait SharedCache: CacheBackend {
+    /// Tells every instance that `path` changed
+    fn announce_removal(&self, path: &Path);
+    /// Tells every instance that everything below `dir` changed
+    fn announce_removal_under(&self, dir: &Path);
+    /// Calls `on_removal` for every announcement, and with [`Removal::Unknown`]
+    /// whenever announcements may have been missed; blocks while connected
+    fn follow_removals(&self, on_removal: &dyn Fn(Removal));
+}
+
+/// A change another instance announced, see [`SharedCache::follow_removals`]
+pub enum Removal<'a> {
+    Path(&'a Path),
+    /// Everything below the directory
+    Under(&'a Path),
+    /// Announcements may have been missed
+    Unknown,
+}
+
+/// The in-process cache every instance has
+///
+/// Holds at most `budget` bytes of file contents and their compressed
+/// variants. Inserting past the budget evicts the entries that were served
+/// least recently.
+///
+/// Entries are spread over [`SHARDS`] independently locked shards by path,
+/// so requests for different files rarely wait on each other, nor on an
+/// invalidation or insert elsewhere. Each shard gets an equal part of the
+/// budget and evicts on its own, so eviction order is only least recently
+/// served within a shard, and a file larger than a shard's part is not
+/// cached at all.
+pub struct MemoryCache {
+    budget: u64,
+    shards: Vec<Mutex<Lru>>,
+}
+
+/// Number of separately locked parts of a [`MemoryCache`]
+const SHARDS: usize = 16;
+
+#[derive(Default)]
+struct Lru {
+    /// Each entry with the tick it was last served at and its size when last counted
+    files: HashMap<PathBuf, (Arc<CachedFile>, u64, u64)>,
+    /// Paths by the tick they were last served at, oldest first
+    recency: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, path: &Path) -> Option<Arc<CachedFile>> {
+        let tick = self.tick + 1;
+        let (file, last, _) = self.files.get_mut(path)?;
+        let path = self.recency.remove(last)?;
+        *last = tick;
+        self.recency.insert(tick, path);
+        self.tick = tick;
+        Some(Arc::clone(file))
+    }
+
+    fn insert(&mut self, path: PathBuf, file: Arc<CachedFile>, budget: u64) {
+        let size = file.size();
+        self.remove(&path);
+        if size > budget {
+            return;
+        }
+        while self.bytes + size > budget {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((_, _, evicted)) = self.files.remove(&oldest) {
+                self.bytes -= evicted;
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, path.clone());
+        self.files.insert(path, (file, self.tick, size));
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some((_, last, size)) = self.files.remove(path) {
+            self.recency.remove(&last);
+            self.bytes -= size;
+        }
+    }
+
+    /// Counts the entry again, leaving it out if it was replaced or removed meanwhile
+    fn grown(&mut self, path: &Path, file: &CachedFile, budget: u64) {
+        let held = match self.files.get(path) {
+            Some((held, _, _)) if std::ptr::eq(Arc::as_ptr(held), file) => Arc::clone(held),
+            _ => return,
+        };
+        self.insert(path.to_path_buf(), held, budget);
+    }
+}
+
+impl MemoryCache {
+    pub fn new(budget: u64) -> MemoryCache {
+        MemoryCache {
+            budget,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, path: &Path) -> MutexGuard<'_, Lru> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        lock(&self.shards[hasher.finish() as usize % SHARDS])
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            *lock(shard) = Lru::default();
+        }
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, path: &Path) -> Option<Arc<CachedFile>> {
+        self.shard(path).touch(path)
+    }
+
+    fn insert(&self, path: PathBuf, file: Arc<CachedFile>) {
+        let budget = self.budget / SHARDS as u64;
+        self.shard(&path).insert(path, file, budget);
+    }
+
+    fn remove(&self, path: &Path) {
+        self.shard(path).remove(path);
+    }
+
+    fn grown(&self, path: &Path, file: &CachedFile) {
+        let budget = self.budget / SHARDS as u64;
+        self.shard(path).grown(path, file, budget);
+    }
+
+    fn remove_under(&self, dir: &Path) {
+        // One shard at a time, so the others keep serving meanwhile
+        for shard in &self.shards {
+            let mut lru = lock(shard);
+            let under: Vec<PathBuf> = lru.files.keys().filter(|path| path.starts_with(dir)).cloned().collect();
+            for path in under {
+                lru.remove(&path);
+            }
+        }
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        let mut usage = Usage {
+            entries: 0,
+            bytes: 0,
+            budget: self.budget,
+        };
+        for shard in &self.shards {
+            let lru = lock(shard);
+            usage.entries += lru.files.len();
+            usage.bytes += lru.bytes;
+        }
+        Some(usage)
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, Arc<CachedFile>)> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            let lru = lock(shard);
+            entries.extend(lru.files.iter().map(|(path, (file, _, _))| (path.clone(), Arc::clone(file))));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A cache that keeps nothing, so every request reads from disk
+pub struct NoCache;
+
+impl CacheBackend for NoCache {
+    fn get(&self, _path: &Path) -> Option<Arc<CachedFile>> {
+        None
+    }
+
+    fn insert(&self, _path: PathBuf, _file: Arc<CachedFile>) {}
+
+    fn remove(&self, _path: &Path) {}
+
+    fn remove_under(&self, _dir: &Path) {}
+}
+
+/// Reads in progress, so that concurrent misses on one file read it only once
+///
+/// The first request to miss reads the file and inserts it into the cache;
+/// requests missing on the same path meanwhile wait for that read and share
+/// its result, including its error.
+#[derive(Default)]
+pub struct Loads(Mutex<HashMap<PathBuf, Arc<Load>>>);
+
+/// What a load ended with; errors keep their kind and message since [`io::Error`] can't be cloned
+type LoadResult = Result<Arc<CachedFile>, (io::ErrorKind, String)>;
+
+#[derive(Default)]
+struct Load {
+    result: Mutex<Option<LoadResult>>,
+    done: Condvar,
+}
+
+impl Loads {
+    /// Returns the cached file at `path`, reading it with `read` unless another request already is
+    pub fn get_or_load(
+        &self,
+        cache: &dyn CacheBackend,
+        path: &Path,
+        read: impl FnOnce() -> io::Result<CachedFile>,
+    ) -> io::Result<Arc<CachedFile>> {
+        let (load, leader) = {
+            let mut loads = lock(&self.0);
+            match loads.get(path) {
+                Some(load) => (Arc::clone(load), false),
+                None => {
+                    let load = Arc::new(Load::default());
+                    loads.insert(path.to_path_buf(), Arc::clone(&load));
+                    (load, true)
+                }
+            }
+        };
+
+        if leader {
+            // Finishes the load even if `read` panics, so nobody waits forever
+            let _finish = Finish { loads: self, path, load: &load };
+            // It may have been inserted between our miss and taking the lead
+            let result = match cache.get(path) {
+                Some(file) => Ok(file),
+                None => read().map(|file| {
+                    let file = Arc::new(file);
+                    cache.insert(path.to_path_buf(), Arc::clone(&file));
+                    file
+                }),
+            };
+            *lock(&load.result) =
+                Some(result.as_ref().map(Arc::clone).map_err(|e| (e.kind(), e.to_string())));
+            return result;
+        }
+
+        let mut result = lock(&load.result);
+        while result.is_none() {
+            result = load.done.wait(result).unwrap_or_else(PoisonError::into_inner);
+        }
+        match result.as_ref() {
+            Some(Ok(file)) => Ok(Arc::clone(file)),
+            Some(Err((kind, message))) => Err(io::Error::new(*kind, message.clone())),
+            None => unreachable!(),
+        }
+    }
+}
+
+/// Ends a [`Load`] when the reading request is done with it
+struct Finish<'a> {
+    loads: &'a Loads,
+    path: &'a Path,
+    load: &'a Load,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        lock(&self.loads.0).remove(self.path);
+        let mut result = lock(&self.load.result);
+        result.get_or_insert_with(|| Err((io::ErrorKind::Other, "the read failed".to_string())));
+        self.load.done.notify_all();
+    }
+}
+
+/// A local cache in front of one shared by several instances
+///
+/// Hits from the shared tier are copied into the local one. Changes are
+/// removed from both and announced, so every instance drops the old content
+/// from its local tier too once [`Tiered::follow_removals`] runs.
+pub struct Tiered<S> {
+    local: MemoryCache,
+    shared: S,
+}
+
+impl<S: SharedCache + 'static> Tiered<S> {
+    pub fn new(local: MemoryCache, shared: S) -> Tiered<S> {
+        Tiered {
+            local,
+            shared,
+        }
+    }
+
+    /// Drops local entries whenever another instance announces a change, on a thread of its own
+    pub fn follow_removals(self: &Arc<Self>) {
+        let tiered = Arc::clone(self);
+        thread::spawn(move || loop {
+            tiered.shared.follow_removals(&|removal| match removal {
+                Removal::Path(path) => tiered.local.remove(path),
+                Removal::Under(dir) => tiered.local.remove_under(dir),
+                Removal::Unknown => tiered.local.clear(),
+            });
+            thread::sleep(RECONNECT_DELAY);
+        });
+    }
+}
+
+impl<S: SharedCache + 'static> CacheBackend for Tiered<S> {
+    fn get(&self, path: &Path) -> Option<Arc<CachedFile>> {
+        if let Some(file) = self.local.get(path) {
+            return Some(file);
+        }
+        let file = self.shared.get(path)?;
+        self.local.insert(path.to_path_buf(), Arc::clone(&file));
+        Some(file)
+    }
+
+    fn insert(&self, path: PathBuf, file: Arc<CachedFile>) {
+        if file.contents.len() <= SHARED_MAX_SIZE {
+            self.shared.insert(path.clone(), Arc::clone(&file));
+        }
+        self.local.insert(path, file);
+    }
+
+    fn remove(&self, path: &Path) {
+        self.local.remove(path);
+        self.shared.remove(path);
+        self.shared.announce_removal(path);
+    }
+
+    fn replace(&self, path: PathBuf, file: Arc<CachedFile>) {
+        // Other instances drop their local copy and pick up this one from the shared tier
+        self.insert(path.clone(), file);
+        self.shared.announce_removal(&path);
+    }
+
+    fn remove_under(&self, dir: &Path) {
+        self.local.remove_under(dir);
+        self.shared.remove_under(dir);
+        self.shared.announce_removal_under(dir);
+    }
+
+    fn grown(&self, path: &Path, file: &CachedFile) {
+        self.local.grown(path, file);
+    }
+
+    fn usage(&self) -> Option<Usage> {
+        self.local.usage()
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, Arc<CachedFile>)> {
+        self.local.entries()
+    }
+}
+
+/// Larger files are only cached locally, so the shared tier holds hot assets rather than downloads
+const SHARED_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+/// How long a Redis command may take before the request goes on without the shared tier
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Prefix of every key the server stores in Redis
+const KEY_PREFIX: &str = "rshttp:file:v3:";
+
+/// Pub/sub channel carrying the paths of changed files
+const REMOVALS_CHANNEL: &str = "rshttp:removed";
+
+/// Pub/sub channel carrying the paths of directories whose every file changed
+const REMOVALS_UNDER_CHANNEL: &str = "rshttp:removed-under";
+
+/// Pause before subscribing again after losing the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A cache in Redis, shared by every instance pointed at it
+///
+/// The connection is made on first use and again after any error, so the
+/// server keeps working (with only its local cache) while Redis is down.
+/// Keys and announcements use paths on disk, so all instances must serve
+/// the content from the same location.
+pub struct RedisCache {
+    address: String,
+    password: Option<String>,
+    database: Option<u32>,
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisCache {
+    /// Parses `redis://[:password@]host[:port][/db]`
+    pub fn new(url: &str) -> Result<RedisCache, String> {
+        let url = url::Url::parse(url).map_err(|e| e.to_string())?;
+        if url.scheme() != "redis" {
+            return Err(format!("unsupported scheme {}, expected redis://", url.scheme()));
+        }
+        let host = url.host_str().ok_or("missing host")?;
+        let database = match url.path().trim_start_matches('/') {
+            "" => None,
+            db => Some(db.parse().map_err(|_| format!("invalid database number {}", db))?),
+        };
+        Ok(RedisCache {
+            address: format!("{}:{}", host, url.port().unwrap_or(6379)),
+            password: url.password().map(str::to_string),
+            database,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// The host:port connected to
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Runs one command, reconnecting first if needed
+    fn command(&self, args: &[&[u8]]) -> Option<Reply> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            match self.connect() {
+                Ok(stream) => *connection = Some(stream),
+                Err(e) => {
+                    warn!("Shared cache unavailable ({}): {}", self.address, e);
+                    return None;
+                }
+            }
+        }
+        let stream = connection.as_mut()?;
+        match send(stream, args) {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                warn!("Shared cache error ({}): {}", self.address, e);
+                *connection = None;
+                None
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        stream.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        let mut stream = BufReader::new(stream);
+        if let Some(password) = &self.password {
+            expect_ok(send(&mut stream, &[b"AUTH", password.as_bytes()])?)?;
+        }
+        if let Some(database) = self.database {
+            expect_ok(send(&mut stream, &[b"SELECT", database.to_string().as_bytes()])?)?;
+        }
+        Ok(stream)
+    }
+}
+
+impl SharedCache for RedisCache {
+    fn announce_removal(&self, path: &Path) {
+        self.command(&[b"PUBLISH", REMOVALS_CHANNEL.as_bytes(), path.display().to_string().as_bytes()]);
+    }
+
+    fn announce_removal_under(&self, dir: &Path) {
+        self.command(&[b"PUBLISH", REMOVALS_UNDER_CHANNEL.as_bytes(), dir.display().to_string().as_bytes()]);
+    }
+
+    fn follow_removals(&self, on_removal: &dyn Fn(Removal)) {
+        let subscribe = || -> io::Result<BufReader<TcpStream>> {
+            let mut stream = self.connect()?;
+            send(&mut stream, &[b"SUBSCRIBE", REMOVALS_CHANNEL.as_bytes(), REMOVALS_UNDER_CHANNEL.as_bytes()])?;
+            // Announcements can be minutes apart
+            stream.get_ref().set_read_timeout(None)?;
+            Ok(stream)
+        };
+        let mut stream = match subscribe() {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Cannot follow shared cache changes ({}): {}", self.address, e);
+                return;
+            }
+        };
+        // Whatever changed while we weren't subscribed went unannounced
+        on_removal(Removal::Unknown);
+
+        loop {
+            match read_reply(&mut stream) {
+                Ok(Reply::Array(parts)) => {
+                    if let [Reply::Bulk(Some(kind)), Reply::Bulk(Some(channel)), Reply::Bulk(Some(path))] = parts.as_slice() {
+                        let path = String::from_utf8_lossy(path);
+                        if kind == b"message" && channel == REMOVALS_UNDER_CHANNEL.as_bytes() {
+                            on_removal(Removal::Under(Path::new(&*path)));
+                        } else if kind == b"message" {
+                            on_removal(Removal::Path(Path::new(&*path)));
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Lost shared cache changes subscription ({}): {}", self.address, e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl CacheBackend for RedisCache {
+    fn get(&self, path: &Path) -> Option<Arc<CachedFile>> {
+        let Reply::Bulk(Some(value)) = self.command(&[b"GET", &key(path)])? else {
+            return None;
+        };
+        // Stored as the MIME type, the modification and expiry times in nanoseconds
+        // since the epoch (empty if unknown or none) and the contents, separated by newlines
+        let mut parts = value.splitn(4, |&byte| byte == b'\n');
+        let (mime_type, modified, expires, contents) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let time = |nanos: &[u8]| {
+            std::str::from_utf8(nanos)
+                .ok()
+                .and_then(|nanos| nanos.parse().ok())
+                .map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos))
+        };
+        let mut file = CachedFile::new(contents.to_vec(), String::from_utf8_lossy(mime_type).into_owned(), time(modified));
+        file.expires = time(expires);
+        Some(Arc::new(file))
+    }
+
+    fn insert(&self, path: PathBuf, file: Arc<CachedFile>) {
+        let nanos = |time: Option<SystemTime>| {
+            time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_nanos().to_string())
+                .unwrap_or_default()
+        };
+        let mut value = format!("{}\n{}\n{}\n", file.mime_type, nanos(file.modified), nanos(file.expires)).into_bytes();
+        value.extend_from_slice(&file.contents);
+        match file.time_to_live() {
+            // Redis drops the entry itself when it expires
+            Some(ttl) => {
+                let millis = ttl.as_millis().max(1).to_string();
+                self.command(&[b"SET", &key(&path), &value, b"PX", millis.as_bytes()]);
+            }
+            None => {
+                self.command(&[b"SET", &key(&path), &value]);
+            }
+        }
+    }
+
+    fn remove(&self, path: &Path) {
+        self.command(&[b"DEL", &key(path)]);
+    }
+
+    fn remove_under(&self, dir: &Path) {
+        // Glob characters in the path must match literally
+        let mut pattern = KEY_PREFIX.to_string();
+        for c in dir.display().to_string().chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push_str("/*");
+
+        let mut cursor = b"0".to_vec();
+        loop {
+            let Some(Reply::Array(reply)) = self.command(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"])
+            else {
+                return;
+            };
+            // The next cursor and a batch of matching keys
+            let mut reply = reply.into_iter();
+            let (Some(Reply::Bulk(Some(next))), Some(Reply::Array(keys))) = (reply.next(), reply.next()) else {
+                return;
+            };
+            let keys: Vec<Vec<u8>> = keys
+                .into_iter()
+                .filter_map(|key| match key {
+                    Reply::Bulk(Some(key)) => Some(key),
+                    _ => None,
+                })
+                .collect();
+            if !keys.is_empty() {
+                let mut args: Vec<&[u8]> = vec![b"DEL"];
+                args.extend(keys.iter().map(Vec::as_slice));
+                self.command(&args);
+            }
+            if next == b"0" {
+                return;
+            }
+            cursor = next;
+        }
+    }
+}
+
+fn key(path: &Path) -> Vec<u8> {
+    format!("{}{}", KEY_PREFIX, path.display()).into_bytes()
+}
+
+/// The parts of a RESP reply the cache looks at
+enum Reply {
+    Status,
+    Error(String),
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// Sends a command as a RESP array of bulk strings and reads the reply
+fn send(stream: &mut BufReader<TcpStream>, args: &[&[u8]]) -> io::Result<Reply> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    stream.get_mut().write_all(&command)?;
+    read_reply(stream)
+}
+
+fn read_reply(stream: &mut BufReader<TcpStream>) -> io::Result<Reply> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    let line = line.trim_end();
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", line));
+    match line.split_at_checked(1).ok_or_else(invalid)? {
+        ("+", _) => Ok(Reply::Status),
+        ("-", error) => Ok(Reply::Error(error.to_string())),
+        (":", _) => Ok(Reply::Integer),
+        ("$", "-1") => Ok(Reply::Bulk(None)),
+        ("$", length) => {
+            let length: usize = length.parse().map_err(|_| invalid())?;
+            let mut value = vec![0; length + 2];
+            io::Read::read_exact(stream, &mut value)?;
+            value.truncate(length);
+            Ok(Reply::Bulk(Some(value)))
+        }
+        ("*", length) => {
+            let length: usize = length.parse().map_err(|_| invalid())?;
+            (0..length).map(|_| read_reply(stream)).collect::<io::Result<_>>().map(Reply::Array)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn expect_ok(reply: Reply) -> io::Result<()> {
+    match reply {
+        Reply::Status => Ok(()),
+        Reply::Error(error) => Err(io::Error::other(error)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn compressed_variants_count_against_the_budget() {
+        let cache = MemoryCache::new(SHARDS as u64 * 1_000_000);
+        let path = PathBuf::from("/srv/page.html");
+        let file = Arc::new(CachedFile::new(b"<p>hello</p>".repeat(200), "text/html".to_string(), None));
+        cache.insert(path.clone(), Arc::clone(&file));
+        let before = cache.usage().unwrap().bytes;
+        let (encoded, new) = file.encoded(Encoding::Gzip);
+        assert!(new);
+        cache.grown(&path, &file);
+        assert_eq!(cache.usage().unwrap().bytes, before + encoded.len() as u64);
+        // A replaced entry isn't counted again through the old one
+        cache.insert(path.clone(), Arc::new(CachedFile::new(b"new".to_vec(), "text/html".to_string(), None)));
+        file.encoded(Encoding::Brotli);
+        cache.grown(&path, &file);
+        assert_eq!(cache.usage().unwrap().bytes, 3);
+    }
+
+    /// An announced path, and whether everything below it changed
+    type Announcement = (bool, PathBuf);
+
+    /// Stands in for Redis: one store, and every announcement delivered to each follower
+    #[derive(Clone, Default)]
+    struct FakeRedis {
+        files: Arc<Mutex<HashMap<PathBuf, Arc<CachedFile>>>>,
+        followers: Arc<Mutex<Vec<mpsc::Sender<Announcement>>>>,
+    }
+
+    impl FakeRedis {
+        fn publish(&self, under: bool, path: &Path) {
+            for follower in self.followers.lock().unwrap().iter() {
+                let _ = follower.send((under, path.to_path_buf()));
+            }
+        }
+    }
+
+    impl CacheBackend for FakeRedis {
+        fn get(&self, path: &Path) -> Option<Arc<CachedFile>> {
+            self.files.lock().unwrap().get(path).cloned()
+        }
+
+        fn insert(&self, path: PathBuf, file: Arc<CachedFile>) {
+            self.files.lock().unwrap().insert(path, file);
+        }
+
+        fn remove(&self, path: &Path) {
+            self.files.lock().unwrap().remove(path);
+        }
+
+        fn remove_under(&self, dir: &Path) {
+            self.files.lock().unwrap().retain(|path, _| !path.starts_with(dir));
+        }
+    }
+
+    impl SharedCache for FakeRedis {
+        fn announce_removal(&self, path: &Path) {
+            self.publish(false, path);
+        }
+
+        fn announce_removal_under(&self, dir: &Path) {
+            self.publish(true, dir);
+        }
+
+        fn follow_removals(&self, on_removal: &dyn Fn(Removal)) {
+            on_removal(Removal::Unknown);
+            let (sender, receiver) = mpsc::channel();
+            self.followers.lock().unwrap().push(sender);
+            for (under, path) in receiver {
+                match under {
+                    true => on_removal(Removal::Under(&path)),
+                    false => on_removal(Removal::Path(&path)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn removing_a_directory_reaches_every_instance() {
+        let redis = FakeRedis::default();
+        let first = Arc::new(Tiered::new(MemoryCache::new(SHARDS as u64 * 1_000_000), redis.clone()));
+        let second = Arc::new(Tiered::new(MemoryCache::new(SHARDS as u64 * 1_000_000), redis.clone()));
+        first.follow_removals();
+        second.follow_removals();
+        while redis.followers.lock().unwrap().len() < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let (inside, outside) = (PathBuf::from("/srv/uploads/a.txt"), PathBuf::from("/srv/other.txt"));
+        for path in [&inside, &outside] {
+            second.insert(path.clone(), Arc::new(CachedFile::new(b"old".to_vec(), "text/plain".to_string(), None)));
+            assert!(first.get(path).is_some());
+        }
+        second.remove_under(Path::new("/srv/uploads"));
+
+        let started = Instant::now();
+        while first.local.get(&inside).is_some() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(first.get(&inside).is_none());
+        assert!(first.local.get(&outside).is_some());
+    }
+}
@@ -0,0 +1,316 @@
+use crate::listener::Connection;
+use rshttp::request::{self, HeaderMap};
+use rshttp::response::Response;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// How long a script may run before it is killed and the client gets a 504
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a running script is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Largest script output accepted
+pub const MAX_OUTPUT: u64 = 64 << 20;
+
+/// Script headers left out, as the server frames the response itself
+const FRAMING: &[&str] = &["Content-Length", "Transfer-Encoding", "Connection"];
+
+/// Scripts run for requests under a path prefix, per RFC 3875 (CGI/1.1), from --cgi
+///
+/// `/cgi-bin/form.py/extra?q=1` runs `ROOT/cgi-bin/form.py` with `PATH_INFO`
+/// set to `/extra` and `QUERY_STRING` to `q=1`. Scripts need to be executable,
+/// and get the request body on stdin. Their output starts with CGI headers:
+/// `Status` sets the status, `Location` without it redirects, and everything
+/// else is passed on to the client.
+pub struct Cgi {
+    prefix: String,
+}
+
+/// A script found for a request path
+pub struct Script {
+    pub file: PathBuf,
+    /// The URL path of the script
+    pub name: String,
+    /// The rest of the request path after it
+    pub path_info: String,
+}
+
+/// The request a script is run for
+pub struct Invocation<'a> {
+    pub method: &'a str,
+    /// Normalized path, without the query
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: &'a HeaderMap<'a>,
+    /// Everything read from the client so far
+    pub head: &'a [u8],
+    pub peer_ip: Option<IpAddr>,
+    /// The authenticated user, if any
+    pub user: Option<&'a str>,
+    pub root: &'a Path,
+}
+
+impl Invocation<'_> {
+    /// The part of the body read along with the head
+    pub fn received_body(&self) -> &[u8] {
+        let length = self.headers.content_length().unwrap_or(0);
+        let body = self.received();
+        &body[..body.len().min(length as usize)]
+    }
+
+    /// Everything read after the head, however the body is framed
+    pub fn received(&self) -> &[u8] {
+        self.head
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(&[][..], |end| &self.head[end + 4..])
+    }
+}
+
+impl Cgi {
+    pub fn new(prefix: &str) -> Cgi {
+        Cgi {
+            prefix: prefix.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Whether `path` is below the prefix
+    pub fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix).is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// Runs the script for a request and sends its response
+    pub fn run(&self, client: &mut impl Connection, invocation: Invocation) -> io::Result<()> {
+        let head_only = invocation.method == "HEAD";
+        let Some(script) = self.find_script(invocation.root, invocation.path) else {
+            return Response::error(404).send(client, head_only);
+        };
+        if !is_executable(&script.file) {
+            debug!("{} is not executable", script.file.display());
+            return Response::error(403).send(client, head_only);
+        }
+        if invocation.headers.get("Transfer-Encoding").is_some() {
+            return Response::error(411).header("Connection", "close").send(client, head_only);
+        }
+        request::send_continue(client, invocation.headers, invocation.received_body())?;
+
+        let mut command = Command::new(&script.file);
+        command
+            .env_clear()
+            .envs(environment(&invocation, &script))
+            .current_dir(script.file.parent().unwrap_or(invocation.root))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run {}: {}", script.file.display(), e);
+                return Response::error(500).send(client, head_only);
+            }
+        };
+
+        // Output is read while the body is written, so a script answering early can't block either side
+        let stdout = child.stdout.take().map(|stdout| thread::spawn(move || read_all(stdout)));
+        let stderr = child.stderr.take().map(|stderr| thread::spawn(move || read_all(stderr)));
+        if let Err(e) = write_body(client, &mut child, &invocation) {
+            let _ = child.kill();
+            let _ = child.wait();
+            debug!("Failed to pass the request body to {}: {}", script.file.display(), e);
+            return Response::error(400).header("Connection", "close").send(client, head_only);
+        }
+        if !wait(&mut child, TIMEOUT)? {
+            warn!("{} did not finish within {}s and was killed", script.file.display(), TIMEOUT.as_secs());
+            return Response::error(504).send(client, head_only);
+        }
+        let output = stdout.and_then(|reader| reader.join().ok()).unwrap_or_default();
+        let errors = stderr.and_then(|reader| reader.join().ok()).unwrap_or_default();
+        for line in String::from_utf8_lossy(&errors).lines() {
+            warn!("{}: {}", script.name, line);
+        }
+        match parse_output(&output) {
+            Some(response) => response.send(client, head_only),
+            None => {
+                warn!("{} printed no valid CGI header", script.file.display());
+                Response::error(502).send(client, head_only)
+            }
+        }
+    }
+
+    fn find_script(&self, root: &Path, path: &str) -> Option<Script> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        let mut file = root.join(self.prefix.trim_start_matches('/'));
+        let mut end = 0;
+        for segment in rest.split('/').skip(1) {
+            file.push(segment);
+            end += 1 + segment.len();
+            if file.is_file() {
+                return Some(Script {
+                    file,
+                    name: format!("{}{}", self.prefix, &rest[..end]),
+                    path_info: rest[end..].to_string(),
+                });
+            }
+            if !file.is_dir() {
+                return None;
+            }
+        }
+        None
+    }
+}
+
+/// The meta-variables of RFC 3875 section 4.1, plus the usual extras
+pub fn environment(invocation: &Invocation, script: &Script) -> Vec<(String, String)> {
+    let host = invocation.headers.get("Host").unwrap_or("localhost");
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, port),
+        _ => (host, "80"),
+    };
+    // Absolute, as backends like php-fpm run elsewhere
+    let absolute = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf()).display().to_string();
+    let request_uri = match invocation.query {
+        "" => request::encode_path(invocation.path),
+        query => format!("{}?{}", request::encode_path(invocation.path), query),
+    };
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_PROTOCOL", "HTTP/1.1".to_string()),
+        ("SERVER_SOFTWARE", format!("rshttp/{}", env!("CARGO_PKG_VERSION"))),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", invocation.method.to_string()),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("SCRIPT_FILENAME", absolute(&script.file)),
+        ("QUERY_STRING", invocation.query.to_string()),
+        ("DOCUMENT_ROOT", absolute(invocation.root)),
+        ("REQUEST_URI", request_uri),
+    ];
+    if !script.path_info.is_empty() {
+        let translated = invocation.root.join(script.path_info.trim_start_matches('/'));
+        env.push(("PATH_INFO", script.path_info.clone()));
+        env.push(("PATH_TRANSLATED", translated.display().to_string()));
+    }
+    if let Some(ip) = invocation.peer_ip {
+        env.push(("REMOTE_ADDR", ip.to_string()));
+    }
+    if let Some(user) = invocation.user {
+        env.push(("REMOTE_USER", user.to_string()));
+    }
+    if let Some(length) = invocation.headers.content_length() {
+        env.push(("CONTENT_LENGTH", length.to_string()));
+    }
+    if let Some(content_type) = invocation.headers.get("Content-Type") {
+        env.push(("CONTENT_TYPE", content_type.to_string()));
+    }
+    if let Ok(path) = std::env::var("PATH") {
+        env.push(("PATH", path));
+    }
+    let mut env: Vec<(String, String)> = env.into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+    // Credentials stay with the server, as the RFC suggests; the rest is passed as HTTP_*. A Proxy
+    // header would become HTTP_PROXY, which scripts' HTTP clients take for their proxy ("httpoxy")
+    for (name, value) in invocation.headers.iter() {
+        let skipped = ["Authorization", "Proxy-Authorization", "Content-Length", "Content-Type"];
+        let variable = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        if skipped.iter().any(|skipped| skipped.eq_ignore_ascii_case(name)) || variable == "HTTP_PROXY" {
+            continue;
+        }
+        env.push((variable, value.to_string()));
+    }
+    env
+}
+
+/// Pipes the request body into the script, then closes its stdin
+fn write_body(client: &mut impl Read, child: &mut Child, invocation: &Invocation) -> io::Result<()> {
+    let Some(mut stdin) = child.stdin.take() else {
+        return Ok(());
+    };
+    let received = invocation.received_body();
+    let remaining = invocation.headers.content_length().unwrap_or(0) - received.len() as u64;
+    // A script that doesn't read all of its input closes the pipe, the rest is read and dropped
+    let mut piped = stdin.write_all(received).is_ok();
+    let mut body = client.take(remaining);
+    let mut buffer = [0u8; 16 * 1024];
+    let mut copied = 0;
+    loop {
+        let read = body.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        copied += read as u64;
+        piped = piped && stdin.write_all(&buffer[..read]).is_ok();
+    }
+    if copied < remaining {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Waits for the script to exit, killing it after `timeout`; returns whether it exited in time
+fn wait(child: &mut Child, timeout: Duration) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if child.try_wait()?.is_some() {
+            return Ok(true);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let _ = child.kill();
+    child.wait()?;
+    Ok(false)
+}
+
+fn read_all(pipe: impl Read) -> Vec<u8> {
+    let mut output = Vec::new();
+    let _ = pipe.take(MAX_OUTPUT).read_to_end(&mut output);
+    output
+}
+
+/// Turns a script's output into a response, or `None` if it has no header section
+pub fn parse_output(output: &[u8]) -> Option<Response<'static>> {
+    let (head, body) = match output.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => (&output[..end], &output[end + 4..]),
+        None => {
+            let end = output.windows(2).position(|window| window == b"\n\n")?;
+            (&output[..end], &output[end + 2..])
+        }
+    };
+    let head = std::str::from_utf8(head).ok()?;
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in head.lines() {
+        let (name, value) = line.split_once(':')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Status") {
+            status = value.split(' ').next()?.parse::<u16>().ok();
+        } else if !FRAMING.iter().any(|framing| framing.eq_ignore_ascii_case(name)) {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    if headers.is_empty() && status.is_none() {
+        return None;
+    }
+    let redirect = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Location"));
+    let status = status.unwrap_or(if redirect { 302 } else { 200 });
+    let mut response = Response::new(status);
+    for (name, value) in headers {
+        response = response.header(&name, value);
+    }
+    Some(response.body(body.to_vec()))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "exe" || extension == "bat" || extension == "cmd")
+}
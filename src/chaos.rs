@@ -0,0 +1,70 @@
+use std::fmt;
+use std::time::Duration;
+
+/// How long a stalled response is held back
+pub const STALL: Duration = Duration::from_secs(10);
+
+/// Paths that are never disrupted, so probes, metrics and admin pages stay reliable
+pub const SPARED_PREFIX: &str = "/_rshttps/";
+
+/// What goes wrong with a response picked by --chaos
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A 500 instead of the response
+    Error,
+    /// The connection closes halfway through the body
+    Truncate,
+    /// The response starts only after [`STALL`]
+    Stall,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Fault::Error => "500 error",
+            Fault::Truncate => "truncated body",
+            Fault::Stall => "stall",
+        })
+    }
+}
+
+/// Disrupts a random fraction of responses, to exercise a front-end's retry and error handling
+#[derive(Debug, Clone, Copy)]
+pub struct Chaos {
+    fraction: f64,
+}
+
+impl Chaos {
+    /// Parses the fraction of responses to disrupt, as a percentage (`5%`) or a fraction (`0.05`)
+    pub fn parse(value: &str) -> Result<Chaos, String> {
+        let invalid = || format!("invalid fraction '{}' (expected e.g. 5% or 0.05)", value);
+        let fraction = match value.trim().strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().map_err(|_| invalid())? / 100.0,
+            None => value.trim().parse::<f64>().map_err(|_| invalid())?,
+        };
+        match (0.0..=1.0).contains(&fraction) {
+            true => Ok(Chaos { fraction }),
+            false => Err(invalid()),
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.fraction * 100.0
+    }
+
+    /// Decides whether the next response is disrupted, and how
+    pub fn roll(&self) -> Option<Fault> {
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).ok()?;
+        let roll = (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64;
+        if roll >= self.fraction {
+            return None;
+        }
+        // Which fault, from where the roll fell below the fraction
+        match (roll / self.fraction * 3.0) as u8 {
+            0 => Some(Fault::Error),
+            1 => Some(Fault::Truncate),
+            _ => Some(Fault::Stall),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::access::AccessPolicy;
+use crate::htpasswd::HtpasswdFile;
+use crate::listener;
+use crate::listing::Listing;
+use crate::markdown::Markdown;
+use crate::recording::Replay;
+use crate::routes::Routes;
+use crate::sitearchive::SiteArchive;
+use crate::startup::Problems;
+use crate::Cli;
+
+/// Checks what the options name without starting the server, for the `check` subcommand
+///
+/// Options read from a configuration file have been parsed, with bad values
+/// reported at their line, before this runs. The roots, the files holding
+/// users, access rules and routes, and the listen addresses are then looked
+/// at, reporting problems with the codes a real start would. No socket is
+/// bound and nothing is written.
+pub fn run(cli: &Cli) -> i32 {
+    let mut problems = Problems::default();
+    if !std::path::Path::new(&cli.directory).is_dir() {
+        problems.push("E100", format!("{} is not a directory", cli.directory), None);
+    }
+    for (host, dir) in &cli.vhosts {
+        if !dir.is_dir() {
+            let message = format!("root of virtual host {} ({}) is not a directory", host, dir.display());
+            problems.push("E101", message, Some("check the DIR part of --vhost HOST=DIR"));
+        }
+    }
+    for dir in cli.exec_watch.iter().filter(|dir| !dir.is_dir()) {
+        problems.push("E103", format!("--exec-watch {} is not a directory", dir.display()), None);
+    }
+    if let Some(Err(e)) = cli.replay.as_deref().map(Replay::load) {
+        problems.push("E109", format!("cannot load the recording: {}", e), None);
+    }
+    if let Some(dir) = cli.mock.as_ref().filter(|dir| !dir.is_dir()) {
+        problems.push("E111", format!("mock directory {} does not exist", dir.display()), None);
+    }
+    if let Err(e) = Markdown::new(cli.markdown_template.as_deref()) {
+        problems.push("E112", format!("cannot use markdown template: {}", e), None);
+    }
+    if let Some(Err(e)) = cli.listing_template.as_deref().map(|template| Listing::new(template, cli.listing_thumbnails)) {
+        problems.push("E126", format!("cannot use listing template: {}", e), None);
+    }
+    if let Some(Err(e)) = cli.site_archive.as_deref().map(SiteArchive::open) {
+        problems.push("E116", e, None);
+    }
+    #[cfg(feature = "plugins")]
+    if let Some(Err(e)) = cli.plugins.as_deref().map(crate::plugins::Plugins::load) {
+        problems.push("E124", format!("cannot load plugins: {}", e), None);
+    }
+    #[cfg(feature = "lua")]
+    if let Some(Err(e)) = cli.lua.as_deref().map(crate::lua::LuaHooks::load) {
+        problems.push("E125", format!("cannot load the Lua script: {}", e), None);
+    }
+    #[cfg(feature = "geoip")]
+    if let Some(Err(e)) = (cli.geoip_db.as_deref()).map(|path| crate::geoip::GeoFilter::open(path, &cli.geo_allow, &cli.geo_deny)) {
+        problems.push("E127", format!("cannot open GeoIP database: {}", e), None);
+    }
+    if let Some(Err(e)) = cli.access_rules.as_deref().map(AccessPolicy::load) {
+        problems.push("E300", format!("invalid access rules: {}", e), None);
+    }
+    if let Some(Err(e)) = cli.routes.as_deref().map(Routes::load) {
+        problems.push("E303", format!("invalid routes: {}", e), None);
+    }
+    if let Some(Err(e)) = cli.auth_file.as_deref().map(HtpasswdFile::load) {
+        problems.push("E305", format!("invalid auth file: {}", e), None);
+    }
+    listener::resolve_all(cli, &mut problems);
+
+    problems.exit_if_any(cli.json_events);
+    match &cli.config {
+        Some(path) => println!("{} and the files it names look fine", path.display()),
+        None => println!("The options and the files they name look fine"),
+    }
+    0
+}
@@ -0,0 +1,46 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{IsTerminal, Write};
+use std::process::{Command, Stdio};
+use tracing::{info, warn};
+
+/// Clipboard tools tried in order, with their arguments
+#[cfg(target_os = "macos")]
+const TOOLS: &[(&str, &[&str])] = &[("pbcopy", &[])];
+#[cfg(windows)]
+const TOOLS: &[(&str, &[&str])] = &[("clip", &[])];
+#[cfg(not(any(target_os = "macos", windows)))]
+const TOOLS: &[(&str, &[&str])] = &[
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+/// Puts `text` on the system clipboard, for --copy-url
+///
+/// The platform's clipboard tool is tried first (pbcopy, clip, or wl-copy,
+/// xclip and xsel). Without one, e.g. over SSH, the text is sent to the
+/// terminal as an OSC 52 sequence, which most terminal emulators put on the
+/// clipboard of the machine they run on.
+pub fn copy(text: &str) {
+    for (tool, args) in TOOLS {
+        let mut command = Command::new(tool);
+        command.args(*args).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null());
+        let Ok(mut child) = command.spawn() else {
+            continue;
+        };
+        let written = child.stdin.take().is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        if written && child.wait().is_ok_and(|status| status.success()) {
+            info!("Copied {} to the clipboard with {}", text, tool);
+            return;
+        }
+    }
+    let mut stdout = std::io::stdout();
+    if stdout.is_terminal() {
+        let _ = write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text));
+        let _ = stdout.flush();
+        info!("Copied {} to the terminal's clipboard", text);
+        return;
+    }
+    warn!("Failed to copy {} to the clipboard: no clipboard tool found", text);
+}
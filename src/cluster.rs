@@ -0,0 +1,172 @@
+use crate::listener;
+use crate::signals::{self, Signal};
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Environment variable telling a worker process which slot it fills, as `SLOT:GENERATION`
+const WORKER_ENV: &str = "RSHTTP_WORKER";
+
+/// A worker that exits sooner than this after starting waits before it is started again
+const CRASH_BACKOFF: Duration = Duration::from_secs(1);
+
+/// What a process started by [`supervise`] knows about itself
+#[derive(Debug, Clone, Copy)]
+pub struct Worker {
+    pub slot: usize,
+    /// How many times the slot's worker was started before this one
+    pub generation: u32,
+}
+
+impl Worker {
+    /// The worker this process was started as, or `None` outside of --workers
+    ///
+    /// Reads and removes the variable, so it doesn't leak into CGI scripts.
+    pub fn current() -> Option<Worker> {
+        let value = std::env::var(WORKER_ENV).ok()?;
+        std::env::remove_var(WORKER_ENV);
+        let (slot, generation) = value.split_once(':')?;
+        Some(Worker {
+            slot: slot.parse().ok()?,
+            generation: generation.parse().ok()?,
+        })
+    }
+
+    /// Whether this worker is the one that prints the banner and opens the browser
+    pub fn announces(&self) -> bool {
+        self.slot == 0 && self.generation == 0
+    }
+}
+
+enum Event {
+    Signal(Signal),
+    Exited { slot: usize, pid: u32, status: io::Result<ExitStatus> },
+}
+
+/// A worker process in its slot
+struct Running {
+    pid: u32,
+    generation: u32,
+    started: Instant,
+}
+
+/// Runs `count` copies of this executable that serve the listeners `listener_fds`, for --workers
+///
+/// Each worker is started with the same arguments and takes the listening
+/// sockets over, so the kernel spreads connections across them. A worker that
+/// dies is started again, which keeps a crash from taking the service down.
+/// SIGINT and SIGTERM drain and stop the workers, SIGHUP and SIGUSR1 are passed
+/// on, and SIGUSR2 replaces the workers one by one, e.g. with an upgraded
+/// binary. Returns the exit code once every worker has stopped.
+pub fn supervise(count: usize, listener_fds: &[i32]) -> i32 {
+    let (events, received) = channel();
+    let signal_events = events.clone();
+    thread::spawn(move || loop {
+        let signal = signals::wait();
+        if signal_events.send(Event::Signal(signal)).is_err() {
+            break;
+        }
+    });
+
+    let mut slots: Vec<Option<Running>> = Vec::new();
+    for slot in 0..count {
+        slots.push(start(slot, 0, listener_fds, &events).ok());
+    }
+    if slots.iter().all(Option::is_none) {
+        return 1;
+    }
+    info!("Started {} worker processes", slots.iter().flatten().count());
+
+    let mut stopping = false;
+    // Workers replaced by SIGUSR2 that are still draining
+    let mut retired = Vec::new();
+    while let Ok(event) = received.recv() {
+        match event {
+            Event::Signal(Signal::Terminate(name)) => {
+                info!("Received {}, stopping the workers", name);
+                stopping = true;
+                for running in slots.iter().flatten() {
+                    kill(running.pid, libc::SIGTERM);
+                }
+            }
+            Event::Signal(Signal::Restart) if !stopping => {
+                info!("Received SIGUSR2, replacing the workers");
+                for (slot, current) in slots.iter_mut().enumerate() {
+                    let generation = current.as_ref().map_or(0, |running| running.generation + 1);
+                    if let Ok(replacement) = start(slot, generation, listener_fds, &events) {
+                        if let Some(old) = current.replace(replacement) {
+                            kill(old.pid, libc::SIGTERM);
+                            retired.push(old.pid);
+                        }
+                    }
+                }
+            }
+            Event::Signal(Signal::Restart) => {}
+            Event::Signal(signal) => {
+                let number = if signal == Signal::Reload { libc::SIGHUP } else { libc::SIGUSR1 };
+                for running in slots.iter().flatten() {
+                    kill(running.pid, number);
+                }
+            }
+            Event::Exited { slot, pid, status } => {
+                if let Some(index) = retired.iter().position(|retired| *retired == pid) {
+                    retired.swap_remove(index);
+                } else if let Some(running) = slots[slot].take_if(|running| running.pid == pid) {
+                    match status {
+                        Ok(status) if stopping => info!("Worker {} (pid {}) {}", slot, pid, status),
+                        Ok(status) => warn!("Worker {} (pid {}) {}, starting it again", slot, pid, status),
+                        Err(e) => warn!("Lost track of worker {} (pid {}): {}", slot, pid, e),
+                    }
+                    if !stopping {
+                        if running.started.elapsed() < CRASH_BACKOFF {
+                            thread::sleep(CRASH_BACKOFF);
+                        }
+                        slots[slot] = start(slot, running.generation + 1, listener_fds, &events).ok();
+                    }
+                }
+                if stopping && retired.is_empty() && slots.iter().all(Option::is_none) {
+                    info!("All workers stopped");
+                    return 0;
+                }
+            }
+        }
+    }
+    1
+}
+
+/// Starts the worker for `slot`, handing it the listeners, and reports its exit as an event
+fn start(slot: usize, generation: u32, listener_fds: &[i32], events: &Sender<Event>) -> io::Result<Running> {
+    let fds: Vec<String> = listener_fds.iter().map(|fd| fd.to_string()).collect();
+    for fd in listener_fds {
+        listener::set_inheritable(*fd, true);
+    }
+    let child = std::env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .env(listener::INHERITED_FDS_ENV, fds.join(","))
+            .env(WORKER_ENV, format!("{}:{}", slot, generation))
+            .spawn()
+    });
+    for fd in listener_fds {
+        listener::set_inheritable(*fd, false);
+    }
+    let mut child: Child = child.inspect_err(|e| error!("Failed to start worker {}: {}", slot, e))?;
+    let pid = child.id();
+    let events = events.clone();
+    thread::spawn(move || {
+        let status = child.wait();
+        let _ = events.send(Event::Exited { slot, pid, status });
+    });
+    Ok(Running {
+        pid,
+        generation,
+        started: Instant::now(),
+    })
+}
+
+fn kill(pid: u32, signal: libc::c_int) {
+    unsafe { libc::kill(pid as libc::pid_t, signal) };
+}
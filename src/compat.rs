@@ -0,0 +1,52 @@
+use std::ffi::OsString;
+
+/// A flag that was renamed, still accepted under its old name with a warning
+struct Renamed {
+    old: &'static str,
+    /// May carry a value, for a switch that became a setting of another flag
+    /// (e.g. `--no-x` becoming `--x-mode=off`)
+    new: &'static str,
+    /// Release the old name was deprecated in
+    since: &'static str,
+}
+
+/// Old flag names, oldest first; entries are dropped a few releases after `since`
+const RENAMED: &[Renamed] = &[];
+
+/// Replaces deprecated flag names in the command line with their current ones, warning about each
+///
+/// Both `--old value` and `--old=value` are rewritten. Nothing after a bare
+/// `--` is touched.
+pub fn translate(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut translated = Vec::new();
+    let mut args = args.into_iter();
+    for arg in args.by_ref() {
+        if arg == "--" {
+            translated.push(arg);
+            break;
+        }
+        let Some(text) = arg.to_str() else {
+            translated.push(arg);
+            continue;
+        };
+        let (name, value) = match text.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (text, None),
+        };
+        match RENAMED.iter().find(|renamed| renamed.old == name) {
+            Some(renamed) => {
+                eprintln!(
+                    "Warning: {} is deprecated since {} and will be removed, use {} instead",
+                    renamed.old, renamed.since, renamed.new
+                );
+                translated.push(match value {
+                    Some(value) => format!("{}={}", renamed.new, value).into(),
+                    None => renamed.new.into(),
+                });
+            }
+            None => translated.push(arg),
+        }
+    }
+    translated.extend(args);
+    translated
+}
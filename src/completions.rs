@@ -0,0 +1,19 @@
+use crate::Cli;
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+/// Arguments of the `completions` subcommand
+#[derive(clap::Args, Debug)]
+pub struct CompletionsArgs {
+    /// Shell to complete in
+    shell: Shell,
+}
+
+/// Prints the completion script for a shell, returning the process exit code
+///
+/// e.g. `rshttp completions bash > /etc/bash_completion.d/rshttp`, or
+/// `rshttp completions fish > ~/.config/fish/completions/rshttp.fish`.
+pub fn run(args: &CompletionsArgs) -> i32 {
+    clap_complete::generate(args.shell, &mut Cli::command(), "rshttp", &mut std::io::stdout());
+    0
+}
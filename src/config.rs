@@ -0,0 +1,198 @@
+use clap::builder::ValueParser;
+use clap::parser::ValueSource;
+use clap::error::ErrorKind;
+use clap::{ArgAction, Command};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Looked for in the working directory when no --config is given
+pub const FILE_NAME: &str = "rshttps.toml";
+
+/// Options that run programs, or hand requests to them, which a file that was
+/// picked up by itself may not set; whoever can write to the directory could
+/// otherwise run commands as the server
+const RUNS_COMMANDS: [&str; 9] =
+    ["exec", "cgi", "fastcgi", "lua", "plugins", "sass", "sass-command", "resize-images", "image-command"];
+
+/// Options from a configuration file, e.g.
+///
+/// ```toml
+/// port = 8080
+/// directory = "public"
+/// auth = ["alice:secret"]          # repeatable flags take arrays
+/// proxy = ["/api=http://localhost:3000"]
+/// cache_ttl = 30
+/// live_reload = true               # switches are booleans
+/// ```
+///
+/// Every key is the long name of a command-line flag, with `-` or `_`
+/// between words. A flag given on the command line wins over the file, and
+/// replaces every value the file has for a repeatable one. Relative paths
+/// given to options that take nothing but a path are taken from the file's
+/// directory.
+pub struct Config {
+    pub path: PathBuf,
+    source: String,
+    table: toml::Table,
+    /// Whether the file was found without being named by --config
+    found: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let table = source.parse().map_err(|e: toml::de::Error| describe(path, &source, &e))?;
+        Ok(Config {
+            path: path.to_path_buf(),
+            source,
+            table,
+            found: false,
+        })
+    }
+
+    /// The command-line arguments the file stands for, minus the flags given in `given`
+    pub fn args(&self, command: &Command, given: &clap::ArgMatches) -> Result<Vec<OsString>, String> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut args = Vec::new();
+        for (key, value) in &self.table {
+            let name = key.replace('_', "-");
+            let located = |message: String| format!("{}:{}: {}", self.path.display(), self.line_of(key), message);
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(&name))
+                .ok_or_else(|| located(format!("unknown option `{}`", key)))?;
+            if arg.get_id() == "config" || arg.get_id() == "no_config" {
+                return Err(located(format!("`{}` only works on the command line", key)));
+            }
+            if self.found && RUNS_COMMANDS.contains(&name.as_str()) {
+                return Err(located(format!("`{}` runs commands, so it is only read from a file named by --config", key)));
+            }
+            if given.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+                continue;
+            }
+            // Including flags whose value may be left out, e.g. `open = true` for --open
+            let is_switch = !arg.get_action().takes_values() || arg.get_num_args().is_some_and(|n| n.min_values() == 0);
+            let is_path = arg.get_value_parser().type_id() == ValueParser::path_buf().type_id();
+            let values = match value {
+                toml::Value::Array(values) => values.as_slice(),
+                value => std::slice::from_ref(value),
+            };
+            for value in values {
+                let value = match value {
+                    // e.g. `verbose = 2` for -vv
+                    toml::Value::Integer(count) if matches!(arg.get_action(), ArgAction::Count) => {
+                        args.extend((0..*count).map(|_| OsString::from(format!("--{}", name))));
+                        continue;
+                    }
+                    toml::Value::Boolean(true) if is_switch => {
+                        args.push(format!("--{}", name).into());
+                        continue;
+                    }
+                    toml::Value::Boolean(false) if is_switch => continue,
+                    toml::Value::String(value) if is_path => dir.join(value).into_os_string(),
+                    toml::Value::String(value) => value.into(),
+                    toml::Value::Integer(value) => value.to_string().into(),
+                    toml::Value::Float(value) => value.to_string().into(),
+                    toml::Value::Boolean(value) => value.to_string().into(),
+                    _ => return Err(located(format!("`{}` takes a string, a number or a boolean", key))),
+                };
+                let mut flag = OsString::from(format!("--{}=", name));
+                flag.push(&value);
+                // Parsed on its own, so a bad value is reported at its line
+                if let Err(e) = command.clone().try_get_matches_from([OsString::from("rshttp"), flag.clone()]) {
+                    if matches!(e.kind(), ErrorKind::ValueValidation | ErrorKind::InvalidValue) {
+                        let reason = std::error::Error::source(&e).map(|source| source.to_string());
+                        let reason = reason.unwrap_or_else(|| format!("invalid value {:?}", value));
+                        return Err(located(format!("`{}`: {}", key, reason)));
+                    }
+                }
+                args.push(flag);
+            }
+        }
+        Ok(args)
+    }
+
+    /// The line `key` is set on, counting from 1
+    fn line_of(&self, key: &str) -> usize {
+        let spans = toml::de::DeTable::parse(&self.source).ok();
+        let span = spans.and_then(|table| {
+            let table = table.into_inner();
+            table.iter().find(|(name, _)| name.get_ref() == key).map(|(name, _)| name.span())
+        });
+        span.map_or(1, |span| line_at(&self.source, span.start))
+    }
+}
+
+/// Inserts the options of the --config file, or of an rshttps.toml in the working directory, in front of `args`
+///
+/// The served directory is never looked in, since its files may come from
+/// anyone who can upload or check out a site. The file is returned as well,
+/// to be read again on reload.
+///
+/// Returns `args` untouched when there is no file, or when they can't be made
+/// sense of (for clap to report what is wrong with them).
+pub fn apply(command: Command, args: Vec<OsString>) -> Result<(Vec<OsString>, Option<Config>), String> {
+    // Requirements may be met by the file
+    let Ok(given) = command.clone().ignore_errors(true).try_get_matches_from(&args) else {
+        return Ok((args, None));
+    };
+    let (path, found) = match given.get_one::<PathBuf>("config") {
+        Some(path) => (path.clone(), false),
+        None if given.get_flag("no_config") => return Ok((args, None)),
+        None => {
+            let path = PathBuf::from(FILE_NAME);
+            if !path.is_file() {
+                return Ok((args, None));
+            }
+            (path, true)
+        }
+    };
+    let mut config = Config { found, ..Config::load(&path)? };
+    let applied = config.insert(&command, &given, &path, args)?;
+    // Still found after a change of directory, e.g. by --daemon
+    config.path = path.canonicalize().unwrap_or(path);
+    Ok((applied, Some(config)))
+}
+
+impl Config {
+    /// The file as it is now, e.g. after it was edited
+    pub fn reload(&self) -> Result<Config, String> {
+        Ok(Config {
+            found: self.found,
+            ..Config::load(&self.path)?
+        })
+    }
+
+    /// `args` with the options of this file in front, like [`apply`] put them
+    pub fn reapply(&self, command: Command, args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+        let given = command.clone().ignore_errors(true).try_get_matches_from(&args).map_err(|e| e.to_string())?;
+        self.insert(&command, &given, &self.path, args)
+    }
+
+    fn insert(&self, command: &Command, given: &clap::ArgMatches, path: &Path, args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+        let mut applied = Vec::with_capacity(args.len() + self.table.len() + 1);
+        let mut args = args.into_iter();
+        applied.extend(args.next());
+        applied.extend(self.args(command, given)?);
+        if given.value_source("config") != Some(ValueSource::CommandLine) {
+            // So the banner shows where the options came from
+            let mut arg = OsString::from("--config=");
+            arg.push(path);
+            applied.push(arg);
+        }
+        applied.extend(args);
+        Ok(applied)
+    }
+}
+
+fn describe(path: &Path, source: &str, error: &toml::de::Error) -> String {
+    match error.span() {
+        Some(span) => format!("{}:{}: {}", path.display(), line_at(source, span.start), error.message()),
+        None => format!("{}: {}", path.display(), error.message()),
+    }
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
@@ -0,0 +1,55 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Detaches from the terminal and sends stdout/stderr to `log_file`
+///
+/// Forks, lets the parent exit and starts a new session in the child. Only the
+/// calling thread survives fork(), so this must run before any thread is spawned.
+pub fn daemonize(log_file: &Path) -> io::Result<()> {
+    let log = OpenOptions::new().create(true).append(true).open(log_file)?;
+    let null = File::open("/dev/null")?;
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        child => {
+            println!("Running in the background as pid {}, logging to {}", child, log_file.display());
+            unsafe { libc::_exit(0) };
+        }
+    }
+
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    Ok(())
+}
+
+/// A file holding the server's PID, removed again when the server exits
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<PidFile> {
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(PidFile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // After a restart the file already names the new process
+        let pid = fs::read_to_string(&self.path).ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+        if pid == Some(process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cache::CacheBackend;
+use crate::metrics::{Metrics, Snapshot};
+use crate::shutdown::Shutdown;
+use crate::units;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the dashboard is redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the dashboard checks whether shutdown has begun
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many of the slowest paths are listed
+const SLOWEST_PATHS: usize = 10;
+
+/// Longest path shown before it is shortened
+const PATH_WIDTH: usize = 48;
+
+/// Shows live request statistics on the terminal until shutdown begins, for --tui
+///
+/// The dashboard takes over the terminal's alternate screen and redraws it in
+/// place every [`REFRESH_INTERVAL`], so diagnostics written in between are
+/// overwritten by the next frame. Leaving restores the screen as it was, with
+/// the startup banner, before the drain and the summary are printed.
+pub fn run(metrics: &Metrics, shutdown: &Shutdown, cache: &dyn CacheBackend) {
+    // Alternate screen, hidden cursor
+    print_raw("\x1b[?1049h\x1b[?25l");
+    let mut last = (Instant::now(), metrics.snapshot(0).requests);
+    while !shutdown.is_draining() {
+        let snapshot = metrics.snapshot(SLOWEST_PATHS);
+        let rate = (snapshot.requests - last.1) as f64 / last.0.elapsed().as_secs_f64();
+        last = (Instant::now(), snapshot.requests);
+        print_raw(&frame(&snapshot, rate, shutdown.active(), cache));
+
+        while last.0.elapsed() < REFRESH_INTERVAL && !shutdown.is_draining() {
+            thread::sleep(SHUTDOWN_CHECK_INTERVAL);
+        }
+    }
+    print_raw("\x1b[?25h\x1b[?1049l");
+}
+
+fn print_raw(text: &str) {
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// One screen of statistics, drawn from the top left and clearing what the last one left behind
+fn frame(snapshot: &Snapshot, rate: f64, active: usize, cache: &dyn CacheBackend) -> String {
+    let mut lines = Vec::new();
+    lines.push(format!("rshttp, up {}  (Ctrl-C to stop)", units::duration(snapshot.uptime)));
+    lines.push(String::new());
+    let average = snapshot.requests as f64 / snapshot.uptime.as_secs_f64().max(1.0);
+    lines.push(format!(
+        "Requests     {} total, {:.1}/s now, {:.1}/s on average",
+        snapshot.requests, rate, average
+    ));
+    lines.push(format!("Connections  {} open", active));
+    lines.push(format!("Sent         {}", units::size(snapshot.bytes_sent)));
+    let lookups = snapshot.cache_hits + snapshot.cache_misses;
+    let hit_rate = percent(snapshot.cache_hits, lookups);
+    let mut cached = format!("{} hits of {} lookups ({:.1}%)", snapshot.cache_hits, lookups, hit_rate);
+    if let Some(usage) = cache.usage() {
+        let _ = write!(cached, ", holding {}", usage);
+    }
+    lines.push(format!("Cache        {}", cached));
+    if snapshot.panics > 0 {
+        lines.push(format!("Panics       {}", snapshot.panics));
+    }
+
+    lines.push(String::new());
+    lines.push(format!("{:<8}{:>10}{:>8}", "Status", "Requests", "Share"));
+    let handled: u64 = snapshot.statuses.values().sum();
+    for (status, count) in &snapshot.statuses {
+        lines.push(format!("{:<8}{:>10}{:>7.1}%", status, count, percent(*count, handled)));
+    }
+
+    lines.push(String::new());
+    let header = ("Slowest paths", "Slowest", "Average", "Requests");
+    lines.push(format!("{:<width$}{:>12}{:>12}{:>10}", header.0, header.1, header.2, header.3, width = PATH_WIDTH + 2));
+    for (path, stats) in &snapshot.slowest_paths {
+        lines.push(format!(
+            "{:<width$}{:>12}{:>12}{:>10}",
+            shorten(path),
+            millis(stats.slowest),
+            millis(stats.total / stats.requests.max(1) as u32),
+            stats.requests,
+            width = PATH_WIDTH + 2
+        ));
+    }
+
+    let mut frame = String::from("\x1b[H");
+    for line in lines {
+        // Clearing to the end of each line removes longer text from the previous frame
+        frame.push_str(&line);
+        frame.push_str("\x1b[K\n");
+    }
+    frame.push_str("\x1b[J");
+    frame
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 * 100.0 / whole as f64,
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Keeps the end of a long path, which tells files apart better than its start
+fn shorten(path: &str) -> String {
+    let chars: Vec<char> = path.chars().collect();
+    match chars.len() > PATH_WIDTH {
+        true => format!("...{}", chars[chars.len() - (PATH_WIDTH - 3)..].iter().collect::<String>()),
+        false => path.to_string(),
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a point in time the way Apache's `%t` does, e.g. `01/May/2024:13:55:36 +0000`
+///
+/// The time is shown `offset` seconds ahead of UTC.
+pub fn clf_timestamp(time: SystemTime, offset: i64) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    } + offset;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} {}",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        offset_text(offset)
+    )
+}
+
+/// A UTC offset in seconds as `+HHMM` or `-HHMM`
+pub fn offset_text(offset: i64) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!("{}{:02}{:02}", sign, offset.abs() / 3600, offset.abs() / 60 % 60)
+}
+
+/// Year, month and day of the date `days` after 1970-01-01 (Howard Hinnant's algorithm)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn clf_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(1714571736);
+        assert_eq!(clf_timestamp(time, 0), "01/May/2024:13:55:36 +0000");
+        assert_eq!(clf_timestamp(time, -5 * 3600 - 1800), "01/May/2024:08:25:36 -0530");
+        assert_eq!(clf_timestamp(UNIX_EPOCH, 3600), "01/Jan/1970:01:00:00 +0100");
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+}
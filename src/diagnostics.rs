@@ -0,0 +1,77 @@
+#[cfg(unix)]
+use crate::syslog::{self, Syslog};
+use std::io::IsTerminal;
+#[cfg(unix)]
+use std::sync::Arc;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Where diagnostics and the access log go, chosen with --log-target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogTarget {
+    /// Diagnostics on stderr, the access log on stdout
+    Console,
+    /// Both to the local syslog daemon or journald
+    Syslog,
+}
+
+impl LogTarget {
+    pub fn parse(value: &str) -> Result<LogTarget, String> {
+        match value {
+            "console" => Ok(LogTarget::Console),
+            #[cfg(unix)]
+            "syslog" => Ok(LogTarget::Syslog),
+            #[cfg(unix)]
+            _ => Err("expected console or syslog".to_string()),
+            #[cfg(not(unix))]
+            _ => Err("expected console (syslog needs a Unix system)".to_string()),
+        }
+    }
+}
+
+/// Sends the server's diagnostics to stderr, at the level -v/-q ask for
+///
+/// Warnings and errors always show, -q hides everything else, the default
+/// adds what the server does (reloads, signals, builds), -v adds per-request
+/// details and -vv the timing of each request phase. A RUST_LOG filter,
+/// e.g. `RUST_LOG=rshttp=debug`, takes precedence over the flags.
+pub fn init(verbose: u8, quiet: bool) {
+    tracing_subscriber::fmt()
+        .with_env_filter(filter(verbose, quiet))
+        .with_span_events(span_events(verbose))
+        .with_target(false)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Like [`init`], but sends diagnostics to syslog, which adds the time and takes the level as the priority
+#[cfg(unix)]
+pub fn init_syslog(verbose: u8, quiet: bool, syslog: Arc<Syslog>) {
+    tracing_subscriber::fmt()
+        .with_env_filter(filter(verbose, quiet))
+        .with_span_events(span_events(verbose))
+        .with_target(false)
+        .with_ansi(false)
+        .without_time()
+        .with_level(false)
+        .with_writer(syslog::Diagnostics(syslog))
+        .init();
+}
+
+fn filter(verbose: u8, quiet: bool) -> EnvFilter {
+    let level = match (quiet, verbose) {
+        (true, _) => "warn",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    };
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level))
+}
+
+fn span_events(verbose: u8) -> FmtSpan {
+    match verbose {
+        0 | 1 => FmtSpan::NONE,
+        _ => FmtSpan::CLOSE,
+    }
+}
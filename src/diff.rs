@@ -0,0 +1,118 @@
+use crate::{units, walk};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Arguments of the `diff` subcommand
+#[derive(clap::Args, Debug)]
+pub struct DiffArgs {
+    /// The currently served root
+    old: PathBuf,
+    /// The root about to replace it
+    new: PathBuf,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Size and content hash of one file
+struct Entry {
+    size: u64,
+    sha256: String,
+}
+
+/// Compares two roots file by file and returns the process exit code
+///
+/// Exits with 0 when the roots are identical and 1 when they differ, like diff(1).
+pub fn run(args: &DiffArgs) -> i32 {
+    for root in [&args.old, &args.new] {
+        if !root.is_dir() {
+            eprintln!("Error: {} is not a directory", root.display());
+            return 2;
+        }
+    }
+    let old = snapshot(&args.old);
+    let new = snapshot(&args.new);
+
+    let added: Vec<_> = new.iter().filter(|(path, _)| !old.contains_key(*path)).collect();
+    let removed: Vec<_> = old.iter().filter(|(path, _)| !new.contains_key(*path)).collect();
+    let changed: Vec<_> = old
+        .iter()
+        .filter_map(|(path, before)| {
+            let after = new.get(path)?;
+            (before.sha256 != after.sha256).then_some((path, before, after))
+        })
+        .collect();
+    let unchanged = old.len() - removed.len() - changed.len();
+
+    if args.json {
+        let entry = |path: &String, entry: &Entry| {
+            serde_json::json!({ "path": path, "size": entry.size, "sha256": entry.sha256 })
+        };
+        let report = serde_json::json!({
+            "added": added.iter().map(|(path, e)| entry(path, e)).collect::<Vec<_>>(),
+            "removed": removed.iter().map(|(path, e)| entry(path, e)).collect::<Vec<_>>(),
+            "changed": changed.iter().map(|(path, before, after)| serde_json::json!({
+                "path": path,
+                "old_size": before.size,
+                "new_size": after.size,
+                "size_delta": after.size as i64 - before.size as i64,
+                "old_sha256": before.sha256,
+                "new_sha256": after.sha256,
+            })).collect::<Vec<_>>(),
+            "unchanged": unchanged,
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+    } else {
+        for (path, entry) in &added {
+            println!("+ {}  {}  {}", path, units::size(entry.size), &entry.sha256[..12]);
+        }
+        for (path, entry) in &removed {
+            println!("- {}  {}  {}", path, units::size(entry.size), &entry.sha256[..12]);
+        }
+        for (path, before, after) in &changed {
+            println!(
+                "~ {}  {} -> {} ({:+} bytes)  {} -> {}",
+                path,
+                units::size(before.size),
+                units::size(after.size),
+                after.size as i64 - before.size as i64,
+                &before.sha256[..12],
+                &after.sha256[..12]
+            );
+        }
+        println!(
+            "{} added, {} removed, {} changed, {} unchanged",
+            added.len(),
+            removed.len(),
+            changed.len(),
+            unchanged
+        );
+    }
+
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Hashes every file below root, keyed by its URL path
+fn snapshot(root: &Path) -> BTreeMap<String, Entry> {
+    walk::files(root)
+        .into_iter()
+        .filter_map(|path| {
+            let contents = fs::read(&path).ok()?;
+            let relative = path.strip_prefix(root).ok()?;
+            let url_path = format!("/{}", relative.to_string_lossy().replace('\\', "/"));
+            Some((
+                url_path,
+                Entry {
+                    size: contents.len() as u64,
+                    sha256: format!("{:x}", Sha256::digest(&contents)),
+                },
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,186 @@
+//! Stand-ins for optional subsystems left out of the build
+//!
+//! Their flags don't exist then, so these types are never constructed and
+//! the request handler can keep using them without cfg attributes.
+#![allow(dead_code)]
+
+/// Replaces the OpenID Connect client in builds without the `oidc` feature
+#[cfg(not(feature = "oidc"))]
+pub mod oidc {
+    use crate::auth::Principal;
+
+    pub const CALLBACK_PATH: &str = "/_rshttps/oidc/callback";
+
+    pub enum OidcOutcome {
+        Redirect { location: String, cookies: Vec<String> },
+        Rejected(String),
+    }
+
+    pub enum OidcClient {}
+
+    impl OidcClient {
+        pub fn login(&self, _path: &str, _query: &str, _cookies: Option<&str>) -> OidcOutcome {
+            match *self {}
+        }
+
+        pub fn session_principal(&self, _cookies: Option<&str>) -> Option<Principal> {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the change webhook in builds without the `webhook` feature
+#[cfg(not(feature = "webhook"))]
+pub mod webhook {
+    use crate::events::Change;
+    use std::time::SystemTime;
+
+    pub enum Webhook {}
+
+    impl Webhook {
+        pub fn send(&self, _changes: &[Change], _first_change: SystemTime) {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the request database in builds without the `sqlite` feature
+#[cfg(not(feature = "sqlite"))]
+pub mod requestdb {
+    use crate::accesslog::Entry;
+
+    pub enum RequestDb {}
+
+    impl RequestDb {
+        pub fn record(&self, _entry: &Entry) {
+            match *self {}
+        }
+
+        pub fn finish(&self) {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the fallback origin in builds without the `fallback` feature
+#[cfg(not(feature = "fallback"))]
+pub mod fallback {
+    use std::time::Duration;
+
+    pub struct Fetched {
+        pub contents: Vec<u8>,
+        pub mime_type: String,
+        pub ttl: Option<Duration>,
+    }
+
+    pub enum FallbackOrigin {}
+
+    impl FallbackOrigin {
+        pub fn fetch(&self, _path: &str, _query: &str) -> Option<Fetched> {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the git ref tree in builds without the `git` feature
+#[cfg(not(feature = "git"))]
+pub mod gitref {
+    use crate::mime::MimeTypes;
+    use rshttp::response::Response;
+
+    pub enum GitTree {}
+
+    impl GitTree {
+        pub fn respond(&self, _url_path: &str, _mime_types: &MimeTypes, _range: Option<&str>) -> Response<'static> {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the S3 bucket in builds without the `s3` feature
+#[cfg(not(feature = "s3"))]
+pub mod s3 {
+    pub enum Bucket {}
+
+    impl Bucket {
+        pub fn fetch(&self, _url_path: &str) -> Result<Option<(String, Vec<u8>)>, String> {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the WebAssembly plugins in builds without the `plugins` feature
+#[cfg(not(feature = "plugins"))]
+pub mod plugins {
+    use rshttp::request::HeaderMap;
+    use rshttp::response::Response;
+
+    pub enum Plugins {}
+
+    pub enum PluginRequest {}
+
+    impl PluginRequest {
+        pub fn path(&self) -> &str {
+            match *self {}
+        }
+
+        pub fn query(&self) -> &str {
+            match *self {}
+        }
+    }
+
+    impl Plugins {
+        pub fn request(&self, _method: &str, _path: &str, _query: &str, _headers: &HeaderMap) -> PluginRequest {
+            match *self {}
+        }
+
+        pub fn on_request(&self, _request: &mut PluginRequest) -> Option<Response<'static>> {
+            match *self {}
+        }
+
+        pub fn changes_response(&self, _request: &PluginRequest) -> bool {
+            match *self {}
+        }
+
+        pub fn on_response(&self, _request: &PluginRequest, _head: &str) -> String {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the Lua hooks in builds without the `lua` feature
+#[cfg(not(feature = "lua"))]
+pub mod lua {
+    use rshttp::request::HeaderMap;
+    use rshttp::response::Response;
+    use std::net::IpAddr;
+
+    pub enum LuaHooks {}
+
+    pub enum Decision {
+        Continue,
+        Rewrite { path: String, query: String },
+        Respond(Response<'static>),
+    }
+
+    impl LuaHooks {
+        pub fn decide(&self, _method: &str, _path: &str, _query: &str, _ip: Option<IpAddr>, _headers: &HeaderMap) -> Decision {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces the country rules in builds without the `geoip` feature
+#[cfg(not(feature = "geoip"))]
+pub mod geoip {
+    use std::net::IpAddr;
+
+    #[derive(Debug)]
+    pub enum GeoFilter {}
+
+    impl GeoFilter {
+        pub fn permits(&self, _ip: IpAddr) -> bool {
+            match *self {}
+        }
+    }
+}
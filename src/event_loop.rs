@@ -0,0 +1,340 @@
+use crate::listener::{BufferedConnection, FileBody, Listener, Timeouts};
+use crate::pool::ThreadPool;
+use crate::{admit, handle_connection, Admission, Context, FileCache, ACCEPT_POLL_INTERVAL};
+use rshttp::request;
+use rshttp::response::Response;
+use mio::event::Source;
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Take, Write};
+use std::net::IpAddr;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Wakes the loop when a worker has finished a response
+const WAKER: Token = Token(usize::MAX);
+
+/// Most bytes written to one connection before the others get a turn
+const WRITE_QUANTUM: usize = 64 * 1024;
+
+/// A non-blocking client socket of any transport
+trait Stream: Read + Write + Source + Send {}
+
+impl<S: Read + Write + Source + Send> Stream for S {}
+
+enum Acceptor {
+    Tcp(mio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(mio::net::UnixListener),
+}
+
+impl Acceptor {
+    fn new(listener: Listener) -> io::Result<Acceptor> {
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(Acceptor::Tcp(mio::net::TcpListener::from_std(listener)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(Acceptor::Unix(mio::net::UnixListener::from_std(listener)))
+            }
+        }
+    }
+
+    /// Takes the next connection, with TCP_NODELAY set if `nodelay`
+    fn accept(&self, nodelay: bool) -> io::Result<(Box<dyn Stream>, Option<IpAddr>)> {
+        match self {
+            Acceptor::Tcp(listener) => listener.accept().map(|(stream, peer)| {
+                if nodelay {
+                    let _ = stream.set_nodelay(true);
+                }
+                (Box::new(stream) as Box<dyn Stream>, Some(peer.ip()))
+            }),
+            #[cfg(unix)]
+            Acceptor::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| (Box::new(stream) as Box<dyn Stream>, None)),
+        }
+    }
+
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Acceptor::Tcp(listener) => listener,
+            #[cfg(unix)]
+            Acceptor::Unix(listener) => listener,
+        }
+    }
+}
+
+/// What [`advance`] left a connection waiting for
+enum Progress {
+    /// Readiness from the socket or a response from a worker, or the connection is gone
+    Waiting,
+    /// The request head is complete and should go to a worker
+    Head(Vec<u8>),
+    /// Used up its write quantum with more to send
+    Yielded,
+}
+
+enum State {
+    /// Collecting the request head
+    Reading(Vec<u8>),
+    /// A worker is running the handler
+    Handling,
+    /// Sending the response the worker produced, then the file body in chunks
+    Writing {
+        response: Vec<u8>,
+        written: usize,
+        body: Option<Take<File>>,
+    },
+}
+
+struct Client {
+    stream: Box<dyn Stream>,
+    peer_ip: Option<IpAddr>,
+    state: State,
+    /// When the connection was accepted, or last took some of its response
+    since: Instant,
+    _admission: Admission,
+}
+
+/// Serves every listener from a single readiness loop
+///
+/// The loop reads request heads and writes responses on non-blocking sockets,
+/// so slow clients only cost a buffer. Workers from `pool` are busy only while
+/// the handler itself runs. Large responses are written round-robin, one
+/// quantum per connection at a time, so a few big downloads don't hold up
+/// small requests. Once shutdown begins the listeners are dropped, and
+/// the loop returns when the remaining connections are done.
+pub fn run(listeners: Vec<Listener>, context: Arc<Context>, cache: FileCache, pool: ThreadPool) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (finished, responses) = channel::<(Token, Vec<u8>, Option<FileBody>)>();
+
+    let mut acceptors = Vec::new();
+    for (index, listener) in listeners.into_iter().enumerate() {
+        let mut acceptor = Acceptor::new(listener)?;
+        poll.registry().register(acceptor.source(), Token(index), Interest::READABLE)?;
+        acceptors.push(acceptor);
+    }
+
+    let mut clients: HashMap<Token, Client> = HashMap::new();
+    let mut next_token = acceptors.len();
+    let mut events = Events::with_capacity(1024);
+    // Connections that can keep writing without waiting for readiness
+    let mut ready = VecDeque::new();
+    let mut last_expiry = Instant::now();
+
+    loop {
+        if !acceptors.is_empty() && context.shutdown.is_draining() {
+            for acceptor in &mut acceptors {
+                poll.registry().deregister(acceptor.source())?;
+            }
+            // Closing the sockets here would also close them for a process taking over
+            acceptors.clear();
+        }
+        if acceptors.is_empty() && clients.is_empty() {
+            return Ok(());
+        }
+
+        let timeout = if ready.is_empty() { ACCEPT_POLL_INTERVAL } else { Duration::ZERO };
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            let token = event.token();
+            if token == WAKER {
+                while let Ok((token, response, body)) = responses.try_recv() {
+                    if let Some(client) = clients.get_mut(&token) {
+                        let Ok(body) = body.map(FileBody::into_reader).transpose() else {
+                            clients.remove(&token);
+                            continue;
+                        };
+                        client.state = State::Writing {
+                            response,
+                            written: 0,
+                            body,
+                        };
+                        client.since = Instant::now();
+                        poll.registry().reregister(&mut client.stream, token, Interest::WRITABLE)?;
+                    }
+                    // The socket is most likely writable already
+                    if let Progress::Yielded = advance(&mut clients, token) {
+                        ready.push_back(token);
+                    }
+                }
+            } else if let Some(acceptor) = acceptors.get(token.0) {
+                loop {
+                    match acceptor.accept(context.tcp_nodelay) {
+                        Ok((mut stream, peer_ip)) => {
+                            let admission = match admit(&context, peer_ip) {
+                                Ok(admission) => admission,
+                                Err(refusal) => {
+                                    // A fresh socket has room for the short response
+                                    let _ = refusal.send(&mut stream, false);
+                                    continue;
+                                }
+                            };
+                            let token = Token(next_token);
+                            next_token = (next_token + 1) % WAKER.0;
+                            poll.registry().register(&mut stream, token, Interest::READABLE)?;
+                            clients.insert(
+                                token,
+                                Client {
+                                    stream,
+                                    peer_ip,
+                                    state: State::Reading(Vec::new()),
+                                    since: Instant::now(),
+                                    _admission: admission,
+                                },
+                            );
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            break;
+                        }
+                    }
+                }
+            } else if !ready.contains(&token) {
+                match advance(&mut clients, token) {
+                    Progress::Waiting => {}
+                    Progress::Head(head) => {
+                        let Some(client) = clients.get(&token) else {
+                            continue;
+                        };
+                        let (context, cache, finished, waker) =
+                            (Arc::clone(&context), Arc::clone(&cache), finished.clone(), Arc::clone(&waker));
+                        let peer_ip = client.peer_ip;
+                        pool.execute(move || {
+                            let mut buffered = BufferedConnection::new(head, peer_ip);
+                            handle_connection(&mut buffered, &context, cache);
+                            let (response, body) = buffered.into_output();
+                            let _ = finished.send((token, response, body));
+                            let _ = waker.wake();
+                        });
+                    }
+                    Progress::Yielded => ready.push_back(token),
+                }
+            }
+        }
+
+        if last_expiry.elapsed() >= ACCEPT_POLL_INTERVAL {
+            expire(&mut clients, context.timeouts);
+            last_expiry = Instant::now();
+        }
+
+        // One more turn for each connection that still has data to send
+        for _ in 0..ready.len() {
+            let Some(token) = ready.pop_front() else {
+                break;
+            };
+            if let Progress::Yielded = advance(&mut clients, token) {
+                ready.push_back(token);
+            }
+        }
+    }
+}
+
+/// Reads or writes as far as the socket and the write quantum allow, dropping the client when it is done
+///
+/// A complete request head is handed back, leaving the client in [`State::Handling`].
+fn advance(clients: &mut HashMap<Token, Client>, token: Token) -> Progress {
+    let Some(client) = clients.get_mut(&token) else {
+        return Progress::Waiting;
+    };
+    let mut chunk = [0; 4096];
+    let outcome = match &mut client.state {
+        State::Reading(head) => loop {
+            match client.stream.read(&mut chunk) {
+                // A client that leaves without a request gets no response
+                Ok(0) if head.is_empty() => break Err(()),
+                Ok(0) => break Ok(true),
+                Ok(bytes_read) => {
+                    head.extend_from_slice(&chunk[..bytes_read]);
+                    // The handler rejects a head that grew too large
+                    if head.windows(4).any(|window| window == b"\r\n\r\n") || head.len() > request::MAX_HEAD {
+                        break Ok(true);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break Err(()),
+            }
+        },
+        State::Handling => Ok(false),
+        State::Writing { response, written, body } => {
+            let mut budget = WRITE_QUANTUM;
+            loop {
+                if *written == response.len() {
+                    match body.as_mut().map(|body| refill(body, response)) {
+                        Some(Ok(read)) if read > 0 => *written = 0,
+                        _ => break Err(()), // Done (or the file failed), close the connection
+                    }
+                }
+                if budget == 0 {
+                    return Progress::Yielded;
+                }
+                let end = (*written + budget).min(response.len());
+                match client.stream.write(&response[*written..end]) {
+                    Ok(0) => break Err(()),
+                    Ok(bytes_written) => {
+                        client.since = Instant::now();
+                        *written += bytes_written;
+                        budget -= bytes_written;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break Err(()),
+                }
+            }
+        }
+    };
+
+    match outcome {
+        Ok(true) => match std::mem::replace(&mut client.state, State::Handling) {
+            State::Reading(head) => Progress::Head(head),
+            _ => Progress::Waiting,
+        },
+        Ok(false) => Progress::Waiting,
+        Err(()) => {
+            clients.remove(&token);
+            Progress::Waiting
+        }
+    }
+}
+
+/// Drops the connections that took too long to send their request head or to take their response
+///
+/// A request head that is late gets a 408, written as far as the socket takes it.
+fn expire(clients: &mut HashMap<Token, Client>, timeouts: Timeouts) {
+    let expired = |timeout: Option<Duration>, since: Instant| timeout.is_some_and(|timeout| since.elapsed() >= timeout);
+    clients.retain(|_, client| match client.state {
+        State::Reading(_) if expired(timeouts.header, client.since) => {
+            let mut response = Vec::new();
+            let _ = Response::error(408).header("Connection", "close").send(&mut response, false);
+            let _ = client.stream.write(&response);
+            false
+        }
+        State::Writing { .. } => !expired(timeouts.write, client.since),
+        _ => true,
+    });
+}
+
+/// Replaces the sent contents of `buffer` with the next chunk of the file body
+///
+/// The file is read on the loop thread, one quantum at a time.
+fn refill(body: &mut Take<File>, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    buffer.clear();
+    body.by_ref().take(WRITE_QUANTUM as u64).read_to_end(buffer)
+}
@@ -0,0 +1,132 @@
+use crate::shutdown::Shutdown;
+use rshttp::response::Response;
+use std::io::{self, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// URL of the change event stream
+pub const PATH: &str = "/_rshttps/events";
+
+/// How often an idle stream gets a comment, so proxies and browsers don't time it out
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often a stream checks whether shutdown has begun
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A file the watcher saw change, by its URL path
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+pub enum Change {
+    Changed(String),
+    Removed(String),
+}
+
+/// Streams the watcher's changes to clients of [`PATH`] as server-sent events
+///
+/// Every changed file becomes a `change` event and every deleted one a
+/// `remove` event, with the file's URL path as data, so build tools and
+/// front-end code can react without the --live-reload script. Each open
+/// stream holds a worker, so only so many are served at once and the rest
+/// get 503.
+pub struct ChangeEvents {
+    subscribers: Mutex<Vec<Sender<Arc<Vec<Change>>>>>,
+    open: AtomicUsize,
+    max_open: usize,
+}
+
+/// An open stream, counted until dropped
+struct OpenStream<'a>(&'a AtomicUsize);
+
+impl Drop for OpenStream<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ChangeEvents {
+    /// Serves at most `max_open` streams at a time
+    pub fn new(max_open: usize) -> ChangeEvents {
+        ChangeEvents {
+            subscribers: Mutex::new(Vec::new()),
+            open: AtomicUsize::new(0),
+            max_open: max_open.max(1),
+        }
+    }
+
+    /// Sends one batch of changes to every open stream, forgetting streams that have ended
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn publish(&self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        let changes = Arc::new(changes);
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(Arc::clone(&changes)).is_ok());
+    }
+
+    fn open(&self) -> Option<OpenStream<'_>> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < self.max_open).then_some(open + 1))
+            .ok()
+            .map(|_| OpenStream(&self.open))
+    }
+
+    fn subscribe(&self) -> Receiver<Arc<Vec<Change>>> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Answers a request for [`PATH`] with an event stream that lasts until the client leaves or shutdown begins
+    pub fn stream(&self, stream: &mut impl Write, shutdown: &Shutdown) -> io::Result<()> {
+        let Some(_open) = self.open() else {
+            return Response::error(503).header("Retry-After", "5").send(stream, false);
+        };
+        let changes = self.subscribe();
+        Response::new(200)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-store")
+            .send_open_head(stream)?;
+
+        let mut last_write = Instant::now();
+        while !shutdown.is_draining() {
+            match changes.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(batch) => {
+                    let mut events = String::new();
+                    for change in batch.iter() {
+                        let (event, path) = match change {
+                            Change::Changed(path) => ("change", path),
+                            Change::Removed(path) => ("remove", path),
+                        };
+                        events.push_str(&format!("event: {}\ndata: {}\n\n", event, path));
+                    }
+                    stream.write_all(events.as_bytes())?;
+                }
+                Err(RecvTimeoutError::Timeout) if last_write.elapsed() >= KEEPALIVE_INTERVAL => {
+                    stream.write_all(b": keepalive\n\n")?;
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            stream.flush()?;
+            last_write = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_streams_are_capped() {
+        let events = ChangeEvents::new(2);
+        let first = events.open();
+        let second = events.open();
+        assert!(first.is_some() && second.is_some());
+        assert!(events.open().is_none());
+        drop(first);
+        assert!(events.open().is_some());
+    }
+}
@@ -0,0 +1,30 @@
+use crate::units;
+use std::process::Command;
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+/// Runs the --exec command through the shell, returning whether it succeeded
+///
+/// Its output goes straight to the server's stdout and stderr.
+pub fn run(command: &str) -> bool {
+    info!("Running `{}`", command);
+    let started = Instant::now();
+    #[cfg(unix)]
+    let status = Command::new("sh").arg("-c").arg(command).status();
+    #[cfg(not(unix))]
+    let status = Command::new("cmd").arg("/C").arg(command).status();
+    match status {
+        Ok(status) if status.success() => {
+            info!("`{}` finished in {}", command, units::duration(started.elapsed()));
+            true
+        }
+        Ok(status) => {
+            warn!("`{}` failed ({}), leaving the served files as they were", command, status);
+            false
+        }
+        Err(e) => {
+            error!("Failed to run `{}`: {}", command, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use rshttp::request;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+use tracing::{debug, debug_span, warn};
+
+/// Largest body accepted from the fallback origin
+const MAX_BODY: u64 = 512 << 20;
+
+/// How long a connection attempt gets before the next address is tried alongside it (as in RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long one connection attempt may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A remote mirror asked for files that are missing locally
+pub struct FallbackOrigin {
+    base: String,
+    agent: ureq::Agent,
+    policy: CachePolicy,
+    /// Where fetched files are saved, from --fallback-store
+    store: Option<PathBuf>,
+}
+
+/// Bounds on how long files from the mirror are cached, whatever its own headers say
+#[derive(Default)]
+pub struct CachePolicy {
+    pub min_ttl: Option<Duration>,
+    pub max_ttl: Option<Duration>,
+    /// Never cache HTML, so pages always come fresh from the mirror
+    pub no_store_html: bool,
+}
+
+/// A file fetched from the mirror
+pub struct Fetched {
+    pub contents: Vec<u8>,
+    pub mime_type: String,
+    /// How long the file may be cached: without limit when `None`, not at all when zero
+    pub ttl: Option<Duration>,
+}
+
+impl FallbackOrigin {
+    pub fn new(base: &str, policy: CachePolicy, store: Option<&Path>) -> FallbackOrigin {
+        FallbackOrigin {
+            base: base.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).resolver(HappyEyeballs).build(),
+            policy,
+            store: store.map(Path::to_path_buf),
+        }
+    }
+
+    /// Fetches `path`, decoded and normalized, from the store if it was saved there, or else from the mirror
+    ///
+    /// Any failure, including a non-2xx status from the mirror, counts as not found.
+    pub fn fetch(&self, path: &str, query: &str) -> Option<Fetched> {
+        let stored = self.stored_path(path);
+        if let Some(contents) = stored.as_ref().and_then(|stored| fs::read(stored).ok()) {
+            return Some(Fetched {
+                contents,
+                mime_type: mime_guess::from_path(stored.as_ref()?).first_or_octet_stream().to_string(),
+                ttl: None,
+            });
+        }
+        let fetched = self.fetch_remote(path, query)?;
+        if let Some(stored) = stored.filter(|_| fetched.ttl.is_none_or(|ttl| !ttl.is_zero())) {
+            match save(&stored, &fetched.contents) {
+                Ok(()) => debug!("Saved {} from the fallback origin to {}", path, stored.display()),
+                Err(e) => warn!("Failed to save {} to {}: {}", path, stored.display(), e),
+            }
+        }
+        Some(fetched)
+    }
+
+    /// Where `path` is kept in the store, directories saved as their index.html
+    fn stored_path(&self, path: &str) -> Option<PathBuf> {
+        let path = request::normalize_path(path);
+        let mut stored = self.store.as_ref()?.join(path.trim_start_matches('/'));
+        if path.ends_with('/') {
+            stored.push("index.html");
+        }
+        Some(stored)
+    }
+
+    fn fetch_remote(&self, path: &str, query: &str) -> Option<Fetched> {
+        let _fetch = debug_span!("fallback_fetch").entered();
+        let url = match query {
+            "" => format!("{}{}", self.base, request::encode_path(path)),
+            query => format!("{}{}?{}", self.base, request::encode_path(path), query),
+        };
+        let response = match self.agent.get(&url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(..)) => return None,
+            Err(e) => {
+                warn!("Fallback origin request for {} failed: {}", url, e);
+                return None;
+            }
+        };
+        let mime_type = match response.header("Content-Type") {
+            Some(content_type) => content_type.split(';').next().unwrap_or("").trim().to_string(),
+            None => mime_guess::from_path(path).first_or_octet_stream().to_string(),
+        };
+
+        let ttl = self.policy.ttl(response.header("Cache-Control"), &mime_type);
+
+        let mut contents = Vec::new();
+        if let Err(e) = response.into_reader().take(MAX_BODY).read_to_end(&mut contents) {
+            warn!("Fallback origin response for {} failed: {}", url, e);
+            return None;
+        }
+        Some(Fetched {
+            contents,
+            mime_type,
+            ttl,
+        })
+    }
+}
+
+/// Writes a file next to its final place first, so concurrent requests never read half of it
+fn save(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = path.with_extension("partial");
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)
+}
+
+impl CachePolicy {
+    /// How long a file may be cached, from the mirror's `Cache-Control` clamped to the configured bounds
+    ///
+    /// `no-store`, `no-cache` and `private` count as a TTL of zero, which
+    /// --fallback-min-ttl can still raise; a missing max-age counts as no limit.
+    fn ttl(&self, cache_control: Option<&str>, mime_type: &str) -> Option<Duration> {
+        if self.no_store_html && mime_type == "text/html" {
+            return Some(Duration::ZERO);
+        }
+        let directives: Vec<String> =
+            cache_control.unwrap_or("").split(',').map(|directive| directive.trim().to_ascii_lowercase()).collect();
+        let max_age = |name: &str| {
+            directives
+                .iter()
+                .find_map(|directive| directive.strip_prefix(name)?.strip_prefix('=')?.trim_matches('"').parse().ok())
+                .map(Duration::from_secs)
+        };
+        let origin = match directives.iter().any(|directive| matches!(directive.as_str(), "no-store" | "no-cache" | "private")) {
+            true => Some(Duration::ZERO),
+            false => max_age("s-maxage").or_else(|| max_age("max-age")),
+        };
+
+        let ttl = match (origin, self.max_ttl) {
+            (Some(ttl), Some(max)) => Some(ttl.min(max)),
+            (ttl, max) => ttl.or(max),
+        };
+        match (ttl, self.min_ttl) {
+            (Some(ttl), Some(min)) => Some(ttl.max(min)),
+            (ttl, _) => ttl,
+        }
+    }
+}
+
+/// Resolves the mirror's host name, racing its first IPv6 and IPv4 addresses
+///
+/// ureq tries the addresses it gets one after another, so a host with a
+/// broken IPv6 route would hang on every new connection until the attempt
+/// times out. This starts with IPv6, tries IPv4 alongside it after
+/// [`CONNECTION_ATTEMPT_DELAY`] (or as soon as IPv6 fails), and puts
+/// whichever connects first at the front of the list.
+struct HappyEyeballs;
+
+impl ureq::Resolver for HappyEyeballs {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let mut addresses: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+        let first_v6 = addresses.iter().copied().find(SocketAddr::is_ipv6);
+        let first_v4 = addresses.iter().copied().find(SocketAddr::is_ipv4);
+        let (Some(v6), Some(v4)) = (first_v6, first_v4) else {
+            return Ok(addresses);
+        };
+        if let Some(winner) = race(&[v6, v4]) {
+            addresses.retain(|address| *address != winner);
+            addresses.insert(0, winner);
+        }
+        Ok(addresses)
+    }
+}
+
+/// Connects to `candidates` in order, each one starting [`CONNECTION_ATTEMPT_DELAY`]
+/// after the one before or once it failed, and returns the first that connects
+fn race(candidates: &[SocketAddr]) -> Option<SocketAddr> {
+    let (tx, rx) = channel();
+    let (mut started, mut failed) = (0, 0);
+    loop {
+        if let Some(&address) = candidates.get(started) {
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let connected = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).is_ok();
+                let _ = tx.send((address, connected));
+            });
+            started += 1;
+        }
+        let wait = match started < candidates.len() {
+            true => CONNECTION_ATTEMPT_DELAY,
+            false => CONNECT_TIMEOUT,
+        };
+        match rx.recv_timeout(wait) {
+            Ok((address, true)) => return Some(address),
+            Ok((_, false)) => {
+                failed += 1;
+                if failed == candidates.len() {
+                    return None;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if started < candidates.len() => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{AccessPolicy, AccessRequest, Decision};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Fetches `target` as a request for it would be, and returns the path the origin was asked for
+    fn requested_from_origin(target: &str) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = FallbackOrigin::new(&format!("http://{}", listener.local_addr().unwrap()), CachePolicy::default(), None);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).unwrap();
+            (&stream).write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").unwrap();
+            request_line.split(' ').nth(1).unwrap().to_string()
+        });
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = crate::request_path(path).unwrap();
+        assert_eq!(origin.fetch(&path, query).unwrap().contents, b"ok");
+        (path, server.join().unwrap())
+    }
+
+    #[test]
+    fn origins_are_asked_for_the_path_the_rules_saw() {
+        let policy = AccessPolicy::parse("deny /private/**").unwrap();
+        for target in ["/pub/%2e%2e/private/a.txt", "/%70rivate/a.txt", "/pub%2F..%2Fprivate/a.txt"] {
+            let (path, requested) = requested_from_origin(target);
+            assert_eq!(requested, "/private/a.txt");
+            let request = AccessRequest { method: "GET", path: &path, ip: None, principal: None };
+            assert_eq!(policy.evaluate(&request), Decision::Deny, "{} was let through", target);
+        }
+        assert_eq!(requested_from_origin("/My%20Files/a%3Fb.txt?v=1").1, "/My%20Files/a%3Fb.txt?v=1");
+    }
+}
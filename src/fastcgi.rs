@@ -0,0 +1,259 @@
+use crate::cgi::{self, Invocation, Script};
+use crate::glob;
+use crate::listener::{self, Connection};
+use rshttp::request;
+use rshttp::response::Response;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How long to wait for the backend to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest content of one record
+const MAX_RECORD: usize = 65535;
+
+/// The only request on each connection
+const REQUEST_ID: u16 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+/// The file served when a directory is requested, if the pattern matches it
+const INDEX: &str = "index.php";
+
+/// Scripts matching a pattern handed to a FastCGI backend such as php-fpm, from --fastcgi PATTERN=ADDRESS
+///
+/// A pattern without a slash matches file names (`*.php`), one with a slash
+/// whole paths (`/app/**/*.php`). The address is `host:port`, or
+/// `unix:/path/to/socket` on Unix. Scripts get the same variables as CGI
+/// ones, with `SCRIPT_FILENAME` for PHP to find them.
+#[derive(Debug, Clone)]
+pub struct FastCgi {
+    pattern: String,
+    address: String,
+}
+
+/// Either end of a backend connection
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+impl FastCgi {
+    /// Parses `PATTERN=ADDRESS`
+    pub fn parse(value: &str) -> Result<FastCgi, String> {
+        let (pattern, address) = value
+            .split_once('=')
+            .filter(|(pattern, address)| !pattern.is_empty() && !address.is_empty())
+            .ok_or_else(|| format!("invalid FastCGI backend '{}' (expected e.g. *.php=127.0.0.1:9000)", value))?;
+        Ok(FastCgi {
+            pattern: pattern.to_string(),
+            address: address.to_string(),
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The script a path runs, if any
+    ///
+    /// `/index.php/blog/post` runs `index.php` with `/blog/post` as PATH_INFO,
+    /// and `/blog/` runs `/blog/index.php` if the pattern matches it.
+    pub fn find_script(&self, root: &Path, path: &str) -> Option<Script> {
+        let mut file = root.to_path_buf();
+        let mut end = 0;
+        for segment in path.split('/').skip(1).filter(|segment| !segment.is_empty()) {
+            file.push(segment);
+            end += 1 + segment.len();
+            if self.matches(&path[..end]) && file.is_file() {
+                return Some(Script {
+                    file,
+                    name: path[..end].to_string(),
+                    path_info: path[end..].to_string(),
+                });
+            }
+            if !file.is_dir() {
+                return None;
+            }
+        }
+        let name = format!("{}/{}", path.trim_end_matches('/'), INDEX);
+        let file = file.join(INDEX);
+        (self.matches(&name) && file.is_file()).then(|| Script {
+            file,
+            name,
+            path_info: String::new(),
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self.pattern.contains('/') {
+            true => glob::matches(&self.pattern, path),
+            false => glob::matches(&self.pattern, path.rsplit('/').next().unwrap_or(path)),
+        }
+    }
+
+    /// Runs a script on the backend and sends its response
+    pub fn run(&self, client: &mut impl Connection, invocation: Invocation, script: &Script) -> io::Result<()> {
+        let head_only = invocation.method == "HEAD";
+        if invocation.headers.get("Transfer-Encoding").is_some() {
+            return Response::error(411).header("Connection", "close").send(client, head_only);
+        }
+        let mut params = cgi::environment(&invocation, script);
+        // php-cgi refuses to run scripts without it
+        params.push(("REDIRECT_STATUS".to_string(), "200".to_string()));
+
+        let mut backend = match self.connect() {
+            Ok(backend) => backend,
+            Err(e) => {
+                warn!("Cannot reach the FastCGI backend {}: {}", self.address, e);
+                return Response::error(502).send(client, head_only);
+            }
+        };
+        request::send_continue(client, invocation.headers, invocation.received_body())?;
+        match send_request(&mut backend, client, &invocation, &params) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                debug!("The client sent less of the body than announced");
+                return Response::error(400).header("Connection", "close").send(client, head_only);
+            }
+            Err(e) => {
+                warn!("Failed to send {} to the FastCGI backend {}: {}", script.name, self.address, e);
+                return Response::error(502).send(client, head_only);
+            }
+        }
+        let output = match read_output(&mut backend, &script.name) {
+            Ok(output) => output,
+            Err(e) if listener::is_timeout(&e) => {
+                warn!("The FastCGI backend {} did not answer {} in time", self.address, script.name);
+                return Response::error(504).send(client, head_only);
+            }
+            Err(e) => {
+                warn!("Failed to read the answer of the FastCGI backend {}: {}", self.address, e);
+                return Response::error(502).send(client, head_only);
+            }
+        };
+        match cgi::parse_output(&output) {
+            Some(response) => response.send(client, head_only),
+            None => {
+                warn!("The FastCGI backend answered {} without a valid CGI header", script.name);
+                Response::error(502).send(client, head_only)
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        #[cfg(unix)]
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            let stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(cgi::TIMEOUT))?;
+            stream.set_write_timeout(Some(cgi::TIMEOUT))?;
+            return Ok(Box::new(stream));
+        }
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address");
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(cgi::TIMEOUT))?;
+                    stream.set_write_timeout(Some(cgi::TIMEOUT))?;
+                    return Ok(Box::new(stream));
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Writes the request as BEGIN_REQUEST, PARAMS and STDIN records
+fn send_request(
+    backend: &mut dyn Stream,
+    client: &mut impl Read,
+    invocation: &Invocation,
+    params: &[(String, String)],
+) -> io::Result<()> {
+    // The responder role, and close the connection afterwards
+    write_record(backend, BEGIN_REQUEST, &[0, 1, 0, 0, 0, 0, 0, 0])?;
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    for chunk in encoded.chunks(MAX_RECORD) {
+        write_record(backend, PARAMS, chunk)?;
+    }
+    write_record(backend, PARAMS, &[])?;
+
+    let received = invocation.received_body();
+    for chunk in received.chunks(MAX_RECORD) {
+        write_record(backend, STDIN, chunk)?;
+    }
+    let remaining = invocation.headers.content_length().unwrap_or(0) - received.len() as u64;
+    let mut body = client.take(remaining);
+    let mut buffer = vec![0u8; MAX_RECORD];
+    let mut copied = 0;
+    loop {
+        let read = body.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        copied += read as u64;
+        write_record(backend, STDIN, &buffer[..read])?;
+    }
+    if copied < remaining {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    write_record(backend, STDIN, &[])?;
+    backend.flush()
+}
+
+/// Collects STDOUT records until END_REQUEST, logging STDERR
+fn read_output(backend: &mut dyn Stream, script_name: &str) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    loop {
+        let mut header = [0u8; 8];
+        backend.read_exact(&mut header)?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0u8; length + header[6] as usize];
+        backend.read_exact(&mut content)?;
+        content.truncate(length);
+        match header[1] {
+            STDOUT if (output.len() + length) as u64 <= cgi::MAX_OUTPUT => output.extend_from_slice(&content),
+            STDOUT => return Err(io::Error::new(io::ErrorKind::InvalidData, "the output is too large")),
+            STDERR => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    warn!("{}: {}", script_name, line);
+                }
+            }
+            END_REQUEST => return Ok(output),
+            _ => {}
+        }
+    }
+}
+
+fn write_record(backend: &mut dyn Stream, kind: u8, content: &[u8]) -> io::Result<()> {
+    let [id_high, id_low] = REQUEST_ID.to_be_bytes();
+    let [length_high, length_low] = (content.len() as u16).to_be_bytes();
+    backend.write_all(&[1, kind, id_high, id_low, length_high, length_low, 0, 0])?;
+    backend.write_all(content)
+}
+
+/// Lengths below 128 take one byte, longer ones four with the top bit set
+fn encode_length(encoded: &mut Vec<u8>, length: usize) {
+    match length < 128 {
+        true => encoded.push(length as u8),
+        false => encoded.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes()),
+    }
+}
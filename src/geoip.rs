@@ -0,0 +1,90 @@
+use maxminddb::{geoip2, Reader};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Countries allowed and denied to connect, looked up in a MaxMind database, from --geoip-db
+///
+/// A peer in a --geo-deny country is refused, and so is one outside the
+/// --geo-allow countries when some are given. Loopback, private and
+/// link-local addresses are left to --allow and --deny; any other address the
+/// database has no country for is refused when --geo-allow is given. Both
+/// --allow/--deny and the country rules have to let a peer through.
+pub struct GeoFilter {
+    reader: Reader<Vec<u8>>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl GeoFilter {
+    /// Reads the GeoLite2 or GeoIP2 Country (or City) database at `path`
+    pub fn open(path: &Path, allow: &[String], deny: &[String]) -> Result<GeoFilter, String> {
+        let reader = Reader::open_readfile(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let codes = |countries: &[String]| countries.iter().map(|country| country.trim().to_ascii_uppercase()).collect();
+        Ok(GeoFilter {
+            reader,
+            allow: codes(allow),
+            deny: codes(deny),
+        })
+    }
+
+    /// What the database is, e.g. `GeoLite2-Country`
+    pub fn describe(&self) -> &str {
+        &self.reader.metadata.database_type
+    }
+
+    /// The ISO 3166 code of the country `ip` is in, if the database knows it
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        let found: geoip2::Country = self.reader.lookup(ip).ok()?;
+        let country = found.country.or(found.registered_country)?;
+        country.iso_code.map(str::to_string)
+    }
+
+    /// Whether a peer at `ip` may connect
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        match self.country(ip) {
+            Some(country) => !self.deny.contains(&country) && (self.allow.is_empty() || self.allow.contains(&country)),
+            None => self.allow.is_empty() || is_local(ip),
+        }
+    }
+}
+
+/// Whether `ip` is an address no country is behind: loopback, private, link-local or unspecified
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_local(IpAddr::V4(v4)),
+            None => {
+                let segment = v6.segments()[0];
+                // Unique local fc00::/7 and link-local fe80::/10
+                v6.is_loopback() || v6.is_unspecified() || segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+impl fmt::Debug for GeoFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GeoFilter").field("allow", &self.allow).field("deny", &self.deny).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_addresses() {
+        for local in ["127.0.0.1", "10.1.2.3", "192.168.0.1", "169.254.1.1", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(is_local(local.parse().unwrap()), "{}", local);
+        }
+        for public in ["8.8.8.8", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_local(public.parse().unwrap()), "{}", public);
+        }
+    }
+}
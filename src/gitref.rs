@@ -0,0 +1,83 @@
+use crate::mime::MimeTypes;
+use git2::{ObjectType, Repository};
+use rshttp::response::Response;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The files of a commit, tag or branch of the repository the root is in, from --git-ref
+///
+/// Files are read out of the repository's objects, so the working tree can
+/// hold unfinished changes while the committed version is previewed. The ref
+/// is looked up again for every request: a branch is served as it moves, a tag
+/// or commit stays put. When the root is a subdirectory of the repository,
+/// that directory of the commit is served.
+pub struct GitTree {
+    repository: Mutex<Repository>,
+    revision: String,
+    /// The root's path inside the repository, ending in a slash, or empty
+    prefix: String,
+}
+
+impl GitTree {
+    pub fn open(root: &Path, revision: &str) -> Result<GitTree, String> {
+        let repository = Repository::discover(root)
+            .map_err(|e| format!("{} is not in a git repository: {}", root.display(), e.message()))?;
+        let workdir = repository.workdir().ok_or_else(|| "the repository has no working tree".to_string())?;
+        let workdir = workdir.canonicalize().unwrap_or_else(|_| workdir.to_path_buf());
+        let relative = root.strip_prefix(&workdir).unwrap_or(Path::new(""));
+        let prefix: String = relative.iter().map(|segment| format!("{}/", segment.to_string_lossy())).collect();
+        let tree = GitTree {
+            repository: Mutex::new(repository),
+            revision: revision.to_string(),
+            prefix,
+        };
+        tree.commit()?;
+        Ok(tree)
+    }
+
+    /// The short id and summary of the commit the ref names now
+    pub fn commit(&self) -> Result<String, String> {
+        let repository = self.repository.lock().unwrap();
+        let commit = repository
+            .revparse_single(&self.revision)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| format!("cannot find {} in the repository: {}", self.revision, e.message()))?;
+        let id = commit.id().to_string();
+        Ok(format!("{} {}", &id[..7], commit.summary().ok().flatten().unwrap_or("")))
+    }
+
+    /// The repository's .git directory, which the sandbox has to leave readable
+    pub fn git_dir(root: &Path) -> Option<PathBuf> {
+        Some(Repository::discover(root).ok()?.path().to_path_buf())
+    }
+
+    /// Answers a request for `url_path` with the file of the commit it names
+    pub fn respond(&self, url_path: &str, mime_types: &MimeTypes, range: Option<&str>) -> Response<'static> {
+        let repository = self.repository.lock().unwrap();
+        let tree = match repository.revparse_single(&self.revision).and_then(|object| object.peel_to_tree()) {
+            Ok(tree) => tree,
+            Err(e) => {
+                tracing::error!("Cannot find {} in the repository: {}", self.revision, e.message());
+                return Response::error(500);
+            }
+        };
+        let mut name = format!("{}{}", self.prefix, url_path.trim_start_matches('/'));
+        let is_tree = |name: &str| {
+            let name = name.trim_end_matches('/');
+            name.is_empty() || tree.get_path(Path::new(name)).is_ok_and(|entry| entry.kind() == Some(ObjectType::Tree))
+        };
+        if is_tree(&name) {
+            name = format!("{}/index.html", name.trim_end_matches('/')).trim_start_matches('/').to_string();
+        }
+        let blob = tree
+            .get_path(Path::new(&name))
+            .ok()
+            .filter(|entry| entry.kind() == Some(ObjectType::Blob) && entry.filemode() != 0o120000)
+            .and_then(|entry| repository.find_blob(entry.id()).ok());
+        let Some(blob) = blob else {
+            return Response::error(404);
+        };
+        let contents = blob.content();
+        Response::file(contents, &mime_types.of(Path::new(&name)), range).into_owned()
+    }
+}
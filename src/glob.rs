@@ -0,0 +1,25 @@
+/// Matches a path against a glob where `*` stays within one segment and `**` spans segments
+pub fn matches(pattern: &str, path: &str) -> bool {
+    match_bytes(pattern.as_bytes(), path.as_bytes())
+}
+
+/// Like [`matches`], but `/dir/**` also covers `/dir` itself, which serves the directory's index
+pub fn covers(pattern: &str, path: &str) -> bool {
+    matches(pattern, path) || pattern.strip_suffix("/**").is_some_and(|dir| dir == path.trim_end_matches('/'))
+}
+
+fn match_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `/**/` may also match a single `/`, so `/a/**/b` matches `/a/b`
+            let rest_without_slash = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=text.len()).any(|i| match_bytes(rest, &text[i..]) || match_bytes(rest_without_slash, &text[i..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| match_bytes(rest, &text[i..])),
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && match_bytes(rest, tail)),
+        [p, rest @ ..] => matches!(text, [c, tail @ ..] if c == p && match_bytes(rest, tail)),
+    }
+}
@@ -0,0 +1,226 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Headers that differ between otherwise identical responses, left out of snapshots
+const VOLATILE_HEADERS: [&str; 1] = ["date"];
+
+/// How long one fetch may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Arguments of the `snapshot` subcommand
+#[derive(clap::Args, Debug)]
+pub struct SnapshotArgs {
+    /// File with one URL per line (`#` starts a comment); lines starting with `/` are fetched from --base
+    #[arg(long, value_name = "FILE")]
+    urls: PathBuf,
+    /// Directory holding one stored response per URL
+    #[arg(long, value_name = "DIR")]
+    out: PathBuf,
+    /// Server that paths in the URL list are fetched from
+    #[arg(long, value_name = "URL", default_value = "http://127.0.0.1:8000")]
+    base: String,
+    /// Compare the responses with the stored ones instead of storing them
+    #[arg(long)]
+    compare: bool,
+    /// Also leave this header out of the comparison (repeatable)
+    #[arg(long = "ignore-header", value_name = "NAME")]
+    ignore_headers: Vec<String>,
+}
+
+/// A response reduced to what should stay the same from one run to the next
+struct Snapshot {
+    status_line: String,
+    /// Lowercased names, sorted, without volatile headers
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Stores or compares the responses to every listed URL and returns the process exit code
+///
+/// Exits with 0 when every response matches its stored copy, 1 when any
+/// differs or is missing, and 2 when a URL can't be fetched.
+pub fn run(args: &SnapshotArgs) -> i32 {
+    let urls = match fs::read_to_string(&args.urls) {
+        Ok(list) => list
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.starts_with('/') {
+                true => format!("{}{}", args.base.trim_end_matches('/'), line),
+                false => line.to_string(),
+            })
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Error: {}: {}", args.urls.display(), e);
+            return 2;
+        }
+    };
+    if !args.compare {
+        if let Err(e) = fs::create_dir_all(&args.out) {
+            eprintln!("Error: {}: {}", args.out.display(), e);
+            return 2;
+        }
+    }
+
+    let ignored: Vec<String> = VOLATILE_HEADERS
+        .iter()
+        .map(|name| name.to_string())
+        .chain(args.ignore_headers.iter().map(|name| name.to_ascii_lowercase()))
+        .collect();
+    let (mut differences, mut errors) = (0, 0);
+    for url in &urls {
+        let snapshot = match fetch(url) {
+            Ok(raw) => match Snapshot::parse(&raw, &ignored) {
+                Some(snapshot) => snapshot,
+                None => {
+                    eprintln!("Error: {}: malformed response", url);
+                    errors += 1;
+                    continue;
+                }
+            },
+            Err(e) => {
+                eprintln!("Error: {}: {}", url, e);
+                errors += 1;
+                continue;
+            }
+        };
+        let file = args.out.join(file_name(url));
+
+        if !args.compare {
+            match fs::write(&file, snapshot.to_bytes()) {
+                Ok(()) => println!("stored    {} -> {}", url, file.display()),
+                Err(e) => {
+                    eprintln!("Error: {}: {}", file.display(), e);
+                    errors += 1;
+                }
+            }
+            continue;
+        }
+
+        let stored = fs::read(&file).ok().and_then(|raw| Snapshot::parse(&raw, &ignored));
+        match stored {
+            None => {
+                println!("missing   {} (no {})", url, file.display());
+                differences += 1;
+            }
+            Some(stored) => match stored.difference(&snapshot) {
+                None => println!("same      {}", url),
+                Some(difference) => {
+                    println!("changed   {}: {}", url, difference);
+                    differences += 1;
+                }
+            },
+        }
+    }
+
+    match args.compare {
+        true => println!("{} URL(s), {} difference(s), {} error(s)", urls.len(), differences, errors),
+        false => println!("{} URL(s), {} error(s)", urls.len(), errors),
+    }
+    match (errors, differences) {
+        (0, 0) => 0,
+        (0, _) => 1,
+        _ => 2,
+    }
+}
+
+impl Snapshot {
+    /// Parses a raw HTTP/1.1 response, or a stored snapshot, which is one
+    fn parse(raw: &[u8], ignored: &[String]) -> Option<Snapshot> {
+        let end = raw.windows(4).position(|window| window == b"\r\n\r\n")?;
+        let head = std::str::from_utf8(&raw[..end]).ok()?;
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next()?.to_string();
+        let mut headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .filter(|(name, _)| !ignored.contains(name))
+            .collect();
+        headers.sort();
+        Some(Snapshot {
+            status_line,
+            headers,
+            body: raw[end + 4..].to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{}\r\n", self.status_line).into_bytes();
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    /// The first difference between the stored response and a new one, if any
+    fn difference(&self, new: &Snapshot) -> Option<String> {
+        if self.status_line != new.status_line {
+            return Some(format!("status {:?} became {:?}", self.status_line, new.status_line));
+        }
+        for (name, value) in &self.headers {
+            match new.headers.iter().find(|(new_name, _)| new_name == name) {
+                None => return Some(format!("header {} is gone", name)),
+                Some((_, new_value)) if new_value != value => {
+                    return Some(format!("header {}: {:?} became {:?}", name, value, new_value));
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some((name, _)) = new.headers.iter().find(|(name, _)| !self.headers.iter().any(|(old, _)| old == name)) {
+            return Some(format!("new header {}", name));
+        }
+        if self.body != new.body {
+            return Some(match self.body.iter().zip(&new.body).position(|(a, b)| a != b) {
+                Some(offset) => format!("body differs from byte {}", offset),
+                None => format!("body was {} bytes, is {}", self.body.len(), new.body.len()),
+            });
+        }
+        None
+    }
+}
+
+/// Fetches `url` with a plain HTTP/1.1 GET and returns the response exactly as received
+fn fetch(url: &str) -> io::Result<Vec<u8>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+    let url = url::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+    if url.scheme() != "http" {
+        return Err(invalid("only http:// URLs can be fetched"));
+    }
+    let host = url.host_str().ok_or_else(|| invalid("missing host"))?;
+    let port = url.port().unwrap_or(80);
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", target, host_header)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+/// A readable, collision-free file name for the snapshot of `url`
+fn file_name(url: &str) -> PathBuf {
+    let readable: String = url
+        .split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .take(80)
+        .collect();
+    let hash = Sha256::digest(url.as_bytes());
+    let hash: String = hash.iter().take(4).map(|byte| format!("{:02x}", byte)).collect();
+    PathBuf::from(format!("{}-{}.http", readable, hash))
+}
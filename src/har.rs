@@ -0,0 +1,212 @@
+use crate::accesslog::Entry;
+use crate::metrics::Phases;
+use crate::units;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rshttp::request;
+use rshttp::response::reason_phrase;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, Seek, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// Most bytes of a response body kept in the file
+pub const MAX_BODY: usize = 1 << 20;
+
+/// Captures every request and response, written out as an HTTP Archive (HAR 1.2) on shutdown for --har
+///
+/// The file is created at startup, so it can be written after privileges are
+/// dropped, and entries are kept in memory until the server exits. Header
+/// values carrying credentials are left out, as browsers do when exporting.
+pub struct Har {
+    file: File,
+    bodies: bool,
+    entries: Mutex<Vec<Value>>,
+}
+
+impl Har {
+    /// Creates `path`, capturing response bodies too when `bodies` is set
+    pub fn create(path: &Path, bodies: bool) -> io::Result<Har> {
+        Ok(Har {
+            file: File::create(path)?,
+            bodies,
+            entries: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn bodies(&self) -> bool {
+        self.bodies
+    }
+
+    /// Adds a finished request, with the head and, when captured, the start of the body of its response
+    pub fn record(&self, entry: &Entry, phases: Phases, head: Option<&str>, body: Option<&[u8]>) {
+        let record = entry.record;
+        if record.method.is_empty() {
+            return; // The client closed the connection without sending a request
+        }
+        let request_head = record.request_head.as_deref().unwrap_or_default();
+        let response_head = head.unwrap_or_default();
+        let (_, target) = request::request_line(request_head);
+        let version = record.request_line.split_whitespace().nth(2).unwrap_or("HTTP/1.1");
+        let host = request::header(request_head, "Host").unwrap_or("localhost");
+        let query = target.split_once('?').map_or("", |(_, query)| query);
+        let status = entry.status.unwrap_or(0);
+
+        let headers_size = if response_head.is_empty() { 0 } else { response_head.len() as u64 + 4 };
+        let body_size = entry.bytes_sent.saturating_sub(headers_size);
+        let content = content(response_head, body_size, body.filter(|_| self.bodies));
+
+        let har_entry = json!({
+            "startedDateTime": units::iso_timestamp(entry.received),
+            "time": millis(entry.elapsed),
+            "request": {
+                "method": record.method,
+                "url": format!("http://{}{}", host, target),
+                "httpVersion": version,
+                "cookies": [],
+                "headers": headers(request_head),
+                "queryString": query_string(query),
+                "headersSize": -1,
+                "bodySize": 0,
+            },
+            "response": {
+                "status": status,
+                "statusText": reason_phrase(status),
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": headers(response_head),
+                "content": content,
+                "redirectURL": request::header(response_head, "Location").unwrap_or_default(),
+                "headersSize": headers_size,
+                "bodySize": body_size,
+            },
+            "cache": {},
+            // The client's phases as the server saw them: reading the request, handling it and sending the response
+            "timings": {
+                "send": millis(phases.reading.unwrap_or_default()),
+                "wait": millis(phases.handling.unwrap_or_default()),
+                "receive": millis(phases.sending.unwrap_or_default()),
+            },
+            "_cacheHit": record.cache_hit,
+        });
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).push(har_entry);
+    }
+
+    /// Writes every captured request to the file, returning how many there were
+    pub fn write(&self) -> io::Result<usize> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.rewind()?;
+        serde_json::to_writer_pretty(&mut file, &document(&entries))?;
+        file.flush()?;
+        Ok(entries.len())
+    }
+}
+
+/// The HAR file holding `entries`
+pub fn document(entries: &[Value]) -> Value {
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": {"name": "rshttp", "version": env!("CARGO_PKG_VERSION")},
+            "entries": entries,
+        }
+    })
+}
+
+/// An entry for a response kept by --record, head and body as sent, to a request for `url` made at `recorded`
+///
+/// A recording keeps neither the request headers nor timings, so those are left empty.
+pub fn recorded_entry(method: &str, url: &str, response: &[u8], recorded: SystemTime) -> Value {
+    let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or(response.len());
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let body = response.get(head_end + 4..).unwrap_or_default();
+    let status_line = head.lines().next().unwrap_or_default();
+    let status: u16 = status_line.split_whitespace().nth(1).and_then(|status| status.parse().ok()).unwrap_or(0);
+    let query = url.split_once('?').map_or("", |(_, query)| query);
+    json!({
+        "startedDateTime": units::iso_timestamp(recorded),
+        "time": 0,
+        "request": {
+            "method": method,
+            "url": url,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": [],
+            "queryString": query_string(query),
+            "headersSize": -1,
+            "bodySize": 0,
+        },
+        "response": {
+            "status": status,
+            "statusText": reason_phrase(status),
+            "httpVersion": status_line.split_whitespace().next().unwrap_or("HTTP/1.1"),
+            "cookies": [],
+            "headers": headers(&head),
+            "content": content(&head, body.len() as u64, Some(body)),
+            "redirectURL": request::header(&head, "Location").unwrap_or_default(),
+            "headersSize": head_end as u64 + 4,
+            "bodySize": body.len(),
+        },
+        "cache": {},
+        "timings": {"send": 0, "wait": 0, "receive": 0},
+    })
+}
+
+/// The content of a response with head `head` and a body of `body_size` bytes, starting with `body` if given
+fn content(head: &str, body_size: u64, body: Option<&[u8]>) -> Value {
+    let mut content = json!({
+        "size": body_size,
+        "mimeType": request::header(head, "Content-Type").unwrap_or_default(),
+    });
+    if let Some(body) = body.filter(|_| body_size > 0) {
+        let body = &body[..body.len().min(MAX_BODY)];
+        match std::str::from_utf8(body) {
+            Ok(text) => content["text"] = json!(text),
+            Err(_) => {
+                content["text"] = json!(STANDARD.encode(body));
+                content["encoding"] = json!("base64");
+            }
+        }
+        if body_size > body.len() as u64 {
+            let kept = units::size(MAX_BODY as u64);
+            content["comment"] = json!(format!("Truncated to the first {}", kept));
+        }
+    }
+    content
+}
+
+fn query_string(query: &str) -> Vec<Value> {
+    url::form_urlencoded::parse(query.as_bytes()).map(|(name, value)| json!({"name": name, "value": value})).collect()
+}
+
+/// The headers of a request or response head as HAR name/value objects, without credentials
+fn headers(head: &str) -> Vec<Value> {
+    request::headers(head)
+        .filter(|(name, _)| !request::is_credential(name))
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_responses_become_entries() {
+        let response = b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\ngone";
+        let entry = recorded_entry("GET", "http://localhost/a?b=c", response, SystemTime::UNIX_EPOCH);
+        assert_eq!(entry["startedDateTime"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(entry["request"]["queryString"], json!([{"name": "b", "value": "c"}]));
+        assert_eq!(entry["response"]["status"], 404);
+        assert_eq!(entry["response"]["headers"][0], json!({"name": "Content-Type", "value": "text/plain"}));
+        assert_eq!(entry["response"]["content"], json!({"size": 4, "mimeType": "text/plain", "text": "gone"}));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::auth::{AuthProvider, Credentials, Principal};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// How often the file is checked for changes, at most
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most verified passwords remembered before the memory is cleared
+const MAX_VERIFIED: usize = 1024;
+
+/// Users and password hashes from an Apache htpasswd file, for --auth-file
+///
+/// Entries hashed with bcrypt (`htpasswd -B`) or SHA-1 (`htpasswd -s`) are
+/// understood. The file is read again once it changes on disk, so passwords
+/// can be rotated while the server runs; if the new contents are invalid,
+/// the previous ones stay in use. As bcrypt is slow by design, passwords it
+/// accepted are remembered (as SHA-256 digests) until the file changes.
+pub struct HtpasswdFile {
+    path: PathBuf,
+    entries: RwLock<Entries>,
+    last_check: Mutex<Instant>,
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+struct Entries {
+    hashes: HashMap<String, Hash>,
+    modified: Option<SystemTime>,
+}
+
+enum Hash {
+    Bcrypt(String),
+    /// The SHA-1 digest of the password
+    Sha1(Vec<u8>),
+}
+
+impl HtpasswdFile {
+    pub fn load(path: &Path) -> Result<HtpasswdFile, String> {
+        Ok(HtpasswdFile {
+            path: path.to_path_buf(),
+            entries: RwLock::new(read(path)?),
+            last_check: Mutex::new(Instant::now()),
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner).hashes.len()
+    }
+
+    /// Reads the file again if it changed since it was last read
+    fn reload_if_changed(&self) {
+        {
+            let mut last_check = self.last_check.lock().unwrap_or_else(PoisonError::into_inner);
+            if last_check.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            *last_check = Instant::now();
+        }
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified == self.entries.read().unwrap_or_else(PoisonError::into_inner).modified {
+            return;
+        }
+        match read(&self.path) {
+            Ok(entries) => {
+                info!("Reloaded {} user(s) from {}", entries.hashes.len(), self.path.display());
+                *self.entries.write().unwrap_or_else(PoisonError::into_inner) = entries;
+                self.verified.lock().unwrap_or_else(PoisonError::into_inner).clear();
+            }
+            Err(e) => {
+                warn!("Keeping the previous users, {} is invalid: {}", self.path.display(), e);
+                self.entries.write().unwrap_or_else(PoisonError::into_inner).modified = modified;
+            }
+        }
+    }
+}
+
+impl AuthProvider for HtpasswdFile {
+    fn authenticate(&self, credentials: &Credentials) -> Option<Principal> {
+        let Credentials::Basic { user, password } = credentials else {
+            return None;
+        };
+        self.reload_if_changed();
+        let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+        let valid = match entries.hashes.get(user)? {
+            Hash::Sha1(digest) => Sha1::digest(password.as_bytes()).as_slice() == digest.as_slice(),
+            Hash::Bcrypt(hash) => {
+                let key: [u8; 32] = Sha256::new()
+                    .chain_update(user)
+                    .chain_update([0])
+                    .chain_update(password)
+                    .chain_update([0])
+                    .chain_update(hash)
+                    .finalize()
+                    .into();
+                let mut verified = self.verified.lock().unwrap_or_else(PoisonError::into_inner);
+                if verified.contains(&key) {
+                    true
+                } else {
+                    drop(verified);
+                    let valid = bcrypt::verify(password, hash).unwrap_or(false);
+                    if valid {
+                        verified = self.verified.lock().unwrap_or_else(PoisonError::into_inner);
+                        if verified.len() >= MAX_VERIFIED {
+                            verified.clear();
+                        }
+                        verified.insert(key);
+                    }
+                    valid
+                }
+            }
+        };
+        valid.then(|| Principal {
+            name: user.clone(),
+            groups: Vec::new(),
+        })
+    }
+}
+
+/// Parses `user:hash` lines, skipping blank lines and `#` comments
+fn read(path: &Path) -> Result<Entries, String> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let contents = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut hashes = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| format!("{}:{}: {}", path.display(), number + 1, reason);
+        let (user, hash) = line.split_once(':').ok_or_else(|| invalid("expected user:hash"))?;
+        let hash = if hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$") {
+            Hash::Bcrypt(hash.to_string())
+        } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+            Hash::Sha1(STANDARD.decode(digest).map_err(|_| invalid("invalid {SHA} digest"))?)
+        } else {
+            return Err(invalid("unsupported hash, create it with htpasswd -B (bcrypt) or -s (SHA-1)"));
+        };
+        hashes.insert(user.to_string(), hash);
+    }
+    Ok(Entries { hashes, modified })
+}
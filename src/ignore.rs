@@ -0,0 +1,75 @@
+use crate::{config, glob, upload};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Read from the served directory at startup
+pub const FILE_NAME: &str = ".rshttpsignore";
+
+/// Whether a URL path names an ignore or configuration file, or an upload still in progress,
+/// which are never served nor written
+pub fn is_reserved(url_path: &str) -> bool {
+    let name = url_path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    name.eq_ignore_ascii_case(FILE_NAME) || name.eq_ignore_ascii_case(config::FILE_NAME) || upload::is_partial(name)
+}
+
+/// Files of the served directory that are never served, from its .rshttpsignore
+///
+/// One glob per line, `#` starting a comment. As with --watch-ignore, a
+/// pattern without `/` matches a file or directory of that name anywhere, e.g.
+/// `*.psd` or `node_modules`; one with `/` matches from the root, e.g.
+/// `drafts/**`. Everything below a matching directory is left out too.
+/// Requests for ignored files are answered with 404.
+pub struct IgnoreRules {
+    patterns: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// The rules in `root`'s .rshttpsignore, `None` if it has none
+    pub fn load(root: &Path) -> io::Result<Option<IgnoreRules>> {
+        match fs::read_to_string(root.join(FILE_NAME)) {
+            Ok(source) => Ok(Some(IgnoreRules::parse(&source))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(source: &str) -> IgnoreRules {
+        let patterns = source
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim().trim_matches('/'))
+            .filter(|pattern| !pattern.is_empty())
+            .map(str::to_string)
+            .collect();
+        IgnoreRules { patterns }
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Whether the file at a URL path, or a directory it is in, is ignored
+    pub fn is_ignored(&self, url_path: &str) -> bool {
+        let segments: Vec<&str> = url_path.split('/').filter(|segment| !segment.is_empty()).collect();
+        self.patterns.iter().any(|pattern| match pattern.contains('/') {
+            true => (1..=segments.len()).any(|depth| glob::matches(pattern, &segments[..depth].join("/"))),
+            false => segments.iter().any(|segment| glob::matches(pattern, segment)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_names() {
+        assert!(is_reserved("/.rshttpsignore"));
+        assert!(is_reserved("/docs/RSHTTPS.TOML"));
+        assert!(is_reserved("/uploads/.video.mp4.partial"));
+        assert!(is_reserved("/.partial.partial"));
+        assert!(!is_reserved("/uploads/video.mp4.partial"));
+        assert!(!is_reserved("/.partial"));
+        assert!(!is_reserved("/docs/index.html"));
+    }
+}
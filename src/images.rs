@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::debug;
+
+/// Formats images can be converted to, with their content types
+const FORMATS: [(&str, &str); 6] = [
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("png", "image/png"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("gif", "image/gif"),
+];
+
+/// Largest width or height that may be asked for
+const MAX_DIMENSION: u32 = 4096;
+
+/// Resizes and converts images as their query asks, from --resize-images
+///
+/// `/photo.jpg?w=400&format=webp` scales the image down to 400 pixels wide,
+/// keeping its aspect ratio, and converts it to WebP. With both `w` and `h`
+/// the image fits within both; images are never enlarged. The work is done by
+/// ImageMagick, `magick` unless --image-command names another program.
+pub struct Images {
+    command: String,
+}
+
+/// What a query asks to be done to an image
+pub struct Transform {
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<&'static str>,
+}
+
+impl Images {
+    pub fn new(command: &str) -> Images {
+        Images {
+            command: command.to_string(),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Checks that the converter can be run, returning the first line of its version
+    pub fn version(&self) -> Result<String, String> {
+        let output = Command::new(&self.command)
+            .arg("-version")
+            .output()
+            .map_err(|e| format!("cannot run `{}`: {}", self.command, e))?;
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or("").trim().to_string()),
+            false => Err(format!("`{} -version` failed ({})", self.command, output.status)),
+        }
+    }
+
+    /// Whether `file` is an image that can be transformed
+    pub fn is_image(file: &Path) -> bool {
+        format_of(file).is_some()
+    }
+
+    /// The transformed image and its content type, or the converter's complaint
+    pub fn transform(&self, file: &Path, transform: &Transform) -> Result<(Vec<u8>, &'static str), String> {
+        let format = transform.format.or_else(|| format_of(file)).unwrap_or("png");
+        let mut command = Command::new(&self.command);
+        command.arg(file).arg("-auto-orient");
+        if transform.width.is_some() || transform.height.is_some() {
+            let dimension = |value: Option<u32>| value.map_or(String::new(), |value| value.to_string());
+            command.arg("-resize").arg(format!("{}x{}>", dimension(transform.width), dimension(transform.height)));
+        }
+        command.arg(format!("{}:-", format));
+        debug!("Transforming {} with {:?}", file.display(), command);
+        let output = command.output().map_err(|e| format!("cannot run `{}`: {}", self.command, e))?;
+        if !output.status.success() || output.stdout.is_empty() {
+            let complaint = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(match complaint.is_empty() {
+                true => format!("`{}` failed ({})", self.command, output.status),
+                false => complaint,
+            });
+        }
+        Ok((output.stdout, content_type(format)))
+    }
+}
+
+impl Transform {
+    /// The transform a query asks for, `None` if it asks for none
+    pub fn parse(query: &str) -> Result<Option<Transform>, String> {
+        let mut transform = Transform {
+            width: None,
+            height: None,
+            format: None,
+        };
+        let dimension = |value: &str| match value.parse::<u32>() {
+            Ok(value) if (1..=MAX_DIMENSION).contains(&value) => Ok(Some(value)),
+            _ => Err(format!("invalid image size '{}' (expected 1 to {} pixels)", value, MAX_DIMENSION)),
+        };
+        for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match name.as_ref() {
+                "w" => transform.width = dimension(&value)?,
+                "h" => transform.height = dimension(&value)?,
+                "format" => {
+                    let format = FORMATS.iter().find(|(name, _)| value.eq_ignore_ascii_case(name));
+                    let names = FORMATS.map(|(name, _)| name).join(", ");
+                    let invalid = || format!("unsupported image format '{}' (expected one of {})", value, names);
+                    transform.format = Some(format.ok_or_else(invalid)?.0);
+                }
+                _ => {}
+            }
+        }
+        let requested = transform.width.is_some() || transform.height.is_some() || transform.format.is_some();
+        Ok(requested.then_some(transform))
+    }
+
+    /// Where the result is cached: next to the image, named after the image and the transform
+    pub fn cache_key(&self, file: &Path) -> PathBuf {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let dimension = |value: Option<u32>| value.map_or("-".to_string(), |value| value.to_string());
+        let (width, height, format) = (dimension(self.width), dimension(self.height), self.format.unwrap_or("-"));
+        file.with_file_name(format!("{}?w={}&h={}&format={}", name, width, height, format))
+    }
+}
+
+/// The format of an image file by its extension
+fn format_of(file: &Path) -> Option<&'static str> {
+    let extension = file.extension()?.to_str()?;
+    FORMATS.iter().find(|(name, _)| extension.eq_ignore_ascii_case(name)).map(|(name, _)| *name)
+}
+
+fn content_type(format: &str) -> &'static str {
+    FORMATS.iter().find(|(name, _)| *name == format).map_or("application/octet-stream", |(_, mime_type)| mime_type)
+}
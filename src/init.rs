@@ -0,0 +1,75 @@
+use crate::{config, ignore};
+use std::fs;
+use std::path::PathBuf;
+
+/// Arguments of the `init` subcommand
+#[derive(clap::Args, Debug)]
+pub struct InitArgs {
+    /// Directory to set up, created if missing
+    #[arg(default_value = ".")]
+    directory: PathBuf,
+    /// Overwrite files that already exist
+    #[arg(long)]
+    force: bool,
+}
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>It works</title>
+</head>
+<body>
+  <h1>It works</h1>
+  <p>Served by rshttp. Edit index.html and reload.</p>
+</body>
+</html>
+"#;
+
+const CONFIG: &str = r#"# Options for rshttp, read when it is started in this directory.
+# Every key is the long name of a command-line flag; flags given on the
+# command line win. Options that run commands (exec, cgi, lua, ...) are only
+# read with --config. Run `rshttp check` after editing.
+
+port = 8000
+# host = "0.0.0.0"          # serve on all interfaces
+# cache_size = "256M"
+# live_reload = true
+# proxy = ["/api=http://localhost:3000"]
+# auth = ["alice:secret"]
+"#;
+
+const IGNORE: &str = r#"# Files that are never served, one glob per line.
+# A pattern without / matches that name anywhere, one with / from the root.
+.rshttpsignore
+rshttps.toml
+.git
+.env
+*.swp
+"#;
+
+/// Writes a starter site, configuration and ignore file, returning the process exit code
+///
+/// Files that already exist are left alone unless --force is given.
+pub fn run(args: &InitArgs) -> i32 {
+    if let Err(e) = fs::create_dir_all(&args.directory) {
+        eprintln!("Error: cannot create {}: {}", args.directory.display(), e);
+        return 1;
+    }
+    let files = [("index.html", INDEX), (config::FILE_NAME, CONFIG), (ignore::FILE_NAME, IGNORE)];
+    for (name, contents) in files {
+        let path = args.directory.join(name);
+        if path.exists() && !args.force {
+            println!("Kept {}, which already exists", path.display());
+            continue;
+        }
+        if let Err(e) = fs::write(&path, contents) {
+            eprintln!("Error: cannot write {}: {}", path.display(), e);
+            return 1;
+        }
+        println!("Wrote {}", path.display());
+    }
+    println!("Serve it with `rshttp serve -d {}`", args.directory.display());
+    0
+}
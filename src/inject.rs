@@ -0,0 +1,28 @@
+/// Markup for the bar shown across the top of every page with --preview-banner
+///
+/// It stays above the page content without taking clicks away from it.
+pub fn banner(text: &str) -> String {
+    format!(
+        "<div style=\"position:fixed;top:0;left:0;right:0;z-index:2147483647;padding:4px 8px;\
+         background:#b00020;color:#fff;font:bold 13px/1.4 sans-serif;text-align:center;\
+         opacity:.9;pointer-events:none\">{}</div>",
+        escape(text)
+    )
+}
+
+/// Adds `snippets` to an HTML page, before its last `</body>` or at the end
+pub fn before_body_end(html: &[u8], snippets: &[String]) -> Vec<u8> {
+    let end = html.windows(7).rposition(|window| window.eq_ignore_ascii_case(b"</body>")).unwrap_or(html.len());
+    let mut page = Vec::with_capacity(html.len() + snippets.iter().map(String::len).sum::<usize>());
+    page.extend_from_slice(&html[..end]);
+    for snippet in snippets {
+        page.extend_from_slice(snippet.as_bytes());
+    }
+    page.extend_from_slice(&html[end..]);
+    page
+}
+
+/// Escapes text for use in HTML content and quoted attribute values
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
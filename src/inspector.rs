@@ -0,0 +1,136 @@
+use crate::accesslog::Entry;
+use crate::inject::escape;
+use crate::metrics::Phases;
+use crate::units;
+use rshttp::request;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// URL of the inspector page enabled by --inspect
+pub const PATH: &str = "/_rshttps/inspect";
+
+/// How many of the latest requests are kept
+const KEPT: usize = 200;
+
+/// Keeps the latest requests with their headers and timings, like the network tab of a browser's devtools
+pub struct Inspector {
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+/// One request and the response it got
+struct Exchange {
+    received: SystemTime,
+    peer_ip: Option<IpAddr>,
+    request_line: String,
+    request_headers: Vec<(String, String)>,
+    status: Option<u16>,
+    response_headers: Vec<(String, String)>,
+    bytes_sent: u64,
+    cache_hit: Option<bool>,
+    phases: Phases,
+    elapsed: Duration,
+}
+
+impl Inspector {
+    pub fn new() -> Inspector {
+        Inspector {
+            exchanges: Mutex::new(VecDeque::with_capacity(KEPT)),
+        }
+    }
+
+    /// Keeps a finished request, with the head of the response it got
+    pub fn record(&self, entry: &Entry, phases: Phases, response_head: Option<&str>) {
+        let record = entry.record;
+        if record.method.is_empty() || record.path == PATH {
+            return;
+        }
+        let exchange = Exchange {
+            received: entry.received,
+            peer_ip: entry.peer_ip,
+            request_line: record.request_line.clone(),
+            request_headers: record.request_head.as_deref().map(headers).unwrap_or_default(),
+            status: entry.status,
+            response_headers: response_head.map(headers).unwrap_or_default(),
+            bytes_sent: entry.bytes_sent,
+            cache_hit: record.cache_hit,
+            phases,
+            elapsed: entry.elapsed,
+        };
+        let mut exchanges = self.exchanges.lock().unwrap_or_else(PoisonError::into_inner);
+        if exchanges.len() == KEPT {
+            exchanges.pop_back();
+        }
+        exchanges.push_front(exchange);
+    }
+
+    /// An HTML page listing the kept requests, newest first, each expanding to its headers and timings
+    pub fn page(&self) -> String {
+        let exchanges = self.exchanges.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut page = String::from(
+            "<!DOCTYPE html>\n<meta charset=\"utf-8\">\n<title>rshttp inspector</title>\n<style>\
+             body{font:13px/1.4 monospace;margin:1em}summary{cursor:pointer;white-space:pre}\
+             table{border-collapse:collapse;margin:.3em 0 .8em 2em}td{padding:0 1em 0 0;vertical-align:top}\
+             h3{margin:.5em 0 0 2em;font-size:inherit}</style>\n",
+        );
+        let _ = writeln!(page, "<h1>Latest {} of up to {} requests</h1>", exchanges.len(), KEPT);
+        for exchange in exchanges.iter() {
+            let cache = match exchange.cache_hit {
+                Some(true) => "cache hit",
+                Some(false) => "cache miss",
+                None => "",
+            };
+            let _ = writeln!(
+                page,
+                "<details><summary>{}  {:<15}  {}  {:<40}  {:>9}  {:>9}  {}</summary>",
+                escape(&units::timestamp(exchange.received)),
+                exchange.peer_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                exchange.status.map_or("-".to_string(), |status| status.to_string()),
+                escape(&exchange.request_line),
+                units::size(exchange.bytes_sent),
+                millis(Some(exchange.elapsed)),
+                cache
+            );
+            let phases = &exchange.phases;
+            let _ = writeln!(
+                page,
+                "<h3>Timing</h3><table><tr><td>Reading the request</td><td>{}</td></tr>\
+                 <tr><td>Handling, until the response started</td><td>{}</td></tr>\
+                 <tr><td>Sending the response</td><td>{}</td></tr>\
+                 <tr><td>Total</td><td>{}</td></tr></table>",
+                millis(phases.reading),
+                millis(phases.handling),
+                millis(phases.sending),
+                millis(Some(exchange.elapsed))
+            );
+            header_table(&mut page, "Request headers", &exchange.request_headers);
+            header_table(&mut page, "Response headers", &exchange.response_headers);
+            page.push_str("</details>\n");
+        }
+        page
+    }
+}
+
+/// The headers of a request or response head, with credentials hidden
+fn headers(head: &str) -> Vec<(String, String)> {
+    request::headers(head)
+        .map(|(name, value)| match request::is_credential(name) {
+            true => (name.to_string(), "(hidden)".to_string()),
+            false => (name.to_string(), value.to_string()),
+        })
+        .collect()
+}
+
+fn header_table(page: &mut String, title: &str, headers: &[(String, String)]) {
+    let _ = write!(page, "<h3>{}</h3><table>", title);
+    for (name, value) in headers {
+        let _ = write!(page, "<tr><td>{}</td><td>{}</td></tr>", escape(name), escape(value));
+    }
+    page.push_str("</table>\n");
+}
+
+fn millis(duration: Option<Duration>) -> String {
+    duration.map_or("-".to_string(), |duration| format!("{:.1} ms", duration.as_secs_f64() * 1000.0))
+}
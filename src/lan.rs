@@ -0,0 +1,81 @@
+use crate::listener::Listener;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use std::net::{IpAddr, SocketAddr};
+
+/// The URLs other machines on the network reach the TCP listeners at
+///
+/// A listener on all interfaces (0.0.0.0 or ::) is reachable at the address
+/// of every interface of its family that is up, except loopback and IPv6
+/// link-local ones. A listener on a single address other than loopback is
+/// reachable there. Loopback listeners aren't reachable from elsewhere.
+pub fn urls(listeners: &[Listener]) -> Vec<String> {
+    let interfaces = interface_addresses();
+    let mut urls = Vec::new();
+    for listener in listeners {
+        let Some(local) = listener.tcp_address() else {
+            continue;
+        };
+        let addresses = match local.ip() {
+            ip if ip.is_loopback() => Vec::new(),
+            ip if ip.is_unspecified() => {
+                interfaces.iter().filter(|address| address.is_ipv4() == ip.is_ipv4()).copied().collect()
+            }
+            ip => vec![ip],
+        };
+        for address in addresses {
+            let url = format!("http://{}/", SocketAddr::new(address, local.port()));
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+    urls
+}
+
+/// `url` as a QR code drawn with half blocks, two rows of modules per line of text
+pub fn qr_code(url: &str) -> Option<String> {
+    let code = QrCode::new(url.as_bytes()).ok()?;
+    // Light on dark, the way most terminals are set up, still scans
+    Some(code.render::<Dense1x2>().dark_color(Dense1x2::Light).light_color(Dense1x2::Dark).quiet_zone(true).build())
+}
+
+/// Addresses of the network interfaces that are up, without loopback and IPv6 link-local ones
+#[cfg(unix)]
+pub fn interface_addresses() -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return addresses;
+    }
+    let mut next = interfaces;
+    while let Some(interface) = unsafe { next.as_ref() } {
+        next = interface.ifa_next;
+        let up = interface.ifa_flags & libc::IFF_UP as libc::c_uint != 0;
+        let Some(address) = (unsafe { interface.ifa_addr.as_ref() }).filter(|_| up) else {
+            continue;
+        };
+        let ip = match address.sa_family as libc::c_int {
+            libc::AF_INET => {
+                let address = unsafe { &*(address as *const libc::sockaddr as *const libc::sockaddr_in) };
+                IpAddr::from(u32::from_be(address.sin_addr.s_addr).to_be_bytes())
+            }
+            libc::AF_INET6 => {
+                let address = unsafe { &*(address as *const libc::sockaddr as *const libc::sockaddr_in6) };
+                IpAddr::from(address.sin6_addr.s6_addr)
+            }
+            _ => continue,
+        };
+        let link_local = matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80);
+        if !ip.is_loopback() && !link_local && !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+    unsafe { libc::freeifaddrs(interfaces) };
+    addresses
+}
+
+#[cfg(not(unix))]
+pub fn interface_addresses() -> Vec<IpAddr> {
+    Vec::new()
+}
@@ -0,0 +1,112 @@
+use rshttp::response::Response;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Picks between translations of a page from the Accept-Language header, from --default-language
+///
+/// Translations sit next to the page with the language before the extension:
+/// `page.nl.html` and `page.fr.html` next to `page.html`, which is taken to
+/// be in the default language. A client asking for `nl-BE` gets `nl` when
+/// there is nothing more specific. Only HTML pages are negotiated.
+pub struct Languages {
+    default: String,
+}
+
+/// The file chosen for a request, and its language
+pub struct Variant {
+    pub file: PathBuf,
+    pub language: String,
+}
+
+impl Languages {
+    pub fn new(default: &str) -> Languages {
+        Languages {
+            default: default.to_ascii_lowercase(),
+        }
+    }
+
+    /// The translation to serve for `file`, or `None` if the page has no translations
+    pub fn negotiate(&self, file: &Path, accept_language: Option<&str>) -> Option<Variant> {
+        let name = file.file_name()?.to_str()?;
+        let (stem, extension) = name.rsplit_once('.')?;
+        if !matches!(extension, "html" | "htm") {
+            return None;
+        }
+        let dir = file.parent()?;
+        let mut languages: Vec<String> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|sibling| {
+                let language = sibling.strip_prefix(stem)?.strip_prefix('.')?.strip_suffix(extension)?.strip_suffix('.')?;
+                is_language_tag(language).then(|| language.to_string())
+            })
+            .collect();
+        if languages.is_empty() {
+            return None;
+        }
+        languages.sort();
+        let unsuffixed = file.is_file();
+        let is_default = |language: &String| language.eq_ignore_ascii_case(&self.default);
+        if unsuffixed && !languages.iter().any(is_default) {
+            languages.push(self.default.clone());
+        }
+
+        let primary = |tag: &str| tag.split('-').next().unwrap_or("").to_string();
+        let chosen = preferences(accept_language.unwrap_or(""))
+            .iter()
+            .find_map(|wanted| match wanted.as_str() {
+                "*" => Some(self.default.clone()).filter(|_| unsuffixed),
+                wanted => (languages.iter())
+                    .find(|language| language.eq_ignore_ascii_case(wanted))
+                    .or_else(|| languages.iter().find(|language| primary(language).eq_ignore_ascii_case(&primary(wanted))))
+                    .cloned(),
+            })
+            .or_else(|| languages.iter().find(|language| is_default(language)).cloned())
+            .unwrap_or_else(|| languages[0].clone());
+
+        let file = match is_default(&chosen) && unsuffixed {
+            true => file.to_path_buf(),
+            false => dir.join(format!("{}.{}.{}", stem, chosen, extension)),
+        };
+        Some(Variant { file, language: chosen })
+    }
+}
+
+/// Tells clients and caches which language they got, and that it depends on Accept-Language
+pub fn label<'a>(response: Response<'a>, variant: Option<&Variant>) -> Response<'a> {
+    match variant {
+        Some(variant) => response
+            .header("Content-Language", variant.language.clone())
+            .header("Vary", "Accept-Language"),
+        None => response,
+    }
+}
+
+/// The languages of an Accept-Language header, most wanted first, leaving out those refused with q=0
+fn preferences(header: &str) -> Vec<String> {
+    let mut weighted: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|parameter| parameter.trim().strip_prefix("q=")?.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equally weighted languages keep the client's order
+    weighted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    weighted.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// Whether `tag` looks like a language tag such as `nl` or `pt-BR`, rather than part of a file name
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    (2..=3).contains(&primary.len())
+        && primary.bytes().all(|byte| byte.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (2..=8).contains(&subtag.len()) && subtag.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        })
+}
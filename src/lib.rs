@@ -0,0 +1,95 @@
+//! Request handling shared by the rshttp binary and embedders
+//!
+//! The core of this crate opens no sockets, spawns no threads and calls no
+//! OS-specific APIs: callers pass in any `Read + Write` stream. That keeps it
+//! building for `wasm32-wasip1`, where the host runtime owns the sockets:
+//!
+//! ```text
+//! cargo build --lib --target wasm32-wasip1
+//! ```
+//!
+//! Elsewhere, [`Server`] puts it behind a listening socket for programs that
+//! embed a static file server instead of running the binary.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub mod auth;
+pub mod date;
+pub mod request;
+pub mod resolve;
+pub mod response;
+#[cfg(not(target_family = "wasm"))]
+pub mod middleware;
+#[cfg(not(target_family = "wasm"))]
+pub mod server;
+
+use response::Response;
+#[cfg(not(target_family = "wasm"))]
+pub use middleware::{Incoming, Middleware};
+#[cfg(not(target_family = "wasm"))]
+pub use server::{RequestEvent, Server, ServerBuilder, StopHandle};
+
+/// What [`handle`] made of one request
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    /// Empty when the request head could not be parsed
+    pub method: String,
+    /// The normalized path, without the query
+    pub path: String,
+    pub status: u16,
+}
+
+/// Answers one GET or HEAD request for a file below `root`, returning the status sent
+///
+/// This is the plain static-file path of the server, without caching,
+/// authentication or any of the optional features of the binary.
+pub fn serve_static<S: Read + Write>(stream: &mut S, root: &Path) -> io::Result<u16> {
+    handle(stream, root).map(|exchange| exchange.status)
+}
+
+/// Like [`serve_static`], returning the method and path answered along with the status
+pub fn handle<S: Read + Write>(stream: &mut S, root: &Path) -> io::Result<Exchange> {
+    let head = request::read_head(stream)?;
+    let request = String::from_utf8_lossy(&head);
+    let (method, target, range) = match request::parse(&request) {
+        Ok(request) => (request.method, request.target, request.headers.get("Range")),
+        Err(malformed) => {
+            let response = Response::error(malformed.status).header("Connection", "close");
+            response.send(stream, false)?;
+            return Ok(Exchange {
+                method: String::new(),
+                path: String::new(),
+                status: response.status(),
+            });
+        }
+    };
+    let path = request::normalize_path(target.split('?').next().unwrap_or(target));
+    let response = respond(root, method, &path, range);
+    response.send(stream, method == "HEAD")?;
+    Ok(Exchange {
+        method: method.to_string(),
+        path,
+        status: response.status(),
+    })
+}
+
+/// The response to a request for the file at a normalized URL path below `root`
+pub fn respond(root: &Path, method: &str, path: &str, range: Option<&str>) -> Response<'static> {
+    if method != "GET" && method != "HEAD" {
+        return Response::error(405).header("Allow", "GET, HEAD");
+    }
+    if cfg!(windows) && request::unsafe_on_windows(path) {
+        return Response::error(400);
+    }
+    let file_path = resolve::file_path(root, &resolve::served_path(root, path));
+    match fs::read(&file_path) {
+        Ok(contents) => {
+            let mime_type = mime_guess::from_path(&file_path).first_or_octet_stream();
+            let mime_type = response::with_charset(mime_type.as_ref(), "utf-8");
+            Response::file(&contents, &mime_type, range).into_owned()
+        }
+        Err(_) => Response::error(404),
+    }
+}
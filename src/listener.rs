@@ -0,0 +1,832 @@
+use crate::startup::Problems;
+use crate::Cli;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// A client stream that can report who is on the other end
+pub trait Connection: Read + Write {
+    /// The peer's IP address, if the transport has one
+    fn peer_ip(&self) -> Option<IpAddr>;
+
+    /// Writes `len` bytes of `file` starting at `offset`
+    ///
+    /// Sockets override this on Linux to have the kernel send straight from the page cache.
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        if std::io::copy(&mut file.take(len), self)? < len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into()); // The file shrank
+        }
+        Ok(())
+    }
+
+    /// Whether writes reach the peer as they are made, rather than once the handler has returned
+    fn streams(&self) -> bool {
+        true
+    }
+
+    /// Makes reads fail once they have waited `timeout` for data, if the transport can wait at all
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes writes fail once they have waited `timeout` for the peer to take data
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the response break off halfway through its body, for --chaos
+    fn cut_short(&mut self) {}
+
+    /// Waits up to `idle` for another request on a kept-alive connection, for --keep-alive
+    ///
+    /// False when the client closed the connection or sent nothing in time,
+    /// and for transports that don't keep connections open.
+    fn wait_for_request(&self, _idle: Duration) -> bool {
+        false
+    }
+}
+
+/// How long a client may take over each part of a request, `None` for no limit
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For the whole request head, however slowly its bytes trickle in
+    pub header: Option<Duration>,
+    /// For each read of the request body
+    pub body: Option<Duration>,
+    /// For each write of the response
+    pub write: Option<Duration>,
+}
+
+/// Whether an error is a read or write running out of time
+///
+/// Sockets report an expired timeout as `WouldBlock` on Unix and as `TimedOut` on Windows.
+pub fn is_timeout(error: &std::io::Error) -> bool {
+    matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+/// Reads from a connection until a deadline, shortening the read timeout as it approaches
+pub struct Deadline<'a, C: Connection> {
+    connection: &'a mut C,
+    deadline: Option<Instant>,
+}
+
+impl<'a, C: Connection> Deadline<'a, C> {
+    pub fn new(connection: &'a mut C, timeout: Option<Duration>) -> Deadline<'a, C> {
+        Deadline {
+            connection,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+}
+
+impl<C: Connection> Read for Deadline<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            self.connection.set_read_timeout(Some(remaining))?;
+        }
+        self.connection.read(buf)
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        (**self).peer_ip()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn cut_short(&mut self) {
+        (**self).cut_short()
+    }
+
+    fn streams(&self) -> bool {
+        (**self).streams()
+    }
+
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+        (**self).write_file(file, offset, len)
+    }
+
+    fn wait_for_request(&self, idle: Duration) -> bool {
+        (**self).wait_for_request(idle)
+    }
+}
+
+/// Rewrites a response head, given without its final blank line
+pub type Filter<'f> = Box<dyn FnOnce(&str) -> String + 'f>;
+
+/// Passes the head of the response through `filter` before it reaches the connection, for --plugins
+///
+/// Responses write their head in one piece, so only the first write is looked at.
+pub struct HeadFilter<'f, C: Connection> {
+    connection: C,
+    filter: Option<Filter<'f>>,
+}
+
+impl<'f, C: Connection> HeadFilter<'f, C> {
+    pub fn new(connection: C, filter: Option<Filter<'f>>) -> HeadFilter<'f, C> {
+        HeadFilter { connection, filter }
+    }
+}
+
+impl<C: Connection> Read for HeadFilter<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.connection.read(buf)
+    }
+}
+
+impl<C: Connection> Write for HeadFilter<'_, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(filter) = self.filter.take() else {
+            return self.connection.write(buf);
+        };
+        let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            return self.connection.write(buf);
+        };
+        let mut filtered = filter(&String::from_utf8_lossy(&buf[..end])).into_bytes();
+        filtered.extend_from_slice(&buf[end..]);
+        self.connection.write_all(&filtered)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+impl<C: Connection> Connection for HeadFilter<'_, C> {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.connection.peer_ip()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.connection.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.connection.set_write_timeout(timeout)
+    }
+
+    fn cut_short(&mut self) {
+        self.connection.cut_short()
+    }
+
+    fn streams(&self) -> bool {
+        self.connection.streams()
+    }
+
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+        self.connection.write_file(file, offset, len)
+    }
+
+    fn wait_for_request(&self, idle: Duration) -> bool {
+        self.connection.wait_for_request(idle)
+    }
+}
+
+impl Connection for std::net::TcpStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::net::TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn wait_for_request(&self, idle: Duration) -> bool {
+        let Ok(previous) = self.read_timeout() else {
+            return false;
+        };
+        if std::net::TcpStream::set_read_timeout(self, Some(idle.max(Duration::from_millis(1)))).is_err() {
+            return false;
+        }
+        // Zero bytes is the client closing the connection
+        let arrived = matches!(self.peek(&mut [0]), Ok(1));
+        arrived && std::net::TcpStream::set_read_timeout(self, previous).is_ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        send_file(self.as_raw_fd(), file, offset, len)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_write_timeout(self, timeout)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        send_file(self.as_raw_fd(), file, offset, len)
+    }
+}
+
+/// Sends part of a file to a socket with sendfile(2), without copying it through user space
+#[cfg(target_os = "linux")]
+fn send_file(socket: std::os::unix::io::RawFd, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        // Linux sends at most 0x7ffff000 bytes per call
+        let count = remaining.min(0x7fff_f000) as usize;
+        match unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut offset, count) } {
+            -1 => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()), // The file shrank
+            sent => remaining -= sent as u64,
+        }
+    }
+    Ok(())
+}
+
+/// A request head that was already received, and the response written for it
+///
+/// Lets the synchronous handler run detached from the socket, which is read and
+/// written by an event loop instead.
+pub struct BufferedConnection {
+    input: std::io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+    file_body: Option<FileBody>,
+    peer_ip: Option<IpAddr>,
+}
+
+/// Part of a file that follows the buffered output, sent by whoever owns the socket
+pub struct FileBody {
+    pub file: File,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl FileBody {
+    /// The range as a reader, so it can be copied to the socket in chunks
+    #[cfg(not(feature = "async"))]
+    pub fn into_reader(mut self) -> std::io::Result<std::io::Take<File>> {
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        Ok(self.file.take(self.len))
+    }
+}
+
+impl BufferedConnection {
+    pub fn new(head: Vec<u8>, peer_ip: Option<IpAddr>) -> BufferedConnection {
+        BufferedConnection {
+            input: std::io::Cursor::new(head),
+            output: Vec::new(),
+            file_body: None,
+            peer_ip,
+        }
+    }
+
+    /// The response written so far, and the file range to send after it
+    pub fn into_output(self) -> (Vec<u8>, Option<FileBody>) {
+        (self.output, self.file_body)
+    }
+}
+
+impl Read for BufferedConnection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for BufferedConnection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.file_body.is_some() && !buf.is_empty() {
+            return Err(std::io::Error::other("cannot write after a file body"));
+        }
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for BufferedConnection {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_ip
+    }
+
+    fn streams(&self) -> bool {
+        false
+    }
+
+    /// Keeps the range instead of copying it, so large files are streamed rather than buffered
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+        if self.file_body.is_some() {
+            return Err(std::io::Error::other("only one file body per response"));
+        }
+        self.file_body = Some(FileBody {
+            file: file.try_clone()?,
+            offset,
+            len,
+        });
+        Ok(())
+    }
+}
+
+/// A bound socket that accepts client connections
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Environment variable listing the listener fds handed over by a restarting parent
+pub const INHERITED_FDS_ENV: &str = "RSHTTP_LISTEN_FDS";
+
+impl Listener {
+    /// Where clients reach the listener, e.g. `http://127.0.0.1:8080/` or `unix:/run/rshttp.sock`
+    pub fn endpoint(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(local) => format!("http://{}/", local),
+                Err(_) => "TCP socket".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let address = listener.local_addr().ok();
+                match address.as_ref().and_then(|address| address.as_pathname()) {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix socket".to_string(),
+                }
+            }
+        }
+    }
+
+    /// The address of a TCP listener, `None` for other sockets
+    pub fn tcp_address(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn raw_fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+
+    /// Waits up to `timeout` for a connection to become ready to accept
+    #[cfg(all(unix, not(feature = "async")))]
+    pub fn poll(&self, timeout: std::time::Duration) -> std::io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => {
+                let err = std::io::Error::last_os_error();
+                match err.kind() {
+                    std::io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(err),
+                }
+            }
+            ready => Ok(ready > 0),
+        }
+    }
+
+    #[cfg(all(not(unix), not(feature = "async")))]
+    pub fn poll(&self, _timeout: std::time::Duration) -> std::io::Result<bool> {
+        Ok(true)
+    }
+
+    /// Switches the listener to non-blocking accepts
+    ///
+    /// The socket may be shared with another server process during a restart,
+    /// so a connection seen by poll() may already be gone by the time we accept.
+    #[cfg(unix)]
+    fn set_nonblocking(&self) -> std::io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+            Listener::Unix(listener) => listener.set_nonblocking(true),
+        }
+    }
+}
+
+/// Sets whether a listener fd survives exec() into a new server process
+#[cfg(unix)]
+pub fn set_inheritable(fd: std::os::unix::io::RawFd, inheritable: bool) {
+    let flags = if inheritable { 0 } else { libc::FD_CLOEXEC };
+    unsafe { libc::fcntl(fd, libc::F_SETFD, flags) };
+}
+
+/// Binds every requested listener, adding each failure to `problems`
+///
+/// Sockets handed over by a restarting parent replace all other listeners.
+/// Sockets passed in by systemd, `--listen` and `--uds` replace the default
+/// `--host`/`--port` listener.
+pub fn bind_all(cli: &Cli, problems: &mut Problems) -> Vec<Listener> {
+    let listeners = bind_requested(cli, problems);
+    prepare(listeners, problems)
+}
+
+/// Resolves every address that would be bound, without binding it, adding each failure to `problems`
+pub fn resolve_all(cli: &Cli, problems: &mut Problems) {
+    use std::net::ToSocketAddrs;
+    let addresses = match cli.listen.is_empty() {
+        true => vec![tcp_address(&cli.host, cli.port)],
+        false => cli.listen.clone(),
+    };
+    for address in addresses {
+        if let Err(e) = address.to_socket_addrs() {
+            problems.push("E202", format!("cannot listen on {}: {}", address, e), None);
+        }
+    }
+}
+
+/// Readies bound or adopted listeners for the acceptor loop
+pub fn prepare(listeners: Vec<Listener>, problems: &mut Problems) -> Vec<Listener> {
+    #[cfg(unix)]
+    for listener in &listeners {
+        if let Err(e) = listener.set_nonblocking() {
+            problems.push("E203", format!("failed to configure listener: {}", e), None);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = problems;
+    listeners
+}
+
+fn bind_requested(cli: &Cli, problems: &mut Problems) -> Vec<Listener> {
+    #[cfg(unix)]
+    {
+        let inherited = inherited_listeners();
+        if !inherited.is_empty() {
+            return inherited;
+        }
+    }
+
+    #[cfg(unix)]
+    let mut listeners = systemd_listeners();
+    #[cfg(not(unix))]
+    let mut listeners = Vec::new();
+    let mut requested = !listeners.is_empty();
+    #[cfg(unix)]
+    let group_size = cli.reuseport.max(1);
+    #[cfg(not(unix))]
+    let group_size = 1;
+    let options = SocketOptions::from_cli(cli);
+    for address in &cli.listen {
+        listeners.extend(bind_tcp(address, group_size, options, problems));
+        requested = true;
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &cli.uds {
+        listeners.extend(bind_unix(path, problems));
+        requested = true;
+    }
+
+    if !requested {
+        listeners.extend(bind_with_retry(&cli.host, cli.port, cli.port_retry, group_size, options, problems));
+    }
+
+    listeners
+}
+
+/// Options set on TCP listening sockets before they bind
+///
+/// Accepted connections inherit the buffer sizes of the listener they came
+/// from, and the receive buffer has to be set this early to be taken into
+/// account for the TCP window scale.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// SO_REUSEADDR, on by default as with std's listeners
+    pub reuse_address: bool,
+    /// SO_SNDBUF in bytes, the system default when `None`
+    pub send_buffer: Option<u64>,
+    /// SO_RCVBUF in bytes, the system default when `None`
+    pub recv_buffer: Option<u64>,
+}
+
+impl SocketOptions {
+    #[cfg(unix)]
+    fn from_cli(cli: &Cli) -> SocketOptions {
+        SocketOptions {
+            reuse_address: cli.reuseaddr,
+            send_buffer: cli.send_buffer,
+            recv_buffer: cli.recv_buffer,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_cli(_cli: &Cli) -> SocketOptions {
+        SocketOptions {
+            reuse_address: true,
+            send_buffer: None,
+            recv_buffer: None,
+        }
+    }
+
+    /// Whether a listener bound by std would already have these options
+    fn are_default(&self) -> bool {
+        self.reuse_address && self.send_buffer.is_none() && self.recv_buffer.is_none()
+    }
+}
+
+/// Binds host:port, moving on to the next port up to `retries` times while it is in use
+fn bind_with_retry(
+    host: &str,
+    port: u16,
+    retries: u16,
+    group_size: usize,
+    options: SocketOptions,
+    problems: &mut Problems,
+) -> Vec<Listener> {
+    let mut port = port;
+    for _ in 0..retries {
+        let address = tcp_address(host, port);
+        match bind_group(&address, group_size, options) {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && port != 0 && port < u16::MAX => {
+                info!("Port {} is in use, trying {} ...", port, port + 1);
+                port += 1;
+            }
+            result => return check_tcp(&address, result, problems),
+        }
+    }
+    bind_tcp(&tcp_address(host, port), group_size, options, problems)
+}
+
+fn tcp_address(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port) // IPv6 literal
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn bind_tcp(address: &str, group_size: usize, options: SocketOptions, problems: &mut Problems) -> Vec<Listener> {
+    check_tcp(address, bind_group(address, group_size, options), problems)
+}
+
+/// Binds `group_size` sockets to `address`, sharing it through SO_REUSEPORT when there is more than one
+///
+/// The kernel then spreads new connections across the sockets, each of which
+/// gets its own acceptor.
+fn bind_group(address: &str, group_size: usize, options: SocketOptions) -> std::io::Result<Vec<std::net::TcpListener>> {
+    if group_size <= 1 && options.are_default() {
+        return std::net::TcpListener::bind(address).map(|listener| vec![listener]);
+    }
+    #[cfg(unix)]
+    {
+        use std::net::ToSocketAddrs;
+
+        let mut last_error = None;
+        for candidate in address.to_socket_addrs()? {
+            let first = match bind_socket(candidate, group_size > 1, options) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            // Port 0 picked a port, the rest of the group has to join that one
+            let local = first.local_addr()?;
+            let mut group = vec![first];
+            for _ in 1..group_size {
+                group.push(bind_socket(local, true, options)?);
+            }
+            return Ok(group);
+        }
+        Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no addresses to bind")))
+    }
+    #[cfg(not(unix))]
+    unreachable!("SO_REUSEPORT groups and socket options are only requested on unix")
+}
+
+/// Creates a listening TCP socket with `options`, and SO_REUSEPORT if `reuse_port`, set before binding
+#[cfg(unix)]
+fn bind_socket(
+    address: std::net::SocketAddr,
+    reuse_port: bool,
+    options: SocketOptions,
+) -> std::io::Result<std::net::TcpListener> {
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+
+    let (domain, storage, len) = unsafe {
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        match address {
+            std::net::SocketAddr::V4(v4) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = v4.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+                (libc::AF_INET, storage, std::mem::size_of::<libc::sockaddr_in>())
+            }
+            std::net::SocketAddr::V6(v6) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = v6.port().to_be();
+                sin6.sin6_addr.s6_addr = v6.ip().octets();
+                sin6.sin6_flowinfo = v6.flowinfo();
+                sin6.sin6_scope_id = v6.scope_id();
+                (libc::AF_INET6, storage, std::mem::size_of::<libc::sockaddr_in6>())
+            }
+        }
+    };
+
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    set_inheritable(fd, false);
+
+    let mut settings = Vec::new();
+    if options.reuse_address {
+        settings.push((libc::SO_REUSEADDR, 1));
+    }
+    if reuse_port {
+        settings.push((libc::SO_REUSEPORT, 1));
+    }
+    if let Some(size) = options.send_buffer {
+        settings.push((libc::SO_SNDBUF, size.min(libc::c_int::MAX as u64) as libc::c_int));
+    }
+    if let Some(size) = options.recv_buffer {
+        settings.push((libc::SO_RCVBUF, size.min(libc::c_int::MAX as u64) as libc::c_int));
+    }
+    for (option, value) in settings {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    unsafe {
+        if libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len as libc::socklen_t) != 0
+            || libc::listen(fd, libc::SOMAXCONN) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(std::net::TcpListener::from(socket))
+}
+
+/// Wraps a bound listener group, or records why binding failed
+fn check_tcp(
+    address: &str,
+    result: std::io::Result<Vec<std::net::TcpListener>>,
+    problems: &mut Problems,
+) -> Vec<Listener> {
+    match result {
+        Ok(group) => group.into_iter().map(Listener::Tcp).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            problems.push(
+                "E200",
+                format!("the address {} is already in use", address),
+                Some("try --port-retry N or --port 0"),
+            );
+            Vec::new()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            problems.push(
+                "E201",
+                format!("not allowed to bind to {}: {}", address, e),
+                Some("ports below 1024 need root; bind as root and use --user to drop privileges"),
+            );
+            Vec::new()
+        }
+        Err(e) => {
+            problems.push("E202", format!("failed to bind to {}: {}", address, e), None);
+            Vec::new()
+        }
+    }
+}
+
+/// File descriptor of the first socket passed by systemd (SD_LISTEN_FDS_START)
+#[cfg(unix)]
+const SYSTEMD_FIRST_FD: i32 = 3;
+
+/// Takes over the sockets passed by systemd socket activation, if any
+///
+/// Follows the sd_listen_fds(3) protocol: LISTEN_PID must name this process and
+/// LISTEN_FDS gives the number of sockets starting at fd 3.
+#[cfg(unix)]
+fn systemd_listeners() -> Vec<Listener> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    // The variables must not leak into child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if !for_us || count <= 0 {
+        return Vec::new();
+    }
+
+    (SYSTEMD_FIRST_FD..SYSTEMD_FIRST_FD + count)
+        .filter_map(|fd| adopt_fd(fd, "systemd"))
+        .collect()
+}
+
+/// Takes over the listeners of the server process that re-executed us
+#[cfg(unix)]
+fn inherited_listeners() -> Vec<Listener> {
+    let Ok(fds) = std::env::var(INHERITED_FDS_ENV) else {
+        return Vec::new();
+    };
+    std::env::remove_var(INHERITED_FDS_ENV);
+    fds.split(',')
+        .filter_map(|fd| fd.trim().parse().ok())
+        .filter_map(|fd| adopt_fd(fd, "inherited"))
+        .collect()
+}
+
+/// Wraps an already bound and listening socket fd
+#[cfg(unix)]
+pub fn adopt_fd(fd: i32, origin: &str) -> Option<Listener> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let family = match unsafe { libc::getsockname(fd, &mut address as *mut _ as *mut libc::sockaddr, &mut len) } {
+        0 => address.ss_family as libc::c_int,
+        _ => {
+            warn!("Ignoring {} fd {}: not a socket", origin, fd);
+            return None;
+        }
+    };
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    if family == libc::AF_UNIX {
+        Some(Listener::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) }))
+    } else {
+        Some(Listener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }))
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, problems: &mut Problems) -> Option<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket file left behind by a previous run would make bind fail
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    match std::os::unix::net::UnixListener::bind(path) {
+        Ok(listener) => Some(Listener::Unix(listener)),
+        Err(e) => {
+            problems.push("E204", format!("failed to bind to socket {}: {}", path.display(), e), None);
+            None
+        }
+    }
+}
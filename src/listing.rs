@@ -0,0 +1,158 @@
+use crate::images::Images;
+use crate::template;
+use crate::units;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What entries can be sorted by, as named in `?sort=`
+const SORT_KEYS: [&str; 3] = ["name", "size", "modified"];
+
+/// Largest width and height of the thumbnails offered for images
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Lists directories without an index page through a Handlebars template, from --listing-template
+///
+/// The template gets `path`, `query`, `parent` (the href of the parent
+/// directory, missing at the root), `breadcrumbs` (`name` and `href` of each
+/// directory from the root down), `entries` (`name`, `href`, `dir`, `size`,
+/// `size_text`, `modified` in seconds since the epoch and `modified_text`),
+/// `sort` (`by` and `descending`) and `columns`, which holds for each of name,
+/// size and modified the href that sorts by it (reversing the order if it is
+/// the current one) and whether it is `active`. Directories come first
+/// whatever the order. The template is read for every listing, so edits show
+/// up on the next request, and a stylesheet can live in the served directory.
+///
+/// With thumbnails, image entries also get a `thumbnail` href asking
+/// --resize-images for a small copy, which is made on the first request for
+/// it and cached like any other resized image.
+pub struct Listing {
+    template: PathBuf,
+    thumbnails: bool,
+}
+
+struct Entry {
+    name: String,
+    dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Listing {
+    /// Checks that the template can be read and parsed
+    pub fn new(template: &Path, thumbnails: bool) -> Result<Listing, String> {
+        template::check_file(template)?;
+        Ok(Listing {
+            template: template.to_path_buf(),
+            thumbnails,
+        })
+    }
+
+    /// Renders the listing of `dir`, requested as `url_path` with `query`, leaving out entries `shown` rejects
+    pub fn render(&self, dir: &Path, url_path: &str, query: &str, shown: &dyn Fn(&str) -> bool) -> Result<String, String> {
+        let base = format!("{}/", url_path.trim_end_matches('/'));
+        let mut entries: Vec<Entry> = fs::read_dir(dir)
+            .map_err(|e| format!("cannot list {}: {}", dir.display(), e))?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                // Follows symlinks, so a link to a directory lists as one
+                let metadata = fs::metadata(entry.path()).ok()?;
+                let entry = Entry {
+                    dir: metadata.is_dir(),
+                    size: if metadata.is_dir() { 0 } else { metadata.len() },
+                    modified: metadata.modified().ok(),
+                    name,
+                };
+                (!entry.name.starts_with('.') && shown(&format!("{}{}", base, entry.name))).then_some(entry)
+            })
+            .collect();
+
+        let query_params = template::query_params(query);
+        let sort_by = (query_params.get("sort").and_then(Value::as_str))
+            .filter(|key| SORT_KEYS.contains(key))
+            .unwrap_or("name");
+        let descending = query_params.get("order").and_then(Value::as_str) == Some("desc");
+        entries.sort_by(|a, b| {
+            let order = match sort_by {
+                "size" => a.size.cmp(&b.size),
+                "modified" => a.modified.cmp(&b.modified),
+                _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            };
+            let order = order.then_with(|| a.name.cmp(&b.name));
+            b.dir.cmp(&a.dir).then(if descending { order.reverse() } else { order })
+        });
+
+        let columns: serde_json::Map<String, Value> = SORT_KEYS
+            .iter()
+            .map(|key| {
+                let active = *key == sort_by;
+                let order = if active && !descending { "desc" } else { "asc" };
+                let href = format!("{}?sort={}&order={}", base, key, order);
+                (key.to_string(), json!({ "href": href, "active": active }))
+            })
+            .collect();
+        let mut breadcrumbs = vec![json!({ "name": "/", "href": "/" })];
+        let mut href = String::from("/");
+        for segment in url_path.split('/').filter(|segment| !segment.is_empty()) {
+            href.push_str(segment);
+            href.push('/');
+            breadcrumbs.push(json!({ "name": segment, "href": href }));
+        }
+        let parent = (breadcrumbs.len() > 1).then(|| breadcrumbs[breadcrumbs.len() - 2]["href"].clone());
+        let entries: Vec<Value> = entries
+            .iter()
+            .map(|entry| {
+                let href = format!("{}{}{}", base, entry.name, if entry.dir { "/" } else { "" });
+                let modified = entry.modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+                let mut value = json!({
+                    "name": entry.name,
+                    "href": href,
+                    "dir": entry.dir,
+                    "size": entry.size,
+                    "size_text": if entry.dir { String::new() } else { units::size(entry.size) },
+                    "modified": modified.map(|modified| modified.as_secs()),
+                    "modified_text": entry.modified.map(units::timestamp),
+                });
+                if self.thumbnails && !entry.dir && Images::is_image(Path::new(&entry.name)) {
+                    value["thumbnail"] = json!(format!("{}?w={}&h={}", href, THUMBNAIL_SIZE, THUMBNAIL_SIZE));
+                }
+                value
+            })
+            .collect();
+
+        let mut context = json!({
+            "path": url_path,
+            "query": query_params,
+            "breadcrumbs": breadcrumbs,
+            "entries": entries,
+            "sort": { "by": sort_by, "descending": descending },
+            "columns": columns,
+        });
+        if let Some(parent) = parent {
+            context["parent"] = parent;
+        }
+        template::render_file(&self.template, &context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_get_thumbnails() {
+        let dir = std::env::temp_dir().join(format!("rshttp-listing-{}", std::process::id()));
+        fs::create_dir_all(dir.join("photos")).unwrap();
+        fs::write(dir.join("photos/a.png"), "").unwrap();
+        fs::write(dir.join("photos/b.txt"), "").unwrap();
+        let template = dir.join("listing.hbs");
+        fs::write(&template, "{{#each entries}}{{name}}:{{thumbnail}};{{/each}}").unwrap();
+        let render = |thumbnails| Listing::new(&template, thumbnails).unwrap().render(&dir.join("photos"), "/photos", "", &|_| true);
+        let (with, without) = (render(true), render(false));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(with.unwrap(), "a.png:/photos/a.png?w&#x3D;256&amp;h&#x3D;256;b.txt:;");
+        assert_eq!(without.unwrap(), "a.png:;b.txt:;");
+    }
+}
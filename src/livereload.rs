@@ -0,0 +1,136 @@
+use crate::shutdown::Shutdown;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// URL the injected script polls for changes
+pub const PATH: &str = "/__livereload";
+
+/// Longest a poll is held open before it is answered without a change
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How often a held poll checks whether shutdown has begun
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reloads open pages in the browser when the watcher sees their files change
+///
+/// Served HTML gets a small script that long-polls [`PATH`] with the
+/// generation the page was served at. A poll is answered as soon as the
+/// generation moves on (or after [`POLL_TIMEOUT`]), and the page reloads
+/// when the answer differs from its own. If only stylesheets changed since,
+/// the answer says `css` and the page swaps in fresh copies of its
+/// stylesheet links instead, keeping its state. Long polling works the same
+/// in every server mode, including those that buffer whole responses; each
+/// open page holds one worker while it waits, so only so many polls are
+/// held at once and the rest are told to try again shortly.
+pub struct LiveReload {
+    generations: Mutex<Generations>,
+    changed: Condvar,
+    max_waiting: usize,
+}
+
+struct Generations {
+    current: u64,
+    /// The last generation that changed more than stylesheets
+    reload: u64,
+    /// Polls being held
+    waiting: usize,
+}
+
+impl LiveReload {
+    /// Holds at most `max_waiting` polls open at a time
+    pub fn new(max_waiting: usize) -> LiveReload {
+        // Starting from the time makes pages left open across a restart reload too
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64);
+        LiveReload {
+            generations: Mutex::new(Generations {
+                current: start,
+                reload: start,
+                waiting: 0,
+            }),
+            changed: Condvar::new(),
+            max_waiting: max_waiting.max(1),
+        }
+    }
+
+    /// Tells every waiting page to reload, or only to swap its stylesheets if `stylesheets_only`
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn notify(&self, stylesheets_only: bool) {
+        let mut generations = self.generations.lock().unwrap();
+        generations.current += 1;
+        if !stylesheets_only {
+            generations.reload = generations.current;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Waits until the generation differs from `since`, the poll times out or shutdown begins
+    ///
+    /// Answers with the current generation, followed by ` css` when only
+    /// stylesheets changed after `since`, or `None` right away when as many
+    /// polls as allowed are already waiting.
+    pub fn wait(&self, since: u64, shutdown: &Shutdown) -> Option<String> {
+        let deadline = Instant::now() + POLL_TIMEOUT;
+        let mut generations = self.generations.lock().unwrap();
+        if generations.current == since {
+            if generations.waiting >= self.max_waiting {
+                return None;
+            }
+            generations.waiting += 1;
+            while generations.current == since && !shutdown.is_draining() {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                generations = self.changed.wait_timeout(generations, left.min(SHUTDOWN_CHECK_INTERVAL)).unwrap().0;
+            }
+            generations.waiting -= 1;
+        }
+        Some(match generations.current != since && generations.reload <= since {
+            true => format!("{} css", generations.current),
+            false => generations.current.to_string(),
+        })
+    }
+
+    /// The polling script for a page served now
+    ///
+    /// Swapped stylesheets are same-origin links loaded again with a
+    /// `livereload` query parameter, the old link removed once the new one
+    /// has loaded so the page doesn't flash unstyled.
+    pub fn script(&self) -> String {
+        format!(
+            "<script>(function () {{ function swap(generation) {{ document.querySelectorAll(\"link[rel=stylesheet]\").forEach(function (link) {{ \
+             var url = new URL(link.href, location.href); if (url.origin !== location.origin) {{ return; }} \
+             url.searchParams.set(\"livereload\", generation); var fresh = link.cloneNode(); fresh.href = url.href; \
+             fresh.onload = fresh.onerror = function () {{ link.remove(); }}; link.after(fresh); }}); }} \
+             (function poll(since) {{ fetch(\"{}?since=\" + since, {{ cache: \"no-store\" }})\
+             .then(function (response) {{ if (!response.ok) {{ throw response.status; }} return response.text(); }})\
+             .then(function (answer) {{ var parts = answer.trim().split(\" \"); \
+             if (parts[0] === since) {{ poll(since); }} else if (parts[1] === \"css\") {{ swap(parts[0]); poll(parts[0]); }} else {{ location.reload(); }} }}, \
+             function () {{ setTimeout(function () {{ poll(since); }}, 1000); }}); }})(\"{}\"); }})();</script>",
+            PATH,
+            self.generations.lock().unwrap().current
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waiting_polls_are_capped() {
+        let live_reload = LiveReload::new(1);
+        let shutdown = Shutdown::new();
+        let current: u64 = live_reload.wait(0, &shutdown).unwrap().parse().unwrap();
+        std::thread::scope(|scope| {
+            let held = scope.spawn(|| live_reload.wait(current, &shutdown));
+            while live_reload.generations.lock().unwrap().waiting == 0 {
+                std::thread::yield_now();
+            }
+            assert_eq!(live_reload.wait(current, &shutdown), None);
+            live_reload.notify(true);
+            assert_eq!(held.join().unwrap(), Some(format!("{} css", current + 1)));
+        });
+        assert_eq!(live_reload.wait(current, &shutdown), Some(format!("{} css", current + 1)));
+    }
+}
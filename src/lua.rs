@@ -0,0 +1,157 @@
+use mlua::{Function, HookTriggers, Lua, Table, Value};
+use rshttp::request::{self, HeaderMap};
+use rshttp::response::Response;
+use std::cell::RefCell;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+/// How long `handle` may run for one request before it is stopped
+const TIME_LIMIT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    /// Each worker's own interpreter, with the modification time of the script it ran
+    static STATE: RefCell<Option<(Option<SystemTime>, Lua)>> = const { RefCell::new(None) };
+}
+
+/// A Lua script from --lua FILE, called for every request
+///
+/// The script defines a global function `handle(request)`, passed a table
+/// with the `method`, `path`, `query`, `ip` and `headers` (names in lower
+/// case) of the request. It returns nothing to let the request through, or a
+/// table:
+///
+/// - with a `status`, and optionally `headers` and a `body`, to answer with
+///   it, e.g. `{status = 302, headers = {Location = "/new"}}`;
+/// - with a `path` and optionally a `query` to serve another path instead.
+///
+/// Every worker thread runs its own interpreter, so globals set by the
+/// script persist between the requests of one worker only. Edits to the
+/// script take effect on the next request. A script that fails, or runs for
+/// more than a second, fails the request with 500.
+pub struct LuaHooks {
+    path: PathBuf,
+}
+
+/// What the script made of a request
+pub enum Decision {
+    Continue,
+    Rewrite { path: String, query: String },
+    Respond(Response<'static>),
+}
+
+/// The parts of a request the script sees
+struct LuaRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    ip: Option<IpAddr>,
+    headers: &'a HeaderMap<'a>,
+}
+
+impl LuaHooks {
+    /// Loads the script once, to report errors in it at startup
+    pub fn load(path: &Path) -> Result<LuaHooks, String> {
+        interpreter(path)?;
+        Ok(LuaHooks { path: path.to_path_buf() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn decide(&self, method: &str, path: &str, query: &str, ip: Option<IpAddr>, headers: &HeaderMap) -> Decision {
+        let request = &LuaRequest {
+            method,
+            path,
+            query,
+            ip,
+            headers,
+        };
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        let decided = STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.as_ref().is_none_or(|(loaded, _)| *loaded != modified) {
+                if state.is_some() {
+                    debug!("Reloading {}", self.path.display());
+                }
+                *state = Some((modified, interpreter(&self.path)?));
+            }
+            let (_, lua) = state.as_ref().expect("loaded above");
+            call(lua, request).map_err(|e| e.to_string())
+        });
+        match decided {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("{} failed on {} {}: {}", self.path.display(), request.method, request.path, e);
+                Decision::Respond(Response::error(500))
+            }
+        }
+    }
+}
+
+/// A fresh interpreter that has run the script, which defines `handle`
+fn interpreter(path: &Path) -> Result<Lua, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let lua = Lua::new();
+    lua.set_app_data(Instant::now() + TIME_LIMIT);
+    lua.set_hook(HookTriggers::new().every_nth_instruction(10_000), |lua, _| {
+        match lua.app_data_ref::<Instant>().is_some_and(|deadline| Instant::now() > *deadline) {
+            true => Err(mlua::Error::RuntimeError(format!("ran for more than {:?}", TIME_LIMIT))),
+            false => Ok(()),
+        }
+    });
+    let name = format!("@{}", path.display());
+    lua.load(&source).set_name(name).exec().map_err(|e| e.to_string())?;
+    if !matches!(lua.globals().get::<_, Value>("handle"), Ok(Value::Function(_))) {
+        return Err(format!("{} does not define a handle(request) function", path.display()));
+    }
+    Ok(lua)
+}
+
+fn call(lua: &Lua, request: &LuaRequest) -> mlua::Result<Decision> {
+    lua.set_app_data(Instant::now() + TIME_LIMIT);
+    let headers = lua.create_table()?;
+    for (name, value) in request.headers.iter() {
+        let name = name.to_ascii_lowercase();
+        if !headers.contains_key(name.as_str())? {
+            headers.set(name, value)?;
+        }
+    }
+    let table = lua.create_table()?;
+    table.set("method", request.method)?;
+    table.set("path", request.path)?;
+    table.set("query", request.query)?;
+    table.set("ip", request.ip.map(|ip| ip.to_string()))?;
+    table.set("headers", headers)?;
+
+    let handle: Function = lua.globals().get("handle")?;
+    let answer = match handle.call::<_, Value>(table)? {
+        Value::Nil => return Ok(Decision::Continue),
+        Value::Table(answer) => answer,
+        other => {
+            let message = format!("handle returned a {}, expected a table or nil", other.type_name());
+            return Err(mlua::Error::RuntimeError(message));
+        }
+    };
+    if let Some(status) = answer.get::<_, Option<u16>>("status")? {
+        let mut response = Response::new(status);
+        if let Some(headers) = answer.get::<_, Option<Table>>("headers")? {
+            for pair in headers.pairs::<String, String>() {
+                let (name, value) = pair?;
+                response = response.header(&name, value);
+            }
+        }
+        let body = answer.get::<_, Option<mlua::String>>("body")?;
+        return Ok(Decision::Respond(response.body(body.map(|body| body.as_bytes().to_vec()).unwrap_or_default())));
+    }
+    match answer.get::<_, Option<String>>("path")? {
+        Some(path) => Ok(Decision::Rewrite {
+            path: request::normalize_path(&path),
+            query: answer.get::<_, Option<String>>("query")?.unwrap_or_else(|| request.query.to_string()),
+        }),
+        None => Ok(Decision::Continue),
+    }
+}
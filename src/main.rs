@@ -1,221 +1,3621 @@
-use std::collections::HashMap;
-use std::fs;
-use std::io::{Read, Write};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(feature = "watch")]
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+#[cfg(feature = "watch")]
+use std::sync::mpsc::{RecvTimeoutError, Sender};
 use std::sync::mpsc::channel;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use tracing::field::Empty;
+use tracing::{debug, debug_span, error, info, info_span, warn, Span};
 
-type FileCache = Arc<RwLock<HashMap<String, (Vec<u8>, String)>>>;
+mod access;
+mod accesslog;
+mod affinity;
+mod archive;
+mod banner;
+mod bench;
+mod browser;
+mod bundle;
+mod cache;
+mod cgi;
+mod check;
+mod chaos;
+mod clipboard;
+#[cfg(unix)]
+mod cluster;
+mod compat;
+mod completions;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod dashboard;
+mod diagnostics;
+mod diff;
+mod disabled;
+mod events;
+#[cfg(feature = "watch")]
+mod exec;
+#[cfg(not(feature = "async"))]
+mod event_loop;
+#[cfg(feature = "fallback")]
+mod fallback;
+mod fastcgi;
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "git")]
+mod gitref;
+mod ignore;
+mod init;
+mod inject;
+mod inspector;
+mod glob;
+mod golden;
+mod har;
+mod htpasswd;
+mod images;
+mod lan;
+mod language;
+mod markdown;
+mod listener;
+mod listing;
+mod livereload;
+#[cfg(feature = "lua")]
+mod lua;
+mod manifest;
+mod mdns;
+mod metrics;
+mod mime;
+mod recording;
+#[cfg(unix)]
+mod mmap;
+mod mock;
+#[cfg(feature = "oidc")]
+mod oidc;
+#[cfg(feature = "async")]
+mod async_server;
+#[cfg(not(feature = "async"))]
+mod pool;
+#[cfg(all(windows, not(feature = "async")))]
+mod pipe;
+mod placeholders;
+#[cfg(feature = "plugins")]
+mod plugins;
+#[cfg(unix)]
+mod privileges;
+mod proxy;
+#[cfg(feature = "sqlite")]
+mod requestdb;
+mod redirect;
+mod rewrite;
+mod routes;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(unix)]
+mod sandbox;
+mod sass;
+mod search;
+#[cfg(windows)]
+mod service;
+mod setup;
+mod shares;
+mod shutdown;
+mod signing;
+mod singlefile;
+mod sitemap;
+mod sitearchive;
+mod ssi;
+#[cfg(unix)]
+mod signals;
+mod snapshots;
+mod startup;
+#[cfg(unix)]
+mod syslog;
+#[cfg(unix)]
+mod takeover;
+mod template;
+mod throttle;
+mod units;
+mod upload;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod validators;
+mod verify;
+mod walk;
+mod webdav;
+#[cfg(feature = "webhook")]
+mod webhook;
+
+use access::{AccessPolicy, AccessRequest, Cidr, Decision, IpFilter, TrustedProxies};
+use accesslog::{AccessLog, LogFormat, Rotation};
+use rshttp::auth::{self, AuthProvider, BasicAuth, JwtAuth, Principal};
+use banner::Banner;
+use cache::{CacheBackend, CachedFile, Loads};
+use cgi::{Cgi, Invocation, Script};
+use chaos::{Chaos, Fault};
+use rshttp::middleware::Encoding;
+use diagnostics::LogTarget;
+#[cfg(not(feature = "fallback"))]
+use disabled::fallback;
+#[cfg(not(feature = "git"))]
+use disabled::gitref;
+#[cfg(not(feature = "geoip"))]
+use disabled::geoip;
+#[cfg(not(feature = "lua"))]
+use disabled::lua;
+#[cfg(not(feature = "oidc"))]
+use disabled::oidc;
+#[cfg(not(feature = "plugins"))]
+use disabled::plugins;
+#[cfg(not(feature = "sqlite"))]
+use disabled::requestdb;
+#[cfg(not(feature = "s3"))]
+use disabled::s3;
+#[cfg(not(feature = "webhook"))]
+use disabled::webhook;
+#[cfg(feature = "watch")]
+use events::Change;
+use events::ChangeEvents;
+use fallback::FallbackOrigin;
+use fastcgi::FastCgi;
+use gitref::GitTree;
+use language::Languages;
+use markdown::Markdown;
+use mime::MimeTypes;
+use placeholders::Robots;
+use listener::{Connection, Deadline, HeadFilter, Listener, Timeouts};
+use har::Har;
+use htpasswd::HtpasswdFile;
+use ignore::IgnoreRules;
+use images::{Images, Transform};
+use inspector::Inspector;
+use livereload::LiveReload;
+use lua::LuaHooks;
+use metrics::{Metered, Metrics, Phases, RequestRecord};
+use mock::Mocks;
+use recording::{Recorder, Replay};
+use oidc::{OidcClient, OidcOutcome};
+use plugins::Plugins;
+#[cfg(not(feature = "async"))]
+use pool::ThreadPool;
+#[cfg(unix)]
+use privileges::Account;
+use requestdb::RequestDb;
+use proxy::{Forwarded, Proxy};
+use redirect::{HostRedirect, Redirect};
+use rewrite::Rewrite;
+use routes::{Matched, Routes};
+use rshttp::response::{FileBody, Response};
+use rshttp::{request, resolve};
+#[cfg(unix)]
+use sandbox::Sandbox;
+use sass::Sass;
+use manifest::Manifest;
+use search::Search;
+use setup::ContextBuilder;
+use sitemap::Sitemap;
+use ssi::Includes;
+use shares::{Redeemed, Shares};
+use shutdown::{ActiveConnection, PeerConnection, PeerConnections, Shutdown};
+use signing::{Signature, UrlSigner};
+use singlefile::SingleFile;
+use sitearchive::SiteArchive;
+use snapshots::Snapshots;
+use startup::Problems;
+#[cfg(unix)]
+use syslog::Syslog;
+#[cfg(unix)]
+use takeover::Takeover;
+use template::Templates;
+use listing::Listing;
+#[cfg(unix)]
+use signals::Signal;
+use throttle::{Shaping, Throttled};
+use units::{SizeUnits, TimeStyle};
+use validators::Validators;
+use upload::Uploads;
+use webdav::{DavRequest, WebDav};
+use webhook::Webhook;
+
+/// Endpoint enabled by --debug-echo
+const DEBUG_ECHO_PATH: &str = "/__debug/echo";
+
+/// Where `--cache-admin` lists (GET) and purges (DELETE) cached files
+const CACHE_ADMIN_PATH: &str = "/__admin/cache";
+
+/// Liveness probe, 200 as long as the process answers
+const HEALTHZ_PATH: &str = "/_rshttps/healthz";
+
+/// Readiness probe, 200 once the file watcher is up and 503 again while draining
+const READYZ_PATH: &str = "/_rshttps/readyz";
+
+/// Connections that may wait for a free worker, per worker, before accepting pauses
+#[cfg(not(feature = "async"))]
+const QUEUED_PER_WORKER: usize = 4;
+
+/// Files this large are sent from disk rather than read into memory and cached
+const STREAM_MIN_SIZE: usize = 1024 * 1024;
+
+/// How often the watcher checks whether a missing root is back
+const ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest the watcher holds back changes while more keep coming, so a build
+/// that never pauses still gets its files reloaded
+#[cfg(feature = "watch")]
+const MAX_CHANGE_BATCH_TIME: Duration = Duration::from_secs(5);
+
+/// How often acceptors wake up to check whether shutdown has begun
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+type FileCache = Arc<dyn CacheBackend>;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+// Flags of features left out of the build stay at their defaults and are never read
+#[cfg_attr(not(feature = "full"), allow(dead_code))]
 struct Cli {
-    /// Port to serve on
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Address to bind to, e.g. 0.0.0.0 to serve on all interfaces
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Port to serve on (0 lets the OS pick a free port)
     #[arg(short, long, default_value = "8000")]
     port: u16,
+    /// If the port is in use, try up to N following ports
+    #[arg(long, value_name = "N", default_value = "0")]
+    port_retry: u16,
     /// Directory to serve files from
     #[arg(short, long, default_value = ".")]
     directory: String,
+    /// Read options from this TOML file, e.g. `port = 8080`; flags given on the command line win
+    /// (default: rshttps.toml in the working directory, if there is one, without options that run commands).
+    /// Its credentials, --protect patterns and access rules are applied again on SIGHUP and when it is saved
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+    /// Don't read rshttps.toml from the working directory
+    #[arg(long, conflicts_with = "config", global = true)]
+    no_config: bool,
+    /// Put the URL the site is served at on the clipboard once listening
+    #[arg(long)]
+    copy_url: bool,
+    /// Advertise the server on the local network as NAME.local, and as an HTTP service called NAME, over mDNS
+    #[arg(long, value_name = "NAME")]
+    mdns: Option<String>,
+    /// Open the served site in the default browser once listening, at PATH if given, e.g. --open /docs/
+    #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "/")]
+    open: Option<String>,
+    /// Don't draw a QR code of the network address in the startup summary when serving beyond loopback
+    #[arg(long)]
+    no_qr: bool,
+    /// Serve HOST from its own directory, e.g. --vhost docs.local=./docs (repeatable)
+    #[arg(long = "vhost", value_name = "HOST=DIR", value_parser = parse_vhost)]
+    vhosts: Vec<(String, PathBuf)>,
+    /// Require HTTP Basic authentication with USER:PASSWORD (repeatable)
+    #[arg(long = "auth", value_name = "USER:PASSWORD", value_parser = BasicAuth::parse)]
+    basic_auth: Vec<BasicAuth>,
+    /// Require HTTP Basic authentication against the users of this htpasswd file (bcrypt or SHA-1 hashes),
+    /// read again when it changes
+    #[arg(long, value_name = "FILE")]
+    auth_file: Option<PathBuf>,
+    /// Only require authentication for paths matching this glob, e.g. /drafts/** (repeatable);
+    /// everything else is public unless access rules say otherwise
+    #[arg(long, value_name = "GLOB")]
+    protect: Vec<String>,
+    /// Accept HS256-signed JWT bearer tokens verified with this secret, which need `sub` and `exp` claims
+    #[arg(long, value_name = "SECRET")]
+    jwt_secret: Option<String>,
+    /// Accept links signed with this secret by `rshttp sign` in place of credentials until they expire
+    #[arg(long, value_name = "SECRET")]
+    url_secret: Option<String>,
+    /// Protect the site with OpenID Connect login against this issuer URL
+    #[cfg_attr(
+        feature = "oidc",
+        arg(long, value_name = "URL", requires_all = ["oidc_client_id", "oidc_client_secret", "oidc_redirect_url"])
+    )]
+    #[cfg_attr(not(feature = "oidc"), arg(skip))]
+    oidc_issuer: Option<String>,
+    /// OAuth client id registered with the OIDC provider
+    #[cfg_attr(feature = "oidc", arg(long, value_name = "ID"))]
+    #[cfg_attr(not(feature = "oidc"), arg(skip))]
+    oidc_client_id: Option<String>,
+    /// OAuth client secret registered with the OIDC provider
+    #[cfg_attr(feature = "oidc", arg(long, value_name = "SECRET"))]
+    #[cfg_attr(not(feature = "oidc"), arg(skip))]
+    oidc_client_secret: Option<String>,
+    /// Public callback URL registered with the provider, e.g. https://files.example.com/_rshttps/oidc/callback
+    #[cfg_attr(feature = "oidc", arg(long, value_name = "URL"))]
+    #[cfg_attr(not(feature = "oidc"), arg(skip))]
+    oidc_redirect_url: Option<String>,
+    /// Evaluate requests against the rules in this file, e.g. `allow GET /public/**`
+    #[arg(long, value_name = "FILE")]
+    access_rules: Option<PathBuf>,
+    /// Accept connections from this network, e.g. 192.168.1.0/24, and refuse the rest unless allowed too
+    /// (repeatable); the most specific of --allow and --deny wins
+    #[arg(long = "allow", value_name = "CIDR", value_parser = Cidr::parse)]
+    allow: Vec<Cidr>,
+    /// Refuse connections from this network, e.g. 0.0.0.0/0 (repeatable)
+    #[arg(long = "deny", value_name = "CIDR", value_parser = Cidr::parse)]
+    deny: Vec<Cidr>,
+    /// Take the client's address from the X-Forwarded-For or Forwarded header of requests from this
+    /// network, e.g. a load balancer at 10.0.0.0/8 (repeatable), for logs, --allow/--deny and limits
+    #[arg(long = "trusted-proxy", value_name = "CIDR", value_parser = Cidr::parse)]
+    trusted_proxies: Vec<Cidr>,
+    /// Look the countries of clients up in this MaxMind GeoLite2 or GeoIP2 database, for --geo-allow and --geo-deny
+    #[cfg_attr(feature = "geoip", arg(long, value_name = "FILE"))]
+    #[cfg_attr(not(feature = "geoip"), arg(skip))]
+    geoip_db: Option<PathBuf>,
+    /// Accept connections only from these countries, as ISO codes, e.g. NL,BE (repeatable); private networks
+    /// are left to --allow and --deny, and other addresses the database doesn't place are refused
+    #[cfg_attr(feature = "geoip", arg(long = "geo-allow", value_name = "COUNTRIES", value_delimiter = ',', requires = "geoip_db"))]
+    #[cfg_attr(not(feature = "geoip"), arg(skip))]
+    geo_allow: Vec<String>,
+    /// Refuse connections from these countries, e.g. XX,YY (repeatable)
+    #[cfg_attr(feature = "geoip", arg(long = "geo-deny", value_name = "COUNTRIES", value_delimiter = ',', requires = "geoip_db"))]
+    #[cfg_attr(not(feature = "geoip"), arg(skip))]
+    geo_deny: Vec<String>,
+    /// Seconds to let in-flight requests finish after SIGINT/SIGTERM
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    drain_timeout: u64,
+    /// Seconds a client may take to send the whole request head, however slowly it trickles in (0 for no limit)
+    #[arg(long, value_name = "SECS", default_value = "30")]
+    header_timeout: u64,
+    /// Seconds each read of a request body may wait for data (0 for no limit)
+    #[arg(long, value_name = "SECS", default_value = "30")]
+    body_timeout: u64,
+    /// Seconds each write of a response may wait for the client to take data (0 for no limit)
+    #[arg(long, value_name = "SECS", default_value = "60")]
+    write_timeout: u64,
+    /// Seconds an idle HTTP/1.1 connection is kept open for another request, holding on to its worker
+    /// (0 closes connections after each response; the --event-loop and --async servers always do)
+    #[arg(long, value_name = "SECS", default_value = "0")]
+    keep_alive: u64,
+    /// Set TCP_NODELAY on accepted connections, sending small responses without waiting to coalesce them
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// Append the access log to this file instead of printing it
+    #[arg(long, value_name = "FILE")]
+    access_log: Option<PathBuf>,
+    /// Rotate the access log file once it reaches this size, e.g. 100M
+    #[arg(long, value_name = "BYTES", requires = "access_log", value_parser = units::parse_size)]
+    access_log_max_size: Option<u64>,
+    /// Rotate the access log file after this many seconds, e.g. 86400 for daily files
+    #[arg(long, value_name = "SECS", requires = "access_log")]
+    access_log_interval: Option<u64>,
+    /// How many rotated access log files to keep (FILE.1 is the newest)
+    #[arg(long, value_name = "N", default_value = "7", requires = "access_log")]
+    access_log_keep: usize,
+    /// Access log format: common, combined (Combined Log Format plus the time taken in microseconds)
+    /// or json (one object per line)
+    #[arg(long, value_name = "FORMAT", default_value = "combined", value_parser = LogFormat::parse)]
+    log_format: LogFormat,
+    /// Also record every request in this SQLite database, in a `requests` table
+    #[cfg_attr(feature = "sqlite", arg(long, value_name = "FILE"))]
+    #[cfg_attr(not(feature = "sqlite"), arg(skip))]
+    request_db: Option<PathBuf>,
+    /// Capture every request and response and write them to this HAR file on shutdown, e.g. to share with devtools
+    #[arg(long, value_name = "FILE")]
+    har: Option<PathBuf>,
+    /// Include response bodies in the HAR file, up to 1 MiB each
+    #[arg(long, requires = "har")]
+    har_bodies: bool,
+    /// Record the response to every request in this directory, for --replay
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Answer requests with the responses recorded in this directory instead of serving files
+    #[arg(long, value_name = "DIR")]
+    replay: Option<PathBuf>,
+    /// Where diagnostics and the access log go: console (stderr and stdout) or syslog (also reaches journald);
+    /// --access-log still takes the access log to a file
+    #[arg(long, value_name = "TARGET", default_value = "console", value_parser = LogTarget::parse)]
+    log_target: LogTarget,
+    /// Also write the shutdown summary report to this file
+    #[arg(long, value_name = "FILE")]
+    stats_file: Option<PathBuf>,
+    /// Listen on ADDRESS:PORT instead of --host/--port (repeatable)
+    #[arg(long = "listen", value_name = "ADDRESS:PORT")]
+    listen: Vec<String>,
+    /// Redirect requests for hosts matching PATTERN, or for any other host without one, to the same path on
+    /// HOST, e.g. "www.example.com -> example.com" (repeatable)
+    #[arg(long = "canonical-host", value_name = "[PATTERN ->] HOST", value_parser = HostRedirect::parse)]
+    canonical_hosts: Vec<HostRedirect>,
+    /// Redirect clients asking for a path elsewhere, e.g. "/old-page -> /new-page 301" or
+    /// "/docs -> https://docs.example.com 302" (repeatable, 301 unless a status is given)
+    #[arg(long = "redirect", value_name = "RULE", value_parser = Redirect::parse)]
+    redirects: Vec<Redirect>,
+    /// Rewrite request paths matching a regex before anything else sees them, e.g. "^/v1/(.*)$ -> /api/$1",
+    /// optionally ending with `last` to stop at this rule (repeatable, applied in order)
+    #[arg(long = "rewrite", value_name = "RULE", value_parser = Rewrite::parse)]
+    rewrites: Vec<Rewrite>,
+    /// Answer requests matching the routes in this TOML file with canned responses, e.g. to stub an API in tests
+    #[arg(long, value_name = "FILE")]
+    routes: Option<PathBuf>,
+    /// Serve each JSON file in this directory as a fake API resource, e.g. users.json at /api/users,
+    /// with /api/users/ID picking an element by its id
+    #[arg(long, value_name = "DIR")]
+    mock: Option<PathBuf>,
+    /// Where the --mock resources are served
+    #[arg(long, value_name = "PREFIX", default_value = "/api", requires = "mock")]
+    mock_prefix: String,
+    /// Wait this long before answering each --mock request, e.g. 300ms
+    #[arg(long, value_name = "DURATION", value_parser = units::parse_duration, requires = "mock")]
+    mock_latency: Option<Duration>,
+    /// Serve translations of pages such as page.nl.html by the Accept-Language header, with LANG the language of
+    /// pages without one (e.g. page.html)
+    #[arg(long, value_name = "LANG")]
+    default_language: Option<String>,
+    /// Serve .md files as HTML pages, and index.md or README.md for directories without an index.html
+    #[arg(long)]
+    render_markdown: bool,
+    /// HTML page to render Markdown into, with {{title}} and {{content}} placeholders
+    #[arg(long, value_name = "FILE", requires = "render_markdown")]
+    markdown_template: Option<PathBuf>,
+    /// Compile style.scss or style.sass when style.css is requested but does not exist
+    #[arg(long)]
+    #[cfg_attr(unix, arg(conflicts_with = "sandbox"))]
+    sass: bool,
+    /// The Sass compiler to run for --sass, called with --no-source-map and the source file
+    #[arg(long, value_name = "PROGRAM", default_value = "sass", requires = "sass")]
+    sass_command: String,
+    /// Render .hbs files as Handlebars templates, with the query parameters under `query`
+    #[arg(long)]
+    templates: bool,
+    /// JSON or TOML file whose contents make up the context of --templates
+    #[arg(long, value_name = "FILE", requires = "templates")]
+    template_data: Option<PathBuf>,
+    /// List directories without an index page through this Handlebars template, sortable with
+    /// ?sort=name|size|modified&order=asc|desc
+    #[arg(long, value_name = "FILE")]
+    listing_template: Option<PathBuf>,
+    /// Give images in --listing-template listings a `thumbnail` href, made and cached by --resize-images
+    #[arg(long, requires_all = ["listing_template", "resize_images"])]
+    listing_thumbnails: bool,
+    /// Resize and convert images by their query, e.g. /photo.jpg?w=400&format=webp
+    #[arg(long)]
+    #[cfg_attr(unix, arg(conflicts_with = "sandbox"))]
+    resize_images: bool,
+    /// The ImageMagick program to run for --resize-images
+    #[arg(long, value_name = "PROGRAM", default_value = "magick", requires = "resize_images")]
+    image_command: String,
+    /// Expand Server-Side Include directives such as <!--#include virtual="/header.html" --> in HTML pages
+    #[arg(long)]
+    ssi: bool,
+    /// Let directories be downloaded as a streamed tar.gz with ?download=tar.gz, resumable with Range requests
+    #[arg(long)]
+    archives: bool,
+    /// Serve the files of this .zip, .tar, .tar.gz or .tgz archive instead of a directory, without extracting it
+    #[arg(long = "archive", value_name = "FILE", conflicts_with_all = ["upload", "webdav"])]
+    site_archive: Option<PathBuf>,
+    /// Serve the files of this commit, tag or branch of the git repository the directory is in,
+    /// instead of the working tree
+    #[cfg_attr(
+        feature = "git",
+        arg(long, value_name = "REF", conflicts_with_all = ["upload", "webdav", "site_archive", "stdin"])
+    )]
+    #[cfg_attr(not(feature = "git"), arg(skip))]
+    git_ref: Option<String>,
+    /// Serve the objects of this S3 bucket, or of a prefix in it (BUCKET/PREFIX), instead of a directory,
+    /// signed with the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY credentials when set
+    #[cfg_attr(
+        feature = "s3",
+        arg(long, value_name = "BUCKET[/PREFIX]", conflicts_with_all = ["upload", "webdav", "site_archive", "stdin", "git_ref"])
+    )]
+    #[cfg_attr(not(feature = "s3"), arg(skip))]
+    s3: Option<String>,
+    /// Fetch --s3 objects from this S3-compatible endpoint (e.g. http://localhost:9000) instead of AWS
+    #[cfg_attr(feature = "s3", arg(long, value_name = "URL", requires = "s3"))]
+    #[cfg_attr(not(feature = "s3"), arg(skip))]
+    s3_endpoint: Option<String>,
+    /// The region --s3 requests are signed for, by default AWS_REGION or us-east-1
+    #[cfg_attr(feature = "s3", arg(long, value_name = "REGION", requires = "s3"))]
+    #[cfg_attr(not(feature = "s3"), arg(skip))]
+    s3_region: Option<String>,
+    /// Keep --s3 objects in the file cache for this many seconds before fetching them again
+    #[cfg_attr(feature = "s3", arg(long, value_name = "SECS", default_value_t = 60, requires = "s3"))]
+    #[cfg_attr(not(feature = "s3"), arg(skip))]
+    s3_ttl: u64,
+    /// Run the WebAssembly plugins (*.wasm) in this directory on every request, in file name order
+    #[cfg_attr(feature = "plugins", arg(long, value_name = "DIR"))]
+    #[cfg_attr(not(feature = "plugins"), arg(skip))]
+    plugins: Option<PathBuf>,
+    /// Call the handle(request) function of this Lua script for every request, to answer or rewrite it
+    #[cfg_attr(feature = "lua", arg(long, value_name = "FILE"))]
+    #[cfg_attr(not(feature = "lua"), arg(skip))]
+    lua: Option<PathBuf>,
+    /// Serve what is piped to stdin at /, read once at startup, as text/plain unless --mime TYPE names another
+    #[arg(long, conflicts_with_all = ["upload", "webdav", "site_archive"])]
+    stdin: bool,
+    /// Index text, HTML and Markdown files for full-text search at /_rshttps/search?q=
+    #[arg(long)]
+    search: bool,
+    /// Serve a sitemap.xml of the HTML pages, with their modification times, unless the served directory has one
+    #[arg(long)]
+    sitemap: bool,
+    /// Accept PUT, and multipart POST to a directory, writing files below the served directory,
+    /// and DELETE removing them
+    #[arg(long, visible_alias = "writable")]
+    upload: bool,
+    /// Answer WebDAV requests so the directory can be mounted as a network drive, read-only unless --upload is given
+    #[arg(long)]
+    webdav: bool,
+    /// Largest upload accepted with --upload
+    #[arg(long, value_name = "BYTES", default_value = "100M", value_parser = units::parse_size, requires = "upload")]
+    max_upload_size: u64,
+    /// Serve files with this extension as TYPE instead of the guessed type, e.g. ts=text/typescript (repeatable);
+    /// a bare TYPE is the type of what --stdin serves
+    #[arg(long = "mime", value_name = "EXT=TYPE", value_parser = mime::parse_override)]
+    mime_overrides: Vec<(String, String)>,
+    /// The charset named in the Content-Type of text, JavaScript and JSON files, or "none" to leave it out
+    #[arg(long, value_name = "CHARSET", default_value = "utf-8")]
+    charset: String,
+    /// Send files matching this glob as downloads rather than showing them in the browser, e.g. '*.svg' or
+    /// '/exports/**' (repeatable; patterns without a slash match the file name); ?download=1 does it for any file
+    #[arg(long = "download", value_name = "GLOB")]
+    downloads: Vec<String>,
+    /// Send text, JavaScript, JSON and SVG files of 1 KiB or more brotli- or gzip-compressed to clients accepting
+    /// it, keeping each compressed version in the cache with the file
+    #[arg(long)]
+    compress: bool,
+    /// Answer requests for a missing /robots.txt with one that lets crawlers everywhere (allow) or nowhere (deny)
+    #[arg(long, value_name = "POLICY", value_parser = Robots::parse)]
+    robots: Option<Robots>,
+    /// Answer requests for a missing /favicon.ico with a built-in icon instead of 404
+    #[arg(long)]
+    favicon: bool,
+    /// Run the executable files under this URL path of the served directory as CGI scripts, e.g. /cgi-bin
+    #[arg(long, value_name = "PREFIX")]
+    #[cfg_attr(unix, arg(conflicts_with = "sandbox"))]
+    cgi: Option<String>,
+    /// Hand scripts matching PATTERN to a FastCGI backend such as php-fpm, e.g. *.php=127.0.0.1:9000 or
+    /// *.php=unix:/run/php/php-fpm.sock (repeatable)
+    #[arg(long = "fastcgi", value_name = "PATTERN=ADDRESS", value_parser = FastCgi::parse)]
+    fastcgi: Vec<FastCgi>,
+    /// Forward requests under PREFIX to an upstream server, e.g. /api=http://localhost:4000, with their method,
+    /// headers and body, and stream its answer back (repeatable)
+    #[arg(long = "proxy", value_name = "PREFIX=URL", value_parser = Proxy::parse)]
+    proxies: Vec<Proxy>,
+    /// Pass the client's Authorization and Cookie headers on to --proxy upstreams, which otherwise never see the
+    /// credentials and sessions meant for this server
+    #[arg(long)]
+    proxy_credentials: bool,
+    /// Fetch files missing locally from this mirror before answering 404, like a pull-through cache
+    #[cfg_attr(feature = "fallback", arg(long, value_name = "URL", visible_alias = "origin"))]
+    #[cfg_attr(not(feature = "fallback"), arg(skip))]
+    fallback_origin: Option<String>,
+    /// Keep files fetched from --fallback-origin in the file cache
+    #[cfg_attr(feature = "fallback", arg(long, requires = "fallback_origin"))]
+    #[cfg_attr(not(feature = "fallback"), arg(skip))]
+    fallback_cache: bool,
+    /// Save files fetched from --fallback-origin in this directory, and serve them from there instead of asking
+    /// the mirror again, so they stay available offline
+    #[cfg_attr(feature = "fallback", arg(long, value_name = "DIR", requires = "fallback_origin"))]
+    #[cfg_attr(not(feature = "fallback"), arg(skip))]
+    fallback_store: Option<PathBuf>,
+    /// Cache files from --fallback-origin for at least this many seconds, even if the mirror says less
+    #[cfg_attr(feature = "fallback", arg(long, value_name = "SECS", requires = "fallback_origin"))]
+    #[cfg_attr(not(feature = "fallback"), arg(skip))]
+    fallback_min_ttl: Option<u64>,
+    /// Cache files from --fallback-origin for at most this many seconds, even if the mirror says more
+    #[cfg_attr(feature = "fallback", arg(long, value_name = "SECS", requires = "fallback_origin"))]
+    #[cfg_attr(not(feature = "fallback"), arg(skip))]
+    fallback_max_ttl: Option<u64>,
+    /// Never cache HTML from --fallback-origin, in the file cache or in browsers
+    #[cfg_attr(feature = "fallback", arg(long, requires = "fallback_origin"))]
+    #[cfg_attr(not(feature = "fallback"), arg(skip))]
+    fallback_no_store_html: bool,
+    /// Keep hard-linked snapshots of the served directory here, browsable under /__snapshots/
+    #[cfg_attr(feature = "watch", arg(long, value_name = "DIR"))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    snapshots: Option<PathBuf>,
+    /// Reload pages open in the browser when their files change, or swap in their stylesheets when only CSS changed,
+    /// through a script added to served HTML
+    /// (each open page holds a worker thread while it waits, up to a quarter of --threads)
+    #[cfg_attr(feature = "watch", arg(long))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    live_reload: bool,
+    /// Show this text in a bar across the top of every served HTML page, e.g. "PREVIEW — build abc123",
+    /// so a shared preview isn't taken for production
+    #[arg(long, value_name = "TEXT")]
+    preview_banner: Option<String>,
+    /// Stream every file change as a server-sent event at /_rshttps/events, for build tools and front-end code
+    /// (not with --event-loop or the async runtime, which send whole responses; each stream holds a worker
+    /// thread, up to a quarter of --threads, and further clients get 503)
+    #[cfg_attr(feature = "watch", arg(long))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    change_events: bool,
+    /// POST the URL paths of every batch of changed files to this URL as JSON, e.g. to purge a CDN
+    #[cfg_attr(feature = "webhook", arg(long, value_name = "URL"))]
+    #[cfg_attr(not(feature = "webhook"), arg(skip))]
+    webhook: Option<String>,
+    /// Number of worker threads handling connections (blocking handler threads with the async runtime)
+    #[arg(long, value_name = "N", default_value = "64")]
+    threads: usize,
+    /// Answer connections beyond N in flight, including those waiting for a worker, with an immediate 503
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+    /// Answer connections beyond N in flight from one client address with an immediate 429, so a few
+    /// clients trickling requests in can't take every worker
+    #[arg(long, value_name = "N")]
+    max_connections_per_ip: Option<usize>,
+    /// Pin worker and acceptor threads to these CPUs round-robin, e.g. 0-7 or 0,2,4,6
+    /// (picking the CPUs of one NUMA node keeps the workers' memory on that node)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "LIST", value_parser = affinity::parse_cpus)]
+    cpus: Option<affinity::CpuList>,
+    /// Wait for requests and send responses from one mio event loop, so slow clients don't hold a worker
+    #[cfg(not(feature = "async"))]
+    #[arg(long, conflicts_with_all = ["throttle", "latency"])]
+    event_loop: bool,
+    /// Serve files of at least this size from a memory mapping (e.g. 8M), instead of sendfile or the cache
+    #[cfg(unix)]
+    #[arg(long, value_name = "BYTES", value_parser = units::parse_size)]
+    mmap_threshold: Option<u64>,
+    /// Read every request from disk, without caching anything
+    /// (for files the watcher can't follow, e.g. behind symlinks or on network mounts)
+    #[arg(long, conflicts_with_all = ["preload", "cache_ttl", "shared_cache"])]
+    no_cache: bool,
+    /// Most bytes of file contents, compressed versions included, kept in memory, evicting the least recently served files first
+    #[arg(long, value_name = "BYTES", default_value = "256M", value_parser = units::parse_size)]
+    cache_size: u64,
+    /// Only reload or drop cached files with these extensions when they change (repeatable;
+    /// e.g. html, css, js), instead of every file
+    #[cfg_attr(feature = "watch", arg(long = "watch-ext", value_name = "EXT"))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    watch_extensions: Vec<String>,
+    /// Don't watch directories matching this glob, e.g. node_modules or .git (repeatable);
+    /// a pattern without `/` matches a directory of that name anywhere below a root
+    #[cfg_attr(feature = "watch", arg(long = "watch-ignore", value_name = "GLOB"))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    watch_ignore: Vec<String>,
+    /// Wait until no file has changed for this many milliseconds before reloading the changed files
+    /// in one pass, so a save made of several writes or a whole build is handled once it is complete
+    #[cfg_attr(feature = "watch", arg(long, value_name = "MS", default_value_t = 200))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    watch_debounce: u64,
+    /// Run this shell command when watched files change, e.g. "npm run build", and only reload changed files
+    /// once it succeeds; files it writes while running don't run it again
+    #[cfg_attr(feature = "watch", arg(long, value_name = "COMMAND"))]
+    #[cfg_attr(all(feature = "watch", unix), arg(conflicts_with = "sandbox"))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    exec: Option<String>,
+    /// Run --exec on changes in this directory instead of the served roots (repeatable), e.g. src
+    #[cfg_attr(feature = "watch", arg(long, value_name = "DIR", requires = "exec"))]
+    #[cfg_attr(not(feature = "watch"), arg(skip))]
+    exec_watch: Vec<PathBuf>,
+    /// Read the served files into the cache at startup, as far as --cache-size allows
+    #[arg(long)]
+    preload: bool,
+    /// Check cached files against the disk again once they are this many seconds old,
+    /// for changes the watcher misses (e.g. on NFS or container volumes)
+    #[arg(long, value_name = "SECS")]
+    cache_ttl: Option<u64>,
+    /// Share cached files with other instances through Redis (redis://[:password@]host[:port][/db])
+    #[arg(long, value_name = "URL")]
+    shared_cache: Option<String>,
+    /// Show sizes in binary (KiB, MiB), si (kB, MB) or exact bytes
+    #[arg(long, value_name = "UNITS", default_value = "binary", value_parser = SizeUnits::parse)]
+    size_units: SizeUnits,
+    /// Show times as iso (ISO 8601 in UTC) or local (the server's time zone), in logs and generated pages
+    #[arg(long, value_name = "STYLE", default_value = "iso", value_parser = TimeStyle::parse)]
+    time_style: TimeStyle,
+    /// Don't print the startup summary, and log only warnings and errors
+    #[arg(long, short)]
+    quiet: bool,
+    /// Log more: -v for details of every request, -vv also for the time each request phase takes
+    #[arg(long, short, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Report startup problems as JSON lines on stderr
+    #[arg(long)]
+    json_events: bool,
+    /// Answer /__debug/echo with the request as received, as JSON
+    #[arg(long)]
+    debug_echo: bool,
+    /// Simulate a slow network by sending responses at this rate, e.g. 56kbps, 1Mbps or 200K/s
+    #[arg(long, value_name = "RATE", value_parser = units::parse_rate)]
+    throttle: Option<u64>,
+    /// Simulate network latency by holding back the first byte of every response, e.g. 200ms
+    #[arg(long, value_name = "DURATION", value_parser = units::parse_duration)]
+    latency: Option<Duration>,
+    /// Disrupt this share of responses, e.g. 5%, with a 500, a body cut off halfway or a 10s stall,
+    /// to test how a front-end copes (paths under /_rshttps/ are spared)
+    #[arg(long, value_name = "FRACTION", value_parser = Chaos::parse)]
+    chaos: Option<Chaos>,
+    /// List cached files on GET /__admin/cache and purge them on DELETE (?path=FILE for one),
+    /// subject to access rules and to authentication, even outside the --protect patterns
+    #[arg(long, conflicts_with = "no_cache")]
+    cache_admin: bool,
+    /// Serve Prometheus metrics at /_rshttps/metrics, subject to authentication and access rules like any other path
+    #[arg(long)]
+    metrics: bool,
+    /// List the served files with their sizes and SHA-256/Subresource Integrity hashes at
+    /// /_rshttps/manifest.json, subject to authentication and access rules like any other path
+    #[arg(long)]
+    manifest: bool,
+    /// Keep the last requests with their headers and timings, browsable at /_rshttps/inspect, subject to
+    /// authentication and access rules like any other path
+    #[arg(long)]
+    inspect: bool,
+    /// Mint links to single files that expire after a time window or a number of downloads, by POSTing
+    /// ?path=FILE&expires=SECONDS&downloads=N to /_rshttps/shares, which always requires authentication
+    #[arg(long)]
+    shares: bool,
+    /// Show live request statistics in the terminal, redrawn in place, instead of printing the access log
+    #[arg(long)]
+    tui: bool,
+    /// Bind N sockets per TCP address with SO_REUSEPORT, each with its own acceptor
+    /// (another process of the same user can then join the address instead of failing to bind)
+    #[cfg(unix)]
+    #[arg(long, value_name = "N", default_value = "1")]
+    reuseport: usize,
+    /// Serve from N worker processes sharing the listeners, supervised by this process, which starts a
+    /// worker again when it crashes (SIGUSR2 replaces them one by one)
+    #[cfg(unix)]
+    #[arg(long, value_name = "N", default_value = "1", conflicts_with_all = ["tui", "takeover", "control_socket"])]
+    workers: usize,
+    /// Set SO_REUSEADDR on TCP listeners, to rebind an address that still has connections in TIME_WAIT
+    #[cfg(unix)]
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    reuseaddr: bool,
+    /// Size of the kernel send buffer (SO_SNDBUF) of TCP listeners and their connections, e.g. 256K
+    #[cfg(unix)]
+    #[arg(long, value_name = "BYTES", value_parser = units::parse_size)]
+    send_buffer: Option<u64>,
+    /// Size of the kernel receive buffer (SO_RCVBUF) of TCP listeners and their connections, e.g. 256K
+    #[cfg(unix)]
+    #[arg(long, value_name = "BYTES", value_parser = units::parse_size)]
+    recv_buffer: Option<u64>,
+    /// Listen on a Unix domain socket at this path instead of TCP
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    uds: Option<PathBuf>,
+    /// Also accept local clients on this named pipe, e.g. rshttp for \\.\pipe\rshttp
+    #[cfg(all(windows, not(feature = "async")))]
+    #[arg(long, value_name = "NAME")]
+    pipe: Option<String>,
+    /// Report to the Windows Service Control Manager as this service, set by `service install`
+    #[cfg(windows)]
+    #[arg(long, value_name = "NAME", hide = true)]
+    as_service: Option<String>,
+    /// Fork into the background once the listeners are bound
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "tui")]
+    daemon: bool,
+    /// Where the background server writes its output
+    #[cfg(unix)]
+    #[arg(long, value_name = "FILE", default_value = "rshttp.log")]
+    log_file: PathBuf,
+    /// Write the server's PID to this file, removing it on exit
+    #[cfg(unix)]
+    #[arg(long, value_name = "FILE")]
+    pid_file: Option<PathBuf>,
+    /// Switch to this user once the listeners are bound (e.g. after binding :80 as root)
+    #[cfg(unix)]
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+    /// Switch to this group once the listeners are bound (defaults to the user's primary group)
+    #[cfg(unix)]
+    #[arg(long, value_name = "GROUP")]
+    group: Option<String>,
+    /// Accept takeover requests from a new instance on this Unix socket
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+    /// Take the listeners over from the instance behind this control socket
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    takeover: Option<PathBuf>,
+    /// Restrict the process to reading the served directories (Landlock, or chroot as root)
+    #[cfg(unix)]
+    #[arg(long)]
+    sandbox: bool,
 }
-fn main() -> std::io::Result<()> {
-    let cli = Cli::parse();
-    let address = format!("127.0.0.1:{}", cli.port);
 
-    match std::net::TcpListener::bind(&address) {
-        Ok(listener) => {
-            println!("Serving HTTP on {} ...", address);
-            let current_dir = Arc::new(PathBuf::from(cli.directory));
-            let cache: FileCache = Arc::new(RwLock::new(HashMap::new()));
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Serve the directory, as when no subcommand is given; the options go after it
+    Serve,
+    /// Write a starter index.html, rshttps.toml and .rshttpsignore into a directory
+    Init(init::InitArgs),
+    /// Check a directory for broken links, missing index files, oversized assets and MIME mismatches
+    Verify(verify::VerifyArgs),
+    /// Report files added, removed or changed between two roots
+    Diff(diff::DiffArgs),
+    /// Store the responses to a list of URLs, or compare them with the stored ones to catch regressions
+    Snapshot(golden::SnapshotArgs),
+    /// Print a link granting access to one path until it expires, for a server started with --url-secret
+    Sign(signing::SignArgs),
+    /// Write a copy of this executable that serves a directory's files built into it
+    Bundle(bundle::BundleArgs),
+    /// Serve exactly one file, at / and at its name, and nothing next to it
+    File(singlefile::FileArgs),
+    /// Check the options, a --config file and the files they name, without binding a socket
+    Check,
+    /// Print the completion script for bash, zsh, fish, elvish or PowerShell
+    Completions(completions::CompletionsArgs),
+    /// Serve the directory in this process and load it with requests, reporting throughput and latency
+    Bench(bench::BenchArgs),
+    /// Write the responses kept by --record as a HAR file, for browser devtools and HAR tools
+    ExportHar(recording::ExportHarArgs),
+    /// Install or uninstall rshttp as a Windows service
+    #[cfg(windows)]
+    Service(service::ServiceArgs),
+}
 
-            let cache_clone = Arc::clone(&cache);
-            let current_dir_clone = Arc::clone(&current_dir);
+/// Shared, read-only state used by every connection handler
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+struct Context {
+    roots: Roots,
+    /// The command line before the configuration file was applied, and the file, read again on reload
+    command_line: Vec<OsString>,
+    config_file: Option<config::Config>,
+    security: RwLock<Arc<Security>>,
+    oidc: Option<OidcClient>,
+    url_signer: Option<UrlSigner>,
+    ip_filter: IpFilter,
+    trusted_proxies: TrustedProxies,
+    ignore_rules: Option<IgnoreRules>,
+    access_log: Option<AccessLog>,
+    request_db: Option<RequestDb>,
+    har: Option<Har>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    debug_echo: bool,
+    cache_admin: bool,
+    metrics_endpoint: bool,
+    manifest: Option<Manifest>,
+    inspector: Option<Inspector>,
+    shares: Option<Shares>,
+    routes: Option<Routes>,
+    canonical_hosts: Vec<HostRedirect>,
+    redirects: Vec<Redirect>,
+    rewrites: Vec<Rewrite>,
+    mocks: Option<Mocks>,
+    languages: Option<Languages>,
+    markdown: Option<Markdown>,
+    sass: Option<Sass>,
+    templates: Option<Templates>,
+    listing: Option<Listing>,
+    images: Option<Images>,
+    ssi: bool,
+    archives: bool,
+    site_archive: Option<SiteArchive>,
+    git_tree: Option<GitTree>,
+    bucket: Option<s3::Bucket>,
+    plugins: Option<Plugins>,
+    lua: Option<LuaHooks>,
+    s3_ttl: Duration,
+    single_file: Option<SingleFile>,
+    search: Option<Search>,
+    sitemap: Option<Sitemap>,
+    uploads: Option<Uploads>,
+    webdav: Option<WebDav>,
+    mime_types: MimeTypes,
+    downloads: Vec<String>,
+    compress: bool,
+    robots: Option<Robots>,
+    favicon: bool,
+    cgi: Option<Cgi>,
+    fastcgi: Vec<FastCgi>,
+    proxies: Vec<Proxy>,
+    proxy_credentials: bool,
+    fallback: Option<FallbackOrigin>,
+    fallback_cache: bool,
+    snapshots: Option<Snapshots>,
+    live_reload: Option<LiveReload>,
+    /// Markup of the --preview-banner bar
+    preview_banner: Option<String>,
+    change_events: Option<ChangeEvents>,
+    webhook: Option<Webhook>,
+    #[cfg(unix)]
+    mmap_threshold: Option<u64>,
+    loads: Loads,
+    cache_ttl: Option<Duration>,
+    /// Extensions the watcher is limited to, all when empty
+    watch_extensions: Vec<String>,
+    /// Directories the watcher leaves out
+    watch_ignore: Vec<String>,
+    /// How long the watcher waits for changes to stop before handling them
+    watch_debounce: Duration,
+    /// Build command run before changes are handled
+    exec: Option<String>,
+    /// Directories whose changes run `exec`, the roots when empty
+    exec_watch: Vec<PathBuf>,
+    /// Whether the watcher has registered the roots, so changes are noticed
+    watching: AtomicBool,
+    metrics: Metrics,
+    shutdown: Arc<Shutdown>,
+    max_connections: Option<usize>,
+    peer_connections: Option<Arc<PeerConnections>>,
+    timeouts: Timeouts,
+    /// How long an idle connection waits for another request, `None` to close it after one
+    keep_alive: Option<Duration>,
+    tcp_nodelay: bool,
+    shaping: Shaping,
+    chaos: Option<Chaos>,
+}
 
-            thread::spawn(move || {
-                setup_file_watcher(current_dir_clone, cache_clone);
-            });
+/// Credentials, the paths that need them and the access rules, replaced as a whole on reload
+struct Security {
+    auth_providers: Vec<Box<dyn AuthProvider>>,
+    /// Paths that need authentication, all of them when empty
+    protected: Vec<String>,
+    access_policy: Option<AccessPolicy>,
+}
 
-            for stream in listener.incoming() {
-                let stream = stream?;
-                let current_dir = Arc::clone(&current_dir);
-                let cache = Arc::clone(&cache);
+impl Security {
+    fn new(cli: &Cli, htpasswd: Option<HtpasswdFile>, access_policy: Option<AccessPolicy>) -> Security {
+        let mut auth_providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        for basic in &cli.basic_auth {
+            auth_providers.push(Box::new(basic.clone()));
+        }
+        if let Some(htpasswd) = htpasswd {
+            auth_providers.push(Box::new(htpasswd));
+        }
+        if let Some(secret) = &cli.jwt_secret {
+            auth_providers.push(Box::new(JwtAuth::new(secret)));
+        }
+        Security {
+            auth_providers,
+            protected: cli.protect.clone(),
+            access_policy,
+        }
+    }
 
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, &current_dir, cache) {
-                        if e.kind() != std::io::ErrorKind::BrokenPipe {
-                            eprintln!("Error handling client: {}", e);
-                        }
-                    }                    
-                });
+    /// Reads the files `cli` names, failing where startup would have
+    #[cfg(any(unix, feature = "watch"))]
+    fn load(cli: &Cli) -> Result<Security, String> {
+        let htpasswd = cli.auth_file.as_deref().map(HtpasswdFile::load).transpose();
+        let htpasswd = htpasswd.map_err(|e| format!("invalid auth file: {}", e))?;
+        let access_policy = cli.access_rules.as_deref().map(AccessPolicy::load).transpose();
+        let access_policy = access_policy.map_err(|e| format!("invalid access rules: {}", e))?;
+        let security = Security::new(cli, htpasswd, access_policy);
+        let authenticates = !security.auth_providers.is_empty() || cli.url_secret.is_some() || cli.oidc_issuer.is_some();
+        if (!cli.protect.is_empty() || cli.shares) && !authenticates {
+            return Err("--protect and --shares need a way to authenticate".to_string());
+        }
+        Ok(security)
+    }
+
+    /// Whether requests to `path` need credentials, before access rules are applied
+    fn protects(&self, path: &str) -> bool {
+        self.protected.is_empty() || self.protected.iter().any(|pattern| protects(pattern, path))
+    }
+}
+
+/// Document roots, selected per request by the Host header
+struct Roots {
+    default: PathBuf,
+    vhosts: HashMap<String, PathBuf>,
+    /// Roots that had a volume mounted on them at startup
+    mountpoints: HashSet<PathBuf>,
+}
+
+impl Roots {
+    /// Returns the root for the given Host header value, falling back to the default root
+    fn resolve(&self, host: Option<&str>) -> &Path {
+        host.map(|host| {
+            let name = match host.rsplit_once(':') {
+                Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+                _ => host,
+            };
+            name.to_ascii_lowercase()
+        })
+        .and_then(|name| self.vhosts.get(&name))
+        .unwrap_or(&self.default)
+    }
+
+    /// Remembers which roots are mountpoints, see [`Roots::available`]
+    fn record_mountpoints(&mut self) {
+        for root in self.all() {
+            if is_mountpoint(&root) {
+                self.mountpoints.insert(root);
             }
         }
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::AddrInUse {
-                eprintln!("Error: The address {} is already in use.", address);
-            } else {
-                eprintln!("Failed to bind to address {}: {}", address, e);
+    }
+
+    /// Whether `root` is there to serve from
+    ///
+    /// Unmounting a volume leaves an empty directory behind, so a root that was
+    /// a mountpoint at startup is only available while something is mounted on it.
+    fn available(&self, root: &Path) -> bool {
+        root.is_dir() && (!self.mountpoints.contains(root) || is_mountpoint(root))
+    }
+
+    /// All distinct roots, starting with the default one
+    fn all(&self) -> Vec<PathBuf> {
+        let mut roots = vec![self.default.clone()];
+        for root in self.vhosts.values() {
+            if !roots.contains(root) {
+                roots.push(root.clone());
             }
         }
+        roots
     }
+}
 
-    Ok(())
+/// Parses a `HOST=DIR` virtual host mapping
+fn parse_vhost(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((host, dir)) if !host.is_empty() && !dir.is_empty() => {
+            Ok((host.to_ascii_lowercase(), PathBuf::from(dir)))
+        }
+        _ => Err(format!("expected HOST=DIR, got '{}'", value)),
+    }
 }
 
-/// Set up the file watcher and invalidate the cache on file changes
-fn setup_file_watcher(base_dir: Arc<PathBuf>, cache: FileCache) {
-    let (tx, rx) = channel();
-    let mut watcher = RecommendedWatcher::new(tx, Config::default()).expect("Failed to create watcher");
-    watcher.watch(&*base_dir, RecursiveMode::Recursive).expect("Failed to watch the directory");
+/// Whether `dir` is on a different device than its parent
+#[cfg(unix)]
+fn is_mountpoint(dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
 
-    let mut last_event_time: HashMap<String, Instant> = HashMap::new();
+    let parent = dir.parent().unwrap_or(dir);
+    match (fs::metadata(dir), fs::metadata(parent)) {
+        (Ok(dir), Ok(parent)) => dir.dev() != parent.dev(),
+        _ => false,
+    }
+}
 
-    for event in rx {
-        match event {
-            Ok(Event {
-                kind: EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_),
-                paths,
-                ..
-            }) => {
-                let mut cache_guard = cache.write().unwrap();
-                for path in paths {
-                    if let Some(extension) = path.extension() {
-                        if extension == "html" || extension == "css" || extension == "js"|| extension == "json" {
-                            let relative_path = format!("/{}", path.strip_prefix(&*base_dir).unwrap_or(&path).to_string_lossy());
-                            let now = Instant::now();
-
-                            // Check if we recently processed this file
-                            if let Some(last_time) = last_event_time.get(&relative_path) {
-                                if now.duration_since(*last_time) < Duration::from_millis(200) {
-                                    continue; // Skip this event
-                                }
-                            }
-
-                            // Update the last event time
-                            last_event_time.insert(relative_path.clone(), now);
-
-                            println!("File change detected: {:?}", path);
-                            println!("Removing cache entry: {:?}", relative_path);
-                            cache_guard.remove(&relative_path);
-                        }
-                    }
-                }
+#[cfg(not(unix))]
+fn is_mountpoint(_dir: &Path) -> bool {
+    false
+}
+
+/// Canonicalizes a root so it matches the absolute paths reported by the watcher
+fn canonical_root(dir: PathBuf) -> PathBuf {
+    let canonical = fs::canonicalize(&dir).unwrap_or(dir);
+    // The watcher reports C:\site\x on Windows, not the \\?\C:\site\x that canonicalize() gives
+    match canonical.to_str().and_then(|path| path.strip_prefix(r"\\?\")).filter(|_| cfg!(windows)) {
+        Some(unc) if unc.starts_with(r"UNC\") => PathBuf::from(format!(r"\\{}", &unc[4..])),
+        Some(drive) => PathBuf::from(drive),
+        None => canonical,
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let mut args = compat::translate(std::env::args_os());
+    // `rshttp serve --port 80` is `rshttp --port 80`
+    if args.get(1).is_some_and(|arg| arg == "serve") {
+        args.remove(1);
+    }
+    let command_line = args.clone();
+    let (args, config_file) = config::apply(Cli::command(), args)
+        .unwrap_or_else(|e| Cli::command().error(clap::error::ErrorKind::InvalidValue, e).exit());
+    let cli = Cli::parse_from(args);
+    #[cfg(unix)]
+    let syslog = (cli.log_target == LogTarget::Syslog).then(|| Syslog::connect().map(Arc::new));
+    #[cfg(unix)]
+    match &syslog {
+        Some(Ok(syslog)) => diagnostics::init_syslog(cli.verbose, cli.quiet, Arc::clone(syslog)),
+        _ => diagnostics::init(cli.verbose, cli.quiet),
+    }
+    #[cfg(not(unix))]
+    diagnostics::init(cli.verbose, cli.quiet);
+    units::configure(units::Formats {
+        sizes: cli.size_units,
+        times: cli.time_style,
+    });
+
+    match &cli.command {
+        Some(Command::Verify(args)) => std::process::exit(verify::run(args)),
+        Some(Command::Diff(args)) => std::process::exit(diff::run(args)),
+        Some(Command::Snapshot(args)) => std::process::exit(golden::run(args)),
+        Some(Command::Sign(args)) => std::process::exit(signing::run(args)),
+        Some(Command::Bundle(args)) => std::process::exit(bundle::run(args)),
+        Some(Command::Check) => std::process::exit(check::run(&cli)),
+        Some(Command::Init(args)) => std::process::exit(init::run(args)),
+        Some(Command::Completions(args)) => std::process::exit(completions::run(args)),
+        Some(Command::ExportHar(args)) => std::process::exit(recording::run(args)),
+        #[cfg(windows)]
+        Some(Command::Service(args)) => std::process::exit(service::run(args)),
+        Some(Command::Serve | Command::File(_) | Command::Bench(_)) | None => {}
+    }
+
+    // Termination signals are handled by a dedicated thread, see below
+    #[cfg(unix)]
+    signals::block_handled_signals()?;
+    // Set in the processes started by --workers
+    #[cfg(unix)]
+    let worker = cluster::Worker::current();
+    #[cfg(unix)]
+    let announces = worker.is_none_or(|worker| worker.announces());
+    #[cfg(not(unix))]
+    let announces = true;
+
+    // Everything that can be checked up front is, so all problems are reported together
+    let mut problems = Problems::default();
+    let mut banner = Banner::default();
+    if let Some(path) = &cli.config {
+        banner.feature("Config", path.display().to_string());
+    }
+    #[cfg(unix)]
+    let (takeover, listeners) = match &cli.takeover {
+        Some(path) => match Takeover::request(path) {
+            Ok((takeover, listeners)) => (Some(takeover), listener::prepare(listeners, &mut problems)),
+            Err(e) => {
+                problems.push(
+                    "E205",
+                    format!("cannot take over from {}: {}", path.display(), e),
+                    Some("the running instance needs --control-socket with the same path"),
+                );
+                (None, Vec::new())
+            }
+        },
+        None => (None, listener::bind_all(&cli, &mut problems)),
+    };
+    #[cfg(not(unix))]
+    let listeners = listener::bind_all(&cli, &mut problems);
+    #[cfg(unix)]
+    let control_socket = match &cli.control_socket {
+        Some(path) => match takeover::listen(path) {
+            Ok(control) => Some(control),
+            Err(e) => {
+                problems.push("E206", format!("failed to bind control socket {}: {}", path.display(), e), None);
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(all(windows, not(feature = "async")))]
+    let pipe = match &cli.pipe {
+        Some(name) => match pipe::PipeListener::bind(name) {
+            Ok(pipe) => {
+                banner.feature("Pipe", pipe.name());
+                Some(pipe)
+            }
+            Err(e) => {
+                problems.push("E207", format!("cannot listen on pipe {}: {}", name, e), Some("is another server using it?"));
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut builder = ContextBuilder::load(&cli, &mut problems, &mut banner);
+
+    let rotation = Rotation {
+        max_size: cli.access_log_max_size,
+        interval: cli.access_log_interval.map(Duration::from_secs),
+        keep: cli.access_log_keep,
+    };
+    #[cfg(unix)]
+    let syslog = match syslog {
+        Some(Err(e)) => {
+            problems.push(
+                "E105",
+                format!("cannot reach syslog: {}", e),
+                Some("is a syslog daemon or journald running?"),
+            );
+            None
+        }
+        syslog => syslog.and_then(Result::ok),
+    };
+    let access_log = match AccessLog::open(cli.log_format, cli.access_log.as_deref(), rotation) {
+        #[cfg(unix)]
+        Ok(_) if cli.access_log.is_none() && syslog.is_some() => {
+            syslog.map(|syslog| AccessLog::to_syslog(cli.log_format, syslog))
+        }
+        // The dashboard and the bench report have the terminal to themselves
+        Ok(_) if cli.access_log.is_none() && (cli.tui || matches!(cli.command, Some(Command::Bench(_)))) => None,
+        Ok(access_log) => Some(access_log),
+        Err(e) => {
+            let path = cli.access_log.as_deref().unwrap_or(Path::new("")).display();
+            problems.push("E104", format!("cannot open access log {}: {}", path, e), None);
+            None
+        }
+    };
+
+    let port = listeners.iter().find_map(Listener::tcp_address).map(|address| address.port());
+    let advertiser = match (&cli.mdns, port) {
+        (Some(name), Some(port)) => match mdns::Advertiser::new(name, port) {
+            Ok(advertiser) => {
+                banner.feature("mDNS", format!("http://{}:{}/", advertiser.host(), port));
+                Some(advertiser)
             }
-            Ok(_) => {}
-            Err(e) => eprintln!("Watch error: {:?}", e),
+            Err(e) => {
+                problems.push("E123", e, Some("pass a name like myapp, to be reached at myapp.local"));
+                None
+            }
+        },
+        (Some(_), None) => {
+            problems.push("E123", "--mdns needs a TCP listener to advertise", None);
+            None
         }
+        (None, _) => None,
+    };
+    if cli.tui && !std::io::stdout().is_terminal() {
+        problems.push("E304", "--tui needs a terminal on stdout", Some("run it in a terminal, or drop --tui"));
     }
-}
 
-/// Handles incoming HTTP requests
-fn handle_client(
-    mut stream: std::net::TcpStream,
-    base_dir: &Path,
-    cache: FileCache,
-) -> std::io::Result<()> {
-    let mut buffer = Vec::new(); // Dynamic buffer
-    let mut temp_buffer = [0; 1024];
+    #[cfg(unix)]
+    let account = match Account::lookup(cli.user.as_deref(), cli.group.as_deref()) {
+        Ok(account) => Some(account),
+        Err(e) => {
+            problems.push("E400", format!("cannot drop privileges: {}", e), None);
+            None
+        }
+    };
 
-    loop {
-        let bytes_read = stream.read(&mut temp_buffer)?;
+    #[cfg(target_os = "linux")]
+    let cpus = cli.cpus.clone().map(|list| list.0).unwrap_or_default();
+    #[cfg(not(target_os = "linux"))]
+    let cpus: Vec<usize> = Vec::new();
+    #[cfg(target_os = "linux")]
+    if !cpus.is_empty() {
+        match affinity::available() {
+            Ok(available) => match cpus.iter().find(|cpu| !available.contains(cpu)) {
+                Some(cpu) => problems.push("E405", format!("CPU {} is not available to this process", cpu), None),
+                None => banner.feature("CPUs", format!("threads pinned to {:?}", cpus)),
+            },
+            Err(e) => problems.push("E405", format!("cannot read the CPU affinity: {}", e), None),
+        }
+    }
+
+    problems.exit_if_any(cli.json_events);
 
-        if bytes_read == 0 {
-            break;
+    // Forking here still reports startup problems on the terminal, and no thread has started yet
+    #[cfg(unix)]
+    if cli.daemon && worker.is_none() {
+        if let Err(e) = daemon::daemonize(&cli.log_file) {
+            problems.push("E401", format!("failed to start in the background: {}", e), None);
         }
+    }
+    #[cfg(unix)]
+    let _pid_file = match cli.pid_file.as_ref().filter(|_| worker.is_none()) {
+        Some(path) => match daemon::PidFile::create(path) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                problems.push("E402", format!("failed to write PID file {}: {}", path.display(), e), None);
+                None
+            }
+        },
+        None => None,
+    };
 
-        buffer.extend_from_slice(&temp_buffer[..bytes_read]);
+    // The supervisor only starts the workers, which go through everything again, then runs until they stop
+    #[cfg(unix)]
+    if cli.workers > 1 && worker.is_none() {
+        if matches!(cli.command, Some(Command::Bench(_))) {
+            eprintln!("Error: bench runs a single server process, drop --workers");
+            std::process::exit(2);
+        }
+        let listener_fds: Vec<_> = listeners.iter().map(Listener::raw_fd).collect();
+        let code = cluster::supervise(cli.workers, &listener_fds);
+        drop(_pid_file);
+        std::process::exit(code);
+    }
 
-        // Check for the end of the request
-        if buffer.windows(4).any(|window| window == b"\r\n\r\n") {
-            break;
+    // Both need to happen before the first thread is spawned
+    #[cfg(unix)]
+    {
+        if cli.sandbox {
+            let config_path = config_file.as_ref().map(|config| config.path.as_path());
+            let roots = builder.roots();
+            match sandbox::enter(&roots.default, &sandbox_paths(&cli, roots, config_path)) {
+                Ok(Sandbox::Landlock) => banner.feature("Sandbox", "Landlock"),
+                Ok(Sandbox::Chroot) => {
+                    banner.feature("Sandbox", format!("chroot into {}", roots.default.display()));
+                    roots.default = PathBuf::from("/");
+                }
+                Err(e) => problems.push("E403", format!("failed to enter the sandbox: {}", e), None),
+            }
+        }
+        if let Some(account) = account.filter(|_| cli.user.is_some() || cli.group.is_some()) {
+            match account.switch() {
+                Ok(()) => banner.feature("User", format!("uid {}, gid {}", unsafe { libc::getuid() }, unsafe { libc::getgid() })),
+                Err(e) => problems.push("E404", format!("failed to drop privileges: {}", e), None),
+            }
         }
     }
+    problems.exit_if_any(cli.json_events);
 
-    let request = String::from_utf8_lossy(&buffer);
-    let first_line = request.lines().next().unwrap_or("");
-    let mut parts = first_line.split_whitespace();
-    let method = parts.next().unwrap_or("");
-    let path = parts.next().unwrap_or("/");
+    let (context, cache) = builder.build(command_line, config_file, access_log, &mut banner);
+    let context = Arc::new(context);
+    let (changes_tx, changes_rx) = channel();
+    let changes_tx = context.snapshots.is_some().then_some(changes_tx);
+
+    #[cfg(feature = "watch")]
+    {
+        let watcher_context = Arc::clone(&context);
+        let cache_clone = Arc::clone(&cache);
+        thread::spawn(move || {
+            setup_file_watcher(watcher_context, cache_clone, changes_tx);
+        });
+    }
+    #[cfg(not(feature = "watch"))]
+    drop(changes_tx);
 
-    println!("Method: {}, File requested: {}", method, path);
+    if context.snapshots.is_some() {
+        let context = Arc::clone(&context);
+        thread::spawn(move || {
+            if let Some(snapshots) = &context.snapshots {
+                snapshots.record_changes(changes_rx);
+            }
+        });
+    }
 
-    if method != "GET" {
-        return respond_with_error(&mut stream, 405, "Method Not Allowed");
+    #[cfg(unix)]
+    {
+        let context = Arc::clone(&context);
+        let cache = Arc::clone(&cache);
+        let listener_fds: Vec<_> = listeners.iter().map(Listener::raw_fd).collect();
+        thread::spawn(move || loop {
+            match signals::wait() {
+                Signal::Terminate(name) => {
+                    info!("Received {}, no longer accepting connections", name);
+                    context.shutdown.begin();
+                    break;
+                }
+                Signal::Restart => match restart(&listener_fds) {
+                    Ok(pid) => {
+                        info!("Received SIGUSR2, handed listeners to new process {}", pid);
+                        context.shutdown.begin();
+                        break;
+                    }
+                    Err(e) => error!("Restart failed, continuing to serve: {}", e),
+                },
+                Signal::Reload => reload(&context, "Received SIGHUP"),
+                Signal::Report => match cache.usage() {
+                    Some(usage) => info!("Received SIGUSR1, cache holds {}", usage),
+                    None => info!("Received SIGUSR1, the cache has no memory budget"),
+                },
+            }
+        });
     }
 
-    // Parse the path and query string
-    let (path_without_query, _) = match path.split_once('?') {
-        Some((path, query)) => (path, query),
-        None => (path, ""),
-    };
+    // The service stops like Ctrl-C stops the server elsewhere, with a drain
+    #[cfg(windows)]
+    if let Some(name) = &cli.as_service {
+        let context = Arc::clone(&context);
+        service::start(name, move || context.shutdown.begin());
+    }
 
-    // Map root path "/" to "/index.html"
-    let final_path = if base_dir.join(&path_without_query[1..]).is_dir() {
-        format!("{}/index.html", path_without_query.trim_end_matches('/'))
+    #[cfg(unix)]
+    if let Some(control) = control_socket {
+        let context = Arc::clone(&context);
+        let listener_fds: Vec<_> = listeners.iter().map(Listener::raw_fd).collect();
+        thread::spawn(move || takeover::serve(control, &listener_fds, || context.shutdown.begin()));
+    }
+    #[cfg(not(feature = "async"))]
+    let workers = if cli.event_loop {
+        format!("{} threads behind a mio event loop", cli.threads)
     } else {
-        path_without_query.to_string()
+        format!("{} threads", cli.threads)
+    };
+    #[cfg(feature = "async")]
+    let workers = format!("tokio runtime, up to {} blocking threads", cli.threads);
+    #[cfg(unix)]
+    let workers = match worker {
+        Some(_) => format!("{} processes, each with {}", cli.workers, workers),
+        None => workers,
+    };
+    banner.feature("Workers", workers);
+    if cli.throttle.is_some() || cli.latency.is_some() {
+        let mut network = Vec::new();
+        if let Some(rate) = cli.throttle {
+            network.push(format!("{}/s", units::size(rate)));
+        }
+        if let Some(latency) = cli.latency {
+            network.push(format!("{} ms latency", latency.as_millis()));
+        }
+        banner.feature("Simulated network", network.join(", "));
+    }
+    if let Some(chaos) = &cli.chaos {
+        banner.feature("Chaos", format!("{}% of responses disrupted", chaos.percent()));
+    }
+    match (cli.max_connections, cli.max_connections_per_ip) {
+        (Some(max), Some(per_ip)) => banner.feature("Connections", format!("at most {}, {} per client", max, per_ip)),
+        (Some(max), None) => banner.feature("Connections", format!("at most {}, the rest get 503", max)),
+        (None, Some(per_ip)) => banner.feature("Connections", format!("at most {} per client", per_ip)),
+        (None, None) => {}
+    }
+    if cli.keep_alive > 0 {
+        banner.feature("Keep-alive", format!("idle connections kept for {}s", cli.keep_alive));
+    }
+    let mut sockets = Vec::new();
+    if cli.tcp_nodelay {
+        sockets.push("TCP_NODELAY".to_string());
+    }
+    #[cfg(unix)]
+    {
+        if !cli.reuseaddr {
+            sockets.push("no SO_REUSEADDR".to_string());
+        }
+        if let Some(size) = cli.send_buffer {
+            sockets.push(format!("{} send buffer", units::size(size)));
+        }
+        if let Some(size) = cli.recv_buffer {
+            sockets.push(format!("{} receive buffer", units::size(size)));
+        }
+    }
+    if !sockets.is_empty() {
+        banner.feature("Sockets", sockets.join(", "));
+    }
+    let bench = match &cli.command {
+        Some(Command::Bench(args)) => Some(args.clone()),
+        _ => None,
+    };
+    // Of the --workers, only the first one started announces the server
+    if !cli.quiet && bench.is_none() && announces {
+        if !cli.no_qr {
+            banner.qr_code();
+        }
+        banner.print(&listeners);
+    }
+    if let Some(advertiser) = advertiser.filter(|_| announces) {
+        thread::spawn(move || advertiser.run());
+    }
+    if let Some(url) = browser::local_url(&listeners, "/").filter(|_| cli.copy_url && announces) {
+        clipboard::copy(&url);
+    }
+    if let Some(url) = cli.open.as_deref().filter(|_| announces).and_then(|path| browser::local_url(&listeners, path)) {
+        browser::open(&url);
+    }
+    // The requests wait in the listen backlog until the acceptors below start
+    let bench = match (bench, browser::local_address(&listeners)) {
+        (Some(args), Some(address)) => {
+            let (context, root) = (Arc::clone(&context), PathBuf::from(&cli.directory));
+            Some(thread::spawn(move || {
+                let code = bench::run(&args, address, &root, &context.metrics);
+                context.shutdown.begin();
+                code
+            }))
+        }
+        (Some(_), None) => {
+            eprintln!("Error: bench needs a TCP listener to send requests to");
+            std::process::exit(2);
+        }
+        (None, _) => None,
     };
-    
+    let dashboard = cli.tui.then(|| {
+        let context = Arc::clone(&context);
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || dashboard::run(&context.metrics, &context.shutdown, &*cache))
+    });
+
+    // New connections wait in the listen backlog until the acceptors below start
+    #[cfg(unix)]
+    if let Some(takeover) = takeover {
+        if let Err(e) = takeover.ready() {
+            warn!("Failed to tell the previous instance to drain: {}", e);
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
     {
-        let cache_guard = cache.read().unwrap();
-        if let Some((contents, mime_type)) = cache_guard.get(&final_path) {
-            println!("Serving from cache: {}", final_path);
-            return respond_with_file(&mut stream, contents, mime_type);
+        let pool = ThreadPool::new(cli.threads, cli.threads * QUEUED_PER_WORKER, &cpus);
+        // Waiting for a pipe client can't be interrupted, so the thread ends with the process
+        #[cfg(windows)]
+        if let Some(mut pipe) = pipe {
+            let (context, cache, pool) = (Arc::clone(&context), Arc::clone(&cache), pool.clone());
+            thread::spawn(move || loop {
+                match pipe.accept() {
+                    Ok(connection) if !context.shutdown.is_draining() => dispatch(connection, &context, &cache, &pool),
+                    Ok(_) => break,
+                    Err(e) => {
+                        error!("Failed to accept a pipe client: {}", e);
+                        break;
+                    }
+                }
+            });
+        }
+        if cli.event_loop {
+            // The loop keeps finishing open connections while the drain below waits for them
+            let loop_context = Arc::clone(&context);
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                affinity::pin(&cpus, 0);
+                if let Err(e) = event_loop::run(listeners, Arc::clone(&loop_context), cache, pool) {
+                    error!("Event loop failed: {}", e);
+                    loop_context.shutdown.begin();
+                }
+            });
+            while !context.shutdown.is_draining() {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+        } else {
+            let acceptors: Vec<_> = listeners
+                .into_iter()
+                .enumerate()
+                .map(|(index, listener)| {
+                    let context = Arc::clone(&context);
+                    let cache = Arc::clone(&cache);
+                    let pool = pool.clone();
+                    let cpus = cpus.clone();
+                    thread::spawn(move || {
+                        affinity::pin(&cpus, index);
+                        serve(listener, context, cache, pool)
+                    })
+                })
+                .collect();
+
+            for acceptor in acceptors {
+                acceptor.join().expect("Acceptor thread panicked")?;
+            }
         }
     }
+    // Connections still in flight run on the runtime, so it has to outlive the drain
+    #[cfg(feature = "async")]
+    let _runtime = async_server::run(listeners, Arc::clone(&context), cache, cli.threads, cpus)?;
 
-    let file_path = base_dir.join(&final_path[1..]); // Remove leading '/'
-    
-    if file_path.exists() && file_path.is_file() {
-        let contents = fs::read(&file_path)?;
-        let mime_type = mime_guess::from_path(&file_path).first_or_octet_stream().to_string();
+    // Back on the normal screen before anything else is printed
+    if let Some(dashboard) = dashboard {
+        let _ = dashboard.join();
+    }
+    let drain_timeout = Duration::from_secs(cli.drain_timeout);
+    info!("Waiting up to {}s for in-flight requests ...", cli.drain_timeout);
+    match context.shutdown.drain(drain_timeout) {
+        0 => info!("All connections finished"),
+        remaining => info!("Drain window elapsed, dropping {} connection(s)", remaining),
+    }
+    if let Some(request_db) = &context.request_db {
+        request_db.finish();
+    }
+    if let (Some(har), Some(path)) = (&context.har, &cli.har) {
+        match har.write() {
+            Ok(requests) => info!("Wrote {} request(s) to {}", requests, path.display()),
+            Err(e) => error!("Failed to write HAR file {}: {}", path.display(), e),
+        }
+    }
 
-        let mut cache_guard = cache.write().unwrap();
-        cache_guard.insert(path.to_string(), (contents.clone(), mime_type.clone()));
-        
-        return respond_with_file(&mut stream, &contents, &mime_type);
-    } else {
-        respond_with_error(&mut stream, 404, "Not Found")
+    if let Some(bench) = bench {
+        std::process::exit(bench.join().unwrap_or(1));
+    }
+    let summary = context.metrics.summary();
+    print!("{}", summary);
+    if let Some(path) = &cli.stats_file {
+        if let Err(e) = fs::write(path, &summary) {
+            error!("Failed to write summary to {}: {}", path.display(), e);
+        }
     }
+    #[cfg(windows)]
+    service::stopped();
+
+    Ok(())
 }
 
-/// Sends a file as an HTTP response
-fn respond_with_file(
-    stream: &mut std::net::TcpStream,
-    contents: &[u8],
-    mime_type: &str,
-) -> std::io::Result<()> {
-    let content_type_header = if mime_type == "application/octet-stream" {
-        "".to_string() // No header for unknown MIME types
-    } else {
-        format!("Content-Type: {}\r\n\r\n", mime_type)
+/// Everything outside the served directories that the sandbox has to leave reachable
+#[cfg(unix)]
+fn sandbox_paths(cli: &Cli, roots: &Roots, config_file: Option<&Path>) -> sandbox::Allowed {
+    let mut allowed = sandbox::Allowed {
+        read: roots.all(),
+        write: Vec::new(),
     };
+    // Read again on reload
+    allowed.read.extend(cli.access_rules.iter().chain(&cli.auth_file).cloned().chain(config_file.map(Path::to_path_buf)));
+    allowed.read.extend(cli.routes.iter().chain(&cli.mock).chain(&cli.site_archive).cloned());
+    #[cfg(feature = "git")]
+    allowed.read.extend(cli.git_ref.as_ref().and_then(|_| GitTree::git_dir(&roots.default)));
+    let fetches = cli.oidc_issuer.is_some() || cli.fallback_origin.is_some() || cli.webhook.is_some();
+    if fetches || cli.s3.is_some() {
+        // Name resolution for outgoing requests
+        let resolver_files = ["/etc/hosts", "/etc/resolv.conf", "/etc/nsswitch.conf", "/etc/host.conf", "/etc/gai.conf"];
+        allowed.read.extend(resolver_files.iter().map(PathBuf::from).filter(|path| path.exists()));
+    }
+    let written_dirs = cli.snapshots.iter().chain(&cli.record).chain(&cli.fallback_store);
+    allowed.write.extend(written_dirs.filter_map(|dir| dir.canonicalize().ok()));
+    if cli.upload {
+        allowed.write.extend(roots.all());
+    }
+    // SQLite keeps its journal next to the database
+    allowed.write.extend(cli.request_db.iter().filter_map(|file| {
+        let parent = file.parent().filter(|parent| !parent.as_os_str().is_empty());
+        parent.unwrap_or(Path::new(".")).canonicalize().ok()
+    }));
+    let rotated_log = cli.access_log.as_ref().filter(|_| cli.access_log_max_size.is_some() || cli.access_log_interval.is_some());
+    // The PID file is removed on exit, which takes write access to its directory
+    for file in cli.stats_file.iter().chain(rotated_log).chain(&cli.pid_file) {
+        let parent = file.parent().filter(|parent| !parent.as_os_str().is_empty());
+        allowed.write.extend(parent.unwrap_or(Path::new(".")).canonicalize().ok());
+    }
+    allowed
+}
+
+/// Reads the configuration file, the auth file and the access rules again
+///
+/// New credentials, --protect patterns and access rules take effect for the
+/// requests that come in next; other options need a restart. If anything is
+/// invalid, the current ones are kept.
+#[cfg(any(unix, feature = "watch"))]
+fn reload(context: &Context, cause: &str) {
+    match reload_security(context.config_file.as_ref(), &context.command_line) {
+        Ok(security) => {
+            *context.security.write().unwrap() = Arc::new(security);
+            info!("{}, reloaded credentials and access rules", cause);
+        }
+        Err(e) => warn!("{}, keeping the current credentials and access rules: {}", cause, e),
+    }
+}
 
-    let header = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}",
-        contents.len(),
-        content_type_header
-    );
+/// Credentials and access rules as the configuration file and the command line now give them
+#[cfg(any(unix, feature = "watch"))]
+fn reload_security(config_file: Option<&config::Config>, command_line: &[OsString]) -> Result<Security, String> {
+    let args = match config_file {
+        Some(config) => config.reload().and_then(|config| config.reapply(Cli::command(), command_line.to_vec())),
+        None => Ok(command_line.to_vec()),
+    };
+    let cli = args.and_then(|args| Cli::try_parse_from(args).map_err(|e| e.to_string().trim_end().to_string()))?;
+    Security::load(&cli)
+}
 
-    stream.write_all(header.as_bytes())?;
-    stream.write_all(contents)?;
-    stream.flush()
+/// Re-executes the current binary, handing it the listening sockets
+#[cfg(unix)]
+fn restart(listener_fds: &[i32]) -> std::io::Result<u32> {
+    let fds: Vec<String> = listener_fds.iter().map(|fd| fd.to_string()).collect();
+    for fd in listener_fds {
+        listener::set_inheritable(*fd, true);
+    }
+    let child = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(listener::INHERITED_FDS_ENV, fds.join(","))
+        .spawn();
+    for fd in listener_fds {
+        listener::set_inheritable(*fd, false);
+    }
+    Ok(child?.id())
 }
 
-/// Sends an HTTP error response
-fn respond_with_error(
-    stream: &mut std::net::TcpStream,
-    code: u16,
-    message: &str,
-) -> std::io::Result<()> {
-    let body = format!("<h1>{} {}</h1>", code, message);
-    let header = format!(
-        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n",
-        code, message, body.len()
-    );
-    stream.write_all(header.as_bytes())?;
-    stream.write_all(body.as_bytes())?;
-    stream.flush()
+/// Accepts connections until shutdown begins, handing each one to the worker pool
+#[cfg(not(feature = "async"))]
+fn serve(listener: Listener, context: Arc<Context>, cache: FileCache, pool: ThreadPool) -> std::io::Result<()> {
+    while !context.shutdown.is_draining() {
+        if !listener.poll(ACCEPT_POLL_INTERVAL)? {
+            continue;
+        }
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                if context.tcp_nodelay {
+                    stream.set_nodelay(true)?;
+                }
+                dispatch(stream, &context, &cache, &pool);
+                Ok(())
+            }),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                dispatch(stream, &context, &cache, &pool);
+                Ok(())
+            }),
+        };
+        match accepted {
+            Ok(()) => {}
+            // Another process sharing the socket took the connection first
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Queues one connection for the worker pool
+#[cfg(not(feature = "async"))]
+fn dispatch<S: Connection + Send + 'static>(stream: S, context: &Arc<Context>, cache: &FileCache, pool: &ThreadPool) {
+    let context = Arc::clone(context);
+    let cache = Arc::clone(cache);
+    let admission = match admit(&context, stream.peer_ip()) {
+        Ok(admission) => admission,
+        Err(refusal) => {
+            let mut stream = stream;
+            let _ = refusal.send(&mut stream, false);
+            return;
+        }
+    };
+
+    pool.execute(move || {
+        let _admission = admission;
+        handle_connection(stream, &context, cache);
+    });
+}
+
+/// Handles one connection, recording it in the metrics and surviving handler panics
+fn handle_connection<S: Connection>(stream: S, context: &Context, cache: FileCache) {
+    let checked = stream.peer_ip().filter(|ip| !context.trusted_proxies.trusts(*ip));
+    if let Some(ip) = checked.filter(|ip| !context.ip_filter.permits(*ip)) {
+        debug!("Refused connection from {}", ip);
+        return;
+    }
+    // Buffered responses are paced by whoever writes them to the socket
+    let shaping = if stream.streams() { context.shaping } else { Shaping::default() };
+    let mut stream = Metered::new(Throttled::new(stream, shaping));
+    if context.inspector.is_some() || context.har.is_some() || context.recorder.is_some() {
+        stream.capture_head();
+    }
+    if context.recorder.is_some() {
+        // One byte over, to tell a body that is too large to record
+        stream.capture_body(recording::MAX_BODY + 1);
+    } else if context.har.as_ref().is_some_and(Har::bodies) {
+        stream.capture_body(har::MAX_BODY);
+    }
+    loop {
+        let mut record = RequestRecord::default();
+        let (received, started) = (SystemTime::now(), Instant::now());
+        let peer_ip = stream.peer_ip().map(|ip| ip.to_string()).unwrap_or_default();
+        let _request = info_span!("request", peer = %peer_ip, method = Empty, path = Empty).entered();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle_client(&mut stream, context, Arc::clone(&cache), &mut record)
+        }));
+        let Ok(result) = result else {
+            context.metrics.record_panic();
+            return;
+        };
+        context.metrics.record(&record, stream.status, stream.bytes_sent, started.elapsed());
+        if let Some((shares, token)) = context.shares.as_ref().zip(record.share.as_ref()) {
+            if result.is_err() || !stream.status.is_some_and(|status| (200..300).contains(&status)) {
+                shares.refund(token);
+            }
+        }
+        let entry = accesslog::Entry {
+            record: &record,
+            peer_ip: record.client_ip.or(stream.peer_ip()),
+            received,
+            elapsed: started.elapsed(),
+            status: stream.status,
+            bytes_sent: stream.bytes_sent,
+        };
+        if let Some(access_log) = &context.access_log {
+            access_log.log(&entry);
+        }
+        if let Some(request_db) = &context.request_db {
+            request_db.record(&entry);
+        }
+        let phases = Phases::new(&record, started, stream.first_byte, entry.elapsed);
+        if let Some(inspector) = &context.inspector {
+            inspector.record(&entry, phases, stream.head.as_deref());
+        }
+        if let Some(har) = &context.har {
+            har.record(&entry, phases, stream.head.as_deref(), stream.body.as_deref());
+        }
+        if let Some(recorder) = &context.recorder {
+            recorder.record(&record, stream.head.as_deref(), stream.body.as_deref());
+        }
+        if let Err(e) = &result {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                warn!("Error handling client: {}", e);
+            }
+        }
+        // Buffered connections are answered once, by whoever flushes them
+        let reusable = result.is_ok() && record.keep_alive && !stream.closes() && stream.streams();
+        let Some(idle) = context.keep_alive.filter(|_| reusable && !context.shutdown.is_draining()) else {
+            return;
+        };
+        if !stream.wait_for_request(idle) {
+            return;
+        }
+        stream.next_response();
+    }
+}
+
+/// Reads every file the cache would hold into it, until `budget` bytes are loaded
+///
+/// Returns the number of files and bytes loaded.
+fn preload(context: &Context, cache: &FileCache, budget: u64) -> (usize, u64) {
+    let (mut files, mut bytes) = (0, 0);
+    for root in context.roots.all() {
+        for path in walk::files(&root) {
+            let Some(file) = read_for_cache(context, &path) else {
+                continue;
+            };
+            if bytes + file.contents.len() as u64 > budget {
+                return (files, bytes);
+            }
+            bytes += file.contents.len() as u64;
+            files += 1;
+            cache.insert(path, Arc::new(file));
+        }
+    }
+    (files, bytes)
+}
+
+/// Reads a file into a cache entry, unless it is served without the cache
+/// (see [`STREAM_MIN_SIZE`] and `--mmap-threshold`) or can't be read
+fn read_for_cache(context: &Context, path: &Path) -> Option<CachedFile> {
+    let metadata = fs::metadata(path).ok()?;
+    let len = metadata.len();
+    #[cfg(unix)]
+    if context.mmap_threshold.is_some_and(|threshold| len >= threshold) {
+        return None;
+    }
+    if !metadata.is_file() || len >= STREAM_MIN_SIZE as u64 {
+        return None;
+    }
+    let contents = fs::read(path).ok()?;
+    let mime_type = context.mime_types.of(path);
+    Some(CachedFile::new(contents, mime_type, metadata.modified().ok()))
+}
+
+/// Set up the file watcher and refresh the cache on file changes
+///
+/// Changes are collected until none has come in for `--watch-debounce` (or
+/// for at most [`MAX_CHANGE_BATCH_TIME`]) and then handled in one pass: changed
+/// files that are cached are read again and swapped in, so requests keep
+/// getting the previous version until the new one is ready rather than all
+/// missing at once, and files that are gone or no longer cacheable are dropped.
+/// With --exec, a batch that touches the sources is built first and only
+/// handled, together with what the build wrote, once the command succeeds.
+///
+/// When `changes` is set, every batch of changes is also reported there once (e.g. to take a snapshot).
+/// Pages open with --live-reload are told to reload after every batch, and
+/// --change-events clients get an event per changed file.
+/// Roots that disappear are watched again once they are back, with their
+/// cache entries dropped since anything may have changed in between.
+#[cfg(feature = "watch")]
+fn setup_file_watcher(context: Arc<Context>, cache: FileCache, changes: Option<Sender<()>>) {
+    let (tx, rx) = channel();
+    let mut watcher = RecommendedWatcher::new(tx, Config::default()).expect("Failed to create watcher");
+    let mut missing = HashSet::new();
+    for root in context.roots.all().iter().chain(&context.exec_watch) {
+        watch_root(&mut watcher, root, &context.watch_ignore).expect("Failed to watch the directory");
+    }
+    // Through its directory, as editors save by replacing the file
+    let config_file = context.config_file.as_ref().map(|config| config.path.clone());
+    if let Some(dir) = config_file.as_deref().and_then(Path::parent) {
+        let watched = context.roots.all().iter().chain(&context.exec_watch).any(|root| dir.starts_with(root));
+        if !watched {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                warn!("Failed to watch {}: {:?}", dir.display(), e);
+            }
+        }
+    }
+    context.watching.store(true, Ordering::SeqCst);
+
+    // Changed files of the current batch, and when the batch started and last grew
+    let mut pending: HashSet<PathBuf> = HashSet::new();
+    let mut batch: Option<(Instant, Instant)> = None;
+    // When the oldest change that is still pending came in
+    let mut first_change = SystemTime::now();
+    let mut last_root_check = Instant::now();
+    // With --exec: whether the batch has to be built first, whether it is
+    // collecting what the last build wrote, and whether that build failed
+    let (mut needs_build, mut absorbing, mut build_failed) = (false, false, false);
+    // Whether the configuration file changed in this batch
+    let mut config_changed = false;
+
+    loop {
+        let timeout = match batch {
+            Some((started, last_change)) => context
+                .watch_debounce
+                .saturating_sub(last_change.elapsed())
+                .min(MAX_CHANGE_BATCH_TIME.saturating_sub(started.elapsed()))
+                .min(ROOT_CHECK_INTERVAL),
+            None => ROOT_CHECK_INTERVAL,
+        };
+        let event = match rx.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if last_root_check.elapsed() >= ROOT_CHECK_INTERVAL {
+            last_root_check = Instant::now();
+            for root in context.roots.all() {
+                let available = context.roots.available(&root);
+                if !available && missing.insert(root.clone()) {
+                    warn!("Root {} is gone, answering 503 until it is back", root.display());
+                    let _ = watcher.unwatch(&root);
+                } else if available && missing.contains(&root) {
+                    match watch_root(&mut watcher, &root, &context.watch_ignore) {
+                        Ok(()) => {
+                            info!("Root {} is back", root.display());
+                            missing.remove(&root);
+                            cache.remove_under(&root);
+                        }
+                        Err(e) => error!("Failed to watch {} again: {:?}", root.display(), e),
+                    }
+                }
+            }
+        }
+
+        match event {
+            Some(Ok(Event {
+                kind: kind @ (EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)),
+                paths,
+                ..
+            })) => {
+                if config_file.as_ref().is_some_and(|file| paths.contains(file)) {
+                    config_changed = true;
+                    let now = Instant::now();
+                    batch = Some((batch.map_or(now, |(started, _)| started), now));
+                }
+                let roots = context.roots.all();
+                let watched_dirs: Vec<PathBuf> = roots.iter().chain(&context.exec_watch).cloned().collect();
+                let paths: Vec<PathBuf> =
+                    paths.into_iter().filter(|path| !is_ignored(&watched_dirs, path, &context.watch_ignore)).collect();
+                // Directories created below a root have to be watched one by one when some are left out
+                if matches!(kind, EventKind::Create(_)) && !context.watch_ignore.is_empty() {
+                    for path in paths.iter().filter(|path| path.is_dir()) {
+                        let _ = watch_tree(&mut watcher, &watched_dirs, path, &context.watch_ignore);
+                    }
+                }
+                if !paths.is_empty() {
+                    let now = Instant::now();
+                    batch = Some((batch.map_or(now, |(started, _)| started), now));
+                }
+                for path in paths {
+                    let served = roots.iter().any(|root| path.starts_with(root));
+                    let watched = served
+                        && (context.watch_extensions.is_empty()
+                            || path.extension().is_some_and(|extension| {
+                                context.watch_extensions.iter().any(|ext| extension == ext.as_str())
+                            }));
+                    let source = match context.exec_watch.is_empty() {
+                        true => watched,
+                        false => context.exec_watch.iter().any(|dir| path.starts_with(dir)),
+                    };
+                    if context.exec.is_some() && source && !absorbing {
+                        needs_build = true;
+                    }
+                    if watched {
+                        if pending.is_empty() {
+                            first_change = SystemTime::now();
+                        }
+                        pending.insert(path);
+                    }
+                }
+            }
+            Some(Ok(_)) | None => {}
+            Some(Err(e)) => warn!("Watch error: {:?}", e),
+        }
+
+        let settled = batch.is_some_and(|(started, last_change)| {
+            last_change.elapsed() >= context.watch_debounce || started.elapsed() >= MAX_CHANGE_BATCH_TIME
+        });
+        if !settled {
+            continue;
+        }
+        batch = None;
+        if std::mem::take(&mut config_changed) {
+            reload(&context, "Configuration file changed");
+        }
+        if let Some(command) = &context.exec {
+            if needs_build {
+                needs_build = false;
+                build_failed = !exec::run(command);
+                // What the command wrote is still on its way; it joins this batch without building again
+                absorbing = true;
+                let now = Instant::now();
+                batch = Some((now, now));
+                continue;
+            }
+            absorbing = false;
+            if build_failed {
+                // Kept until a later build succeeds
+                continue;
+            }
+        }
+        let (changed, mut reloaded, mut dropped) = (pending.len(), 0, 0);
+        // Pages swap in changed stylesheets without reloading, Sass sources included
+        let stylesheets_only = pending.iter().all(|path| {
+            let css = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("css"));
+            css || (context.sass.is_some() && Sass::is_source(path))
+        });
+        let mut events = Vec::new();
+        for path in pending.drain() {
+            if let Some(sass) = context.sass.as_ref().filter(|_| Sass::is_source(&path)) {
+                for compiled in sass.take_compiled() {
+                    cache.remove(&compiled);
+                }
+            }
+            let reported = context.change_events.is_some() || context.webhook.is_some();
+            if let Some(url) = reported.then(|| url_path(&context.roots.all(), &path)).flatten() {
+                events.push(match path.exists() {
+                    true => Change::Changed(url),
+                    false => Change::Removed(url),
+                });
+            }
+            if let Some(search) = &context.search {
+                search.update(&path);
+            }
+            if let Some(sitemap) = &context.sitemap {
+                sitemap.update(&path);
+            }
+            if cache.get(&path).is_none() {
+                cache.remove(&path);
+                continue;
+            }
+            match read_for_cache(&context, &path) {
+                Some(file) => {
+                    cache.replace(path, Arc::new(file));
+                    reloaded += 1;
+                }
+                None => {
+                    cache.remove(&path);
+                    dropped += 1;
+                }
+            }
+        }
+        if changed > 0 {
+            info!("{} file(s) changed: reloaded {} and dropped {} cache entries", changed, reloaded, dropped);
+        }
+        if let Some(changes) = &changes {
+            let _ = changes.send(());
+        }
+        if let Some(live_reload) = context.live_reload.as_ref().filter(|_| changed > 0) {
+            live_reload.notify(stylesheets_only);
+        }
+        if let Some(webhook) = &context.webhook {
+            webhook.send(&events, first_change);
+        }
+        if let Some(change_events) = &context.change_events {
+            change_events.publish(events);
+        }
+    }
+}
+
+/// Watches a root and everything below it, except for directories matching `ignore`
+#[cfg(feature = "watch")]
+fn watch_root(watcher: &mut RecommendedWatcher, root: &Path, ignore: &[String]) -> notify::Result<()> {
+    match ignore.is_empty() {
+        true => watcher.watch(root, RecursiveMode::Recursive),
+        false => watch_tree(watcher, &[root.to_path_buf()], root, ignore),
+    }
+}
+
+/// Watches `dir` and the directories below it that aren't ignored, one by one
+#[cfg(feature = "watch")]
+fn watch_tree(watcher: &mut RecommendedWatcher, roots: &[PathBuf], dir: &Path, ignore: &[String]) -> notify::Result<()> {
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) && !is_ignored(roots, &path, ignore) {
+            if let Err(e) = watch_tree(watcher, roots, &path, ignore) {
+                warn!("Failed to watch {}: {:?}", path.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` is, or lies below, a directory matching one of the `--watch-ignore` patterns
+#[cfg(feature = "watch")]
+fn is_ignored(roots: &[PathBuf], path: &Path, ignore: &[String]) -> bool {
+    let Some(relative) = roots.iter().find_map(|root| path.strip_prefix(root).ok()) else {
+        return false;
+    };
+    let segments: Vec<String> = relative.iter().map(|segment| segment.to_string_lossy().into_owned()).collect();
+    ignore.iter().any(|pattern| match pattern.contains('/') {
+        true => (1..=segments.len()).any(|depth| glob::matches(pattern, &segments[..depth].join("/"))),
+        false => segments.iter().any(|segment| glob::matches(pattern, segment)),
+    })
+}
+
+/// The URL path a file below one of the roots is served at
+#[cfg(feature = "watch")]
+fn url_path(roots: &[PathBuf], path: &Path) -> Option<String> {
+    let relative = roots.iter().find_map(|root| path.strip_prefix(root).ok())?;
+    let segments: Vec<String> = relative.iter().map(|segment| segment.to_string_lossy().into_owned()).collect();
+    Some(format!("/{}", segments.join("/")))
+}
+
+/// Handles incoming HTTP requests
+fn handle_client<S: Connection>(
+    mut stream: S,
+    context: &Context,
+    cache: FileCache,
+    record: &mut RequestRecord,
+) -> std::io::Result<()> {
+    stream.set_write_timeout(context.timeouts.write)?;
+    let mut head_stream = Deadline::new(&mut stream, context.timeouts.header);
+    let head = debug_span!("parse").in_scope(|| request::read_head(&mut head_stream));
+    let buffer = match head {
+        Ok(buffer) => buffer,
+        Err(e) if listener::is_timeout(&e) => {
+            debug!("No complete request head within the header timeout");
+            return Response::error(408).header("Connection", "close").send(&mut stream, false);
+        }
+        Err(e) => return Err(e),
+    };
+    stream.set_read_timeout(context.timeouts.body)?;
+    record.head_read = Some(Instant::now());
+
+    if buffer.is_empty() {
+        return Ok(()); // The client left without sending a request
+    }
+    let request = String::from_utf8_lossy(&buffer);
+    let parsed = match request::parse(&request) {
+        Ok(parsed) => parsed,
+        Err(malformed) => {
+            debug!("Rejected a malformed request: {}", malformed.reason);
+            record.request_line = request.lines().next().unwrap_or("").chars().take(200).collect();
+            return Response::error(malformed.status).header("Connection", "close").send(&mut stream, false);
+        }
+    };
+    let (method, path, headers) = (parsed.method, parsed.target, &parsed.headers);
+    // Another request may follow on the connection, once nothing of this one is left unread
+    let closing = |value: &str| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("close"));
+    record.keep_alive = parsed.version == "HTTP/1.1"
+        && !headers.get_all("Connection").any(closing)
+        && headers.get("Transfer-Encoding").is_none()
+        && headers.get("Content-Length").is_none_or(|len| len.trim() == "0")
+        && buffer.ends_with(b"\r\n\r\n");
+
+    let host = headers.get("Host");
+    let base_dir = context.roots.resolve(host);
+
+    Span::current().record("method", method).record("path", path);
+    record.method = method.to_string();
+    record.request_line = request.lines().next().unwrap_or("").to_string();
+    record.referer = headers.get("Referer").map(str::to_string);
+    record.user_agent = headers.get("User-Agent").map(str::to_string);
+    if context.inspector.is_some() || context.har.is_some() {
+        record.request_head = Some(request.to_string());
+    }
+
+    // Behind a --trusted-proxy, the client's address is the one the proxies report
+    let peer_ip = match stream.peer_ip() {
+        Some(peer) if context.trusted_proxies.trusts(peer) => {
+            let client = context.trusted_proxies.client(peer, headers);
+            record.client_ip = Some(client);
+            Some(client)
+        }
+        peer => peer,
+    };
+    // The proxy itself was let through without these checks, which apply to the client instead
+    let _client = match record.client_ip {
+        Some(client) if !context.ip_filter.permits(client) => {
+            debug!("Refused request from {} through a trusted proxy", client);
+            return Response::error(403).send(&mut stream, method == "HEAD");
+        }
+        Some(client) => match context.peer_connections.as_ref().map(|peers| peers.track(client)) {
+            Some(None) => {
+                debug!("Refused request from {}, which has too many open", client);
+                return Response::error(429).header("Retry-After", "1").send(&mut stream, method == "HEAD");
+            }
+            tracked => tracked.flatten(),
+        },
+        None => None,
+    };
+
+    // Parse the path and query string
+    let (path_without_query, query) = match path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path, ""),
+    };
+    // Everything below, from access rules to the file served, sees the same path
+    let Some(normalized) = request_path(path_without_query) else {
+        debug!("Rejected a path with control characters");
+        record.path = path_without_query.to_string();
+        return Response::error(400).send(&mut stream, method == "HEAD");
+    };
+    if cfg!(windows) && request::unsafe_on_windows(&normalized) {
+        debug!("Rejected {}, which Windows would not read as a file below the root", normalized);
+        record.path = normalized;
+        return Response::error(400).send(&mut stream, method == "HEAD");
+    }
+    // Plugins see the request first, and may answer it, rewrite it or change its response's head
+    let mut plugged = (context.plugins.as_ref())
+        .map(|plugins| (plugins, plugins.request(method, &normalized, query, headers)));
+    if let Some((plugins, request)) = &mut plugged {
+        if let Some(response) = plugins.on_request(request) {
+            record.path = normalized.clone();
+            return response.send(&mut stream, method == "HEAD");
+        }
+    }
+    let (normalized, query) = match &plugged {
+        Some((_, request)) => (request.path().to_string(), request.query()),
+        None => (normalized, query),
+    };
+    let filter = (plugged.as_ref().filter(|(plugins, request)| plugins.changes_response(request)))
+        .map(|(plugins, request)| Box::new(move |head: &str| plugins.on_response(request, head)) as Box<_>);
+    let mut stream = HeadFilter::new(stream, filter);
+    let scripted = context.lua.as_ref().map(|lua| lua.decide(method, &normalized, query, peer_ip, headers));
+    let (normalized, query) = match scripted {
+        Some(lua::Decision::Respond(response)) => {
+            record.path = normalized.clone();
+            return response.send(&mut stream, method == "HEAD");
+        }
+        Some(lua::Decision::Rewrite { path, query }) => {
+            debug!("The Lua script rewrote {} to {}", normalized, path);
+            (path, Cow::Owned(query))
+        }
+        Some(lua::Decision::Continue) | None => (normalized, Cow::Borrowed(query)),
+    };
+    let query = query.as_ref();
+    // Probes are left alone, as orchestrators address them by IP
+    let probe = normalized == HEALTHZ_PATH || normalized == READYZ_PATH;
+    let canonical = host.filter(|_| !probe).map(|host| (HostRedirect::find(&context.canonical_hosts, host), host));
+    if let Some((Some(redirect), host)) = canonical {
+        record.path = normalized.clone();
+        return redirect.response(host, &request::encode_path(&normalized), query).send(&mut stream, method == "HEAD");
+    }
+    if let Some(redirect) = Redirect::find(&context.redirects, &normalized) {
+        record.path = normalized.clone();
+        return redirect.response(query).send(&mut stream, method == "HEAD");
+    }
+    let rewritten = rewrite::apply(&context.rewrites, &normalized, query);
+    if let Some((path, _)) = &rewritten {
+        debug!("Rewrote {} to {}", normalized, path);
+    }
+    let (path_without_query, query) = match &rewritten {
+        Some((path, query)) => (path.as_str(), query.as_str()),
+        None => (normalized.as_str(), query),
+    };
+    record.path = path_without_query.to_string();
+
+    let spared = path_without_query.starts_with(chaos::SPARED_PREFIX);
+    if let Some(fault) = context.chaos.filter(|_| !spared).and_then(|chaos| chaos.roll()) {
+        info!("Injecting a {} into {} {}", fault, method, path_without_query);
+        match fault {
+            Fault::Error => return Response::error(500).send(&mut stream, method == "HEAD"),
+            Fault::Truncate => stream.cut_short(),
+            Fault::Stall => thread::sleep(chaos::STALL),
+        }
+    }
+
+    // Stub routes answer any method, before authentication and access rules
+    if let Some(routes) = &context.routes {
+        match routes.find(method, path_without_query, headers) {
+            Matched::Route(route) => {
+                routes::discard_body(&mut stream, &buffer, headers)?;
+                return route.respond().send(&mut stream, method == "HEAD");
+            }
+            Matched::Rejected(failures) => {
+                warn!("Route expectations not met for {} {}: {}", method, path_without_query, failures.join("; "));
+                routes::discard_body(&mut stream, &buffer, headers)?;
+                return Response::new(400)
+                    .header("Content-Type", "text/plain")
+                    .body(format!("Route expectations not met:\n{}\n", failures.join("\n")).into_bytes())
+                    .send(&mut stream, method == "HEAD");
+            }
+            Matched::NoRoute => {}
+        }
+    }
+
+    // Proxied paths and scripts take any method, the upstream or the script decides what it answers
+    let proxy = context.proxies.iter().find(|proxy| proxy.matches(path_without_query));
+    let cgi = context.cgi.as_ref().filter(|cgi| cgi.matches(path_without_query));
+    let fastcgi = (context.fastcgi.iter())
+        .find_map(|fastcgi| fastcgi.find_script(base_dir, path_without_query).map(|script| (fastcgi, script)));
+    let cache_admin = context.cache_admin && path_without_query == CACHE_ADMIN_PATH;
+    let shares_admin = context.shares.is_some() && path_without_query == shares::PATH;
+    let admin_method = match method {
+        "DELETE" => cache_admin || shares_admin,
+        "POST" => shares_admin,
+        _ => false,
+    };
+    let upload_method = context.uploads.is_some() && matches!(method, "PUT" | "POST" | "DELETE");
+    let upload_page = context.uploads.is_some() && path_without_query == upload::PAGE_PATH;
+    let dav_method = context.webdav.is_some() && webdav::METHODS.contains(&method);
+    let dav_write = dav_method && webdav::WRITES.contains(&method);
+    let other_method = !admin_method && !upload_method && !dav_method;
+    if method != "GET" && method != "HEAD" && other_method && proxy.is_none() && cgi.is_none() && fastcgi.is_none() {
+        let allow = match (&context.webdav, &context.uploads) {
+            (Some(webdav), _) => webdav.allow(),
+            (None, Some(_)) => "GET, HEAD, PUT, POST, DELETE",
+            (None, None) => "GET, HEAD",
+        };
+        return Response::error(405).header("Allow", allow).send(&mut stream, false);
+    }
+    let head_only = method == "HEAD";
+
+    // Probes answer before authentication, as orchestrators send no credentials
+    if path_without_query == HEALTHZ_PATH {
+        return probe_response(true, "ok").send(&mut stream, head_only);
+    }
+    if path_without_query == READYZ_PATH {
+        let response = match (context.shutdown.is_draining(), context.watching.load(Ordering::SeqCst)) {
+            (true, _) => probe_response(false, "draining"),
+            (false, false) => probe_response(false, "starting"),
+            (false, true) => probe_response(true, "ready"),
+        };
+        return response.send(&mut stream, head_only);
+    }
+
+    let handling = Handling {
+        context,
+        cache: &cache,
+        parsed: &parsed,
+        head: &buffer,
+        method,
+        path: path_without_query,
+        query,
+        host,
+        base_dir,
+        peer_ip,
+        head_only,
+        shared: false,
+        proxy,
+        cgi,
+        fastcgi: fastcgi.as_ref().map(|(fastcgi, script)| (*fastcgi, script)),
+        cache_admin,
+        shares_admin,
+        upload_method,
+        upload_page,
+        dav_method,
+        dav_write,
+    };
+    let (access, shared) = match authorize(&handling, record) {
+        Ok(authorized) => authorized,
+        Err(response) => return response.send(&mut stream, head_only),
+    };
+    let handling = match &shared {
+        Some(path) => Handling { path, shared: true, ..handling },
+        None => handling,
+    };
+    if let Some(forwarded) = forward(&mut stream, &handling, &access) {
+        return forwarded;
+    }
+    if let Some(answered) = serve_endpoint(&mut stream, &handling, &access) {
+        return answered;
+    }
+    if let Some(served) = serve_source(&mut stream, &handling, &access, record) {
+        return served;
+    }
+    serve_file(&mut stream, &handling, &access, record)
+}
+
+/// A request on its way through the stages of [`handle_client`], once its path is settled
+#[derive(Clone, Copy)]
+struct Handling<'r> {
+    context: &'r Context,
+    cache: &'r FileCache,
+    parsed: &'r request::Request<'r>,
+    /// Everything read from the client so far
+    head: &'r [u8],
+    method: &'r str,
+    /// Decoded, normalized and rewritten, or the shared file's for a share link
+    path: &'r str,
+    query: &'r str,
+    host: Option<&'r str>,
+    base_dir: &'r Path,
+    /// The client's address, as reported by a --trusted-proxy if it came through one
+    peer_ip: Option<IpAddr>,
+    head_only: bool,
+    /// Requested through a share link, which serves its file whatever else would answer for the path
+    shared: bool,
+    proxy: Option<&'r Proxy>,
+    cgi: Option<&'r Cgi>,
+    fastcgi: Option<(&'r FastCgi, &'r Script)>,
+    cache_admin: bool,
+    shares_admin: bool,
+    upload_method: bool,
+    upload_page: bool,
+    dav_method: bool,
+    dav_write: bool,
+}
+
+impl<'r> Handling<'r> {
+    fn headers(&self) -> &'r request::HeaderMap<'r> {
+        &self.parsed.headers
+    }
+
+    /// The request as CGI and FastCGI scripts, uploads and WebDAV see it
+    fn invocation<'a>(&'a self, access: &'a Access) -> Invocation<'a> {
+        Invocation {
+            method: self.method,
+            path: self.path,
+            query: self.query,
+            headers: self.headers(),
+            head: self.head,
+            peer_ip: self.peer_ip,
+            user: access.principal.as_ref().map(|principal| principal.name.as_str()),
+            root: self.base_dir,
+        }
+    }
+}
+
+/// Who a request comes from and what the access rules let it through to, once [`authorize`] let it in
+struct Access<'r> {
+    context: &'r Context,
+    /// Held for the whole request, so a reload in between can't mix old and new rules
+    security: Arc<Security>,
+    principal: Option<Principal>,
+    peer_ip: Option<IpAddr>,
+    /// Let in by a signed or share link, whatever the rules say of its path
+    signed: bool,
+}
+
+impl Access<'_> {
+    /// Whether a request for another path would be let through, for paths a response reads or writes besides its own
+    fn permitted(&self, method: &str, path: &str) -> bool {
+        let decision = match &self.security.access_policy {
+            Some(policy) => policy.evaluate(&AccessRequest {
+                method,
+                path,
+                ip: self.peer_ip,
+                principal: self.principal.as_ref(),
+            }),
+            None => Decision::NoMatch,
+        };
+        let writes = method != "GET" && method != "HEAD";
+        let protected = writes || self.security.protects(path);
+        let auth_required = (self.authenticates() || self.context.url_signer.is_some()) && protected;
+        match decision {
+            Decision::Allow => true,
+            Decision::NoMatch => self.principal.is_some() || !auth_required,
+            Decision::Deny | Decision::Unauthenticated => false,
+        }
+    }
+
+    fn readable(&self, path: &str) -> bool {
+        self.permitted("GET", path)
+    }
+
+    /// Whether a path may show up in generated indexes of the files
+    fn listed(&self, path: &str) -> bool {
+        self.readable(path)
+            && !ignore::is_reserved(path)
+            && !self.context.ignore_rules.as_ref().is_some_and(|rules| rules.is_ignored(path))
+    }
+
+    /// Whether there are credentials to ask the client for
+    fn authenticates(&self) -> bool {
+        !self.security.auth_providers.is_empty() || self.context.oidc.is_some()
+    }
+}
+
+/// Authenticates a request and holds it to the access rules, along with the file its share link serves
+///
+/// The error is the response to answer with instead, e.g. a 401 or the OIDC provider's login page.
+fn authorize<'r>(
+    handling: &Handling<'r>,
+    record: &mut RequestRecord,
+) -> Result<(Access<'r>, Option<String>), Response<'static>> {
+    let Handling { context, method, path, query, head_only, .. } = *handling;
+    let headers = handling.headers();
+    let cookies = headers.get("Cookie");
+    if let Some(oidc) = &context.oidc {
+        if path == oidc::CALLBACK_PATH {
+            return Err(oidc_response(oidc.login(path, query, cookies)));
+        }
+    }
+
+    let security = Arc::clone(&context.security.read().unwrap());
+    let principal = auth::authenticate(&security.auth_providers, headers.get("Authorization"))
+        .or_else(|| context.oidc.as_ref().and_then(|oidc| oidc.session_principal(cookies)));
+    record.user = principal.as_ref().map(|principal| principal.name.clone());
+
+    // A share link serves its file as if it had been requested, without credentials
+    let share = context.shares.as_ref().zip(path.strip_prefix(shares::LINK_PREFIX));
+    let shared = match share {
+        Some((shares, token)) => match shares.redeem(token) {
+            Redeemed::Path(path) => Some(path),
+            Redeemed::Gone => return Err(share_gone()),
+            Redeemed::Unknown => return Err(Response::error(404)),
+        },
+        None => None,
+    };
+    let path = shared.as_deref().unwrap_or(path);
+
+    let signed = match context.url_signer.as_ref().map(|signer| signer.check(path, query)) {
+        _ if shared.is_some() => true,
+        Some(Signature::Valid) => true,
+        Some(Signature::Expired) => {
+            return Err(Response::new(403)
+                .header("Content-Type", "text/plain")
+                .body(b"This link has expired\n".to_vec()));
+        }
+        Some(Signature::Invalid) => return Err(Response::error(403)),
+        Some(Signature::Missing) | None => false,
+    };
+
+    let access = Access {
+        context,
+        security,
+        principal,
+        peer_ip: handling.peer_ip,
+        signed,
+    };
+    let decision = match &access.security.access_policy {
+        Some(policy) => policy.evaluate(&AccessRequest {
+            method,
+            path,
+            ip: access.peer_ip,
+            principal: access.principal.as_ref(),
+        }),
+        None => Decision::NoMatch,
+    };
+    let Handling { shares_admin, cache_admin, upload_method, dav_write, upload_page, .. } = *handling;
+    let protected =
+        shares_admin || cache_admin || upload_method || dav_write || upload_page || access.security.protects(path);
+    let authenticates = access.authenticates();
+    let auth_required = (authenticates || context.url_signer.is_some()) && protected;
+    match decision {
+        Decision::Allow => {}
+        Decision::NoMatch if access.principal.is_some() || signed || !auth_required => {}
+        Decision::Unauthenticated if signed => {}
+        Decision::Deny => return Err(Response::error(403)),
+        Decision::Unauthenticated | Decision::NoMatch => match &context.oidc {
+            Some(oidc) => return Err(oidc_response(oidc.login(path, query, cookies))),
+            // Only a signed link lets the client in, there are no credentials to ask for
+            None if !authenticates => return Err(Response::error(403)),
+            None => return Err(Response::error(401).header("WWW-Authenticate", "Basic realm=\"rshttp\"")),
+        },
+    }
+
+    // A download of a share link counts once the request is let through, and is given back unless the file is served
+    if let Some((shares, token)) = share.filter(|_| !head_only) {
+        if !shares.spend(token) {
+            return Err(share_gone());
+        }
+        record.share = Some(token.to_string());
+    }
+    Ok((access, shared))
+}
+
+/// Answers a request from the --replay recording, or hands it to the --proxy upstream, CGI script or
+/// FastCGI backend that takes its path
+fn forward<S: Connection>(stream: &mut S, handling: &Handling, access: &Access) -> Option<std::io::Result<()>> {
+    let Handling { context, method, path, query, head_only, .. } = *handling;
+    // Recorded responses are held to the same rules as the files they came from
+    if let Some(replay) = &context.replay {
+        let target = handling.parsed.target;
+        return Some(match replay.response(method, target) {
+            Some(response) => stream.write_all(response).and_then(|()| stream.flush()),
+            None => Response::new(404)
+                .header("Content-Type", "text/plain")
+                .body(format!("Not recorded: {} {}\n", method, target).into_bytes())
+                .send(stream, head_only),
+        });
+    }
+    if handling.shared {
+        return None;
+    }
+    if let Some(proxy) = handling.proxy {
+        let forwarded = Forwarded {
+            method,
+            path,
+            query,
+            headers: handling.headers(),
+            head: handling.head,
+            // The upstream sees the chain of proxies up to this server
+            peer_ip: stream.peer_ip(),
+            peer_trusted: stream.peer_ip().is_some_and(|ip| context.trusted_proxies.trusts(ip)),
+            credentials: context.proxy_credentials,
+        };
+        return Some(proxy.forward(stream, forwarded, &context.shutdown));
+    }
+    if let Some(cgi) = handling.cgi {
+        return Some(cgi.run(stream, handling.invocation(access)));
+    }
+    let (fastcgi, script) = handling.fastcgi?;
+    Some(fastcgi.run(stream, handling.invocation(access), script))
+}
+
+/// Answers a request for one of the built-in endpoints, e.g. the metrics or a snapshot
+fn serve_endpoint<S: Connection>(stream: &mut S, handling: &Handling, access: &Access) -> Option<std::io::Result<()>> {
+    let Handling { context, cache, method, path, query, host, base_dir, head_only, .. } = *handling;
+    if let Some(response) = context.mocks.as_ref().and_then(|mocks| mocks.respond(path)) {
+        return Some(response.send(stream, head_only));
+    }
+    if context.debug_echo && path == DEBUG_ECHO_PATH {
+        return Some(echo_response(handling.parsed, handling.peer_ip).send(stream, head_only));
+    }
+    if handling.cache_admin {
+        return Some(cache_admin_response(method, query, &context.roots, &**cache).send(stream, head_only));
+    }
+    if let Some(shares) = context.shares.as_ref().filter(|_| handling.shares_admin) {
+        let readable = |path: &str| access.readable(path);
+        let response = shares_response(method, query, host, base_dir, shares, &readable);
+        let discarded = routes::discard_body(stream, handling.head, handling.headers());
+        return Some(discarded.and_then(|()| response.send(stream, head_only)));
+    }
+    if let Some(inspector) = context.inspector.as_ref().filter(|_| path == inspector::PATH) {
+        let response = Response::new(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body(inspector.page().into_bytes());
+        return Some(response.send(stream, head_only));
+    }
+    if let Some(uploads) = context.uploads.as_ref().filter(|_| handling.upload_page && !handling.upload_method) {
+        let response = Response::new(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body(uploads.page().into_bytes());
+        return Some(response.send(stream, head_only));
+    }
+    if context.metrics_endpoint && path == metrics::PATH {
+        let body = context.metrics.prometheus(context.shutdown.active(), cache.usage());
+        let response = Response::new(200)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .header("Cache-Control", "no-store")
+            .body(body.into_bytes());
+        return Some(response.send(stream, head_only));
+    }
+    if let Some(live_reload) = context.live_reload.as_ref().filter(|_| path == livereload::PATH) {
+        let since = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("since="))
+            .and_then(|since| since.parse().ok())
+            .unwrap_or(0);
+        return Some(match live_reload.wait(since, &context.shutdown) {
+            Some(answer) => Response::new(200)
+                .header("Content-Type", "text/plain")
+                .header("Cache-Control", "no-store")
+                .body(answer.into_bytes())
+                .send(stream, head_only),
+            None => Response::error(503).header("Retry-After", "1").send(stream, head_only),
+        });
+    }
+    if let Some(change_events) = context.change_events.as_ref().filter(|_| path == events::PATH) {
+        return Some(match (stream.streams(), head_only) {
+            (false, _) => Response::error(501).send(stream, head_only),
+            (true, true) => Response::new(200).header("Content-Type", "text/event-stream").send(stream, true),
+            (true, false) => change_events.stream(stream, &context.shutdown),
+        });
+    }
+
+    let snapshots = context.snapshots.as_ref()?;
+    match path.strip_prefix(snapshots::PREFIX) {
+        Some("" | "/") => {
+            let response = Response::new(200)
+                .header("Content-Type", "text/html")
+                .body(snapshots.index_page().into_bytes());
+            Some(response.send(stream, head_only))
+        }
+        Some(rest) if rest.starts_with('/') => {
+            let file = snapshots.resolve(&rest[1..]);
+            // The path the file had in the served directory, which the rules apply to as if it was requested
+            let inner = format!("/{}", rest[1..].split_once('/').map_or("", |(_, inner)| inner));
+            let served = match file.as_ref().is_some_and(|file| file.ends_with("index.html")) {
+                true if !inner.ends_with("/index.html") => format!("{}/index.html", inner.trim_end_matches('/')),
+                _ => inner.clone(),
+            };
+            if !access.listed(&inner) || !access.listed(&served) {
+                let status = if access.readable(&inner) && access.readable(&served) { 404 } else { 403 };
+                return Some(Response::error(status).send(stream, head_only));
+            }
+            Some(match file.and_then(|file| fs::read(&file).ok().map(|contents| (file, contents))) {
+                Some((file, contents)) => {
+                    let mime_type = context.mime_types.of(&file);
+                    Response::file(&contents, &mime_type, ranges(handling).0).send(stream, head_only)
+                }
+                None => Response::error(404).send(stream, head_only),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The Range a response that isn't a file honors, along with the request's Range and If-Range
+fn ranges<'r>(handling: &Handling<'r>) -> (Option<&'r str>, Option<&'r str>, Option<&'r str>) {
+    let (requested_range, if_range) = (handling.headers().get("Range"), handling.headers().get("If-Range"));
+    // Only files have validators for If-Range to match, so other responses ignore the Range it comes with
+    (requested_range.filter(|_| if_range.is_none()), requested_range, if_range)
+}
+
+/// Answers a request from a source other than the files below the root, or writes the upload or WebDAV
+/// change it makes to them
+fn serve_source<S: Connection>(
+    stream: &mut S,
+    handling: &Handling,
+    access: &Access,
+    record: &mut RequestRecord,
+) -> Option<std::io::Result<()>> {
+    let Handling { context, cache, path, query, host, base_dir, head_only, .. } = *handling;
+    let (range, requested_range, if_range) = ranges(handling);
+    if !context.roots.available(base_dir) {
+        return Some(root_unavailable().send(stream, head_only));
+    }
+    if ignore::is_reserved(path) {
+        return Some(Response::error(404).send(stream, head_only));
+    }
+    if let Some(uploads) = context.uploads.as_ref().filter(|_| handling.upload_method && !handling.shared) {
+        let written = uploads.handle(stream, &handling.invocation(access));
+        for path in &written.changed {
+            cache.remove_under(path);
+        }
+        return Some(written.response.send(stream, false));
+    }
+
+    if let Some(site) = &context.site_archive {
+        return Some(site.respond(path, &context.mime_types, range).send(stream, head_only));
+    }
+    if let Some(git_tree) = &context.git_tree {
+        return Some(git_tree.respond(path, &context.mime_types, range).send(stream, head_only));
+    }
+    if let Some(bucket) = &context.bucket {
+        return Some(send_object(stream, context, cache, bucket, path, range, head_only, record));
+    }
+    if let Some(single_file) = &context.single_file {
+        return Some(single_file.respond(path, &context.mime_types, range).send(stream, head_only));
+    }
+    let readable = |path: &str| access.readable(path);
+    let listed = |path: &str| access.listed(path);
+    if let Some(search) = context.search.as_ref().filter(|_| path == search::PATH) {
+        return Some(search.respond(base_dir, query, &readable).send(stream, head_only));
+    }
+    let sitemap = context.sitemap.as_ref().filter(|_| path == sitemap::PATH);
+    if let Some(sitemap) = sitemap.filter(|_| !resolve::file_path(base_dir, sitemap::PATH).is_file()) {
+        return Some(sitemap.respond(base_dir, host, &listed).send(stream, head_only));
+    }
+    if let Some(manifest) = context.manifest.as_ref().filter(|_| path == manifest::PATH) {
+        let response = debug_span!("hash").in_scope(|| manifest.respond(base_dir, &listed));
+        return Some(response.send(stream, head_only));
+    }
+
+    if context.ignore_rules.as_ref().is_some_and(|rules| rules.is_ignored(path)) {
+        return Some(Response::error(404).send(stream, head_only));
+    }
+
+    if let Some(webdav) = context.webdav.as_ref().filter(|_| handling.dav_method && !handling.shared) {
+        let request = DavRequest {
+            invocation: &handling.invocation(access),
+            permitted: &|method, path| access.permitted(method, path),
+            mime_types: &context.mime_types,
+        };
+        let written = webdav.handle(stream, &request);
+        for path in &written.changed {
+            cache.remove_under(path);
+        }
+        return Some(written.response.send(stream, false));
+    }
+
+    let dir = resolve::file_path(base_dir, path);
+    if context.archives && query == archive::QUERY && dir.is_dir() {
+        let range = (requested_range, if_range);
+        return Some(send_archive(stream, &dir, path, &readable, range, head_only));
+    }
+    None
+}
+
+/// Answers a request with the file below the root it asks for, rendered or transformed as configured,
+/// or with what stands in for a missing one
+fn serve_file<S: Connection>(
+    stream: &mut S,
+    handling: &Handling,
+    access: &Access,
+    record: &mut RequestRecord,
+) -> std::io::Result<()> {
+    let Handling { context, path, query, base_dir, head_only, .. } = *handling;
+    let cache = &**handling.cache;
+    let headers = handling.headers();
+    let (range, requested_range, if_range) = ranges(handling);
+    let dir = resolve::file_path(base_dir, path);
+
+    // Map root path "/" to "/index.html"
+    let final_path = resolve::served_path(base_dir, path);
+    // The rules are held to the file served as well, e.g. a directory's index
+    if final_path != path && !access.signed && !access.readable(&final_path) {
+        return Response::error(403).send(stream, head_only);
+    }
+    let file_path = resolve::file_path(base_dir, &final_path);
+    let variant = (context.languages.as_ref())
+        .and_then(|languages| languages.negotiate(&file_path, headers.get("Accept-Language")));
+    let file_path = variant.as_ref().map_or(file_path, |variant| variant.file.clone());
+    // Included files are fetched as if they had been requested, through the access rules and the cache
+    let fetch_included = |included: &str| {
+        if !access.readable(included) {
+            return None;
+        }
+        let file = resolve::file_path(base_dir, &resolve::served_path(base_dir, included));
+        if let Some(cached) = cache.get(&file) {
+            return Some(cached);
+        }
+        let loaded = Arc::new(read_for_cache(context, &file)?);
+        cache.insert(file, Arc::clone(&loaded));
+        Some(loaded)
+    };
+    let includes = context.ssi.then(|| Includes::new(&final_path, &fetch_included));
+    let includes = includes.as_ref();
+    let file_path = match &context.markdown {
+        Some(markdown) if final_path.ends_with("/index.html") && !file_path.exists() => {
+            markdown.index(&file_path).unwrap_or(file_path)
+        }
+        _ => file_path,
+    };
+    let forced = url::form_urlencoded::parse(query.as_bytes()).any(|(name, value)| name == "download" && value == "1");
+    let downloaded = forced
+        || context.downloads.iter().any(|pattern| match pattern.contains('/') {
+            true => glob::matches(pattern, &final_path),
+            false => glob::matches(pattern, final_path.rsplit('/').next().unwrap_or(&final_path)),
+        });
+    let disposition = downloaded.then(|| attachment(&file_path));
+
+    if let Some(markdown) = context.markdown.as_ref().filter(|markdown| markdown.renders(&file_path)) {
+        // Rendered once per version of the file, so HEAD and later GETs get the same page, and the same
+        // compressed sizes, without rendering it again
+        let key = markdown.cache_key(&file_path);
+        let modified = fs::metadata(&file_path).and_then(|metadata| metadata.modified()).ok();
+        match cache.get(&key) {
+            Some(cached) if cached.modified == modified => record.cache_hit = Some(true),
+            Some(_) => cache.remove(&key),
+            None => {}
+        }
+        let rendered = context.loads.get_or_load(cache, &key, || {
+            let source = fs::read(&file_path)?;
+            let page = debug_span!("render").in_scope(|| markdown.page(&file_path, &String::from_utf8_lossy(&source)));
+            Ok(CachedFile::new(page.into_bytes(), "text/html; charset=utf-8".to_string(), modified))
+        });
+        match rendered {
+            Ok(page) => {
+                record.cache_hit.get_or_insert(false);
+                let contents = with_page_additions(context, &page.contents, &page.mime_type, includes);
+                let entry = (key.as_path(), &*page);
+                let encoded = compressed(context, cache, entry, headers.get("Accept-Encoding"), &contents, requested_range);
+                let body = encoded.as_ref().map_or(&*contents, |(_, body)| body);
+                let validators = Validators::new(body.len(), modified);
+                let range = validators.range(requested_range, if_range);
+                let response = encoding_label(Response::file(body, &page.mime_type, range), context, &page.mime_type, encoded.as_ref());
+                return debug_span!("write").in_scope(|| validators.label(response).send(stream, head_only));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Response::error(403).send(stream, head_only);
+            }
+            Err(_) => {}
+        }
+    }
+
+    let template = context.templates.as_ref().filter(|_| Templates::renders(&file_path) && file_path.is_file());
+    if let Some(templates) = template {
+        record.cache_hit = Some(false);
+        let rendered = debug_span!("render").in_scope(|| templates.render(&file_path, path, query));
+        return match rendered {
+            Ok(page) => {
+                let mime_type = context.mime_types.of(&Templates::output_path(&file_path));
+                let contents = with_page_additions(context, page.as_bytes(), &mime_type, includes);
+                let response = Response::file(&contents, &mime_type, range);
+                debug_span!("write").in_scope(|| response.send(stream, head_only))
+            }
+            Err(e) => {
+                warn!("Failed to render {}: {}", file_path.display(), e);
+                failed_page(&format!("Failed to render {}:\n{}", final_path, e)).send(stream, head_only)
+            }
+        };
+    }
+
+    let transform = match context.images.as_ref().filter(|_| Images::is_image(&file_path)) {
+        Some(images) => match Transform::parse(query) {
+            Ok(transform) => transform.map(|transform| (images, transform)),
+            Err(e) => {
+                return Response::new(400)
+                    .header("Content-Type", "text/plain")
+                    .body(format!("{}\n", e).into_bytes())
+                    .send(stream, head_only);
+            }
+        },
+        None => None,
+    };
+    if let Some((images, transform)) = transform.filter(|_| file_path.is_file()) {
+        let key = transform.cache_key(&file_path);
+        let modified = fs::metadata(&file_path).and_then(|metadata| metadata.modified()).ok();
+        // Made again once the image changes
+        match cache.get(&key) {
+            Some(cached) if cached.modified == modified => record.cache_hit = Some(true),
+            Some(_) => cache.remove(&key),
+            None => {}
+        }
+        record.cache_hit.get_or_insert(false);
+        let transformed = context.loads.get_or_load(cache, &key, || {
+            let _transform = debug_span!("transform").entered();
+            let (contents, mime_type) = images.transform(&file_path, &transform).map_err(std::io::Error::other)?;
+            Ok(CachedFile::new(contents, mime_type.to_string(), modified))
+        });
+        return match transformed {
+            Ok(file) => {
+                let response = Response::file(&file.contents, &file.mime_type, range);
+                debug_span!("write").in_scope(|| response.send(stream, head_only))
+            }
+            Err(e) => {
+                warn!("Failed to transform {}: {}", file_path.display(), e);
+                failed_page(&format!("Failed to transform {}:\n{}", final_path, e)).send(stream, head_only)
+            }
+        };
+    }
+
+    {
+        let lookup = debug_span!("cache_lookup").entered();
+        let cached = cache.get(&file_path).filter(|cached| match context.cache_ttl {
+            _ if cached.is_expired() => {
+                debug!("Cached copy of {} has expired", final_path);
+                cache.remove(&file_path);
+                false
+            }
+            Some(ttl) if !cached.is_fresh(&file_path, ttl) => {
+                debug!("Cached copy of {} is out of date", final_path);
+                cache.remove(&file_path);
+                false
+            }
+            _ => true,
+        });
+        lookup.exit();
+        if let Some(cached) = cached {
+            cached.hits.fetch_add(1, Ordering::Relaxed);
+            record.cache_hit = Some(true);
+            let contents = with_page_additions(context, &cached.contents, &cached.mime_type, includes);
+            let entry = (file_path.as_path(), &*cached);
+            let encoded = compressed(context, cache, entry, headers.get("Accept-Encoding"), &contents, requested_range);
+            let body = encoded.as_ref().map_or(&*contents, |(_, body)| body);
+            let validators = Validators::new(body.len(), cached.modified);
+            let range = validators.range(requested_range, if_range);
+            let response = language::label(Response::file(body, &cached.mime_type, range), variant.as_ref());
+            let response = encoding_label(response, context, &cached.mime_type, encoded.as_ref());
+            let mut response = attach_if(validators.label(response), disposition.as_deref());
+            // Browsers may keep it for as long as the cache does
+            if let Some(ttl) = cached.time_to_live() {
+                response = response.header("Cache-Control", format!("max-age={}", ttl.as_secs()));
+            }
+            return debug_span!("write").in_scope(|| response.send(stream, head_only));
+        }
+    }
+
+    if file_path.exists() && file_path.is_file() {
+        record.cache_hit = Some(false);
+        let mime_type = context.mime_types.of(&file_path);
+
+        let file = match File::open(&file_path) {
+            Ok(file) => file,
+            // e.g. a symlink pointing out of the sandbox
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Response::error(403).send(stream, head_only);
+            }
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata()?;
+        let (len, modified) = (metadata.len() as usize, metadata.modified().ok());
+        #[cfg(unix)]
+        if context.mmap_threshold.is_some_and(|threshold| len as u64 >= threshold) {
+            let mapping = debug_span!("read").in_scope(|| mmap::Mapping::new(&file, len))?;
+            let validators = Validators::new(len, modified);
+            let range = validators.range(requested_range, if_range);
+            let response = language::label(Response::file(&mapping, &mime_type, range), variant.as_ref());
+            let response = attach_if(validators.label(response), disposition.as_deref());
+            return debug_span!("write").in_scope(|| response.send(stream, head_only));
+        }
+        // Large files go from the page cache straight to the socket, bypassing the file cache
+        if len >= STREAM_MIN_SIZE {
+            let _write = debug_span!("write").entered();
+            let validators = Validators::new(len, modified);
+            let range = validators.range(requested_range, if_range);
+            let (response, body) = Response::file_head(len, &mime_type, range);
+            let response = validators.label(language::label(response, variant.as_ref()));
+            let response = attach_if(response, disposition.as_deref());
+            let Some(body) = body else {
+                return response.send(stream, head_only);
+            };
+            response.send_head(stream, body.len())?;
+            if head_only {
+                return stream.flush();
+            }
+            match body {
+                FileBody::Range(range) => stream.write_file(&file, range.start as u64, range.len() as u64)?,
+                FileBody::Parts { parts, end } => {
+                    for (head, range) in parts {
+                        stream.write_all(head.as_bytes())?;
+                        stream.write_file(&file, range.start as u64, range.len() as u64)?;
+                    }
+                    stream.write_all(end.as_bytes())?;
+                }
+            }
+            return stream.flush();
+        }
+
+        // Concurrent misses on this file wait for the first one's read
+        let loaded = context.loads.get_or_load(cache, &file_path, || {
+            let _read = debug_span!("read").entered();
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            let contents = uring::read(&file_path)?;
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            let contents = fs::read(&file_path)?;
+            Ok(CachedFile::new(contents, mime_type, modified))
+        });
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            // e.g. a symlink pointing out of the sandbox
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Response::error(403).send(stream, head_only);
+            }
+            Err(e) => return Err(e),
+        };
+        let contents = with_page_additions(context, &loaded.contents, &loaded.mime_type, includes);
+        let entry = (file_path.as_path(), &*loaded);
+        let encoded = compressed(context, cache, entry, headers.get("Accept-Encoding"), &contents, requested_range);
+        let body = encoded.as_ref().map_or(&*contents, |(_, body)| body);
+        let validators = Validators::new(body.len(), loaded.modified);
+        let range = validators.range(requested_range, if_range);
+        let response = language::label(Response::file(body, &loaded.mime_type, range), variant.as_ref());
+        let response = encoding_label(response, context, &loaded.mime_type, encoded.as_ref());
+        let response = attach_if(validators.label(response), disposition.as_deref());
+        debug_span!("write").in_scope(|| response.send(stream, head_only))
+    } else if let Some((sass, source)) = context.sass.as_ref().and_then(|sass| Some((sass, sass.source(&file_path)?))) {
+        record.cache_hit = Some(false);
+        let compiled = debug_span!("compile").in_scope(|| sass.compile(&file_path, &source));
+        match compiled {
+            Ok(css) => {
+                let file = Arc::new(CachedFile::new(css, context.mime_types.of(&file_path), None));
+                cache.insert(file_path, Arc::clone(&file));
+                let response = Response::file(&file.contents, &file.mime_type, range);
+                debug_span!("write").in_scope(|| response.send(stream, head_only))
+            }
+            Err(e) => {
+                warn!("Failed to compile {}: {}", source.display(), e);
+                failed_page(&format!("Failed to compile {}:\n{}", final_path, e)).send(stream, head_only)
+            }
+        }
+    } else if let Some(fetched) = context.fallback.as_ref().and_then(|origin| {
+        // The origin sees the path the access rules were checked against
+        origin.fetch(path, query)
+    }) {
+        let contents = with_page_additions(context, &fetched.contents, &fetched.mime_type, includes);
+        let mut response = Response::file(&contents, &fetched.mime_type, range);
+        match fetched.ttl {
+            Some(ttl) if ttl.is_zero() => response = response.header("Cache-Control", "no-store"),
+            Some(ttl) => response = response.header("Cache-Control", format!("max-age={}", ttl.as_secs())),
+            None => {}
+        }
+        let sent = debug_span!("write").in_scope(|| response.send(stream, head_only));
+        if context.fallback_cache && fetched.ttl.is_none_or(|ttl| !ttl.is_zero()) {
+            let file = CachedFile::new(fetched.contents, fetched.mime_type, None);
+            let file = match fetched.ttl {
+                Some(ttl) => file.expiring_after(ttl),
+                None => file,
+            };
+            cache.insert(file_path, Arc::new(file));
+        }
+        sent
+    } else if let Some(listing) = context.listing.as_ref().filter(|_| dir.is_dir()) {
+        let listed = |path: &str| access.listed(path);
+        match debug_span!("render").in_scope(|| listing.render(&dir, path, query, &listed)) {
+            Ok(page) => {
+                let contents = with_page_additions(context, page.as_bytes(), "text/html", None);
+                let response = Response::file(&contents, "text/html; charset=utf-8", range);
+                debug_span!("write").in_scope(|| response.send(stream, head_only))
+            }
+            Err(e) => {
+                warn!("Failed to list {}: {}", path, e);
+                failed_page(&format!("Failed to list {}:\n{}", path, e)).send(stream, head_only)
+            }
+        }
+    } else if let (Some(robots), "/robots.txt") = (context.robots, path) {
+        Response::file(robots.txt(), "text/plain; charset=utf-8", range).send(stream, head_only)
+    } else if context.favicon && path == "/favicon.ico" {
+        let response = Response::file(placeholders::favicon(), "image/x-icon", range);
+        response.header("Cache-Control", "max-age=86400").send(stream, head_only)
+    } else {
+        Response::error(404).send(stream, head_only)
+    }
+}
+
+/// Answers with the --s3 object for a URL path, from the file cache until --s3-ttl has passed
+#[allow(clippy::too_many_arguments)]
+fn send_object<S: Connection>(
+    stream: &mut S,
+    context: &Context,
+    cache: &FileCache,
+    bucket: &s3::Bucket,
+    url_path: &str,
+    range: Option<&str>,
+    head_only: bool,
+    record: &mut RequestRecord,
+) -> std::io::Result<()> {
+    // Keyed apart from files, which never start with a scheme
+    let key = PathBuf::from(format!("s3:{}", url_path));
+    match cache.get(&key) {
+        Some(cached) if cached.is_expired() => cache.remove(&key),
+        Some(_) => record.cache_hit = Some(true),
+        None => {}
+    }
+    record.cache_hit.get_or_insert(false);
+    let fetched = context.loads.get_or_load(&**cache, &key, || {
+        let _fetch = debug_span!("fetch").entered();
+        match bucket.fetch(url_path) {
+            Ok(Some((key, contents))) => {
+                let file = CachedFile::new(contents, context.mime_types.of(Path::new(&key)), None);
+                Ok(file.expiring_after(context.s3_ttl))
+            }
+            Ok(None) => Err(std::io::ErrorKind::NotFound.into()),
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    });
+    match fetched {
+        Ok(file) => {
+            file.hits.fetch_add(1, Ordering::Relaxed);
+            let contents = with_page_additions(context, &file.contents, &file.mime_type, None);
+            let mut response = Response::file(&contents, &file.mime_type, range);
+            if let Some(ttl) = file.time_to_live() {
+                response = response.header("Cache-Control", format!("max-age={}", ttl.as_secs()));
+            }
+            debug_span!("write").in_scope(|| response.send(stream, head_only))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Response::error(404).send(stream, head_only),
+        Err(e) => {
+            warn!("Failed to fetch {} from S3: {}", url_path, e);
+            Response::error(502).send(stream, head_only)
+        }
+    }
+}
+
+/// The body to send for a served file, with the --ssi includes expanded and the --preview-banner
+/// bar and the --live-reload script added to HTML pages
+fn with_page_additions<'a>(
+    context: &Context,
+    contents: &'a [u8],
+    mime_type: &str,
+    includes: Option<&Includes>,
+) -> Cow<'a, [u8]> {
+    if mime_type.split(';').next() != Some("text/html") {
+        return Cow::Borrowed(contents);
+    }
+    let contents = match includes {
+        Some(includes) => Cow::Owned(includes.expand(contents)),
+        None => Cow::Borrowed(contents),
+    };
+    let additions: Vec<String> = context
+        .preview_banner
+        .iter()
+        .cloned()
+        .chain(context.live_reload.as_ref().map(LiveReload::script))
+        .collect();
+    match additions.is_empty() {
+        true => contents,
+        false => Cow::Owned(inject::before_body_end(&contents, &additions)),
+    }
+}
+
+/// `contents`, the body to send for `file`, compressed for the client with --compress
+///
+/// Brotli is preferred over gzip when the client takes both. Types that are
+/// already compressed, such as images and archives, and small bodies are sent
+/// as they are, and so are range requests, whose ranges refer to the file's
+/// bytes. Bodies sent as the cached file is are compressed once per coding
+/// and kept with it in the cache; pages with additions are compressed on
+/// every request.
+fn compressed(
+    context: &Context,
+    cache: &dyn CacheBackend,
+    (path, file): (&Path, &CachedFile),
+    accept_encoding: Option<&str>,
+    contents: &[u8],
+    range: Option<&str>,
+) -> Option<(Encoding, Arc<Vec<u8>>)> {
+    if !context.compress
+        || range.is_some()
+        || contents.len() < rshttp::middleware::MIN_COMPRESSED_SIZE
+        || !rshttp::middleware::is_compressible(&file.mime_type)
+    {
+        return None;
+    }
+    let accepted = |encoding: &Encoding| rshttp::middleware::accepts(accept_encoding, encoding.name());
+    let encoding = [Encoding::Brotli, Encoding::Gzip].into_iter().find(accepted)?;
+    let body = match std::ptr::eq(contents, file.contents.as_slice()) {
+        true => {
+            let (body, new) = file.encoded(encoding);
+            if new {
+                cache.grown(path, file);
+            }
+            body
+        }
+        false => Arc::new(encoding.encode(contents)),
+    };
+    Some((encoding, body))
+}
+
+/// `response` with the Content-Encoding of its body, and Vary for types --compress may compress
+fn encoding_label<'a>(
+    response: Response<'a>,
+    context: &Context,
+    mime_type: &str,
+    encoded: Option<&(Encoding, Arc<Vec<u8>>)>,
+) -> Response<'a> {
+    let response = match encoded {
+        Some((encoding, _)) => response.header("Content-Encoding", encoding.name()),
+        None => response,
+    };
+    match context.compress && rshttp::middleware::is_compressible(mime_type) {
+        true => response.header("Vary", "Accept-Encoding"),
+        false => response,
+    }
+}
+
+/// A timeout of `secs` seconds, where 0 means none
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Keeps an admitted connection counted against the connection limits until it closes
+struct Admission {
+    _active: ActiveConnection,
+    _peer: Option<PeerConnection>,
+}
+
+/// Counts a new connection in, or picks the response refusing it, without reading its request
+///
+/// Clients over --max-connections-per-ip get 429, and connections beyond --max-connections 503.
+fn admit(context: &Context, peer_ip: Option<IpAddr>) -> Result<Admission, Response<'static>> {
+    let peer = match (&context.peer_connections, peer_ip) {
+        // The clients behind a trusted proxy are counted once their requests name them
+        (Some(peers), Some(ip)) if !context.trusted_proxies.trusts(ip) => match peers.track(ip) {
+            Some(peer) => Some(peer),
+            None => {
+                debug!("Refused connection from {}, which has too many open", ip);
+                return Err(Response::error(429).header("Retry-After", "1").header("Connection", "close"));
+            }
+        },
+        _ => None,
+    };
+    // Counted from now on, so a drain also waits for connections still in the queue
+    match context.shutdown.track(context.max_connections) {
+        Some(active) => Ok(Admission {
+            _active: active,
+            _peer: peer,
+        }),
+        None => Err(Response::error(503).header("Retry-After", "1").header("Connection", "close")),
+    }
+}
+
+/// Answer while the root is deleted or its volume is unmounted
+fn root_unavailable() -> Response<'static> {
+    Response::new(503)
+        .header("Content-Type", "text/html")
+        .header("Retry-After", ROOT_CHECK_INTERVAL.as_secs().max(1).to_string())
+        .body(
+            "<h1>503 Service Unavailable</h1>\n<p>The content served here is temporarily unavailable. Please try again shortly.</p>"
+                .as_bytes(),
+        )
+}
+
+/// A 500 telling the developer why a page could not be built
+fn failed_page(message: &str) -> Response<'static> {
+    Response::new(500)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("{}\n", message).into_bytes())
+}
+
+/// A Content-Disposition that has browsers save `file` under its name rather than show it
+fn attachment(file: &Path) -> String {
+    let name = file.file_name().map_or("download".into(), |name| name.to_string_lossy());
+    // A line break would end the header early
+    let name: String = name.chars().map(|c| if c.is_control() { '_' } else { c }).collect();
+    let ascii: String = name.chars().map(|c| if c.is_ascii() && !"\"\\".contains(c) { c } else { '_' }).collect();
+    match ascii == name {
+        true => format!("attachment; filename=\"{}\"", ascii),
+        // Clients that understand filename* (RFC 6266) get the name as it is
+        false => {
+            const ATTR_CHARS: &percent_encoding::AsciiSet =
+                &percent_encoding::NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_');
+            let encoded = percent_encoding::utf8_percent_encode(&name, ATTR_CHARS);
+            format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii, encoded)
+        }
+    }
+}
+
+/// `response` with the Content-Disposition `disposition`, if any
+fn attach_if<'a>(response: Response<'a>, disposition: Option<&str>) -> Response<'a> {
+    match disposition {
+        Some(disposition) => response.header("Content-Disposition", disposition),
+        None => response,
+    }
+}
+
+/// Sends the files below `dir` that `readable` lets through as a tar.gz named after the directory
+///
+/// The same files always make the same archive, so an interrupted download
+/// can resume with a `Range` request: the archive is made once to learn its
+/// length and again to send the range asked for. Several ranges at once get
+/// the whole archive.
+fn send_archive(
+    stream: &mut impl Connection,
+    dir: &Path,
+    url_path: &str,
+    readable: &dyn Fn(&str) -> bool,
+    (requested_range, if_range): (Option<&str>, Option<&str>),
+    head_only: bool,
+) -> std::io::Result<()> {
+    let name = dir.file_name().map_or("download".into(), |name| name.to_string_lossy().replace(['"', '\\'], "_"));
+    let files = walk::files(dir);
+    let entries = archive::entries(files.iter().filter_map(|file| {
+        let relative = file.strip_prefix(dir).ok()?.to_string_lossy().replace('\\', "/");
+        readable(&format!("{}/{}", url_path.trim_end_matches('/'), relative))
+            .then(|| (file.as_path(), format!("{}/{}", name, relative)))
+    }));
+    let validators = Validators::generated(archive::etag(&entries), archive::modified(&entries));
+    let range = validators.range(requested_range, if_range);
+    let label = |response: Response<'static>| {
+        let response = response.header("Content-Disposition", format!("attachment; filename=\"{}.tar.gz\"", name));
+        validators.label(response)
+    };
+    if !stream.streams() {
+        let mut archive = Vec::new();
+        archive::write_tar_gz(&mut archive, &entries)?;
+        return label(Response::file(&archive, "application/gzip", range).into_owned()).send(stream, head_only);
+    }
+    if let Some(range) = range {
+        let len = archive::len(&entries)?;
+        match Response::file_head(len as usize, "application/gzip", Some(range)) {
+            (response, Some(FileBody::Range(range))) if response.status() == 206 => {
+                label(response).send_head(stream, range.len())?;
+                if head_only {
+                    return Ok(());
+                }
+                return archive::write_tar_gz_range(stream, &entries, range.start as u64..range.end as u64);
+            }
+            (response, None) => return label(response).send(stream, head_only),
+            _ => {}
+        }
+    }
+    label(Response::new(200).header("Content-Type", "application/gzip").header("Accept-Ranges", "bytes")).send_open_head(stream)?;
+    if head_only {
+        return Ok(());
+    }
+    archive::write_tar_gz(stream, &entries)
+}
+
+/// Describes the request exactly as it reached the server
+fn echo_response(request: &request::Request, peer_ip: Option<IpAddr>) -> Response<'static> {
+    let headers: Vec<_> = request
+        .headers
+        .iter()
+        .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+        .collect();
+    let echo = serde_json::json!({
+        "method": request.method,
+        "path": request.target,
+        "version": request.version,
+        "client": peer_ip.map(|ip| ip.to_string()),
+        "headers": headers,
+    });
+    Response::new(200)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(serde_json::to_vec_pretty(&echo).unwrap_or_default())
+}
+
+/// The path of a request target as access rules and everything after them see it, decoded and normalized
+///
+/// Decoding comes first, so `/%61dmin` or `/%2e%2e/` can't slip past a rule
+/// for the path an upstream or the filesystem would take them for. Paths
+/// with control characters, which could end up in a header, are refused.
+fn request_path(target: &str) -> Option<String> {
+    let decoded = request::decode_path(target);
+    (!decoded.contains(|c: char| c.is_control())).then(|| request::normalize_path(&decoded))
+}
+
+/// Whether a --protect glob covers `path`, where `/drafts/**` also covers `/drafts` itself
+fn protects(pattern: &str, path: &str) -> bool {
+    glob::covers(pattern, path)
+}
+
+/// Answer of the health and readiness probes, 503 when not `ok`
+fn probe_response(ok: bool, state: &str) -> Response<'static> {
+    Response::new(if ok { 200 } else { 503 })
+        .header("Content-Type", "text/plain")
+        .header("Cache-Control", "no-store")
+        .body(format!("{}\n", state).into_bytes())
+}
+
+/// Lists the cached files as JSON, or purges one (`?path=`, as listed) or all of them on DELETE
+fn cache_admin_response(method: &str, query: &str, roots: &Roots, cache: &dyn CacheBackend) -> Response<'static> {
+    if method == "DELETE" {
+        let path = url::form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "path");
+        match path {
+            Some((_, path)) => {
+                info!("Purging cache entry: {}", path);
+                cache.remove(Path::new(&*path));
+            }
+            None => {
+                info!("Purging the whole cache");
+                for root in roots.all() {
+                    cache.remove_under(&root);
+                }
+            }
+        }
+        return Response::new(204);
+    }
+
+    let files: Vec<_> = cache
+        .entries()
+        .iter()
+        .map(|(path, file)| {
+            serde_json::json!({
+                "path": path.display().to_string(),
+                "size": file.contents.len(),
+                "mime_type": file.mime_type,
+                "hits": file.hits.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    let usage = cache.usage().map(|usage| {
+        serde_json::json!({ "entries": usage.entries, "bytes": usage.bytes, "budget": usage.budget })
+    });
+    Response::new(200)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(serde_json::to_vec_pretty(&serde_json::json!({ "usage": usage, "files": files })).unwrap_or_default())
+}
+
+/// The answer to a share link that expired or ran out of downloads
+fn share_gone() -> Response<'static> {
+    Response::new(410)
+        .header("Content-Type", "text/plain")
+        .body(b"This link has expired or been used up\n".to_vec())
+}
+
+/// Mints a share link on POST, revokes one on DELETE (?token=) and lists them on GET
+fn shares_response(
+    method: &str,
+    query: &str,
+    host: Option<&str>,
+    base_dir: &Path,
+    shares: &Shares,
+    readable: &dyn Fn(&str) -> bool,
+) -> Response<'static> {
+    let params: HashMap<_, _> = url::form_urlencoded::parse(query.as_bytes()).collect();
+    let json = |status, value: serde_json::Value| {
+        Response::new(status)
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(serde_json::to_vec_pretty(&value).unwrap_or_default())
+    };
+    match method {
+        "POST" => {
+            let Some(path) = params.get("path").filter(|path| path.starts_with('/')) else {
+                return Response::error(400);
+            };
+            let ttl = match params.get("expires").map(|expires| expires.parse()) {
+                Some(Ok(seconds)) => Duration::from_secs(seconds),
+                Some(Err(_)) => return Response::error(400),
+                None => shares::DEFAULT_TTL,
+            };
+            let downloads = match params.get("downloads").map(|downloads| downloads.parse()) {
+                Some(Ok(downloads)) => Some(downloads),
+                Some(Err(_)) => return Response::error(400),
+                None => None,
+            };
+            let path = request::normalize_path(path);
+            let served = resolve::served_path(base_dir, &path);
+            // Nobody can hand out more than they may read themselves
+            if !readable(&path) || !readable(&served) {
+                return Response::error(403);
+            }
+            if !resolve::file_path(base_dir, &served).is_file() {
+                return Response::error(404);
+            }
+            let Some(token) = shares.create(&path, ttl, downloads) else {
+                return Response::error(400);
+            };
+            let mut share = shares.describe(&token).unwrap_or_default();
+            share["url"] = format!("http://{}{}{}", host.unwrap_or("localhost"), shares::LINK_PREFIX, token).into();
+            info!("Shared {} as {}{}", path, shares::LINK_PREFIX, token);
+            json(201, share)
+        }
+        "DELETE" => match params.get("token").map(|token| shares.revoke(token)) {
+            Some(true) => Response::new(204),
+            Some(false) => Response::error(404),
+            None => Response::error(400),
+        },
+        _ => json(200, shares.list()),
+    }
+}
+
+/// Turns the result of the OpenID Connect flow into a redirect or error
+fn oidc_response(outcome: OidcOutcome) -> Response<'static> {
+    match outcome {
+        OidcOutcome::Redirect { location, cookies } => cookies
+            .into_iter()
+            .fold(Response::redirect(302, &location), |response, cookie| response.header("Set-Cookie", cookie)),
+        OidcOutcome::Rejected(reason) => {
+            warn!("OpenID Connect login failed: {}", reason);
+            Response::error(403)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachment_names_are_sanitized() {
+        assert_eq!(attachment(Path::new("/srv/report.pdf")), "attachment; filename=\"report.pdf\"");
+        assert_eq!(attachment(Path::new("/srv/a\"b")), "attachment; filename=\"a_b\"; filename*=UTF-8''a%22b");
+        assert_eq!(attachment(Path::new("/srv/x\r\nSet-Cookie: y")), "attachment; filename=\"x__Set-Cookie: y\"");
+        assert_eq!(attachment(Path::new("/srv/é\n")), "attachment; filename=\"__\"; filename*=UTF-8''%C3%A9_");
+        assert_eq!(attachment(Path::new("/srv/café")), "attachment; filename=\"caf_\"; filename*=UTF-8''caf%C3%A9");
+    }
+
+    #[test]
+    fn request_paths_are_decoded_before_normalizing() {
+        assert_eq!(request_path("/api/%61dmin").as_deref(), Some("/api/admin"));
+        assert_eq!(request_path("/My%20Files/").as_deref(), Some("/My Files/"));
+        assert_eq!(request_path("/a/%2e%2e/b").as_deref(), Some("/b"));
+        assert_eq!(request_path("/%2e%2e%2f%2e%2e/etc/passwd").as_deref(), Some("/etc/passwd"));
+        assert_eq!(request_path("/a%2Fb").as_deref(), Some("/a/b"));
+        assert_eq!(request_path("/x%0d%0aSet-Cookie:%20y"), None);
+        assert_eq!(request_path("/x%00.html"), None);
+    }
+
+    fn denied(policy: &str, target: &str) -> bool {
+        let policy = AccessPolicy::parse(policy).unwrap();
+        let path = request_path(target).unwrap();
+        let request = AccessRequest { method: "GET", path: &path, ip: None, principal: None };
+        policy.evaluate(&request) == Decision::Deny
+    }
+
+    #[test]
+    fn encoded_paths_cannot_reach_a_denied_upstream_path() {
+        let proxy = Proxy::parse("/api=http://localhost:4000").unwrap();
+        for target in ["/api/admin", "/api/%61dmin", "/api/%61%64%6D%69%6E/users", "/api/x/%2e%2e/admin", "/api/x%2F..%2Fadmin"] {
+            assert!(denied("deny /api/admin/**", target), "{} was let through", target);
+            assert!(proxy.matches(&request_path(target).unwrap()));
+        }
+        assert!(!denied("deny /api/admin/**", "/api/%61dmins"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reload_and_the_pid_file_work_in_the_sandbox() {
+        let dir = std::env::temp_dir().join(format!("rshttp-sandbox-{}", std::process::id()));
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::create_dir_all(dir.join("run")).unwrap();
+        fs::write(dir.join("rules"), "deny /private/**\n").unwrap();
+        fs::write(dir.join("rshttps.toml"), "access_rules = \"rules\"\n").unwrap();
+        fs::write(dir.join("outside.txt"), "out of reach\n").unwrap();
+        let pid_path = dir.join("run/rshttp.pid");
+        let command_line: Vec<OsString> = ["rshttp", "--sandbox", "--pid-file"]
+            .map(OsString::from)
+            .into_iter()
+            .chain([pid_path.clone().into(), "--config".into(), dir.join("rshttps.toml").into()])
+            .chain(["--directory".into(), dir.join("root").into()])
+            .collect();
+        let (args, config_file) = config::apply(Cli::command(), command_line.clone()).unwrap();
+        let cli = Cli::parse_from(args);
+        let roots = Roots {
+            default: dir.join("root").canonicalize().unwrap(),
+            vhosts: HashMap::new(),
+            mountpoints: HashSet::new(),
+        };
+        let allowed = sandbox_paths(&cli, &roots, config_file.as_ref().map(|config| config.path.as_path()));
+        let pid_file = daemon::PidFile::create(&pid_path).unwrap();
+
+        // Landlock confines only the thread that enters it, and `/` as the root rules out a chroot
+        let sandboxed = {
+            let (dir, pid_path) = (dir.clone(), pid_path.clone());
+            std::thread::spawn(move || {
+                if !matches!(sandbox::enter(Path::new("/"), &allowed), Ok(Sandbox::Landlock)) {
+                    return false;
+                }
+                assert!(fs::read(dir.join("outside.txt")).is_err());
+                let security = reload_security(config_file.as_ref(), &command_line).unwrap();
+                assert!(security.access_policy.is_some());
+                drop(pid_file);
+                assert!(!pid_path.exists());
+                true
+            })
+            .join()
+            .unwrap()
+        };
+        fs::remove_dir_all(&dir).unwrap();
+        if !sandboxed {
+            eprintln!("Landlock is unavailable, skipped");
+        }
+    }
+
+    fn security(protected: &[&str]) -> Security {
+        Security {
+            auth_providers: Vec::new(),
+            protected: protected.iter().map(|pattern| pattern.to_string()).collect(),
+            access_policy: None,
+        }
+    }
+
+    #[test]
+    fn everything_is_protected_without_patterns() {
+        assert!(security(&[]).protects("/"));
+        assert!(security(&[]).protects("/any/file.txt"));
+    }
+
+    #[test]
+    fn protected_patterns() {
+        let security = security(&["/drafts/**", "/**/*.key"]);
+        assert!(security.protects("/drafts"));
+        assert!(security.protects("/drafts/"));
+        assert!(security.protects("/drafts/2024/plan.md"));
+        assert!(!security.protects("/drafts-old/plan.md"));
+        assert!(!security.protects("/index.html"));
+        assert!(security.protects("/server.key"));
+        assert!(security.protects("/certs/server.key"));
+        // A glob is matched against the whole path
+        assert!(!security.protects("/server.key.txt"));
+    }
 }
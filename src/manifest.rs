@@ -0,0 +1,87 @@
+use crate::{units, walk};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rshttp::response::Response;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// URL that answers with the manifest
+pub const PATH: &str = "/_rshttps/manifest.json";
+
+/// Lists the served files with their sizes and hashes, from --manifest
+///
+/// `GET /_rshttps/manifest.json` answers with every file below the requested
+/// root that the ignore and access rules let the client see, hidden files
+/// left out, each with its size, modification time, SHA-256 in hex and
+/// Subresource Integrity value. A file is hashed the first time it is listed
+/// and again only once its size or modification time has changed.
+#[derive(Default)]
+pub struct Manifest {
+    hashes: Mutex<HashMap<PathBuf, Hashed>>,
+}
+
+/// A file's hash, with what it was taken of
+struct Hashed {
+    len: u64,
+    modified: Option<SystemTime>,
+    sha256: [u8; 32],
+}
+
+impl Manifest {
+    /// Answers with the manifest of the files below `root`
+    pub fn respond(&self, root: &Path, shown: &dyn Fn(&str) -> bool) -> Response<'static> {
+        let mut files = Vec::new();
+        let walked = walk::files(root);
+        for file in &walked {
+            let Ok(relative) = file.strip_prefix(root) else {
+                continue;
+            };
+            let path = format!("/{}", relative.to_string_lossy().replace('\\', "/"));
+            if relative.iter().any(|segment| segment.to_string_lossy().starts_with('.')) || !shown(&path) {
+                continue;
+            }
+            let Ok(metadata) = fs::metadata(file) else {
+                continue;
+            };
+            let Ok(sha256) = self.hash(file, metadata.len(), metadata.modified().ok()) else {
+                // Gone or unreadable since the directory was walked
+                continue;
+            };
+            files.push(json!({
+                "path": path,
+                "size": metadata.len(),
+                "modified": metadata.modified().ok().map(units::iso_timestamp),
+                "sha256": sha256.iter().map(|byte| format!("{:02x}", byte)).collect::<String>(),
+                "integrity": format!("sha256-{}", STANDARD.encode(sha256)),
+            }));
+        }
+        // Forgets files that are gone
+        (self.hashes.lock().unwrap()).retain(|file, _| !file.starts_with(root) || walked.binary_search(file).is_ok());
+        let body = json!({ "generated": units::iso_timestamp(SystemTime::now()), "files": files });
+        Response::new(200)
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(body.to_string().into_bytes())
+    }
+
+    /// The SHA-256 of `file`, from the last time it was hashed if it hasn't changed since
+    fn hash(&self, file: &Path, len: u64, modified: Option<SystemTime>) -> io::Result<[u8; 32]> {
+        if let Some(hashed) = self.hashes.lock().unwrap().get(file) {
+            if hashed.len == len && hashed.modified == modified {
+                return Ok(hashed.sha256);
+            }
+        }
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(file)?, &mut hasher)?;
+        let sha256: [u8; 32] = hasher.finalize().into();
+        let hashed = Hashed { len, modified, sha256 };
+        self.hashes.lock().unwrap().insert(file.to_path_buf(), hashed);
+        Ok(sha256)
+    }
+}
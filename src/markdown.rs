@@ -0,0 +1,896 @@
+use crate::inject::escape;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The page `.md` files are rendered into, unless --markdown-template names another
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{title}}</title>
+<style>
+body { max-width: 46em; margin: 2em auto; padding: 0 1em; font: 16px/1.6 system-ui, sans-serif; color: #222; }
+a { color: #0b57d0; }
+pre, code { font-family: ui-monospace, monospace; font-size: .9em; background: #f4f4f4; border-radius: 3px; }
+code { padding: .1em .3em; }
+pre { padding: .8em 1em; overflow-x: auto; }
+pre code { padding: 0; background: none; }
+blockquote { margin: 0; padding-left: 1em; border-left: 3px solid #ddd; color: #555; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ddd; padding: .3em .6em; }
+img { max-width: 100%; }
+hr { border: 0; border-top: 1px solid #ddd; }
+</style>
+</head>
+<body>
+{{content}}
+</body>
+</html>
+"#;
+
+/// Files served as the index of a directory without an index.html, in order of preference
+const INDEXES: [&str; 2] = ["index.md", "README.md"];
+
+/// Serves Markdown files as HTML pages, from --render-markdown
+///
+/// Pages are rendered on every request into the template, where `{{title}}`
+/// stands for the first heading (or the file name) and `{{content}}` for the
+/// rendered document. The renderer covers CommonMark's blocks and inlines plus
+/// pipe tables and strikethrough; raw HTML is passed through as is.
+pub struct Markdown {
+    template: String,
+}
+
+impl Markdown {
+    /// Uses `template`, or the built-in page with basic styling
+    pub fn new(template: Option<&Path>) -> io::Result<Markdown> {
+        let template = match template {
+            Some(path) => fs::read_to_string(path)?,
+            None => TEMPLATE.to_string(),
+        };
+        if !template.contains("{{content}}") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the template has no {{content}} placeholder"));
+        }
+        Ok(Markdown { template })
+    }
+
+    /// Whether `file` is rendered rather than sent as is
+    pub fn renders(&self, file: &Path) -> bool {
+        file.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("md") || extension == "markdown")
+    }
+
+    /// Where the page rendered from `file` is kept in the file cache, apart from the file itself
+    pub fn cache_key(&self, file: &Path) -> PathBuf {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        file.with_file_name(format!("{}?render=html", name))
+    }
+
+    /// The Markdown file standing in for a directory's missing index.html
+    pub fn index(&self, index_html: &Path) -> Option<PathBuf> {
+        let dir = index_html.parent()?;
+        INDEXES.iter().map(|name| dir.join(name)).find(|file| file.is_file())
+    }
+
+    /// The HTML page for the Markdown `source` of `file`
+    pub fn page(&self, file: &Path, source: &str) -> String {
+        let mut renderer = Renderer::default();
+        let content = renderer.render(source);
+        let title = match renderer.title {
+            Some(title) => title,
+            None => escape(&file.file_stem().unwrap_or_default().to_string_lossy()),
+        };
+        self.template.replace("{{title}}", &title).replace("{{content}}", &content)
+    }
+}
+
+/// Link reference definitions, and the first heading for the page title
+#[derive(Default)]
+struct Renderer {
+    references: HashMap<String, (String, Option<String>)>,
+    title: Option<String>,
+}
+
+/// How a list item starts
+struct Marker {
+    ordered: bool,
+    start: u64,
+    /// `-`, `+` or `*` for bullets, `.` or `)` for ordered items
+    delimiter: char,
+    /// Where the item's content starts
+    width: usize,
+}
+
+impl Renderer {
+    fn render(&mut self, source: &str) -> String {
+        let lines: Vec<String> = source.lines().map(|line| line.replace('\t', "    ")).collect();
+        let lines = self.collect_references(lines);
+        let mut html = String::new();
+        self.blocks(&lines, false, &mut html);
+        html
+    }
+
+    /// Takes the `[label]: url "title"` lines out of the document, outside code blocks
+    fn collect_references(&mut self, lines: Vec<String>) -> Vec<String> {
+        let mut fence: Option<String> = None;
+        let mut kept = Vec::with_capacity(lines.len());
+        for line in lines {
+            let trimmed = line.trim_start();
+            match &fence {
+                Some(open) if trimmed.starts_with(open.as_str()) => fence = None,
+                Some(_) => {}
+                None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                    fence = Some(trimmed[..3].to_string());
+                }
+                None if indent(&line) < 4 => {
+                    if let Some((label, destination, title)) = parse_definition(trimmed) {
+                        self.references.entry(normalize_label(label)).or_insert((destination, title));
+                        continue;
+                    }
+                }
+                None => {}
+            }
+            kept.push(line);
+        }
+        kept
+    }
+
+    /// Renders block-level content; `tight` leaves out the `<p>` of paragraphs in tight list items
+    fn blocks(&mut self, lines: &[String], tight: bool, html: &mut String) {
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i];
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() {
+                i += 1;
+                continue;
+            }
+
+            if indent(line) >= 4 {
+                let mut code = Vec::new();
+                while i < lines.len() && (lines[i].trim().is_empty() || indent(&lines[i]) >= 4) {
+                    code.push(lines[i].get(4..).unwrap_or(""));
+                    i += 1;
+                }
+                while code.last().is_some_and(|line| line.trim().is_empty()) {
+                    code.pop();
+                }
+                html.push_str(&format!("<pre><code>{}\n</code></pre>\n", escape(&code.join("\n"))));
+                continue;
+            }
+
+            if let Some((fence, info)) = fence_open(trimmed) {
+                let offset = indent(line);
+                let mut code = Vec::new();
+                i += 1;
+                while i < lines.len() && !is_fence_close(lines[i].trim(), &fence) {
+                    let strip = indent(&lines[i]).min(offset);
+                    code.push(format!("{}\n", &lines[i][strip..]));
+                    i += 1;
+                }
+                i += 1;
+                let class = match info.split_whitespace().next() {
+                    Some(language) => format!(" class=\"language-{}\"", escape(language)),
+                    None => String::new(),
+                };
+                html.push_str(&format!("<pre><code{}>{}</code></pre>\n", class, escape(&code.concat())));
+                continue;
+            }
+
+            if let Some((level, text)) = atx_heading(trimmed) {
+                self.heading(level, text, html);
+                i += 1;
+                continue;
+            }
+
+            if is_thematic_break(trimmed) {
+                html.push_str("<hr>\n");
+                i += 1;
+                continue;
+            }
+
+            if trimmed.starts_with('>') {
+                let mut quoted = Vec::new();
+                while i < lines.len() && !lines[i].trim().is_empty() {
+                    let inner = lines[i].trim_start();
+                    match inner.strip_prefix('>') {
+                        Some(rest) => quoted.push(rest.strip_prefix(' ').unwrap_or(rest).to_string()),
+                        // Lazy continuation of a quoted paragraph
+                        None if starts_block(inner) => break,
+                        None => quoted.push(inner.to_string()),
+                    }
+                    i += 1;
+                }
+                html.push_str("<blockquote>\n");
+                self.blocks(&quoted, false, html);
+                html.push_str("</blockquote>\n");
+                continue;
+            }
+
+            if let Some(marker) = list_marker(line) {
+                i = self.list(lines, i, marker, html);
+                continue;
+            }
+
+            if let Some(alignments) = lines.get(i + 1).and_then(|next| table_delimiter(next)) {
+                let header = split_row(trimmed);
+                if trimmed.contains('|') && header.len() == alignments.len() {
+                    i = self.table(lines, i, &header, &alignments, html);
+                    continue;
+                }
+            }
+
+            if is_html_block(trimmed) {
+                while i < lines.len() && !lines[i].trim().is_empty() {
+                    html.push_str(&lines[i]);
+                    html.push('\n');
+                    i += 1;
+                }
+                continue;
+            }
+
+            let mut paragraph = vec![trimmed];
+            let mut setext = None;
+            i += 1;
+            while let Some(next) = lines.get(i).map(|line| line.trim()) {
+                let underline = |mark| !next.is_empty() && next.bytes().all(|byte| byte == mark);
+                if underline(b'=') || underline(b'-') {
+                    setext = Some(if underline(b'=') { 1 } else { 2 });
+                    i += 1;
+                    break;
+                }
+                let ordered_list = list_marker(&lines[i]).is_some_and(|marker| marker.start == 1);
+                if next.is_empty() || starts_block(next) || ordered_list {
+                    break;
+                }
+                paragraph.push(lines[i].trim_start());
+                i += 1;
+            }
+            let text = paragraph.join("\n");
+            match setext {
+                Some(level) => self.heading(level, &text, html),
+                None if tight => html.push_str(&format!("{}\n", self.inline(text.trim_end()))),
+                None => html.push_str(&format!("<p>{}</p>\n", self.inline(text.trim_end()))),
+            }
+        }
+        if tight && html.ends_with('\n') {
+            html.pop();
+        }
+    }
+
+    fn heading(&mut self, level: usize, text: &str, html: &mut String) {
+        let content = self.inline(text.trim());
+        if self.title.is_none() {
+            self.title = Some(strip_tags(&content));
+        }
+        html.push_str(&format!("<h{}>{}</h{}>\n", level, content, level));
+    }
+
+    /// Renders the list starting at `lines[start]` and returns the index of the line after it
+    fn list(&mut self, lines: &[String], start: usize, first: Marker, html: &mut String) -> usize {
+        let mut items: Vec<Vec<String>> = Vec::new();
+        let mut loose = false;
+        let mut marker = first;
+        let (ordered, delimiter, number) = (marker.ordered, marker.delimiter, marker.start);
+        let mut i = start;
+        loop {
+            let mut item = vec![lines[i].get(marker.width..).unwrap_or("").to_string()];
+            i += 1;
+            let mut blank = false;
+            while i < lines.len() {
+                let line = &lines[i];
+                if line.trim().is_empty() {
+                    blank = true;
+                    item.push(String::new());
+                } else if indent(line) >= marker.width {
+                    loose |= blank && item.iter().any(|line| !line.is_empty());
+                    blank = false;
+                    item.push(line[marker.width..].to_string());
+                } else if !blank && list_marker(line).is_none() && !starts_block(line.trim_start()) {
+                    // Lazy continuation of the item's paragraph
+                    item.push(line.trim_start().to_string());
+                } else {
+                    break;
+                }
+                i += 1;
+            }
+            while item.last().is_some_and(|line| line.is_empty()) {
+                item.pop();
+            }
+            items.push(item);
+            match lines.get(i).and_then(|line| list_marker(line)) {
+                Some(next) if next.ordered == ordered && next.delimiter == delimiter => {
+                    loose |= blank;
+                    marker = next;
+                }
+                _ => break,
+            }
+        }
+
+        let tag = if ordered { "ol" } else { "ul" };
+        match ordered && number != 1 {
+            true => html.push_str(&format!("<ol start=\"{}\">\n", number)),
+            false => html.push_str(&format!("<{}>\n", tag)),
+        }
+        for item in items {
+            html.push_str("<li>");
+            if loose {
+                html.push('\n');
+            }
+            self.blocks(&item, !loose, html);
+            html.push_str("</li>\n");
+        }
+        html.push_str(&format!("</{}>\n", tag));
+        i
+    }
+
+    /// Renders a pipe table and returns the index of the line after it
+    fn table(&self, lines: &[String], start: usize, header: &[String], alignments: &[&str], html: &mut String) -> usize {
+        let cell = |tag: &str, column: usize, text: &str| {
+            let align = match alignments.get(column).copied().unwrap_or("") {
+                "" => String::new(),
+                align => format!(" style=\"text-align:{}\"", align),
+            };
+            format!("<{}{}>{}</{}>", tag, align, self.inline(text), tag)
+        };
+        html.push_str("<table>\n<thead>\n<tr>");
+        for (column, text) in header.iter().enumerate() {
+            html.push_str(&cell("th", column, text));
+        }
+        html.push_str("</tr>\n</thead>\n");
+        let mut i = start + 2;
+        let mut body = String::new();
+        while i < lines.len() && lines[i].contains('|') && !lines[i].trim().is_empty() {
+            let row = split_row(lines[i].trim());
+            body.push_str("<tr>");
+            for column in 0..header.len() {
+                body.push_str(&cell("td", column, row.get(column).map_or("", String::as_str)));
+            }
+            body.push_str("</tr>\n");
+            i += 1;
+        }
+        if !body.is_empty() {
+            html.push_str(&format!("<tbody>\n{}</tbody>\n", body));
+        }
+        html.push_str("</table>\n");
+        i
+    }
+
+    /// Renders inline content: code spans, emphasis, links, images, autolinks and line breaks
+    fn inline(&self, text: &str) -> String {
+        let mut html = String::new();
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < text.len() {
+            let rest = &text[i..];
+            match bytes[i] {
+                b'\\' if bytes.get(i + 1).is_some_and(u8::is_ascii_punctuation) => {
+                    html.push_str(&escape(&rest[1..2]));
+                    i += 2;
+                }
+                b'\\' if rest.starts_with("\\\n") => {
+                    html.push_str("<br>\n");
+                    i += 2;
+                }
+                b'\n' => {
+                    match html.ends_with("  ") {
+                        true => {
+                            html.truncate(html.trim_end_matches(' ').len());
+                            html.push_str("<br>\n");
+                        }
+                        false => html.push('\n'),
+                    }
+                    i += 1;
+                }
+                b'`' => {
+                    let run = rest.bytes().take_while(|&byte| byte == b'`').count();
+                    match find_code_end(&rest[run..], run) {
+                        Some(end) => {
+                            let code = rest[run..run + end].replace('\n', " ");
+                            let code = match code.starts_with(' ') && code.ends_with(' ') && code.trim() != "" {
+                                true => &code[1..code.len() - 1],
+                                false => code.as_str(),
+                            };
+                            html.push_str(&format!("<code>{}</code>", escape(code)));
+                            i += run + end + run;
+                        }
+                        None => {
+                            html.push_str(&rest[..run]);
+                            i += run;
+                        }
+                    }
+                }
+                b'!' if rest.starts_with("![") => match self.link(&rest[1..]) {
+                    Some((label, destination, title, length)) => {
+                        let alt = strip_tags(&self.inline(label));
+                        let title = title.map_or(String::new(), |title| format!(" title=\"{}\"", escape(&title)));
+                        html.push_str(&format!("<img src=\"{}\" alt=\"{}\"{}>", escape(&destination), alt, title));
+                        i += 1 + length;
+                    }
+                    None => {
+                        html.push('!');
+                        i += 1;
+                    }
+                },
+                b'[' => match self.link(rest) {
+                    Some((label, destination, title, length)) => {
+                        let title = title.map_or(String::new(), |title| format!(" title=\"{}\"", escape(&title)));
+                        let content = self.inline(label);
+                        html.push_str(&format!("<a href=\"{}\"{}>{}</a>", escape(&destination), title, content));
+                        i += length;
+                    }
+                    None => {
+                        html.push('[');
+                        i += 1;
+                    }
+                },
+                b'<' => match autolink(rest).or_else(|| raw_tag(rest).map(|tag| (tag.to_string(), tag.len()))) {
+                    Some((markup, length)) => {
+                        html.push_str(&markup);
+                        i += length;
+                    }
+                    None => {
+                        html.push_str("&lt;");
+                        i += 1;
+                    }
+                },
+                b'&' => match entity(rest) {
+                    Some(entity) => {
+                        html.push_str(entity);
+                        i += entity.len();
+                    }
+                    None => {
+                        html.push_str("&amp;");
+                        i += 1;
+                    }
+                },
+                delimiter @ (b'*' | b'_' | b'~') => match self.emphasis(text, i, delimiter) {
+                    Some((markup, length)) => {
+                        html.push_str(&markup);
+                        i += length;
+                    }
+                    None => {
+                        let run = rest.bytes().take_while(|&byte| byte == delimiter).count();
+                        html.push_str(&rest[..run]);
+                        i += run;
+                    }
+                },
+                _ => {
+                    let character = rest.chars().next().unwrap_or_default();
+                    match character {
+                        '>' => html.push_str("&gt;"),
+                        '"' => html.push_str("&quot;"),
+                        character => html.push(character),
+                    }
+                    i += character.len_utf8();
+                }
+            }
+        }
+        html
+    }
+
+    /// Emphasis, strong emphasis or strikethrough opening at `text[start]`, and the length it spans
+    fn emphasis(&self, text: &str, start: usize, delimiter: u8) -> Option<(String, usize)> {
+        let rest = &text[start..];
+        let run = rest.bytes().take_while(|&byte| byte == delimiter).count();
+        let after = rest[run..].chars().next()?;
+        if after.is_whitespace() {
+            return None;
+        }
+        // Underscores inside words, as in snake_case, are not emphasis
+        let before = text[..start].chars().next_back();
+        if delimiter == b'_' && before.is_some_and(char::is_alphanumeric) {
+            return None;
+        }
+        let (width, open, close) = match (delimiter, run) {
+            (b'~', 2) => (2, "<del>", "</del>"),
+            (b'~', _) => return None,
+            (_, 1) => (1, "<em>", "</em>"),
+            (_, 2) => (2, "<strong>", "</strong>"),
+            _ => (3, "<em><strong>", "</strong></em>"),
+        };
+        let closing = &rest[..width];
+        let content_start = width;
+        let mut search = content_start;
+        while let Some(found) = rest[search..].find(closing) {
+            let end = search + found;
+            let closing_run = rest[end..].bytes().take_while(|&byte| byte == delimiter).count();
+            let preceded_by_space = rest[..end].chars().next_back().is_some_and(char::is_whitespace);
+            let followed_by_word = rest[end + closing_run..].chars().next().is_some_and(char::is_alphanumeric);
+            let fits = closing_run == width || width == 3;
+            if end > content_start && !preceded_by_space && fits && !(delimiter == b'_' && followed_by_word) {
+                let content = self.inline(&rest[content_start..end]);
+                return Some((format!("{}{}{}", open, content, close), end + width));
+            }
+            search = end + closing_run.max(1);
+        }
+        None
+    }
+
+    /// A `[label](destination "title")`, `[label][reference]` or `[reference]` link at the start of
+    /// `text`: its label, destination, title and length
+    fn link<'t>(&self, text: &'t str) -> Option<(&'t str, String, Option<String>, usize)> {
+        let label_end = closing_bracket(text)?;
+        let label = &text[1..label_end];
+        let after = &text[label_end + 1..];
+        if let Some(inner) = after.strip_prefix('(') {
+            let close = closing_paren(inner)?;
+            let (destination, title) = parse_destination(inner[..close].trim())?;
+            return Some((label, destination, title, label_end + 2 + close + 1));
+        }
+        let (reference, length) = match after.strip_prefix('[').and_then(|rest| Some((rest, rest.find(']')?))) {
+            Some((rest, end)) if end > 0 => (&rest[..end], label_end + 1 + end + 2),
+            Some((_, 0)) => (label, label_end + 3),
+            _ => (label, label_end + 1),
+        };
+        let (destination, title) = self.references.get(&normalize_label(reference))?;
+        Some((label, destination.clone(), title.clone(), length))
+    }
+}
+
+/// Leading spaces of a line
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Whether a line (without its indentation) interrupts a paragraph
+fn starts_block(line: &str) -> bool {
+    atx_heading(line).is_some()
+        || fence_open(line).is_some()
+        || is_thematic_break(line)
+        || line.starts_with('>')
+        || (line.starts_with(['-', '*', '+']) && line[1..].starts_with(' '))
+        || is_html_block(line)
+}
+
+/// An opening ``` or ~~~ fence and its info string
+fn fence_open(line: &str) -> Option<(String, &str)> {
+    let character = line.chars().next().filter(|&character| character == '`' || character == '~')?;
+    let run = line.chars().take_while(|&c| c == character).count();
+    let info = line[run..].trim();
+    (run >= 3 && !(character == '`' && info.contains('`'))).then(|| (line[..run].to_string(), info))
+}
+
+fn is_fence_close(line: &str, fence: &str) -> bool {
+    line.starts_with(fence) && line.trim_start_matches(fence.as_bytes()[0] as char).is_empty()
+}
+
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|&byte| byte == b'#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    let text = rest.trim();
+    let without_closing = text.trim_end_matches('#');
+    match without_closing.is_empty() || without_closing.ends_with(' ') {
+        true => Some((level, without_closing.trim_end())),
+        false => Some((level, text)),
+    }
+}
+
+fn is_thematic_break(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|character| !character.is_whitespace()).collect();
+    marks.len() >= 3 && matches!(marks[0], '-' | '*' | '_') && marks.iter().all(|&mark| mark == marks[0])
+}
+
+fn list_marker(line: &str) -> Option<Marker> {
+    let offset = indent(line);
+    if offset > 3 {
+        return None;
+    }
+    let rest = &line[offset..];
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    let (ordered, start, delimiter, marker_width) = match rest.chars().next()? {
+        character @ ('-' | '*' | '+') => (false, 0, character, 1),
+        _ if (1..=9).contains(&digits) => {
+            let delimiter = rest[digits..].chars().next().filter(|&c| c == '.' || c == ')')?;
+            (true, rest[..digits].parse().ok()?, delimiter, digits + 1)
+        }
+        _ => return None,
+    };
+    let content = &rest[marker_width..];
+    if !content.is_empty() && !content.starts_with(' ') {
+        return None;
+    }
+    let spaces = indent(content);
+    // Content indented further than that is an indented code block inside the item
+    let spaces = if content.trim().is_empty() || spaces > 4 { 1 } else { spaces };
+    Some(Marker {
+        ordered,
+        start,
+        delimiter,
+        width: offset + marker_width + spaces,
+    })
+}
+
+/// The alignments of a `| --- | :-: |` row under a table's header
+fn table_delimiter(line: &str) -> Option<Vec<&'static str>> {
+    let line = line.trim();
+    if !line.contains('-') || line.contains(|character: char| !"|:- ".contains(character)) {
+        return None;
+    }
+    let row = line.trim_start_matches('|').trim_end_matches('|');
+    row.split('|')
+        .map(|cell| {
+            let cell = cell.trim();
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            if dashes.is_empty() || !dashes.bytes().all(|byte| byte == b'-') {
+                return None;
+            }
+            Some(match (cell.starts_with(':'), cell.ends_with(':')) {
+                (true, true) => "center",
+                (false, true) => "right",
+                (true, false) => "left",
+                (false, false) => "",
+            })
+        })
+        .collect()
+}
+
+/// The cells of a table row, splitting on pipes that are not escaped
+fn split_row(line: &str) -> Vec<String> {
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(inner) if !inner.ends_with('\\') => inner,
+        _ => line,
+    };
+    let mut cells = vec![String::new()];
+    let mut characters = line.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' if characters.peek() == Some(&'|') => {
+                cells.last_mut().unwrap().push('|');
+                characters.next();
+            }
+            '|' => cells.push(String::new()),
+            character => cells.last_mut().unwrap().push(character),
+        }
+    }
+    cells.iter().map(|cell| cell.trim().to_string()).collect()
+}
+
+/// Elements whose tags start a block of raw HTML whatever follows them on the line
+const BLOCK_TAGS: [&str; 66] = [
+    "address", "article", "aside", "base", "basefont", "blockquote", "body", "caption", "center", "col", "colgroup",
+    "dd", "details", "dialog", "dir", "div", "dl", "dt", "fieldset", "figcaption", "figure", "footer", "form",
+    "frame", "frameset", "h1", "h2", "h3", "h4", "h5", "h6", "head", "header", "hr", "html", "iframe", "legend", "li",
+    "link", "main", "menu", "menuitem", "nav", "noframes", "ol", "optgroup", "option", "p", "param", "pre", "script",
+    "search", "section", "style", "summary", "table", "tbody", "td", "textarea", "tfoot", "th", "thead", "title", "tr",
+    "track", "ul",
+];
+
+/// Whether a line starts a block of raw HTML
+///
+/// That is a comment, a block-level tag, or any other tag alone on its line;
+/// a line like `<b>Note:</b> text` starts a paragraph instead.
+fn is_html_block(line: &str) -> bool {
+    let Some(rest) = line.strip_prefix('<') else {
+        return false;
+    };
+    if rest.starts_with("!--") {
+        return true;
+    }
+    let name = rest.strip_prefix('/').unwrap_or(rest);
+    let name = &name[..name.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(name.len())];
+    if BLOCK_TAGS.contains(&name.to_ascii_lowercase().as_str()) {
+        return true;
+    }
+    let tag = rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '/');
+    tag && raw_tag(line).is_some_and(|tag| line[tag.len()..].trim().is_empty())
+}
+
+/// A raw HTML tag or comment at the start of `text`
+fn raw_tag(text: &str) -> Option<&str> {
+    if text.starts_with("<!--") {
+        return Some(&text[..text.find("-->")? + 3]);
+    }
+    let name = text[1..].strip_prefix('/').unwrap_or(&text[1..]);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let end = text.find('>')?;
+    let tag = &text[..end + 1];
+    (!tag[1..].contains('<')).then_some(tag)
+}
+
+/// An `<https://example.com>` or `<someone@example.com>` autolink and its length
+fn autolink(text: &str) -> Option<(String, usize)> {
+    let end = text.find('>')?;
+    let target = &text[1..end];
+    if target.is_empty() || target.contains(char::is_whitespace) || target.contains('<') {
+        return None;
+    }
+    let href = match target.split_once(':') {
+        Some((scheme, _)) if scheme.len() >= 2 && scheme.bytes().all(|byte| byte.is_ascii_alphanumeric()) => {
+            target.to_string()
+        }
+        _ if target.contains('@') && !target.contains(':') => format!("mailto:{}", target),
+        _ => return None,
+    };
+    Some((format!("<a href=\"{}\">{}</a>", escape(&href), escape(target)), end + 1))
+}
+
+/// An HTML entity such as `&amp;` or `&#8212;` at the start of `text`
+fn entity(text: &str) -> Option<&str> {
+    let end = text.find(';')?;
+    let name = &text[1..end];
+    let valid = match name.strip_prefix('#') {
+        Some(number) => match number.strip_prefix(['x', 'X']) {
+            Some(hex) => (1..=6).contains(&hex.len()) && hex.bytes().all(|byte| byte.is_ascii_hexdigit()),
+            None => (1..=7).contains(&number.len()) && number.bytes().all(|byte| byte.is_ascii_digit()),
+        },
+        None => (2..=32).contains(&name.len()) && name.bytes().all(|byte| byte.is_ascii_alphanumeric()),
+    };
+    valid.then(|| &text[..end + 1])
+}
+
+/// Where a code span opened by `run` backticks ends in `text`
+fn find_code_end(text: &str, run: usize) -> Option<usize> {
+    let mut i = 0;
+    while let Some(found) = text[i..].find('`') {
+        let start = i + found;
+        let length = text[start..].bytes().take_while(|&byte| byte == b'`').count();
+        if length == run {
+            return Some(start);
+        }
+        i = start + length;
+    }
+    None
+}
+
+/// The index of the `]` closing the `[` at the start of `text`
+fn closing_bracket(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, byte) in text.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'[' => depth += 1,
+            b']' if depth == 1 => return Some(i),
+            b']' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The index of the `)` ending a link destination, allowing balanced parentheses inside it
+fn closing_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quoted = None;
+    for (i, byte) in text.bytes().enumerate() {
+        match byte {
+            b'"' | b'\'' if quoted == Some(byte) => quoted = None,
+            b'"' | b'\'' if quoted.is_none() && text[..i].ends_with(' ') => quoted = Some(byte),
+            _ if quoted.is_some() => {}
+            b'(' => depth += 1,
+            b')' if depth == 0 => return Some(i),
+            b')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits `url "title"` (or `<url> 'title'`) into the destination and the title
+fn parse_destination(text: &str) -> Option<(String, Option<String>)> {
+    let (destination, rest) = match text.strip_prefix('<') {
+        Some(inner) => {
+            let end = inner.find('>')?;
+            (&inner[..end], inner[end + 1..].trim())
+        }
+        None => match text.split_once(char::is_whitespace) {
+            Some((destination, rest)) => (destination, rest.trim()),
+            None => (text, ""),
+        },
+    };
+    let title = match rest.chars().next() {
+        None => None,
+        Some(quote @ ('"' | '\'')) if rest.len() >= 2 && rest.ends_with(quote) => {
+            Some(rest[1..rest.len() - 1].to_string())
+        }
+        Some('(') if rest.ends_with(')') => Some(rest[1..rest.len() - 1].to_string()),
+        Some(_) => return None,
+    };
+    Some((destination.replace(' ', "%20"), title))
+}
+
+/// A `[label]: destination "title"` line
+fn parse_definition(line: &str) -> Option<(&str, String, Option<String>)> {
+    let end = closing_bracket(line)?;
+    let label = &line[1..end];
+    let rest = line[end + 1..].strip_prefix(':')?.trim();
+    if label.trim().is_empty() || rest.is_empty() {
+        return None;
+    }
+    let (destination, title) = parse_destination(rest)?;
+    Some((label, destination, title))
+}
+
+/// Labels match case-insensitively and regardless of spacing
+fn normalize_label(label: &str) -> String {
+    label.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// The text of rendered HTML, for the page title and image descriptions
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for character in html.chars() {
+        match character {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            character if !in_tag => text.push(character),
+            _ => {}
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str) -> String {
+        Renderer::default().render(source)
+    }
+
+    #[test]
+    fn headings() {
+        let mut renderer = Renderer::default();
+        let html = renderer.render("# Title *one*\n\n## Sub `code` ##\nSetext\n===\n\n####### seven\n");
+        assert_eq!(
+            html,
+            "<h1>Title <em>one</em></h1>\n<h2>Sub <code>code</code></h2>\n<h1>Setext</h1>\n<p>####### seven</p>\n"
+        );
+        assert_eq!(renderer.title.as_deref(), Some("Title one"));
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            render("- a\n- b\n  - nested\n\n3) three\n4) four\n"),
+            "<ul>\n<li>a</li>\n<li>b\n<ul>\n<li>nested</li>\n</ul></li>\n</ul>\n<ol start=\"3\">\n<li>three</li>\n<li>four</li>\n</ol>\n"
+        );
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(
+            render("```rust\nif a < b && c {}\n```\n\n    <indented>\n"),
+            "<pre><code class=\"language-rust\">if a &lt; b &amp;&amp; c {}\n</code></pre>\n<pre><code>&lt;indented&gt;\n</code></pre>\n"
+        );
+        assert_eq!(render("`a<b` and ``x ` y``\n"), "<p><code>a&lt;b</code> and <code>x ` y</code></p>\n");
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            render("[site](https://example.com \"T\") and [ref][r] and ![alt](/i.png)\n\n[r]: /page\n"),
+            "<p><a href=\"https://example.com\" title=\"T\">site</a> and <a href=\"/page\">ref</a> and <img src=\"/i.png\" alt=\"alt\"></p>\n"
+        );
+        assert_eq!(
+            render("<https://a.b/?x=1&y=2> [q](/x\"y) [s](<a b>)\n"),
+            "<p><a href=\"https://a.b/?x=1&amp;y=2\">https://a.b/?x=1&amp;y=2</a> <a href=\"/x&quot;y\">q</a> <a href=\"a%20b\">s</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn html_escaping() {
+        assert_eq!(
+            render("5 < 6 & \"q\" &amp; &copy; \\*not\\*\n"),
+            "<p>5 &lt; 6 &amp; &quot;q&quot; &amp; &copy; *not*</p>\n"
+        );
+        assert_eq!(render("| a | b |\n|---|:-:|\n| 1 | <2> |\n").matches("&lt;2&gt;").count(), 1);
+    }
+
+    #[test]
+    fn raw_html() {
+        assert_eq!(render("<div>\n*kept*\n</div>\n"), "<div>\n*kept*\n</div>\n");
+        assert_eq!(render("<b>Note:</b> 1 < 2\n"), "<p><b>Note:</b> 1 &lt; 2</p>\n");
+    }
+}
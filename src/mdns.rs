@@ -0,0 +1,256 @@
+use crate::lan;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// The mDNS group and port (RFC 6762)
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+/// Seconds answers may be cached, as RFC 6762 recommends for host and service records
+const TTL: u32 = 120;
+
+/// The DNS-SD service type browsed for web servers
+const SERVICE: &str = "_http._tcp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+/// Class IN with the cache-flush bit, for records only this host answers for
+const CLASS_IN_FLUSH: u16 = 0x8001;
+const CLASS_IN: u16 = 1;
+
+/// Advertises the server on the local network over multicast DNS, from --mdns NAME
+///
+/// `NAME.local` resolves to the machine's IPv4 addresses, and the server is
+/// announced as an `_http._tcp` DNS-SD service called NAME, so browsers of
+/// local services (and `avahi-browse`, `dns-sd -B`) list it. Other responders
+/// on the machine, such as avahi, keep working since the port is shared.
+pub struct Advertiser {
+    socket: UdpSocket,
+    host: String,
+    instance: String,
+    port: u16,
+}
+
+impl Advertiser {
+    pub fn new(name: &str, port: u16) -> Result<Advertiser, String> {
+        let name = name.trim_end_matches('.').trim_end_matches(".local");
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-';
+        if name.is_empty() || name.len() > 63 || !name.chars().all(valid) {
+            return Err(format!("invalid mDNS name '{}' (expected letters, digits and dashes)", name));
+        }
+        let socket = bind().map_err(|e| format!("cannot listen for mDNS queries: {}", e))?;
+        Ok(Advertiser {
+            socket,
+            host: format!("{}.local", name),
+            instance: format!("{}.{}", name, SERVICE),
+            port,
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Announces the records, then answers queries for them until the process exits
+    pub fn run(&self) {
+        // RFC 6762 section 8.3: at least two announcements, a second apart
+        for _ in 0..2 {
+            let announcement = self.response(0, &[], &[(SERVICE.to_string(), TYPE_PTR)]);
+            let _ = self.socket.send_to(&announcement, (GROUP, PORT));
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        let mut buffer = [0; 9000];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("mDNS responder stopped: {}", e);
+                    return;
+                }
+            };
+            let Some((id, questions)) = parse_query(&buffer[..len]) else {
+                continue;
+            };
+            let names = [self.host.as_str(), self.instance.as_str(), SERVICE];
+            let ours: Vec<(String, u16)> = (questions.iter())
+                .filter(|(name, _)| names.iter().any(|ours| name.eq_ignore_ascii_case(ours)))
+                .cloned()
+                .collect();
+            if ours.is_empty() {
+                continue;
+            }
+            debug!("Answering mDNS query from {} for {:?}", from, ours);
+            // Queries from another port than 5353 are one-shot, answered directly with the question repeated
+            let response = match from.port() == PORT {
+                true => self.response(0, &[], &ours),
+                false => self.response(id, &questions, &ours),
+            };
+            let to = match from.port() == PORT {
+                true => SocketAddr::from((GROUP, PORT)),
+                false => from,
+            };
+            let _ = self.socket.send_to(&response, to);
+        }
+    }
+
+    /// A response packet answering `asked`, repeating `questions`
+    fn response(&self, id: u16, questions: &[(String, u16)], asked: &[(String, u16)]) -> Vec<u8> {
+        let addresses: Vec<Ipv4Addr> = lan::interface_addresses()
+            .into_iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        let mut answers = Vec::new();
+        for (name, kind) in asked {
+            let any = *kind == TYPE_ANY;
+            if name.eq_ignore_ascii_case(&self.host) && (any || *kind == TYPE_A) {
+                for ip in &addresses {
+                    answers.push(record(&self.host, TYPE_A, CLASS_IN_FLUSH, &ip.octets()));
+                }
+            } else if name.eq_ignore_ascii_case(SERVICE) && (any || *kind == TYPE_PTR) {
+                answers.push(record(SERVICE, TYPE_PTR, CLASS_IN, &encode_name(&self.instance)));
+                answers.extend(self.service_records(&addresses));
+            } else if name.eq_ignore_ascii_case(&self.instance) {
+                answers.extend(self.service_records(&addresses));
+            }
+        }
+
+        let mut packet = Vec::new();
+        packet.extend(id.to_be_bytes());
+        // A response, authoritative
+        packet.extend(0x8400u16.to_be_bytes());
+        packet.extend((questions.len() as u16).to_be_bytes());
+        packet.extend((answers.len() as u16).to_be_bytes());
+        packet.extend([0, 0, 0, 0]);
+        for (name, kind) in questions {
+            packet.extend(encode_name(name));
+            packet.extend(kind.to_be_bytes());
+            packet.extend(CLASS_IN.to_be_bytes());
+        }
+        for answer in answers {
+            packet.extend(answer);
+        }
+        packet
+    }
+
+    /// The SRV, TXT and A records of the service instance
+    fn service_records(&self, addresses: &[Ipv4Addr]) -> Vec<Vec<u8>> {
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend(self.port.to_be_bytes());
+        srv.extend(encode_name(&self.host));
+        let mut records = vec![
+            record(&self.instance, TYPE_SRV, CLASS_IN_FLUSH, &srv),
+            record(&self.instance, TYPE_TXT, CLASS_IN_FLUSH, b"\x06path=/"),
+        ];
+        records.extend(addresses.iter().map(|ip| record(&self.host, TYPE_A, CLASS_IN_FLUSH, &ip.octets())));
+        records
+    }
+}
+
+/// A UDP socket on the mDNS port shared with other responders, joined to the group
+#[cfg(unix)]
+fn bind() -> io::Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let on: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let size = std::mem::size_of_val(&on) as libc::socklen_t;
+        let option_value = &on as *const libc::c_int as *const libc::c_void;
+        if unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, option_value, size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        ..unsafe { std::mem::zeroed() }
+    };
+    let size = std::mem::size_of_val(&address) as libc::socklen_t;
+    if unsafe { libc::bind(fd, &address as *const libc::sockaddr_in as *const libc::sockaddr, size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn bind() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT))?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+/// The id and questions (name and type) of a query, `None` for responses and malformed packets
+fn parse_query(packet: &[u8]) -> Option<(u16, Vec<(String, u16)>)> {
+    let header = packet.get(..12)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([header[4], header[5]]);
+    let mut offset = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let (name, next) = read_name(packet, offset)?;
+        let kind = u16::from_be_bytes(packet.get(next..next + 2)?.try_into().ok()?);
+        questions.push((name, kind));
+        offset = next + 4;
+    }
+    Some((id, questions))
+}
+
+/// The dotted name at `offset`, following compression pointers, and the offset after it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so a loop of them ends
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some((labels.join("."), end.unwrap_or(offset + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                let target = (len & 0x3f) << 8 | *packet.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = target;
+            }
+            len => {
+                labels.push(String::from_utf8_lossy(packet.get(offset + 1..offset + 1 + len)?).into_owned());
+                offset += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+fn record(name: &str, kind: u16, class: u16, data: &[u8]) -> Vec<u8> {
+    let mut record = encode_name(name);
+    record.extend(kind.to_be_bytes());
+    record.extend(class.to_be_bytes());
+    record.extend(TTL.to_be_bytes());
+    record.extend((data.len() as u16).to_be_bytes());
+    record.extend(data);
+    record
+}
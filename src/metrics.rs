@@ -0,0 +1,473 @@
+use crate::cache::Usage;
+use crate::listener::Connection;
+use crate::units;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How many of the most requested paths the summary lists
+const TOP_PATHS: usize = 10;
+
+/// Most paths counted one by one, so clients requesting ever new paths can't grow the table without end
+const MAX_PATHS: usize = 1000;
+
+/// Where requests answered 404 are counted, whatever their path
+const NOT_FOUND_PATHS: &str = "(not found)";
+
+/// Where requests for paths beyond [`MAX_PATHS`] are counted
+const OTHER_PATHS: &str = "(other)";
+
+/// Upper bounds of the request duration histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// URL of the Prometheus metrics enabled by --metrics
+pub const PATH: &str = "/_rshttps/metrics";
+
+/// What the handler learned about a request while serving it
+#[derive(Default)]
+pub struct RequestRecord {
+    pub method: String,
+    pub path: String,
+    /// The first line of the request, as sent
+    pub request_line: String,
+    /// Who the request authenticated as
+    pub user: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether the body came from the file cache, when a file was served
+    pub cache_hit: Option<bool>,
+    /// When the request head had been read
+    pub head_read: Option<Instant>,
+    /// The client's address as a --trusted-proxy reported it
+    pub client_ip: Option<IpAddr>,
+    /// Whether the request lets the connection carry another one, for --keep-alive
+    pub keep_alive: bool,
+    /// The request head as received, kept for the inspector
+    pub request_head: Option<String>,
+    /// The share link a download was counted against, given back if the file wasn't served
+    pub share: Option<String>,
+}
+
+/// Where a request's time went, as far as the server saw it
+#[derive(Clone, Copy)]
+pub struct Phases {
+    /// Reading the request head
+    pub reading: Option<Duration>,
+    /// From the request head to the first byte of the response
+    pub handling: Option<Duration>,
+    /// From the first byte of the response to its end
+    pub sending: Option<Duration>,
+}
+
+impl Phases {
+    /// Splits `elapsed`, counted from `started`, at the points the handler recorded
+    pub fn new(record: &RequestRecord, started: Instant, first_byte: Option<Instant>, elapsed: Duration) -> Phases {
+        let head_read = record.head_read;
+        Phases {
+            reading: head_read.map(|head_read| head_read - started),
+            handling: head_read.zip(first_byte).map(|(head_read, first_byte)| first_byte - head_read),
+            sending: first_byte.map(|first_byte| (started + elapsed).saturating_duration_since(first_byte)),
+        }
+    }
+}
+
+/// Server-wide counters, summarized when the server exits
+///
+/// Counters are updated after each handler finishes, including handlers that
+/// panicked, and poisoned locks are recovered so one bad request can't lose
+/// the statistics of all the others.
+pub struct Metrics {
+    started: Instant,
+    requests: AtomicU64,
+    bytes_sent: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    panics: AtomicU64,
+    statuses: Mutex<BTreeMap<u16, u64>>,
+    paths: Mutex<HashMap<String, PathStats>>,
+    /// Requests per [`LATENCY_BUCKETS`] bucket (not cumulative), the last one for slower requests
+    latencies: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_us: AtomicU64,
+}
+
+/// Requests for one path, and how long they took
+#[derive(Clone, Copy, Default)]
+pub struct PathStats {
+    pub requests: u64,
+    pub total: Duration,
+    pub slowest: Duration,
+}
+
+/// The counters at one point in time, for the --tui dashboard
+pub struct Snapshot {
+    pub uptime: Duration,
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub panics: u64,
+    pub statuses: BTreeMap<u16, u64>,
+    /// Paths by their slowest request, slowest first
+    pub slowest_paths: Vec<(String, PathStats)>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            statuses: Mutex::new(BTreeMap::new()),
+            paths: Mutex::new(HashMap::new()),
+            latencies: Default::default(),
+            latency_sum_us: AtomicU64::new(0),
+        }
+    }
+
+    /// Records one handled connection
+    pub fn record(&self, record: &RequestRecord, status: Option<u16>, bytes_sent: u64, elapsed: Duration) {
+        if record.method.is_empty() {
+            return; // The client closed the connection without sending a request
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes_sent, Ordering::Relaxed);
+        match record.cache_hit {
+            Some(true) => self.cache_hits.fetch_add(1, Ordering::Relaxed),
+            Some(false) => self.cache_misses.fetch_add(1, Ordering::Relaxed),
+            None => 0,
+        };
+        if let Some(status) = status {
+            *lock(&self.statuses).entry(status).or_insert(0) += 1;
+        }
+        let mut paths = lock(&self.paths);
+        let key = match status {
+            Some(404) => NOT_FOUND_PATHS,
+            _ if paths.len() >= MAX_PATHS && !paths.contains_key(&record.path) => OTHER_PATHS,
+            _ => &record.path,
+        };
+        let path = paths.entry(key.to_string()).or_default();
+        path.requests += 1;
+        path.total += elapsed;
+        path.slowest = path.slowest.max(elapsed);
+        drop(paths);
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.latencies[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records a handler that panicked instead of returning
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A human-readable report of everything served so far
+    pub fn summary(&self) -> String {
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+
+        let mut report = String::new();
+        let _ = writeln!(report, "=== rshttp summary ===");
+        let _ = writeln!(report, "Uptime:         {}", units::duration(self.started.elapsed()));
+        let _ = writeln!(report, "Requests:       {}", self.requests.load(Ordering::Relaxed));
+        let _ = writeln!(report, "Bytes served:   {}", units::size(self.bytes_sent.load(Ordering::Relaxed)));
+        let _ = writeln!(report, "Handler panics: {}", self.panics.load(Ordering::Relaxed));
+        if hits + misses > 0 {
+            let _ = writeln!(
+                report,
+                "Cache hits:     {} / {} ({:.1}%)",
+                hits,
+                hits + misses,
+                hits as f64 * 100.0 / (hits + misses) as f64
+            );
+        }
+
+        let statuses = lock(&self.statuses);
+        if !statuses.is_empty() {
+            let _ = writeln!(report, "Status codes:");
+            for (status, count) in statuses.iter() {
+                let _ = writeln!(report, "  {}  {}", status, count);
+            }
+        }
+
+        let paths = lock(&self.paths);
+        let mut top: Vec<_> = paths.iter().collect();
+        top.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then_with(|| a.0.cmp(b.0)));
+        if !top.is_empty() {
+            let _ = writeln!(report, "Top paths:");
+            for (path, stats) in top.into_iter().take(TOP_PATHS) {
+                let _ = writeln!(report, "  {:>6}  {}", stats.requests, path);
+            }
+        }
+        report
+    }
+
+    /// The current counters, with the `top` slowest paths
+    pub fn snapshot(&self, top: usize) -> Snapshot {
+        let mut slowest_paths: Vec<_> = lock(&self.paths).iter().map(|(path, stats)| (path.clone(), *stats)).collect();
+        slowest_paths.sort_by(|a, b| b.1.slowest.cmp(&a.1.slowest).then_with(|| a.0.cmp(&b.0)));
+        slowest_paths.truncate(top);
+        Snapshot {
+            uptime: self.started.elapsed(),
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            statuses: lock(&self.statuses).clone(),
+            slowest_paths,
+        }
+    }
+}
+
+impl Metrics {
+    /// The counters in the Prometheus text exposition format, served at [`PATH`]
+    pub fn prometheus(&self, active_connections: usize, cache: Option<Usage>) -> String {
+        let mut out = Exposition(String::new());
+        let statuses: Vec<_> = lock(&self.statuses)
+            .iter()
+            .map(|(status, count)| (format!("{{status=\"{}\"}}", status), count.to_string()))
+            .collect();
+        out.metric("requests_total", "counter", "Requests handled, by response status.", &statuses);
+
+        let bounds = LATENCY_BUCKETS.iter().map(f64::to_string).chain(["+Inf".to_string()]);
+        let mut buckets = Vec::new();
+        let mut cumulative = 0;
+        for (bound, count) in bounds.zip(&self.latencies) {
+            cumulative += count.load(Ordering::Relaxed);
+            buckets.push((format!("_bucket{{le=\"{}\"}}", bound), cumulative.to_string()));
+        }
+        let sum = self.latency_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        buckets.push(("_sum".to_string(), sum.to_string()));
+        buckets.push(("_count".to_string(), cumulative.to_string()));
+        let help = "Time from accepting a connection to finishing its response.";
+        out.metric("request_duration_seconds", "histogram", help, &buckets);
+
+        out.value("sent_bytes_total", "counter", "Bytes written to clients, headers included.", &self.bytes_sent);
+        out.value("cache_hits_total", "counter", "Files served from the file cache.", &self.cache_hits);
+        out.value("cache_misses_total", "counter", "Files read from disk as they weren't cached.", &self.cache_misses);
+        out.value("handler_panics_total", "counter", "Requests whose handler panicked.", &self.panics);
+        let help = "Connections in flight, including those waiting for a worker.";
+        out.value("active_connections", "gauge", help, &active_connections);
+        if let Some(usage) = cache {
+            out.value("cache_entries", "gauge", "Files in the memory cache.", &usage.entries);
+            out.value("cache_bytes", "gauge", "Bytes held by the memory cache.", &usage.bytes);
+            out.value("cache_budget_bytes", "gauge", "Most bytes the memory cache may hold.", &usage.budget);
+        }
+        out.value("uptime_seconds", "gauge", "Seconds since the server started.", &self.started.elapsed().as_secs());
+        out.0
+    }
+}
+
+/// Prometheus text format being written
+struct Exposition(String);
+
+impl Exposition {
+    fn metric(&mut self, name: &str, kind: &str, help: &str, samples: &[(String, String)]) {
+        let _ = writeln!(self.0, "# HELP rshttp_{} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE rshttp_{} {}", name, kind);
+        for (suffix, value) in samples {
+            let _ = writeln!(self.0, "rshttp_{}{} {}", name, suffix, value);
+        }
+    }
+
+    fn value(&mut self, name: &str, kind: &str, help: &str, value: &dyn std::fmt::Debug) {
+        self.metric(name, kind, help, &[(String::new(), format!("{:?}", value))]);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Wraps a connection to count the bytes written and capture the response status
+pub struct Metered<S> {
+    inner: S,
+    pub bytes_sent: u64,
+    pub status: Option<u16>,
+    /// When the response started
+    pub first_byte: Option<Instant>,
+    /// The response's status line and headers, once [`Metered::capture_head`] asked for them
+    pub head: Option<String>,
+    /// The response body, or its start, once [`Metered::capture_body`] asked for it
+    pub body: Option<Vec<u8>>,
+    body_limit: usize,
+    /// Whether the body breaks off halfway, see [`Connection::cut_short`]
+    cut_short: bool,
+    /// Whether the response head leaves the connection unusable for another response
+    closes: bool,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S) -> Metered<S> {
+        Metered {
+            inner,
+            bytes_sent: 0,
+            status: None,
+            first_byte: None,
+            head: None,
+            body: None,
+            body_limit: 0,
+            cut_short: false,
+            closes: false,
+        }
+    }
+
+    /// Starts over for the next response on a kept-alive connection
+    pub fn next_response(&mut self) {
+        self.bytes_sent = 0;
+        self.status = None;
+        self.first_byte = None;
+        self.head = self.head.as_ref().map(|_| String::new());
+        self.body = self.body.as_ref().map(|_| Vec::new());
+        self.cut_short = false;
+        self.closes = false;
+    }
+
+    /// Whether the connection can't carry another response after this one
+    ///
+    /// That is when nothing was sent, the body was cut short, or the head
+    /// asked to close or gave no Content-Length to tell where the body ends.
+    pub fn closes(&self) -> bool {
+        self.status.is_none() || self.cut_short || self.closes
+    }
+
+    /// Keeps the head of the response in [`Metered::head`]
+    pub fn capture_head(&mut self) {
+        self.head = Some(String::new());
+    }
+
+    /// Keeps up to `limit` bytes of the response body in [`Metered::body`]
+    pub fn capture_body(&mut self, limit: usize) {
+        self.body = Some(Vec::new());
+        self.body_limit = limit;
+    }
+}
+
+impl<S: Read> Read for Metered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Metered<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let starts = self.bytes_sent == 0 && self.status.is_none();
+        let head_end = match starts {
+            true => buf.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or(buf.len()),
+            false => 0,
+        };
+        if starts {
+            // Responses start with "HTTP/1.1 NNN"
+            self.status = buf
+                .get(9..12)
+                .and_then(|code| std::str::from_utf8(code).ok())
+                .and_then(|code| code.parse().ok());
+            self.first_byte = Some(Instant::now());
+            let lower = String::from_utf8_lossy(&buf[..head_end]).to_ascii_lowercase();
+            self.closes = lower.contains("\r\nconnection: close") || !lower.contains("\r\ncontent-length:");
+            // The head is written in one piece
+            if let Some(head) = &mut self.head {
+                *head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+            }
+        }
+        let body_start = if starts { (head_end + 4).min(buf.len()) } else { 0 };
+        let buf = match self.cut_short && buf.len() > body_start {
+            true => &buf[..body_start + (buf.len() - body_start) / 2],
+            false => buf,
+        };
+        let written = self.inner.write(buf)?;
+        self.bytes_sent += written as u64;
+        if let Some(body) = &mut self.body {
+            let body_start = if starts { (head_end + 4).min(written) } else { 0 };
+            let room = self.body_limit.saturating_sub(body.len());
+            let bytes = &buf[body_start..written];
+            body.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        }
+        if self.cut_short && written == buf.len() && buf.len() > body_start {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "response cut short by --chaos"));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Connection> Connection for Metered<S> {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.inner.peer_ip()
+    }
+
+    fn streams(&self) -> bool {
+        self.inner.streams()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn cut_short(&mut self) {
+        self.cut_short = true;
+    }
+
+    fn wait_for_request(&self, idle: Duration) -> bool {
+        self.inner.wait_for_request(idle)
+    }
+
+    fn write_file(&mut self, file: &std::fs::File, offset: u64, len: u64) -> io::Result<()> {
+        let len = if self.cut_short { len / 2 } else { len };
+        if let Some(body) = &mut self.body {
+            let room = self.body_limit.saturating_sub(body.len()) as u64;
+            let mut file = file;
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(len.min(room)).read_to_end(body)?;
+        }
+        self.inner.write_file(file, offset, len)?;
+        self.bytes_sent += len;
+        if self.cut_short {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "response cut short by --chaos"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> RequestRecord {
+        RequestRecord {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn paths_are_capped() {
+        let metrics = Metrics::new();
+        for n in 0..MAX_PATHS + 50 {
+            metrics.record(&request(&format!("/missing/{}", n)), Some(404), 0, Duration::ZERO);
+            metrics.record(&request(&format!("/file/{}", n)), Some(200), 0, Duration::ZERO);
+        }
+        metrics.record(&request("/file/0"), Some(200), 0, Duration::ZERO);
+        let paths = lock(&metrics.paths);
+        assert!(paths.len() <= MAX_PATHS + 1);
+        assert_eq!(paths[NOT_FOUND_PATHS].requests, MAX_PATHS as u64 + 50);
+        assert_eq!(paths["/file/0"].requests, 2);
+        assert!(paths[OTHER_PATHS].requests > 0);
+    }
+}
@@ -0,0 +1,390 @@
+//! Request and response interception for [`Server`](crate::Server)
+//!
+//! Middlewares run in the order they were added with
+//! [`ServerBuilder::middleware`](crate::ServerBuilder::middleware).
+//! [`Middleware::on_request`] may change the request or answer it itself,
+//! which skips the middlewares after it and the file lookup;
+//! [`Middleware::on_response`] then runs in reverse order on the middlewares
+//! whose `on_request` ran, so the first one added sees the final response.
+//!
+//! ```no_run
+//! use rshttp::middleware::{AccessLog, BasicAuth, Compression, Headers};
+//!
+//! let server = rshttp::Server::builder()
+//!     .middleware(AccessLog::stderr())
+//!     .middleware(BasicAuth::new("admin", "secret"))
+//!     .middleware(Headers::new().add("X-Frame-Options", "DENY"))
+//!     .middleware(Compression::default())
+//!     .build()?;
+//! server.serve()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::auth::{self, AuthProvider, Principal};
+use crate::date;
+use crate::response::Response;
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A parsed request as the middlewares see it
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    pub peer: Option<SocketAddr>,
+    pub method: String,
+    /// Normalized, without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Who sent the request, once an [`Authenticate`] middleware accepted its credentials
+    pub principal: Option<Principal>,
+}
+
+impl Incoming {
+    /// The value of the first header named `name`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let mut headers = self.headers.iter();
+        headers.find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// A step of the chain every request of a [`Server`](crate::Server) goes through
+pub trait Middleware: Send + Sync {
+    /// Runs before the file is looked up; returning a response answers the request with it
+    fn on_request(&self, _request: &mut Incoming) -> Option<Response<'static>> {
+        None
+    }
+
+    /// Runs on the response before it is sent, returning the one to send instead
+    fn on_response(&self, _request: &Incoming, response: Response<'static>) -> Response<'static> {
+        response
+    }
+}
+
+/// Runs `request` through `chain`, with `answer` producing the response when no middleware does
+pub fn run(
+    chain: &[Box<dyn Middleware>],
+    request: &mut Incoming,
+    answer: impl FnOnce(&Incoming) -> Response<'static>,
+) -> Response<'static> {
+    let mut ran = 0;
+    let mut response = None;
+    for middleware in chain {
+        ran += 1;
+        response = middleware.on_request(request);
+        if response.is_some() {
+            break;
+        }
+    }
+    let mut response = response.unwrap_or_else(|| answer(request));
+    for middleware in chain[..ran].iter().rev() {
+        response = middleware.on_response(request, response);
+    }
+    response
+}
+
+/// Writes a line in the Common Log Format for every response
+pub struct AccessLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn stderr() -> AccessLog {
+        AccessLog::to(io::stderr())
+    }
+
+    pub fn to(out: impl Write + Send + 'static) -> AccessLog {
+        AccessLog {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+}
+
+impl Middleware for AccessLog {
+    fn on_response(&self, request: &Incoming, response: Response<'static>) -> Response<'static> {
+        let peer = request.peer.map(|peer| peer.ip().to_string()).unwrap_or_else(|| "-".to_string());
+        let target = match &request.query {
+            Some(query) => format!("{}?{}", request.path, query),
+            None => request.path.clone(),
+        };
+        let line = format!(
+            "{} - - [{}] \"{} {} HTTP/1.1\" {} {}",
+            peer,
+            date::clf_timestamp(SystemTime::now(), 0),
+            request.method,
+            target,
+            response.status(),
+            response.contents().len()
+        );
+        let mut out = self.out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = writeln!(out, "{}", line);
+        response
+    }
+}
+
+/// Answers 401 to requests whose credentials none of its [`AuthProvider`]s accept
+///
+/// The principal they resolve to is set on the request for the middlewares after it.
+pub struct Authenticate {
+    providers: Vec<Box<dyn AuthProvider>>,
+    realm: String,
+}
+
+impl Authenticate {
+    pub fn new(provider: impl AuthProvider + 'static) -> Authenticate {
+        Authenticate {
+            providers: vec![Box::new(provider)],
+            realm: "rshttp".to_string(),
+        }
+    }
+
+    /// Also accepts the credentials `provider` accepts, asking it after the ones before
+    pub fn or(mut self, provider: impl AuthProvider + 'static) -> Authenticate {
+        self.providers.push(Box::new(provider));
+        self
+    }
+
+    pub fn realm(mut self, realm: impl Into<String>) -> Authenticate {
+        self.realm = realm.into();
+        self
+    }
+}
+
+impl Middleware for Authenticate {
+    fn on_request(&self, request: &mut Incoming) -> Option<Response<'static>> {
+        match auth::authenticate(&self.providers, request.header("Authorization")) {
+            Some(principal) => {
+                request.principal = Some(principal);
+                None
+            }
+            None => {
+                let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm);
+                Some(Response::error(401).header("WWW-Authenticate", challenge))
+            }
+        }
+    }
+}
+
+/// Answers 401 to requests without the given user and password
+///
+/// The same check as `--auth USER:PASSWORD`, on top of [`Authenticate`].
+pub struct BasicAuth(Authenticate);
+
+impl BasicAuth {
+    pub fn new(user: &str, password: &str) -> BasicAuth {
+        BasicAuth(Authenticate::new(auth::BasicAuth::new(user, password)))
+    }
+
+    pub fn realm(self, realm: impl Into<String>) -> BasicAuth {
+        BasicAuth(self.0.realm(realm))
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn on_request(&self, request: &mut Incoming) -> Option<Response<'static>> {
+        self.0.on_request(request)
+    }
+}
+
+/// Adds fixed headers to every response
+#[derive(Default)]
+pub struct Headers {
+    headers: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    pub fn add(mut self, name: impl Into<String>, value: impl Into<String>) -> Headers {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+impl Middleware for Headers {
+    fn on_response(&self, _request: &Incoming, mut response: Response<'static>) -> Response<'static> {
+        for (name, value) in &self.headers {
+            response = response.header(name, value);
+        }
+        response
+    }
+}
+
+/// Bodies smaller than this aren't worth compressing
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// A content coding response bodies are compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The Content-Encoding value
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// `contents` compressed with this coding
+    pub fn encode(self, contents: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoded = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams {
+                    quality: 9,
+                    ..Default::default()
+                };
+                // Writing to a Vec can't fail
+                let _ = brotli::BrotliCompress(&mut &contents[..], &mut encoded, &params);
+                encoded
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                let _ = encoder.write_all(contents);
+                encoder.finish().unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// Gzips text responses of at least `min_size` bytes for clients accepting it
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            min_size: MIN_COMPRESSED_SIZE,
+        }
+    }
+}
+
+impl Compression {
+    pub fn min_size(mut self, bytes: usize) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+}
+
+impl Middleware for Compression {
+    fn on_response(&self, request: &Incoming, response: Response<'static>) -> Response<'static> {
+        let accepts_gzip = accepts(request.header("Accept-Encoding"), "gzip");
+        let compressible = response.get_header("Content-Type").is_some_and(is_compressible);
+        if response.status() != 200
+            || !accepts_gzip
+            || !compressible
+            || response.contents().len() < self.min_size
+            || response.get_header("Content-Encoding").is_some()
+        {
+            return response;
+        }
+        let compressed = Encoding::Gzip.encode(response.contents());
+        response.header("Content-Encoding", "gzip").header("Vary", "Accept-Encoding").body(compressed)
+    }
+}
+
+/// Whether an Accept-Encoding header lets the response be sent in `coding`
+///
+/// The coding has to be listed, or covered by `*`, without `q=0`.
+pub fn accepts(accept_encoding: Option<&str>, coding: &str) -> bool {
+    // Each listed coding, and whether it is refused
+    let codings: Vec<(&str, bool)> = (accept_encoding.unwrap_or("").split(','))
+        .map(|listed| {
+            let mut parts = listed.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let refused = parts.any(|parameter| {
+                let q = parameter.trim().strip_prefix("q=");
+                q.is_some_and(|q| q.trim().parse::<f32>().is_ok_and(|q| q == 0.0))
+            });
+            (name, refused)
+        })
+        .collect();
+    let listed = codings.iter().find(|(name, _)| name.eq_ignore_ascii_case(coding));
+    match listed.or_else(|| codings.iter().find(|(name, _)| *name == "*")) {
+        Some((_, refused)) => !refused,
+        None => false,
+    }
+}
+
+/// Whether responses of this Content-Type are worth compressing
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || ["application/javascript", "application/json", "application/xml", "image/svg+xml"].contains(&essence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_encoding_q_values() {
+        assert!(accepts(Some("gzip, deflate"), "gzip"));
+        assert!(accepts(Some("GZIP;q=0.5"), "gzip"));
+        assert!(accepts(Some("*"), "gzip"));
+        assert!(!accepts(None, "gzip"));
+        assert!(!accepts(Some("gzip;q=0"), "gzip"));
+        assert!(!accepts(Some("gzip; q=0.000, br"), "gzip"));
+        assert!(!accepts(Some("*, gzip;q=0"), "gzip"));
+        assert!(!accepts(Some("*;q=0"), "gzip"));
+        assert!(!accepts(Some("deflate"), "gzip"));
+    }
+
+    struct Directory;
+
+    impl AuthProvider for Directory {
+        fn authenticate(&self, credentials: &auth::Credentials) -> Option<Principal> {
+            match credentials {
+                auth::Credentials::Bearer(token) if token == "let-me-in" => Some(Principal {
+                    name: "ada".to_string(),
+                    groups: Vec::new(),
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    fn incoming(authorization: Option<&str>) -> Incoming {
+        Incoming {
+            peer: None,
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            query: None,
+            headers: authorization.map(|value| ("Authorization".to_string(), value.to_string())).into_iter().collect(),
+            principal: None,
+        }
+    }
+
+    #[test]
+    fn any_provider_can_authenticate() {
+        let authenticate = Authenticate::new(auth::BasicAuth::parse("admin:secret").unwrap()).or(Directory);
+        let mut request = incoming(Some("Bearer let-me-in"));
+        assert!(authenticate.on_request(&mut request).is_none());
+        assert_eq!(request.principal.unwrap().name, "ada");
+        assert!(authenticate.on_request(&mut incoming(Some("Basic YWRtaW46c2VjcmV0"))).is_none());
+
+        let refused = authenticate.on_request(&mut incoming(Some("Bearer guess"))).unwrap();
+        assert_eq!(refused.status(), 401);
+        assert!(authenticate.on_request(&mut incoming(None)).is_some());
+    }
+
+    #[test]
+    fn basic_auth_checks_the_decoded_credentials() {
+        let basic = BasicAuth::new("admin", "secret").realm("files");
+        assert!(basic.on_request(&mut incoming(Some("basic  YWRtaW46c2VjcmV0"))).is_none());
+        assert!(basic.on_request(&mut incoming(Some("Basic YWRtaW46c2VjcmV1"))).is_some());
+        let refused = basic.on_request(&mut incoming(None)).unwrap();
+        assert_eq!(refused.status(), 401);
+    }
+}
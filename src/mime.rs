@@ -0,0 +1,57 @@
+use rshttp::response;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Content types of served files: mime_guess's, unless --mime maps the extension to another
+///
+/// Text types get the --charset parameter unless they already name one.
+pub struct MimeTypes {
+    /// Lowercase extensions without the dot
+    overrides: HashMap<String, String>,
+    charset: Option<String>,
+}
+
+impl MimeTypes {
+    pub fn new(overrides: &[(String, String)], charset: Option<&str>) -> MimeTypes {
+        MimeTypes {
+            overrides: overrides.iter().cloned().collect(),
+            charset: charset.map(str::to_string),
+        }
+    }
+
+    /// The content type of `path`, `application/octet-stream` when neither the overrides nor mime_guess know it
+    pub fn of(&self, path: &Path) -> String {
+        let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+        let mime_type = match extension.and_then(|extension| self.overrides.get(&extension)) {
+            Some(mime_type) => mime_type.clone(),
+            None => mime_guess::from_path(path).first_or_octet_stream().to_string(),
+        };
+        match &self.charset {
+            Some(charset) => response::with_charset(&mime_type, charset),
+            None => mime_type,
+        }
+    }
+}
+
+/// Parses an `EXT=TYPE` override such as `ts=text/typescript` or `txt=text/plain; charset=latin1`
+///
+/// A bare TYPE is the type of what --stdin serves, and comes back with an
+/// empty extension, which no file has.
+pub fn parse_override(value: &str) -> Result<(String, String), String> {
+    let invalid = || format!("invalid MIME type override '{}' (expected e.g. ts=text/typescript)", value);
+    let (extension, mime_type) = match value.split_once('=') {
+        Some((extension, mime_type)) if !extension.contains(['/', ';']) => (extension, mime_type),
+        // A bare TYPE, whose = belongs to a parameter such as charset=latin1
+        _ => ("", value),
+    };
+    let bare = extension.is_empty();
+    let extension = extension.trim().trim_start_matches('.').to_ascii_lowercase();
+    let mime_type = mime_type.trim();
+    let essence = mime_type.split(';').next().unwrap_or("").trim();
+    let (kind, subtype) = essence.split_once('/').ok_or_else(invalid)?;
+    let valid = !kind.is_empty() && !subtype.is_empty() && !essence.contains(char::is_whitespace);
+    if !valid || (extension.is_empty() && !bare) {
+        return Err(invalid());
+    }
+    Ok((extension, mime_type.to_string()))
+}
@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+
+/// A read-only view of a whole file, served without copying it onto the heap
+///
+/// Truncating the file while it is mapped makes reads past the new end fault
+/// (SIGBUS), so only files that are replaced rather than rewritten in place
+/// are safe to serve this way.
+pub struct Mapping {
+    address: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by whoever holds it
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the first `len` bytes of `file`, hinting that they will be read front to back
+    pub fn new(file: &File, len: usize) -> io::Result<Mapping> {
+        if len == 0 {
+            // mmap refuses empty mappings
+            return Ok(Mapping {
+                address: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let address = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Only a hint, serving works the same without it
+        unsafe { libc::madvise(address, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mapping { address, len })
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.address as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.address, self.len) };
+        }
+    }
+}
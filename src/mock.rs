@@ -0,0 +1,85 @@
+use rshttp::response::Response;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A fake JSON API from a directory of files, json-server style, from --mock
+///
+/// With `--mock mocks`, `mocks/users.json` answers `/api/users`, and when it
+/// holds an array, `/api/users/7` answers with its element whose `id` is 7
+/// (as a number or a string). Files are read on every request, so edits show
+/// up without a restart.
+pub struct Mocks {
+    dir: PathBuf,
+    prefix: String,
+    latency: Duration,
+}
+
+impl Mocks {
+    pub fn new(dir: &Path, prefix: &str, latency: Duration) -> Mocks {
+        Mocks {
+            dir: dir.to_path_buf(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            latency,
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The number of resources, one per JSON file
+    pub fn len(&self) -> usize {
+        fs::read_dir(&self.dir)
+            .map(|entries| {
+                let files = entries.filter_map(Result::ok).map(|entry| entry.path());
+                files.filter(|path| path.extension().is_some_and(|extension| extension == "json")).count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Answers a path below the prefix after the configured latency, or returns `None` for other paths
+    pub fn respond(&self, path: &str) -> Option<Response<'static>> {
+        let rest = path.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let mut segments = rest.trim_end_matches('/').split('/');
+        let (resource, id) = (segments.next()?, segments.next());
+        if segments.next().is_some() || resource.is_empty() || resource.contains('.') {
+            return Some(Response::error(404));
+        }
+        if !self.latency.is_zero() {
+            std::thread::sleep(self.latency);
+        }
+        let file = self.dir.join(format!("{}.json", resource));
+        let Ok(contents) = fs::read(&file) else {
+            return Some(Response::error(404));
+        };
+        let Some(id) = id else {
+            return Some(json_response(200, contents));
+        };
+        let items = match serde_json::from_slice::<Value>(&contents) {
+            Ok(Value::Array(items)) => items,
+            Ok(_) => return Some(Response::error(404)),
+            Err(e) => {
+                let message = format!("{} is not valid JSON: {}\n", file.display(), e);
+                return Some(Response::new(500).header("Content-Type", "text/plain").body(message.into_bytes()));
+            }
+        };
+        let item = items.into_iter().find(|item| match item.get("id") {
+            Some(Value::String(value)) => value == id,
+            Some(Value::Number(value)) => value.to_string() == id,
+            _ => false,
+        });
+        Some(match item {
+            Some(item) => json_response(200, serde_json::to_vec_pretty(&item).unwrap_or_default()),
+            None => json_response(404, b"{}".to_vec()),
+        })
+    }
+}
+
+fn json_response(status: u16, body: Vec<u8>) -> Response<'static> {
+    Response::new(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(body)
+}
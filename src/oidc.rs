@@ -0,0 +1,392 @@
+use crate::auth::Principal;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rshttp::request;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const CALLBACK_PATH: &str = "/_rshttps/oidc/callback";
+const SESSION_COOKIE: &str = "rshttps_session";
+const STATE_COOKIE: &str = "rshttps_oidc_state";
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Most logins waiting for their callback, the oldest given up on beyond this
+///
+/// Anyone can start a login, so without a bound a flood of requests would fill memory.
+const MAX_PENDING_LOGINS: usize = 10_000;
+
+/// Most sessions held at once, those closest to expiring dropped beyond this
+const MAX_SESSIONS: usize = 100_000;
+
+/// Outcome of running a request through the OIDC relying party
+pub enum OidcOutcome {
+    /// Send the browser to this location, setting these cookies
+    Redirect { location: String, cookies: Vec<String> },
+    /// The callback was invalid or the token exchange failed
+    Rejected(String),
+}
+
+/// A login started by redirecting to the identity provider
+struct PendingLogin {
+    nonce: String,
+    return_to: String,
+    started: Instant,
+}
+
+/// A browser session established after a successful callback
+struct Session {
+    principal: Principal,
+    expires: Instant,
+}
+
+/// OpenID Connect relying party using the authorization code flow
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    /// Whether cookies are marked Secure, when the callback is reached over https
+    secure: bool,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks: JwkSet,
+    signing_algorithms: Vec<Algorithm>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl OidcClient {
+    /// Fetches the provider metadata and signing keys from the issuer
+    ///
+    /// `redirect_url` is the public address of the callback path registered
+    /// with the provider.
+    pub fn discover(issuer: &str, client_id: &str, client_secret: &str, redirect_url: &str) -> Result<OidcClient, String> {
+        let callback = url::Url::parse(redirect_url).map_err(|e| format!("invalid redirect URL {}: {}", redirect_url, e))?;
+        if callback.path() != CALLBACK_PATH {
+            return Err(format!("the redirect URL has to end in {}", CALLBACK_PATH));
+        }
+        let issuer = issuer.trim_end_matches('/').to_string();
+        let metadata = fetch_json(&format!("{}/.well-known/openid-configuration", issuer))?;
+        let endpoint = |name: &str| {
+            metadata[name]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| format!("provider metadata is missing {}", name))
+        };
+        let jwks_uri = endpoint("jwks_uri")?;
+        let jwks = serde_json::from_value(fetch_json(&jwks_uri)?)
+            .map_err(|e| format!("invalid JWKS at {}: {}", jwks_uri, e))?;
+        // Keys without an `alg` are held to what the provider says it signs
+        // with, RS256 if it doesn't say; shared-secret algorithms never apply
+        let signing_algorithms = match metadata["id_token_signing_alg_values_supported"].as_array() {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| name.as_str()?.parse().ok())
+                .filter(|algorithm| !matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+                .collect(),
+            None => vec![Algorithm::RS256],
+        };
+
+        Ok(OidcClient {
+            authorization_endpoint: endpoint("authorization_endpoint")?,
+            token_endpoint: endpoint("token_endpoint")?,
+            issuer,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            redirect_url: redirect_url.to_string(),
+            secure: callback.scheme() == "https",
+            jwks,
+            signing_algorithms,
+            pending: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Completes a callback, or starts a new login for the requested page
+    ///
+    /// The login's state is also set in a short-lived cookie, so a callback
+    /// is only completed in the browser that started it.
+    pub fn login(&self, path: &str, query: &str, cookies: Option<&str>) -> OidcOutcome {
+        if path == CALLBACK_PATH {
+            return match self.complete_login(query, cookies) {
+                Ok((session_id, return_to)) => OidcOutcome::Redirect {
+                    location: return_to,
+                    cookies: vec![
+                        format!("{}={}; Path=/; {}", SESSION_COOKIE, session_id, self.cookie_attributes()),
+                        format!("{}=; Path={}; {}; Max-Age=0", STATE_COOKIE, CALLBACK_PATH, self.cookie_attributes()),
+                    ],
+                },
+                Err(e) => OidcOutcome::Rejected(e),
+            };
+        }
+
+        let state = random_token();
+        let nonce = random_token();
+        let return_to = if query.is_empty() {
+            request::encode_path(path)
+        } else {
+            format!("{}?{}", request::encode_path(path), query)
+        };
+
+        let mut location = match url::Url::parse(&self.authorization_endpoint) {
+            Ok(url) => url,
+            Err(e) => return OidcOutcome::Rejected(format!("invalid authorization endpoint: {}", e)),
+        };
+        location
+            .query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", "openid profile email")
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce);
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+        if pending.len() >= MAX_PENDING_LOGINS {
+            let oldest = pending.iter().min_by_key(|(_, login)| login.started).map(|(state, _)| state.clone());
+            pending.remove(&oldest.unwrap_or_default());
+        }
+        let cookie = format!(
+            "{}={}; Path={}; {}; Max-Age={}",
+            STATE_COOKIE,
+            state,
+            CALLBACK_PATH,
+            self.cookie_attributes(),
+            LOGIN_TIMEOUT.as_secs()
+        );
+        pending.insert(
+            state,
+            PendingLogin {
+                nonce,
+                return_to,
+                started: Instant::now(),
+            },
+        );
+
+        OidcOutcome::Redirect {
+            location: location.into(),
+            cookies: vec![cookie],
+        }
+    }
+
+    /// What the login cookies are set with besides their path
+    fn cookie_attributes(&self) -> &'static str {
+        match self.secure {
+            true => "HttpOnly; SameSite=Lax; Secure",
+            false => "HttpOnly; SameSite=Lax",
+        }
+    }
+
+    /// The principal behind the session cookie, if any
+    pub fn session_principal(&self, cookies: Option<&str>) -> Option<Principal> {
+        self.session(cookies?)
+    }
+
+    /// Looks up a live session from the Cookie header
+    fn session(&self, cookies: &str) -> Option<Principal> {
+        let session_id = cookie(cookies, SESSION_COOKIE)?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(session_id) {
+            Some(session) if session.expires > Instant::now() => Some(session.principal.clone()),
+            Some(_) => {
+                sessions.remove(session_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Exchanges the authorization code and validates the returned ID token
+    fn complete_login(&self, query: &str, cookies: Option<&str>) -> Result<(String, String), String> {
+        let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        if let Some(error) = params.get("error") {
+            return Err(format!("provider returned error: {}", error));
+        }
+        let code = params.get("code").ok_or("callback is missing code")?;
+        let state = params.get("state").ok_or("callback is missing state")?;
+        if cookies.and_then(|cookies| cookie(cookies, STATE_COOKIE)) != Some(state.as_str()) {
+            return Err("login state does not belong to this browser".to_string());
+        }
+
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.started.elapsed() < LOGIN_TIMEOUT)
+            .ok_or("unknown or expired login state")?;
+
+        let response = ureq::post(&self.token_endpoint)
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .map_err(|e| format!("token request failed: {}", e))?
+            .into_string()
+            .map_err(|e| format!("token response unreadable: {}", e))?;
+        let tokens: serde_json::Value =
+            serde_json::from_str(&response).map_err(|e| format!("token response is not JSON: {}", e))?;
+        let id_token = tokens["id_token"].as_str().ok_or("token response has no id_token")?;
+
+        let claims = self.validate_id_token(id_token)?;
+        if claims["nonce"].as_str() != Some(login.nonce.as_str()) {
+            return Err("ID token nonce does not match".to_string());
+        }
+
+        let name = ["email", "preferred_username", "sub"]
+            .iter()
+            .find_map(|claim| claims[*claim].as_str())
+            .unwrap_or("")
+            .to_string();
+        let groups = claims["groups"]
+            .as_array()
+            .map(|groups| groups.iter().filter_map(|g| g.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        let lifetime = claims["exp"]
+            .as_u64()
+            .and_then(|exp| {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+                exp.checked_sub(now.as_secs())
+            })
+            .unwrap_or(3600);
+
+        let session_id = random_token();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > Instant::now());
+        if sessions.len() >= MAX_SESSIONS {
+            let closest = sessions.iter().min_by_key(|(_, session)| session.expires).map(|(id, _)| id.clone());
+            sessions.remove(&closest.unwrap_or_default());
+        }
+        sessions.insert(
+            session_id.clone(),
+            Session {
+                principal: Principal { name, groups },
+                expires: Instant::now() + Duration::from_secs(lifetime),
+            },
+        );
+        Ok((session_id, login.return_to))
+    }
+
+    /// Verifies signature, issuer, audience, and expiry of an ID token
+    fn validate_id_token(&self, id_token: &str) -> Result<serde_json::Value, String> {
+        let header = decode_header(id_token).map_err(|e| format!("malformed ID token: {}", e))?;
+        let jwk = match &header.kid {
+            Some(kid) => self.jwks.find(kid),
+            None => self.jwks.keys.first(),
+        }
+        .ok_or("no matching signing key for ID token")?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("unusable signing key: {}", e))?;
+
+        // The algorithm comes from the key or the provider, never from the token
+        let allowed = match jwk.common.key_algorithm.map(|algorithm| algorithm.to_string().parse::<Algorithm>()) {
+            Some(Ok(algorithm)) => vec![algorithm],
+            Some(Err(_)) => return Err("signing key has an unsupported algorithm".to_string()),
+            None => self.signing_algorithms.clone(),
+        };
+        if !allowed.contains(&header.alg) {
+            return Err(format!("ID token is signed with {:?}, expected one of {:?}", header.alg, allowed));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        decode::<serde_json::Value>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| format!("ID token rejected: {}", e))
+    }
+}
+
+/// The value of cookie `name` in a Cookie header
+fn cookie<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// GETs a URL and parses the body as JSON
+fn fetch_json(url: &str) -> Result<serde_json::Value, String> {
+    let body = ureq::get(url)
+        .call()
+        .map_err(|e| format!("failed to fetch {}: {}", url, e))?
+        .into_string()
+        .map_err(|e| format!("failed to read {}: {}", url, e))?;
+    serde_json::from_str(&body).map_err(|e| format!("invalid JSON from {}: {}", url, e))
+}
+
+/// A random URL-safe token for states, nonces, and session ids
+fn random_token() -> String {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes).expect("Failed to read random bytes");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn client(keys: serde_json::Value) -> OidcClient {
+        OidcClient {
+            issuer: "https://id.example.com".to_string(),
+            client_id: "rshttp".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: format!("https://files.example.com{}", CALLBACK_PATH),
+            secure: true,
+            authorization_endpoint: "https://id.example.com/authorize".to_string(),
+            token_endpoint: "https://id.example.com/token".to_string(),
+            jwks: serde_json::from_value(keys).unwrap(),
+            signing_algorithms: vec![Algorithm::RS256],
+            pending: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn tokens_cannot_pick_their_own_algorithm() {
+        let client = client(serde_json::json!({"keys": [{"kty": "RSA", "kid": "k", "n": "AQAB", "e": "AQAB"}]}));
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("k".to_string());
+        let claims = serde_json::json!({"iss": "https://id.example.com", "aud": "rshttp", "exp": u32::MAX});
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"AQAB")).unwrap();
+        let error = client.validate_id_token(&token).unwrap_err();
+        assert!(error.contains("expected one of"), "{}", error);
+    }
+
+    #[test]
+    fn callbacks_need_the_state_cookie() {
+        let client = client(serde_json::json!({"keys": []}));
+        let OidcOutcome::Redirect { cookies, .. } = client.login("/page", "", None) else {
+            panic!("login did not redirect");
+        };
+        let state = cookie(cookies[0].split(';').next().unwrap(), STATE_COOKIE).unwrap().to_string();
+        let query = format!("code=c&state={}", state);
+        let error = client.complete_login(&query, Some("rshttps_oidc_state=other")).unwrap_err();
+        assert!(error.contains("this browser"), "{}", error);
+    }
+
+    #[test]
+    fn pending_logins_are_bounded() {
+        let client = client(serde_json::json!({"keys": []}));
+        let OidcOutcome::Redirect { cookies, .. } = client.login("/first", "", None) else {
+            panic!("login did not redirect");
+        };
+        assert!(cookies[0].contains("; Secure"), "{}", cookies[0]);
+        let first = cookie(cookies[0].split(';').next().unwrap(), STATE_COOKIE).unwrap().to_string();
+        for _ in 0..MAX_PENDING_LOGINS {
+            client.login("/page", "", None);
+        }
+        let pending = client.pending.lock().unwrap();
+        assert_eq!(pending.len(), MAX_PENDING_LOGINS);
+        assert!(!pending.contains_key(&first));
+    }
+}
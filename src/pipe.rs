@@ -0,0 +1,127 @@
+use crate::listener::Connection;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::ptr;
+use windows_sys::Win32::Foundation::{ERROR_BROKEN_PIPE, ERROR_NO_DATA, ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE};
+use windows_sys::Win32::Storage::FileSystem::{FlushFileBuffers, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX};
+use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+    PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+
+/// Size of each pipe instance's buffers, in both directions
+const BUFFER_SIZE: u32 = 64 * 1024;
+
+/// A named pipe accepting local clients, for --pipe
+///
+/// Every client gets its own instance of the pipe; one instance always waits
+/// for the next client.
+pub struct PipeListener {
+    name: Vec<u16>,
+    /// The instance the next client connects to
+    waiting: File,
+}
+
+/// A client connected to a [`PipeListener`]
+pub struct PipeConnection {
+    pipe: File,
+}
+
+impl PipeListener {
+    /// Creates the pipe, failing if another process already serves it
+    ///
+    /// `name` is either a full `\\.\pipe\NAME` or just its last part.
+    pub fn bind(name: &str) -> io::Result<PipeListener> {
+        let full = match name.starts_with(r"\\") {
+            true => name.to_string(),
+            false => format!(r"\\.\pipe\{}", name),
+        };
+        let name: Vec<u16> = OsStr::new(&full).encode_wide().chain([0]).collect();
+        let waiting = instance(&name, true)?;
+        Ok(PipeListener { name, waiting })
+    }
+
+    /// The full name of the pipe
+    pub fn name(&self) -> String {
+        String::from_utf16_lossy(&self.name[..self.name.len() - 1])
+    }
+
+    /// Waits for the next client
+    pub fn accept(&mut self) -> io::Result<PipeConnection> {
+        loop {
+            let connected = unsafe { ConnectNamedPipe(self.waiting.as_raw_handle(), ptr::null_mut()) } != 0;
+            let error = io::Error::last_os_error();
+            let next = instance(&self.name, false)?;
+            let pipe = std::mem::replace(&mut self.waiting, next);
+            match error.raw_os_error().map(|code| code as u32) {
+                // The client may have connected between creating the instance and waiting for it
+                _ if connected => return Ok(PipeConnection { pipe }),
+                Some(ERROR_PIPE_CONNECTED) => return Ok(PipeConnection { pipe }),
+                // It left again before it was seen
+                Some(ERROR_NO_DATA) => continue,
+                _ => return Err(error),
+            }
+        }
+    }
+}
+
+/// Creates an instance of the pipe `name`, a NUL-terminated UTF-16 string
+fn instance(name: &[u16], first: bool) -> io::Result<File> {
+    let open_mode = if first { PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE } else { PIPE_ACCESS_DUPLEX };
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            open_mode,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUFFER_SIZE,
+            BUFFER_SIZE,
+            0,
+            ptr::null(),
+        )
+    };
+    match handle == INVALID_HANDLE_VALUE {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(unsafe { File::from_raw_handle(handle) }),
+    }
+}
+
+impl Read for PipeConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.pipe.read(buf) {
+            // The client closed its end
+            Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for PipeConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pipe.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pipe.flush()
+    }
+}
+
+impl Connection for PipeConnection {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Drop for PipeConnection {
+    fn drop(&mut self) {
+        // Closing the instance would throw away what the client hasn't read yet
+        unsafe {
+            FlushFileBuffers(self.pipe.as_raw_handle());
+            DisconnectNamedPipe(self.pipe.as_raw_handle());
+        }
+    }
+}
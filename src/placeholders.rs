@@ -0,0 +1,65 @@
+use std::sync::OnceLock;
+
+/// What the robots.txt served in place of a missing one tells crawlers, from --robots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Robots {
+    Allow,
+    Deny,
+}
+
+impl Robots {
+    pub fn parse(value: &str) -> Result<Robots, String> {
+        match value {
+            "allow" => Ok(Robots::Allow),
+            "deny" => Ok(Robots::Deny),
+            _ => Err("expected allow or deny".to_string()),
+        }
+    }
+
+    pub fn txt(self) -> &'static [u8] {
+        match self {
+            Robots::Allow => b"User-agent: *\nDisallow:\n",
+            Robots::Deny => b"User-agent: *\nDisallow: /\n",
+        }
+    }
+}
+
+/// Side of the built-in favicon, in pixels
+const FAVICON_SIZE: usize = 16;
+
+/// The built-in favicon served by --favicon, a blue dot as a 32-bit ICO
+pub fn favicon() -> &'static [u8] {
+    static ICON: OnceLock<Vec<u8>> = OnceLock::new();
+    ICON.get_or_init(|| {
+        let pixels = FAVICON_SIZE * FAVICON_SIZE * 4;
+        // One bit per pixel, rows padded to 32 bits; all zero as the alpha channel decides
+        let mask = FAVICON_SIZE.div_ceil(32) * 4 * FAVICON_SIZE;
+        let image = 40 + pixels + mask;
+        let mut ico = Vec::with_capacity(6 + 16 + image);
+        // ICONDIR: reserved, type 1 (icon), one image
+        ico.extend([0, 0, 1, 0, 1, 0]);
+        // ICONDIRENTRY: width, height, no palette, reserved, 1 plane, 32 bits per pixel, size, offset
+        ico.extend([FAVICON_SIZE as u8, FAVICON_SIZE as u8, 0, 0, 1, 0, 32, 0]);
+        ico.extend((image as u32).to_le_bytes());
+        ico.extend(22u32.to_le_bytes());
+        // BITMAPINFOHEADER, with the height doubled to count the mask
+        ico.extend(40u32.to_le_bytes());
+        ico.extend((FAVICON_SIZE as i32).to_le_bytes());
+        ico.extend((2 * FAVICON_SIZE as i32).to_le_bytes());
+        ico.extend(1u16.to_le_bytes());
+        ico.extend(32u16.to_le_bytes());
+        ico.extend([0; 24]);
+        // BGRA rows from the bottom up; the dot is symmetric, so the order doesn't matter
+        let center = (FAVICON_SIZE as f32 - 1.0) / 2.0;
+        for y in 0..FAVICON_SIZE {
+            for x in 0..FAVICON_SIZE {
+                let distance = (x as f32 - center).hypot(y as f32 - center);
+                // Edges fade out over a pixel
+                let alpha = (FAVICON_SIZE as f32 / 2.0 - distance).clamp(0.0, 1.0);
+                ico.extend([0xd0, 0x6a, 0x25, (alpha * 255.0) as u8]);
+            }
+        }
+        ico.resize(ico.len() + mask, 0);
+        ico
+    })
+}
@@ -0,0 +1,247 @@
+use rshttp::request::{self, HeaderMap};
+use rshttp::response::Response;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+use tracing::{debug, warn};
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+/// Instructions a hook may run for one request before it is stopped
+const FUEL: u64 = 50_000_000;
+
+/// WebAssembly plugins from --plugins DIR, run on every request
+///
+/// Each `*.wasm` file of the directory is a plugin, run in file name order.
+/// A plugin exports its `memory`, an `alloc(len: i32) -> i32` returning room
+/// for `len` bytes, and at least one of the hooks
+/// `on_request(ptr: i32, len: i32) -> i64` and
+/// `on_response(ptr: i32, len: i32) -> i64`. It imports nothing.
+///
+/// A hook is passed a JSON object written to memory from `alloc`, and returns
+/// 0 to change nothing, or the address of its JSON answer in the high 32 bits
+/// and its length in the low 32 bits:
+///
+/// - `on_request` is passed `{"method", "path", "query", "headers"}`. An
+///   answer with a `status` (and optionally `headers` and a `body` string) is
+///   sent as the response, skipping the plugins after it. Otherwise its `path`
+///   and `query` replace the request's, and its `headers` are set on the
+///   response.
+/// - `on_response` is passed `{"method", "path", "status", "headers"}` for the
+///   response about to be sent, and its answer's `headers` are set on it, a
+///   `null` value removing the header.
+///
+/// Every call gets a fresh instance, so plugins keep no state between
+/// requests. A plugin that traps or runs out of fuel fails the request with 500.
+pub struct Plugins {
+    engine: Engine,
+    plugins: Vec<Plugin>,
+}
+
+struct Plugin {
+    name: String,
+    module: Module,
+    on_request: bool,
+    on_response: bool,
+}
+
+/// A request as the plugins left it
+pub struct PluginRequest {
+    method: String,
+    path: String,
+    query: String,
+    headers: Map<String, Value>,
+    /// Set on the response once it is written
+    stamped: Map<String, Value>,
+}
+
+impl PluginRequest {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+}
+
+impl Plugins {
+    pub fn load(dir: &Path) -> Result<Plugins, String> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+        let entries = fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+        let mut files: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "wasm"))
+            .collect();
+        files.sort();
+        let mut plugins = Vec::new();
+        for file in files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let module = Module::from_file(&engine, &file).map_err(|e| format!("cannot load {}: {}", name, e))?;
+            let exports: Vec<&str> = module.exports().map(|export| export.name()).collect();
+            for required in ["memory", "alloc"] {
+                if !exports.contains(&required) {
+                    return Err(format!("{} does not export {}", name, required));
+                }
+            }
+            let (on_request, on_response) = (exports.contains(&"on_request"), exports.contains(&"on_response"));
+            if !on_request && !on_response {
+                return Err(format!("{} exports neither on_request nor on_response", name));
+            }
+            plugins.push(Plugin {
+                name,
+                module,
+                on_request,
+                on_response,
+            });
+        }
+        Ok(Plugins { engine, plugins })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.plugins.iter().map(|plugin| plugin.name.as_str()).collect()
+    }
+
+    pub fn request(&self, method: &str, path: &str, query: &str, headers: &HeaderMap) -> PluginRequest {
+        let mut map = Map::new();
+        for (name, value) in headers.iter() {
+            map.entry(name.to_ascii_lowercase()).or_insert_with(|| Value::from(value));
+        }
+        PluginRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            headers: map,
+            stamped: Map::new(),
+        }
+    }
+
+    /// Runs the `on_request` hooks, returning the response to send if one answered
+    pub fn on_request(&self, request: &mut PluginRequest) -> Option<Response<'static>> {
+        for plugin in self.plugins.iter().filter(|plugin| plugin.on_request) {
+            let input = json!({
+                "method": request.method,
+                "path": request.path,
+                "query": request.query,
+                "headers": request.headers,
+            });
+            let answer = match self.call(plugin, "on_request", &input) {
+                Ok(Some(answer)) => answer,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Plugin {} failed on {} {}: {}", plugin.name, request.method, request.path, e);
+                    return Some(Response::error(500));
+                }
+            };
+            if let Some(status) = answer.get("status").and_then(Value::as_u64) {
+                debug!("Plugin {} answered {} {} with {}", plugin.name, request.method, request.path, status);
+                let mut response = Response::new(status as u16);
+                for (name, value) in answer.get("headers").and_then(Value::as_object).into_iter().flatten() {
+                    if let Some(value) = value.as_str() {
+                        response = response.header(name, value);
+                    }
+                }
+                let body = answer.get("body").and_then(Value::as_str).unwrap_or("");
+                return Some(response.body(body.as_bytes().to_vec()));
+            }
+            if let Some(path) = answer.get("path").and_then(Value::as_str) {
+                debug!("Plugin {} rewrote {} to {}", plugin.name, request.path, path);
+                request.path = request::normalize_path(path);
+            }
+            if let Some(query) = answer.get("query").and_then(Value::as_str) {
+                request.query = query.to_string();
+            }
+            if let Some(headers) = answer.get("headers").and_then(Value::as_object) {
+                request.stamped.extend(headers.clone());
+            }
+        }
+        None
+    }
+
+    /// Whether [`Plugins::on_response`] would change anything for `request`
+    pub fn changes_response(&self, request: &PluginRequest) -> bool {
+        !request.stamped.is_empty() || self.plugins.iter().any(|plugin| plugin.on_response)
+    }
+
+    /// The response head `head` with the headers set by the `on_request` answers and the `on_response` hooks
+    pub fn on_response(&self, request: &PluginRequest, head: &str) -> String {
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or("").to_string();
+        let mut headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+            .collect();
+        let status = status_line.get(9..12).and_then(|code| code.parse::<u16>().ok()).unwrap_or(0);
+        apply(&mut headers, &request.stamped);
+        for plugin in self.plugins.iter().filter(|plugin| plugin.on_response) {
+            let mut map = Map::new();
+            for (name, value) in &headers {
+                map.entry(name.to_ascii_lowercase()).or_insert_with(|| Value::from(value.as_str()));
+            }
+            let input = json!({
+                "method": request.method,
+                "path": request.path,
+                "status": status,
+                "headers": map,
+            });
+            match self.call(plugin, "on_response", &input) {
+                Ok(Some(answer)) => {
+                    if let Some(changes) = answer.get("headers").and_then(Value::as_object) {
+                        apply(&mut headers, changes);
+                    }
+                }
+                Ok(None) => {}
+                // The status line is already decided, the headers are left as they are
+                Err(e) => warn!("Plugin {} failed on the response to {}: {}", plugin.name, request.path, e),
+            }
+        }
+        let mut head = status_line;
+        for (name, value) in headers {
+            head.push_str(&format!("\r\n{}: {}", name, value));
+        }
+        head
+    }
+
+    /// Calls `hook` of a fresh instance of `plugin` with `input`, returning its answer
+    fn call(&self, plugin: &Plugin, hook: &str, input: &Value) -> Result<Option<Value>, String> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL).map_err(|e| e.to_string())?;
+        let instance = Instance::new(&mut store, &plugin.module, &[]).map_err(|e| e.to_string())?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or("no exported memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc").map_err(|e| e.to_string())?;
+        let hook = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook).map_err(|e| e.to_string())?;
+
+        let input = input.to_string().into_bytes();
+        let len = i32::try_from(input.len()).map_err(|_| "request too large")?;
+        let ptr = alloc.call(&mut store, len).map_err(|e| e.to_string())?;
+        memory.write(&mut store, ptr as u32 as usize, &input).map_err(|e| e.to_string())?;
+        let packed = hook.call(&mut store, (ptr, len)).map_err(|e| e.to_string())? as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+        memory.read(&store, (packed >> 32) as usize, &mut output).map_err(|e| e.to_string())?;
+        let answer: Value = serde_json::from_slice(&output).map_err(|e| format!("invalid answer: {}", e))?;
+        match answer {
+            Value::Object(_) => Ok(Some(answer)),
+            Value::Null => Ok(None),
+            _ => Err("the answer is not a JSON object".to_string()),
+        }
+    }
+}
+
+/// Sets `changes` on `headers`, replacing headers of the same name and removing those set to null
+///
+/// The headers framing the body are left alone, as the body is already decided.
+fn apply(headers: &mut Vec<(String, String)>, changes: &Map<String, Value>) {
+    for (name, value) in changes {
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+        headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        if let Some(value) = value.as_str() {
+            headers.push((name.clone(), value.to_string()));
+        }
+    }
+}
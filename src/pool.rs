@@ -0,0 +1,53 @@
+use crate::affinity;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of worker threads fed through a bounded queue
+///
+/// When every worker is busy and the queue is full, [`ThreadPool::execute`]
+/// blocks, so the acceptors stop accepting and new connections wait in the
+/// kernel's listen backlog instead of each getting a thread.
+#[derive(Clone)]
+pub struct ThreadPool {
+    jobs: SyncSender<Job>,
+}
+
+impl ThreadPool {
+    /// Starts the workers, pinning them round-robin to `cpus` unless it is empty
+    pub fn new(threads: usize, queue: usize, cpus: &[usize]) -> ThreadPool {
+        let (jobs, receiver) = sync_channel::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for id in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let cpus = cpus.to_vec();
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || {
+                    affinity::pin(&cpus, id);
+                    work(&receiver)
+                })
+                .expect("Failed to spawn worker thread");
+        }
+        ThreadPool { jobs }
+    }
+
+    /// Queues a job, waiting while the queue is full
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // Workers only exit once every sender is gone, so this can't fail
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is released before the job runs
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
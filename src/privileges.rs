@@ -0,0 +1,85 @@
+use std::ffi::CString;
+use std::io;
+
+/// The unprivileged user and group to switch to, e.g. after binding port 80 as root
+///
+/// Ids are looked up before [`Account::switch`] so that the switch still works
+/// once /etc/passwd is out of reach (see the sandbox).
+pub struct Account {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+}
+
+impl Account {
+    /// Resolves the names; `group` defaults to the user's primary group
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> io::Result<Account> {
+        let (uid, primary_gid) = match user {
+            Some(user) => {
+                let (uid, gid) = lookup_user(user)?;
+                (Some(uid), Some(gid))
+            }
+            None => (None, None),
+        };
+        let gid = match group {
+            Some(group) => Some(lookup_group(group)?),
+            None => primary_gid,
+        };
+        Ok(Account { uid, gid })
+    }
+
+    /// Switches to the account
+    ///
+    /// Nothing happens when the process already runs as the requested ids, as
+    /// after a restart handoff.
+    pub fn switch(&self) -> io::Result<()> {
+        unsafe {
+            if let Some(gid) = self.gid {
+                if libc::getegid() != gid {
+                    check(libc::setgroups(1, &gid))?;
+                    check(libc::setgid(gid))?;
+                }
+            }
+            if let Some(uid) = self.uid {
+                if libc::geteuid() != uid {
+                    check(libc::setuid(uid))?;
+                }
+                // Regaining root must no longer be possible
+                if uid != 0 && libc::setuid(0) == 0 {
+                    return Err(io::Error::other("privileges could be regained after setuid"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name).map_err(|_| invalid("user", name))?;
+    let entry = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) });
+    }
+    // Numeric ids work without an entry in /etc/passwd
+    let uid = name.parse().map_err(|_| invalid("user", name))?;
+    Ok((uid, uid))
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name).map_err(|_| invalid("group", name))?;
+    let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    name.parse().map_err(|_| invalid("group", name))
+}
+
+fn invalid(kind: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("unknown {} '{}'", kind, name))
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,388 @@
+use crate::listener::{self, Connection};
+use crate::shutdown::Shutdown;
+use rshttp::request::{self, HeaderMap};
+use rshttp::response::Response;
+use std::io::{self, Read, Write};
+use std::net::{self, IpAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use tracing::{debug, warn};
+use url::Url;
+
+/// How long to wait for the upstream to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the upstream to start answering
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long each side of a proxied WebSocket is waited on before checking the other
+const SPLICE_POLL: Duration = Duration::from_millis(20);
+
+/// How often a proxied event stream with nothing to relay checks whether shutdown has begun
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Headers that describe one connection rather than the request, so they are not forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+/// Headers carrying the client's credentials for this server, kept from the upstream unless asked for
+const CREDENTIALS: &[&str] = &["Authorization", "Cookie"];
+
+/// Requests under a path prefix forwarded to an upstream server, from --proxy PREFIX=URL
+///
+/// The upstream sees the full path when its URL has none (`/api=http://localhost:4000`),
+/// or the prefix replaced by its path otherwise (`/api=http://localhost:4000/v2`).
+/// Only plain HTTP upstreams are supported, as in a local development setup.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    prefix: String,
+    upstream: Url,
+    /// host:port to connect to and send as Host
+    authority: String,
+    /// Replaces the prefix when set
+    base_path: Option<String>,
+}
+
+impl Proxy {
+    /// Parses `PREFIX=URL`
+    pub fn parse(value: &str) -> Result<Proxy, String> {
+        let (prefix, upstream) = value
+            .split_once('=')
+            .ok_or_else(|| format!("invalid proxy '{}' (expected PREFIX=URL, e.g. /api=http://localhost:4000)", value))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("proxy prefix '{}' must start with '/'", prefix));
+        }
+        let upstream = Url::parse(upstream).map_err(|e| format!("invalid proxy URL '{}': {}", upstream, e))?;
+        if upstream.scheme() != "http" {
+            return Err(format!("proxy URL '{}' must use http://", upstream));
+        }
+        let host = upstream.host_str().ok_or_else(|| format!("proxy URL '{}' has no host", upstream))?;
+        let authority = format!("{}:{}", host, upstream.port_or_known_default().unwrap_or(80));
+        let base_path = Some(upstream.path().trim_end_matches('/')).filter(|path| !path.is_empty());
+        Ok(Proxy {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            authority,
+            base_path: base_path.map(str::to_string),
+            upstream,
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn upstream(&self) -> &Url {
+        &self.upstream
+    }
+
+    /// Whether `path` is the prefix or below it
+    pub fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Forwards a request to the upstream and streams its response back to the client
+    ///
+    /// Failing to reach the upstream is answered with a 502, and an upstream that
+    /// doesn't start answering within [`RESPONSE_TIMEOUT`] with a 504. Request bodies
+    /// need a Content-Length; chunked ones are refused with a 411.
+    ///
+    /// A WebSocket handshake is passed on too, and once the upstream accepts it
+    /// bytes are relayed both ways until either side closes or the server drains.
+    ///
+    /// The body is relayed as it arrives. An upstream that goes quiet for
+    /// [`RESPONSE_TIMEOUT`] mid-body is given up on, except for event streams
+    /// (`text/event-stream`), which may stay idle for as long as the upstream
+    /// likes and end when the server drains. Server modes that send whole
+    /// responses can't relay an event stream and answer it with a 501.
+    pub fn forward(&self, client: &mut impl Connection, forwarded: Forwarded, shutdown: &Shutdown) -> io::Result<()> {
+        let head_only = forwarded.method == "HEAD";
+        let websocket = forwarded.headers.get("Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+        if websocket && !client.streams() {
+            warn!("Cannot proxy a WebSocket in this server mode");
+            return Response::error(501).header("Connection", "close").send(client, head_only);
+        }
+        if forwarded.headers.get("Transfer-Encoding").is_some() {
+            return Response::error(411).header("Connection", "close").send(client, head_only);
+        }
+        let length = forwarded.headers.content_length().unwrap_or(0);
+        if !client.streams() && (forwarded.received_body().len() as u64) < length {
+            // Buffered connections hand over the head and nothing more
+            warn!("Cannot proxy a request body this large in this server mode");
+            return Response::error(501).header("Connection", "close").send(client, head_only);
+        }
+        let mut upstream = match self.connect() {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("Cannot reach the proxy upstream {}: {}", self.authority, e);
+                return Response::error(502).send(client, head_only);
+            }
+        };
+        request::send_continue(client, forwarded.headers, forwarded.received_body())?;
+        match self.send_request(&mut upstream, client, &forwarded, websocket) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                debug!("The client sent less of the body than announced");
+                return Response::error(400).header("Connection", "close").send(client, head_only);
+            }
+            Err(e) => {
+                warn!("Failed to forward {} {} to {}: {}", forwarded.method, forwarded.path, self.authority, e);
+                return Response::error(502).send(client, head_only);
+            }
+        }
+        let response_head = match request::read_head(&mut upstream) {
+            Ok(head) if !head.is_empty() => head,
+            Ok(_) => {
+                warn!("The proxy upstream {} closed without answering", self.authority);
+                return Response::error(502).send(client, head_only);
+            }
+            Err(e) if listener::is_timeout(&e) => {
+                warn!("The proxy upstream {} did not answer in time", self.authority);
+                return Response::error(504).send(client, head_only);
+            }
+            Err(e) => {
+                warn!("Failed to read the response of {}: {}", self.authority, e);
+                return Response::error(502).send(client, head_only);
+            }
+        };
+        let switched = response_head.get(9..12) == Some(b"101");
+        let event_stream = !websocket && is_event_stream(&response_head);
+        if event_stream && !client.streams() {
+            warn!("Cannot proxy an event stream in this server mode");
+            return Response::error(501).header("Connection", "close").send(client, head_only);
+        }
+        // The response goes back as it came, framing included, since both connections close after it
+        client.write_all(&response_head)?;
+        client.flush()?;
+        if websocket && switched {
+            return splice(client, &mut upstream, shutdown);
+        }
+        if event_stream {
+            upstream.set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL))?;
+        }
+        let mut buffer = [0u8; 16 * 1024];
+        loop {
+            let read = match upstream.read(&mut buffer) {
+                Ok(read) => read,
+                Err(e) if event_stream && listener::is_timeout(&e) && !shutdown.is_draining() => continue,
+                Err(e) if event_stream && listener::is_timeout(&e) => 0,
+                // A refused upgrade may leave the upstream connection open
+                Err(e) if websocket && listener::is_timeout(&e) => 0,
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                return Ok(());
+            }
+            client.write_all(&buffer[..read])?;
+            client.flush()?;
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address");
+        for address in self.authority.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+                    stream.set_write_timeout(Some(RESPONSE_TIMEOUT))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Writes the request head and body to the upstream
+    fn send_request(
+        &self,
+        upstream: &mut TcpStream,
+        client: &mut impl Read,
+        forwarded: &Forwarded,
+        websocket: bool,
+    ) -> io::Result<()> {
+        let head = self.request_head(forwarded, websocket);
+        upstream.write_all(head.as_bytes())?;
+
+        let received = forwarded.received_body();
+        upstream.write_all(received)?;
+        let remaining = forwarded.headers.content_length().unwrap_or(0) - received.len() as u64;
+        if io::copy(&mut client.take(remaining), upstream)? < remaining {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        upstream.flush()
+    }
+
+    /// The request head sent to the upstream
+    fn request_head(&self, forwarded: &Forwarded, websocket: bool) -> String {
+        // The path was decoded for the access rules, and goes to the upstream encoded again
+        let path = match &self.base_path {
+            Some(base_path) => format!("{}{}", base_path, request::encode_path(&forwarded.path[self.prefix.len()..])),
+            None => request::encode_path(forwarded.path),
+        };
+        let target = match forwarded.query {
+            "" => path,
+            query => format!("{}?{}", path, query),
+        };
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", forwarded.method, target, self.authority);
+        let mut forwarded_for = None;
+        for (name, value) in forwarded.headers.iter() {
+            if name.eq_ignore_ascii_case("X-Forwarded-For") {
+                // Only a trusted proxy's list is passed on, anyone else could make up addresses in it
+                forwarded_for = Some(value).filter(|_| forwarded.peer_trusted);
+            } else if name.eq_ignore_ascii_case("Host") {
+                // Replaced by the upstream's, and passed on as X-Forwarded-Host
+            } else if name.eq_ignore_ascii_case("Expect") {
+                // Already answered with 100 Continue, and the body follows the head straight away
+            } else if CREDENTIALS.iter().any(|header| header.eq_ignore_ascii_case(name)) && !forwarded.credentials {
+                // Meant for this server, whose passwords, tokens and sessions the upstream has no business seeing
+            } else if !HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name)) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if let Some(ip) = forwarded.peer_ip {
+            match forwarded_for {
+                Some(earlier) => head.push_str(&format!("X-Forwarded-For: {}, {}\r\n", earlier, ip)),
+                None => head.push_str(&format!("X-Forwarded-For: {}\r\n", ip)),
+            }
+        }
+        if let Some(host) = forwarded.headers.get("Host") {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        head.push_str("X-Forwarded-Proto: http\r\n");
+        match websocket {
+            true => head.push_str("Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n"),
+            false => head.push_str("Connection: close\r\n\r\n"),
+        }
+        head
+    }
+}
+
+/// Whether a response head announces a `text/event-stream` body
+fn is_event_stream(head: &[u8]) -> bool {
+    String::from_utf8_lossy(head).split("\r\n").skip(1).any(|line| match line.split_once(':') {
+        Some((name, value)) => {
+            name.trim().eq_ignore_ascii_case("Content-Type")
+                && value.trim().get(..17).is_some_and(|media_type| media_type.eq_ignore_ascii_case("text/event-stream"))
+        }
+        None => false,
+    })
+}
+
+/// Relays bytes both ways between the client and the upstream until either closes
+///
+/// Connections can't be split across threads, so each side is read with a short
+/// timeout in turn.
+fn splice(client: &mut impl Connection, upstream: &mut TcpStream, shutdown: &Shutdown) -> io::Result<()> {
+    client.set_read_timeout(Some(SPLICE_POLL))?;
+    upstream.set_read_timeout(Some(SPLICE_POLL))?;
+    let mut buffer = [0u8; 16 * 1024];
+    while !shutdown.is_draining() {
+        match client.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => upstream.write_all(&buffer[..read])?,
+            Err(e) if listener::is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+        match upstream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                client.write_all(&buffer[..read])?;
+                client.flush()?;
+            }
+            Err(e) if listener::is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let _ = upstream.shutdown(net::Shutdown::Both);
+    Ok(())
+}
+
+/// The parts of a client request that are passed on to the upstream
+pub struct Forwarded<'a> {
+    pub method: &'a str,
+    /// Decoded and normalized path, without the query
+    pub path: &'a str,
+    pub query: &'a str,
+    pub headers: &'a HeaderMap<'a>,
+    /// Everything read from the client so far
+    pub head: &'a [u8],
+    pub peer_ip: Option<IpAddr>,
+    /// Whether the peer is a trusted proxy, whose X-Forwarded-For is extended rather than replaced
+    pub peer_trusted: bool,
+    /// Whether Authorization and Cookie headers are passed on, from --proxy-credentials
+    pub credentials: bool,
+}
+
+impl Forwarded<'_> {
+    /// The part of the body read along with the head
+    fn received_body(&self) -> &[u8] {
+        let length = self.headers.content_length().unwrap_or(0);
+        let body = self
+            .head
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(&[][..], |end| &self.head[end + 4..]);
+        &body[..body.len().min(length as usize)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_streams_are_recognized() {
+        assert!(is_event_stream(b"HTTP/1.1 200 OK\r\ncontent-type: Text/Event-Stream; charset=utf-8\r\n\r\n"));
+        assert!(!is_event_stream(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nX-Note: text/event-stream\r\n\r\n"));
+        assert!(!is_event_stream(b"HTTP/1.1 200 OK\r\nContent-Type: text/event\r\n\r\n"));
+    }
+
+    const HEAD: &str = "GET /api/a%20b?x=1 HTTP/1.1\r\nHost: example.com\r\nAuthorization: Basic dTpw\r\n\
+                        Cookie: rshttp_session=abc\r\nX-Forwarded-For: 6.6.6.6\r\nAccept: */*\r\n\r\n";
+
+    fn forwarded<'a>(headers: &'a HeaderMap<'a>, peer_trusted: bool, credentials: bool) -> Forwarded<'a> {
+        Forwarded {
+            method: "GET",
+            path: "/api/a b",
+            query: "x=1",
+            headers,
+            head: HEAD.as_bytes(),
+            peer_ip: Some("10.0.0.5".parse().unwrap()),
+            peer_trusted,
+            credentials,
+        }
+    }
+
+    #[test]
+    fn upstreams_get_encoded_paths_without_credentials() {
+        let request = request::parse(HEAD).unwrap();
+        let proxy = Proxy::parse("/api=http://localhost:4000/v2").unwrap();
+        let head = proxy.request_head(&forwarded(&request.headers, false, false), false);
+        assert!(head.starts_with("GET /v2/a%20b?x=1 HTTP/1.1\r\nHost: localhost:4000\r\n"));
+        assert!(head.contains("Accept: */*\r\n"));
+        assert!(!head.contains("Authorization") && !head.contains("Cookie"));
+
+        let head = proxy.request_head(&forwarded(&request.headers, false, true), false);
+        assert!(head.contains("Authorization: Basic dTpw\r\n") && head.contains("Cookie: rshttp_session=abc\r\n"));
+    }
+
+    #[test]
+    fn forwarded_for_is_only_extended_for_trusted_peers() {
+        let request = request::parse(HEAD).unwrap();
+        let proxy = Proxy::parse("/api=http://localhost:4000").unwrap();
+        let head = proxy.request_head(&forwarded(&request.headers, false, false), false);
+        assert!(head.contains("X-Forwarded-For: 10.0.0.5\r\n") && !head.contains("6.6.6.6"));
+        let head = proxy.request_head(&forwarded(&request.headers, true, false), false);
+        assert!(head.contains("X-Forwarded-For: 6.6.6.6, 10.0.0.5\r\n"));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::har;
+use crate::metrics::RequestRecord;
+use crate::units;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use tracing::warn;
+
+/// File in a recording that maps each request to the file holding its response, one JSON line per request
+const INDEX: &str = "index.jsonl";
+
+/// Largest response body recorded; bigger responses are left out of the recording
+pub const MAX_BODY: usize = 16 << 20;
+
+/// Arguments of the `export-har` subcommand
+#[derive(clap::Args, Debug)]
+pub struct ExportHarArgs {
+    /// Recording made with --record
+    dir: PathBuf,
+    /// Write the HAR file here instead of to standard output
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+    /// Server the recorded requests were made to, for the URLs in the file
+    #[arg(long, value_name = "URL", default_value = "http://127.0.0.1:8000")]
+    base: String,
+}
+
+/// Keeps the response to every request in a directory, for --record
+///
+/// Each response is stored as sent, head and body, in a numbered `.http`
+/// file; every line of `index.jsonl` maps `METHOD target` to one, and is
+/// appended the first time that request is made. A request made again
+/// replaces its earlier response, and recording into an existing recording
+/// adds to it.
+pub struct Recorder {
+    dir: PathBuf,
+    files: Mutex<HashMap<String, String>>,
+    index: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(dir: &Path) -> io::Result<Recorder> {
+        fs::create_dir_all(dir)?;
+        let files = match fs::read_to_string(dir.join(INDEX)) {
+            Ok(index) => read_index(&index)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        // Also finds out now whether the directory is writable
+        let index = OpenOptions::new().create(true).append(true).open(dir.join(INDEX))?;
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            files: Mutex::new(files),
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Stores the response a request got, given its head and its body as captured up to `MAX_BODY + 1` bytes
+    pub fn record(&self, record: &RequestRecord, head: Option<&str>, body: Option<&[u8]>) {
+        let (Some(target), Some(head)) = (record.request_line.split_whitespace().nth(1), head) else {
+            return; // No request, or no response
+        };
+        if body.is_some_and(|body| body.len() > MAX_BODY) {
+            warn!("Not recording {} {}: the response is larger than {}", record.method, target, units::size(MAX_BODY as u64));
+            return;
+        }
+        let key = key(&record.method, target);
+        let (file, added) = {
+            let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+            match files.get(&key) {
+                Some(file) => (file.clone(), false),
+                None => {
+                    let file = format!("{}.http", files.len() + 1);
+                    files.insert(key.clone(), file.clone());
+                    (file, true)
+                }
+            }
+        };
+
+        let mut response = format!("{}\r\n\r\n", head).into_bytes();
+        response.extend_from_slice(body.unwrap_or_default());
+        // Written aside and renamed, so a request made twice at once leaves one whole response
+        let partial = self.dir.join(format!("{}.{}.partial", file, UNIQUE.fetch_add(1, Ordering::Relaxed)));
+        let stored = fs::write(&partial, response).and_then(|()| fs::rename(&partial, self.dir.join(&file)));
+        let indexed = stored.and_then(|()| match added {
+            true => {
+                let line = json!({ "request": key, "file": file }).to_string() + "\n";
+                self.index.lock().unwrap_or_else(PoisonError::into_inner).write_all(line.as_bytes())
+            }
+            false => Ok(()),
+        });
+        if let Err(e) = indexed {
+            let _ = fs::remove_file(&partial);
+            warn!("Failed to record {} {} in {}: {}", record.method, target, self.dir.display(), e);
+        }
+    }
+}
+
+/// Numbers the files responses are written to before they are renamed into place
+static UNIQUE: AtomicU64 = AtomicU64::new(0);
+
+/// The file of each request in an index, a later line winning over an earlier one
+fn read_index(index: &str) -> io::Result<HashMap<String, String>> {
+    let mut files = HashMap::new();
+    for line in index.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: serde_json::Value = serde_json::from_str(line)?;
+        let (Some(request), Some(file)) = (entry["request"].as_str(), entry["file"].as_str()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid index line: {}", line)));
+        };
+        files.insert(request.to_string(), file.to_string());
+    }
+    Ok(files)
+}
+
+/// Responses of a recording, served instead of the files for --replay
+///
+/// Everything is read at startup, so answering requests never touches the filesystem.
+pub struct Replay {
+    responses: HashMap<String, Vec<u8>>,
+}
+
+impl Replay {
+    pub fn load(dir: &Path) -> io::Result<Replay> {
+        let index = read_index(&fs::read_to_string(dir.join(INDEX))?)?;
+        let mut responses = HashMap::new();
+        for (key, file) in index {
+            responses.insert(key, fs::read(dir.join(file))?);
+        }
+        Ok(Replay { responses })
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    /// The recorded response, head and body, to `method` and `target`
+    ///
+    /// A HEAD request that wasn't recorded gets the head of the recorded GET.
+    pub fn response(&self, method: &str, target: &str) -> Option<&[u8]> {
+        if let Some(response) = self.responses.get(&key(method, target)) {
+            return Some(response);
+        }
+        let get = self.responses.get(&key("GET", target)).filter(|_| method == "HEAD")?;
+        let head_end = get.windows(4).position(|window| window == b"\r\n\r\n")?;
+        Some(&get[..head_end + 4])
+    }
+}
+
+/// Writes the responses of a recording as a HAR file, in the order they were first recorded
+pub fn run(args: &ExportHarArgs) -> i32 {
+    match export_har(args) {
+        Ok(count) => {
+            eprintln!("Exported {} recorded response(s) from {}", count, args.dir.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: cannot export {}: {}", args.dir.display(), e);
+            1
+        }
+    }
+}
+
+fn export_har(args: &ExportHarArgs) -> io::Result<usize> {
+    let mut files: Vec<_> = read_index(&fs::read_to_string(args.dir.join(INDEX))?)?.into_iter().collect();
+    let number = |file: &str| file.trim_end_matches(".http").parse::<u64>().unwrap_or(u64::MAX);
+    files.sort_by_key(|(_, file)| number(file));
+    let mut entries = Vec::new();
+    for (key, file) in &files {
+        let path = args.dir.join(file);
+        let response = fs::read(&path)?;
+        let recorded = fs::metadata(&path)?.modified()?;
+        let (method, target) = key.split_once(' ').unwrap_or(("GET", key));
+        let url = format!("{}{}", args.base.trim_end_matches('/'), target);
+        entries.push(har::recorded_entry(method, &url, &response, recorded));
+    }
+    let document = har::document(&entries);
+    match &args.output {
+        Some(output) => serde_json::to_writer_pretty(File::create(output)?, &document)?,
+        None => serde_json::to_writer_pretty(io::stdout().lock(), &document)?,
+    }
+    Ok(entries.len())
+}
+
+fn key(method: &str, target: &str) -> String {
+    format!("{} {}", method, target)
+}
@@ -0,0 +1,111 @@
+use crate::glob;
+use rshttp::response::Response;
+
+/// Statuses a redirect may answer with
+const STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// An external redirect for one path, from --redirect "FROM -> TO [STATUS]"
+///
+/// `FROM` is matched exactly against the path the client asked for, before
+/// rewrites and without touching the filesystem. `TO` is a path or an absolute
+/// URL; the request's query is carried over unless `TO` has one of its own.
+/// The status defaults to 301.
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    from: String,
+    to: String,
+    status: u16,
+}
+
+impl Redirect {
+    pub fn parse(value: &str) -> Result<Redirect, String> {
+        let invalid = || format!("invalid redirect '{}' (expected e.g. \"/old-page -> /new-page 301\")", value);
+        let (from, rest) = value.split_once("->").ok_or_else(invalid)?;
+        let from = from.trim();
+        let mut words = rest.split_whitespace();
+        let to = words.next().ok_or_else(invalid)?;
+        let status = match words.next() {
+            Some(status) => status.parse().ok().filter(|status| STATUSES.contains(status)).ok_or_else(|| {
+                format!("invalid redirect status '{}' (expected one of 301, 302, 303, 307 or 308)", status)
+            })?,
+            None => 301,
+        };
+        if !from.starts_with('/') || !(to.starts_with('/') || to.contains("://")) || words.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Redirect {
+            from: from.to_string(),
+            to: to.to_string(),
+            status,
+        })
+    }
+
+    /// The redirect for `path`, if there is one
+    pub fn find<'a>(redirects: &'a [Redirect], path: &str) -> Option<&'a Redirect> {
+        redirects.iter().find(|redirect| redirect.from == path)
+    }
+
+    pub fn response(&self, query: &str) -> Response<'static> {
+        match query {
+            _ if query.is_empty() || self.to.contains('?') => Response::redirect(self.status, &self.to),
+            query => Response::redirect(self.status, &format!("{}?{}", self.to, query)),
+        }
+    }
+}
+
+/// A permanent redirect to a canonical host, from --canonical-host "[PATTERN ->] HOST"
+///
+/// Requests whose Host matches `PATTERN` (a glob such as `www.example.com` or
+/// `*.example.org`), or any other host when there is no pattern, are sent to
+/// the same path on `HOST`. A port in the request's Host is kept unless `HOST`
+/// names one.
+#[derive(Debug, Clone)]
+pub struct HostRedirect {
+    pattern: Option<String>,
+    host: String,
+}
+
+impl HostRedirect {
+    pub fn parse(value: &str) -> Result<HostRedirect, String> {
+        let (pattern, host) = match value.split_once("->") {
+            Some((pattern, host)) => (Some(pattern.trim().to_ascii_lowercase()), host.trim()),
+            None => (None, value.trim()),
+        };
+        if host.is_empty() || host.contains(['/', ' ']) || pattern.as_deref().is_some_and(str::is_empty) {
+            let example = "\"www.example.com -> example.com\"";
+            return Err(format!("invalid canonical host '{}' (expected e.g. {})", value, example));
+        }
+        Ok(HostRedirect {
+            pattern,
+            host: host.to_ascii_lowercase(),
+        })
+    }
+
+    /// The redirect for a request's Host header, if its host needs one
+    pub fn find<'a>(redirects: &'a [HostRedirect], host: &str) -> Option<&'a HostRedirect> {
+        let name = host_name(host).to_ascii_lowercase();
+        redirects.iter().find(|redirect| match &redirect.pattern {
+            Some(pattern) => glob::matches(pattern, &name) && name != host_name(&redirect.host),
+            None => name != host_name(&redirect.host),
+        })
+    }
+
+    /// A redirect to the same path and query on the canonical host
+    pub fn response(&self, host: &str, path: &str, query: &str) -> Response<'static> {
+        let names_port = self.host != host_name(&self.host);
+        let port = host.strip_prefix(host_name(host)).filter(|port| !port.is_empty() && !names_port);
+        let mut location = format!("http://{}{}{}", self.host, port.unwrap_or(""), path);
+        if !query.is_empty() {
+            location = format!("{}?{}", location, query);
+        }
+        Response::redirect(301, &location)
+    }
+}
+
+/// A Host header without its port
+fn host_name(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
+}
@@ -0,0 +1,297 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::io::{self, Read, Write};
+
+/// Longest request line accepted; longer ones get 414 URI Too Long
+pub const MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/// Longest request head accepted, request line included; longer ones get 431 Request Header Fields Too Large
+pub const MAX_HEAD: usize = 64 * 1024;
+
+/// Most header fields accepted in one request
+pub const MAX_HEADERS: usize = 100;
+
+/// Reads from `stream` until the end of the request head (`\r\n\r\n`) or EOF
+///
+/// Stops once more than [`MAX_HEAD`] bytes have arrived without the head
+/// ending, leaving it to [`parse`] to reject, so a client sending an endless
+/// head can't grow the buffer without bound.
+pub fn read_head(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new(); // Dynamic buffer
+    let mut temp_buffer = [0; 1024];
+
+    loop {
+        let bytes_read = stream.read(&mut temp_buffer)?;
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        buffer.extend_from_slice(&temp_buffer[..bytes_read]);
+
+        // Check for the end of the request
+        if buffer.windows(4).any(|window| window == b"\r\n\r\n") || buffer.len() > MAX_HEAD {
+            break;
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// A request head that passed [`parse`]
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// The request target as sent, path and query string
+    pub target: &'a str,
+    pub version: &'a str,
+    pub headers: HeaderMap<'a>,
+}
+
+/// The header fields of a request in the order they were sent, looked up by case-insensitive name
+#[derive(Debug, Default)]
+pub struct HeaderMap<'a> {
+    fields: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> HeaderMap<'a> {
+    /// The value of the first field named `name`
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.get_all(name).next()
+    }
+
+    /// The values of every field named `name`
+    pub fn get_all<'m>(&'m self, name: &'m str) -> impl Iterator<Item = &'a str> + 'm {
+        self.fields.iter().filter(move |(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| *value)
+    }
+
+    /// Every field as name and value, values trimmed
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        self.fields.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The length of the request body, which [`parse`] has checked is a single valid number
+    pub fn content_length(&self) -> Option<u64> {
+        self.get("Content-Length").and_then(|length| length.parse().ok())
+    }
+}
+
+/// Why a request head was rejected, and the status to answer it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Malformed {
+    pub status: u16,
+    pub reason: &'static str,
+}
+
+/// Parses a request head as read by [`read_head`]
+///
+/// Only what HTTP/1.x allows gets through: a request line of method, origin-form
+/// target and version separated by single spaces, and `name: value` fields
+/// without line folding or control characters, all within [`MAX_REQUEST_LINE`],
+/// [`MAX_HEAD`] and [`MAX_HEADERS`]. Conflicting body lengths are refused too,
+/// as a proxy in front might frame the body differently.
+pub fn parse(head: &str) -> Result<Request<'_>, Malformed> {
+    let reject = |status, reason| Err(Malformed { status, reason });
+    let Some(end) = head.find("\r\n\r\n") else {
+        return match head.len() > MAX_HEAD {
+            true => reject(431, "request head too large"),
+            false => reject(400, "incomplete request head"),
+        };
+    };
+    if end > MAX_HEAD {
+        return reject(431, "request head too large");
+    }
+    let mut lines = head[..end].split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    if request_line.len() > MAX_REQUEST_LINE {
+        return reject(414, "request line too long");
+    }
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return reject(400, "request line is not METHOD TARGET VERSION");
+    };
+    if method.is_empty() || !method.bytes().all(is_token) {
+        return reject(400, "invalid method");
+    }
+    if !target.starts_with('/') || target.bytes().any(|byte| byte.is_ascii_control() || byte == b' ') {
+        return reject(400, "invalid request target");
+    }
+    match version {
+        "HTTP/1.0" | "HTTP/1.1" => {}
+        version if version.starts_with("HTTP/") => return reject(505, "unsupported HTTP version"),
+        _ => return reject(400, "invalid HTTP version"),
+    }
+
+    let mut fields = Vec::new();
+    for line in lines {
+        if line.starts_with([' ', '\t']) {
+            return reject(400, "folded header line");
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return reject(400, "header line without a colon");
+        };
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return reject(400, "invalid header name");
+        }
+        let value = value.trim_matches([' ', '\t']);
+        if value.bytes().any(|byte| byte.is_ascii_control() && byte != b'\t') {
+            return reject(400, "control character in a header value");
+        }
+        if fields.len() == MAX_HEADERS {
+            return reject(431, "too many header fields");
+        }
+        fields.push((name, value));
+    }
+    let headers = HeaderMap { fields };
+
+    if let Some(length) = headers.get("Content-Length") {
+        // A sign would parse, but isn't valid HTTP
+        if !length.bytes().all(|byte| byte.is_ascii_digit()) || length.parse::<u64>().is_err() {
+            return reject(400, "invalid Content-Length");
+        }
+        if headers.get_all("Content-Length").any(|other| other != length) {
+            return reject(400, "conflicting Content-Length fields");
+        }
+        if headers.get("Transfer-Encoding").is_some() {
+            return reject(400, "both Content-Length and Transfer-Encoding");
+        }
+    }
+    // 100-continue is the only expectation HTTP defines
+    if headers.get_all("Expect").any(|expect| !expect.eq_ignore_ascii_case("100-continue")) {
+        return reject(417, "unsupported expectation");
+    }
+    Ok(Request {
+        method,
+        target,
+        version,
+        headers,
+    })
+}
+
+/// Tells a client waiting with `Expect: 100-continue` to send its body
+///
+/// Call it once the request is known to be accepted, just before reading the
+/// body. Nothing is sent if some of the body came along with the head, as the
+/// client has stopped waiting.
+pub fn send_continue(client: &mut impl Write, headers: &HeaderMap, received_body: &[u8]) -> io::Result<()> {
+    let expects = headers.get("Expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+    if !expects || !received_body.is_empty() || headers.content_length() == Some(0) {
+        return Ok(());
+    }
+    client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    client.flush()
+}
+
+/// Whether a byte may appear in a method or header name (`tchar` in RFC 9110)
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// Splits the request line into method and target, defaulting to `/`
+pub fn request_line(request: &str) -> (&str, &str) {
+    let first_line = request.lines().next().unwrap_or("");
+    let mut parts = first_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("/");
+    (method, target)
+}
+
+/// Characters escaped in the paths of URLs
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Decodes a request path, e.g. `/My%20Files/` into `/My Files/`
+pub fn decode_path(path: &str) -> String {
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+/// Encodes a path for a URL, the reverse of [`decode_path`]
+pub fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, PATH).to_string()
+}
+
+/// Resolves `.` and `..` segments and collapses repeated slashes, keeping a trailing slash
+///
+/// The result can't climb above `/`, so it stays below the root it is served
+/// from, and it is the form access rules see: `//drafts/./x` can't slip past
+/// a rule for `/drafts/**`.
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    let directory = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if directory && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Whether Windows would read a normalized path as something other than the file it names below the root
+///
+/// That is a backslash, which Windows takes for a separator that
+/// [`normalize_path`] never saw, a colon, which starts a drive (`/C:/...`) or
+/// an alternate data stream (`/page.html::$DATA`), a segment ending in a dot
+/// or space, which Windows trims (`/page.html.`), or a reserved device name
+/// like `CON` or `NUL.txt`.
+pub fn unsafe_on_windows(path: &str) -> bool {
+    const DEVICES: [&str; 6] = ["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+    path.contains(['\\', ':'])
+        || path.split('/').any(|segment| {
+            let stem = segment.split('.').next().unwrap_or(segment).trim_end();
+            let numbered = ["COM", "LPT"].iter().any(|prefix| {
+                (stem.get(..3)).is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+                    && stem.len() == 4
+                    && stem.as_bytes()[3].is_ascii_digit()
+            });
+            segment.ends_with(['.', ' '])
+                || numbered
+                || DEVICES.iter().any(|device| stem.eq_ignore_ascii_case(device))
+        })
+}
+
+/// Returns the value of the first header matching `name` (case-insensitive)
+pub fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    headers(request).find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+}
+
+/// Every header of a request or response head, as trimmed names and values
+pub fn headers(head: &str) -> impl Iterator<Item = (&str, &str)> {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+}
+
+/// Whether a header carries credentials, which are hidden wherever requests are shown or exported
+pub fn is_credential(name: &str) -> bool {
+    name.eq_ignore_ascii_case("Authorization") || name.eq_ignore_ascii_case("Proxy-Authorization")
+}
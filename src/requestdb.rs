@@ -0,0 +1,147 @@
+use crate::accesslog::Entry;
+use crate::units;
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use tracing::warn;
+
+/// Most rows written in one transaction
+const MAX_BATCH: usize = 512;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS requests (
+        time TEXT NOT NULL,
+        remote_addr TEXT,
+        user TEXT,
+        method TEXT NOT NULL,
+        path TEXT NOT NULL,
+        query TEXT,
+        status INTEGER,
+        bytes INTEGER NOT NULL,
+        latency_us INTEGER NOT NULL,
+        referer TEXT,
+        user_agent TEXT,
+        cache_hit INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS requests_time ON requests (time);
+";
+
+/// Records every request in an SQLite database, for ad-hoc SQL over the traffic history
+///
+/// Rows go into the `requests` table, with `time` as ISO 8601 in UTC so it
+/// sorts and compares as text, e.g.
+/// `SELECT path, avg(latency_us) FROM requests WHERE time > '2024-05-01' GROUP BY path`.
+/// A thread of its own writes them in batches, and the database is in WAL
+/// mode so it can be queried while the server runs.
+pub struct RequestDb {
+    rows: Sender<Message>,
+}
+
+struct Row {
+    time: String,
+    remote_addr: Option<String>,
+    user: Option<String>,
+    method: String,
+    path: String,
+    query: Option<String>,
+    status: Option<u16>,
+    bytes: i64,
+    latency_us: i64,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    cache_hit: Option<bool>,
+}
+
+enum Message {
+    Row(Box<Row>),
+    /// Answered once every row sent before it is written
+    Finish(Sender<()>),
+}
+
+impl RequestDb {
+    pub fn open(path: &Path) -> rusqlite::Result<RequestDb> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        let (rows, rx) = channel();
+        thread::spawn(move || write_rows(connection, rx));
+        Ok(RequestDb { rows })
+    }
+
+    pub fn record(&self, entry: &Entry) {
+        let record = entry.record;
+        if record.method.is_empty() {
+            return; // The client closed the connection without sending a request
+        }
+        let query = record.request_line.split_whitespace().nth(1).and_then(|target| target.split_once('?'));
+        let row = Row {
+            time: units::iso_timestamp(entry.received),
+            remote_addr: entry.peer_ip.map(|ip| ip.to_string()),
+            user: record.user.clone(),
+            method: record.method.clone(),
+            path: record.path.clone(),
+            query: query.map(|(_, query)| query.to_string()),
+            status: entry.status,
+            bytes: entry.bytes_sent as i64,
+            latency_us: entry.elapsed.as_micros() as i64,
+            referer: record.referer.clone(),
+            user_agent: record.user_agent.clone(),
+            cache_hit: record.cache_hit,
+        };
+        let _ = self.rows.send(Message::Row(Box::new(row)));
+    }
+
+    /// Waits until every request recorded so far is in the database
+    pub fn finish(&self) {
+        let (done, finished) = channel();
+        if self.rows.send(Message::Finish(done)).is_ok() {
+            let _ = finished.recv();
+        }
+    }
+}
+
+fn write_rows(mut connection: Connection, rx: Receiver<Message>) {
+    while let Ok(first) = rx.recv() {
+        let (mut batch, mut waiting) = (Vec::new(), Vec::new());
+        for message in std::iter::once(first).chain(rx.try_iter().take(MAX_BATCH - 1)) {
+            match message {
+                Message::Row(row) => batch.push(row),
+                Message::Finish(done) => waiting.push(done),
+            }
+        }
+        if let Err(e) = insert(&mut connection, &batch) {
+            warn!("Failed to record {} request(s) in the request database: {}", batch.len(), e);
+        }
+        for done in waiting {
+            let _ = done.send(());
+        }
+    }
+}
+
+fn insert(connection: &mut Connection, rows: &[Box<Row>]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO requests (time, remote_addr, user, method, path, query, status, bytes, latency_us, \
+             referer, user_agent, cache_hit) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )?;
+        for row in rows {
+            statement.execute(params![
+                row.time,
+                row.remote_addr,
+                row.user,
+                row.method,
+                row.path,
+                row.query,
+                row.status,
+                row.bytes,
+                row.latency_us,
+                row.referer,
+                row.user_agent,
+                row.cache_hit,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
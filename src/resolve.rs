@@ -0,0 +1,15 @@
+use std::path::{Path, PathBuf};
+
+/// The URL path of the file served for `path`: directories map to their index.html
+pub fn served_path(root: &Path, path: &str) -> String {
+    if file_path(root, path).is_dir() {
+        format!("{}/index.html", path.trim_end_matches('/'))
+    } else {
+        path.to_string()
+    }
+}
+
+/// The file below `root` for a URL path
+pub fn file_path(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
@@ -0,0 +1,291 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// An HTTP response assembled by the handler and written in one place
+///
+/// Every response goes through [`Response::send`], so GET and HEAD produce the
+/// same status line and headers (including Content-Length) and only differ in
+/// whether the body is written.
+pub struct Response<'a> {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Cow<'a, [u8]>,
+}
+
+impl<'a> Response<'a> {
+    pub fn new(status: u16) -> Response<'a> {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Cow::Borrowed(&[]),
+        }
+    }
+
+    /// A small HTML error page, e.g. `<h1>404 Not Found</h1>`
+    pub fn error(status: u16) -> Response<'a> {
+        let body = format!("<h1>{} {}</h1>", status, reason_phrase(status));
+        Response::new(status)
+            .header("Content-Type", "text/html")
+            .header("Accept-Ranges", "none")
+            .body(body.into_bytes())
+    }
+
+    /// A redirect to `location`
+    pub fn redirect(status: u16, location: &str) -> Response<'a> {
+        Response::error(status).header("Location", location)
+    }
+
+    /// A file body, honouring a `Range: bytes=...` request, with a multipart/byteranges body for several ranges
+    pub fn file(contents: &'a [u8], mime_type: &str, range: Option<&str>) -> Response<'a> {
+        match Response::file_head(contents.len(), mime_type, range) {
+            (response, Some(FileBody::Range(body))) => response.body(&contents[body]),
+            (response, Some(FileBody::Parts { parts, end })) => {
+                let mut body = Vec::with_capacity(FileBody::parts_len(&parts, &end));
+                for (head, range) in parts {
+                    body.extend_from_slice(head.as_bytes());
+                    body.extend_from_slice(&contents[range]);
+                }
+                body.extend_from_slice(end.as_bytes());
+                response.body(body)
+            }
+            (response, None) => response,
+        }
+    }
+
+    /// Status and headers for a file of `len` bytes, and the parts of it that make up the body
+    ///
+    /// For files that are not in memory: send the head with [`Response::send_head`]
+    /// for [`FileBody::len`] bytes, then the body from the file. Without a body
+    /// the response is a complete error to pass to [`Response::send`].
+    pub fn file_head(len: usize, mime_type: &str, range: Option<&str>) -> (Response<'a>, Option<FileBody>) {
+        let (response, body) = match range.map(|range| parse_ranges(range, len)) {
+            Some(Some(ranges)) if ranges.is_empty() => {
+                return (Response::error(416).header("Content-Range", format!("bytes */{}", len)), None);
+            }
+            Some(Some(ranges)) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                let response = Response::new(206).header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+                (response.header("Content-Type", mime_type), FileBody::Range(start..end + 1))
+            }
+            Some(Some(ranges)) => {
+                let boundary = boundary();
+                let parts = (ranges.into_iter())
+                    .map(|(start, end)| {
+                        let head = format!(
+                            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                            boundary, mime_type, start, end, len
+                        );
+                        (head, start..end + 1)
+                    })
+                    .collect();
+                let content_type = format!("multipart/byteranges; boundary={}", boundary);
+                let response = Response::new(206).header("Content-Type", content_type);
+                (response, FileBody::Parts { parts, end: format!("\r\n--{}--\r\n", boundary) })
+            }
+            // Malformed requests, and ones asking for more than the whole file, get the full body
+            Some(None) | None => (Response::new(200).header("Content-Type", mime_type), FileBody::Range(0..len)),
+        };
+        (response.header("Accept-Ranges", "bytes"), Some(body))
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Response<'a> {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Cow<'a, [u8]>>) -> Response<'a> {
+        self.body = body.into();
+        self
+    }
+
+    /// The value of the first header named `name`
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        let mut headers = self.headers.iter();
+        headers.find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The response with a body of its own, no longer borrowing the one it was made from
+    pub fn into_owned(self) -> Response<'static> {
+        Response {
+            status: self.status,
+            headers: self.headers,
+            body: Cow::Owned(self.body.into_owned()),
+        }
+    }
+
+    /// Writes the response, leaving out the body when answering a HEAD request
+    pub fn send(&self, stream: &mut impl Write, head_only: bool) -> io::Result<()> {
+        self.send_head(stream, self.body.len())?;
+        if !head_only {
+            stream.write_all(&self.body)?;
+        }
+        stream.flush()
+    }
+
+    /// Writes the status line and headers for a body of `content_length` bytes that the caller sends
+    pub fn send_head(&self, stream: &mut impl Write, content_length: usize) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", content_length));
+        stream.write_all(head.as_bytes())
+    }
+
+    /// Writes the status line and headers for a body that runs until the connection is closed, e.g. an event stream
+    pub fn send_open_head(&self, stream: &mut impl Write) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.flush()
+    }
+}
+
+/// The part of a file that makes up a response body
+pub enum FileBody {
+    /// One stretch of the file, possibly all of it
+    Range(Range<usize>),
+    /// Several stretches of a multipart/byteranges body, each after the head of its part, then the closing delimiter
+    Parts { parts: Vec<(String, Range<usize>)>, end: String },
+}
+
+impl FileBody {
+    /// Length of the body in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            FileBody::Range(range) => range.len(),
+            FileBody::Parts { parts, end } => FileBody::parts_len(parts, end),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn parts_len(parts: &[(String, Range<usize>)], end: &str) -> usize {
+        parts.iter().map(|(head, range)| head.len() + range.len()).sum::<usize>() + end.len()
+    }
+}
+
+/// Most ranges one request may ask for before it gets the whole file instead
+const MAX_RANGES: usize = 64;
+
+/// Parses `bytes=` followed by `start-end`, `start-` or `-suffix` ranges separated by commas
+///
+/// Returns the inclusive start and end of the satisfiable ones, in the order
+/// asked, which is empty if none is. Malformed values and ones that would
+/// add up to more than the file (overlapping ranges can) give `None`, as do
+/// more than [`MAX_RANGES`] ranges.
+fn parse_ranges(value: &str, len: usize) -> Option<Vec<(usize, usize)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in spec.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        if let Some(range) = parse_range(spec, len)? {
+            ranges.push(range);
+        }
+    }
+    let total: usize = ranges.iter().map(|(start, end)| end - start + 1).sum();
+    (ranges.len() <= MAX_RANGES && (ranges.len() <= 1 || total <= len)).then_some(ranges)
+}
+
+/// Parses one range, which is `None` if malformed and `Some(None)` if it lies beyond the file
+fn parse_range(spec: &str, len: usize) -> Option<Option<(usize, usize)>> {
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(None);
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    Some((start < len).then_some((start, end)))
+}
+
+/// A boundary for multipart/byteranges bodies, random so that it won't turn up in the file
+fn boundary() -> String {
+    let mut bytes = [0u8; 12];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        // Unlikely, and a fixed boundary is still unlikely to be in the file
+        return "rshttp-byteranges".to_string();
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `mime_type` with a charset parameter if it is text that does not name its encoding
+///
+/// Covers `text/*`, JavaScript and JSON, for which browsers would otherwise
+/// guess the encoding of non-ASCII content.
+pub fn with_charset(mime_type: &str, charset: &str) -> String {
+    let essence = mime_type.split(';').next().unwrap_or("").trim();
+    let textual = essence.starts_with("text/") || matches!(essence, "application/javascript" | "application/json");
+    match textual && !mime_type.to_ascii_lowercase().contains("charset=") {
+        true => format!("{}; charset={}", mime_type, charset),
+        false => mime_type.to_string(),
+    }
+}
+
+/// The standard reason phrase for a status code
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,69 @@
+use regex_automata::meta::Regex;
+use rshttp::request;
+
+/// An internal rewrite of the request path, from --rewrite "PATTERN -> REPLACEMENT [last|continue]"
+///
+/// Rules are tried in order against the path without its query, each one
+/// seeing the result of the rules before it. `$1` or `${name}` in the
+/// replacement stand for capture groups. A matching rule marked `last` ends
+/// the rewriting; by default the next rules are tried too. A query in the
+/// replacement comes before the request's own.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pattern: Regex,
+    replacement: String,
+    last: bool,
+}
+
+impl Rewrite {
+    pub fn parse(value: &str) -> Result<Rewrite, String> {
+        let invalid = || format!("invalid rewrite '{}' (expected e.g. \"^/v1/(.*)$ -> /api/$1 last\")", value);
+        let (pattern, rest) = value.split_once("->").ok_or_else(invalid)?;
+        let mut words = rest.split_whitespace();
+        let replacement = words.next().filter(|replacement| replacement.starts_with('/')).ok_or_else(invalid)?;
+        let last = match words.next() {
+            Some("last") => true,
+            Some("continue") | None => false,
+            Some(_) => return Err(invalid()),
+        };
+        if words.next().is_some() {
+            return Err(invalid());
+        }
+        let pattern = Regex::new(pattern.trim()).map_err(|e| {
+            let reason = e.syntax_error().map_or_else(|| e.to_string(), |syntax| syntax.to_string());
+            format!("invalid rewrite pattern '{}':\n{}", pattern.trim(), reason)
+        })?;
+        Ok(Rewrite {
+            pattern,
+            replacement: replacement.to_string(),
+            last,
+        })
+    }
+}
+
+/// Applies the rules to a path and query, returning the rewritten ones if any rule matched
+pub fn apply(rules: &[Rewrite], path: &str, query: &str) -> Option<(String, String)> {
+    let (mut path, mut query) = (path.to_string(), query.to_string());
+    let mut rewritten = false;
+    for rule in rules {
+        let mut captures = rule.pattern.create_captures();
+        rule.pattern.captures(path.as_str(), &mut captures);
+        if !captures.is_match() {
+            continue;
+        }
+        let mut target = String::new();
+        captures.interpolate_string_into(&path, &rule.replacement, &mut target);
+        (path, query) = match target.split_once('?') {
+            Some((target_path, target_query)) if query.is_empty() => (target_path.to_string(), target_query.to_string()),
+            Some((target_path, target_query)) => (target_path.to_string(), format!("{}&{}", target_query, query)),
+            None => (target, query),
+        };
+        // A capture may have brought in `..` or `//`
+        path = request::normalize_path(&path);
+        rewritten = true;
+        if rule.last {
+            break;
+        }
+    }
+    rewritten.then_some((path, query))
+}
@@ -0,0 +1,205 @@
+use crate::glob;
+use rshttp::request::HeaderMap;
+use rshttp::response::Response;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+/// Largest request body read and thrown away before a stub response is sent
+const MAX_DISCARDED_BODY: u64 = 1024 * 1024;
+
+/// Canned responses from a routes file, for using the server as a stub in tests
+///
+/// ```toml
+/// [[route]]
+/// method = "POST"                  # optional, any method if left out
+/// path = "/api/users/*"            # a glob, as in access rules
+/// status = 201                     # optional, 200 by default
+/// body = '{"id": 1}'               # or body_file = "fixtures/user.json"
+/// delay_ms = 250                   # optional
+/// headers = { Content-Type = "application/json" }
+/// expect_headers = { Authorization = "Bearer test-token" }
+/// ```
+///
+/// The first route whose method, path and expected headers match answers
+/// the request. When a route's method and path match but its expected
+/// headers don't, and no later route matches either, the request is
+/// answered with 400 and the failed expectations, so tests see what the
+/// server actually received.
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+pub struct Route {
+    method: Option<String>,
+    path: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    delay: Duration,
+    expect_headers: Vec<(String, String)>,
+}
+
+/// What [`Routes::find`] made of a request
+pub enum Matched<'a> {
+    Route(&'a Route),
+    /// A route matched by method and path, but not all of its expected headers were there
+    Rejected(Vec<String>),
+    NoRoute,
+}
+
+impl Routes {
+    /// Loads a routes file; `body_file` paths are relative to the file's directory
+    pub fn load(path: &Path) -> Result<Routes, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Routes::parse(&source, dir).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(source: &str, dir: &Path) -> Result<Routes, String> {
+        let table: toml::Table = source.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        let entries = match table.get("route") {
+            Some(toml::Value::Array(entries)) => entries.as_slice(),
+            Some(_) => return Err("`route` must be an array of tables ([[route]])".to_string()),
+            None => &[],
+        };
+        let routes = entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| parse_route(entry, dir).map_err(|e| format!("route {}: {}", index + 1, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Routes { routes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn find(&self, method: &str, path: &str, headers: &HeaderMap) -> Matched<'_> {
+        let mut failed = Vec::new();
+        for route in &self.routes {
+            if route.method.as_deref().is_some_and(|expected| expected != method) || !glob::matches(&route.path, path) {
+                continue;
+            }
+            let missing: Vec<String> = route
+                .expect_headers
+                .iter()
+                .filter_map(|(name, expected)| match headers.get(name) {
+                    Some(actual) if actual == expected => None,
+                    Some(actual) => Some(format!("{}: expected {:?}, got {:?}", name, expected, actual)),
+                    None => Some(format!("{}: expected {:?}, but it was missing", name, expected)),
+                })
+                .collect();
+            if missing.is_empty() {
+                return Matched::Route(route);
+            }
+            if failed.is_empty() {
+                failed = missing;
+            }
+        }
+        match failed.is_empty() {
+            true => Matched::NoRoute,
+            false => Matched::Rejected(failed),
+        }
+    }
+}
+
+impl Route {
+    /// Waits for the configured delay, then builds the canned response
+    pub fn respond(&self) -> Response<'_> {
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+        let mut response = Response::new(self.status);
+        for (name, value) in &self.headers {
+            response = response.header(name, value.clone());
+        }
+        response.body(self.body.as_slice())
+    }
+}
+
+fn parse_route(entry: &toml::Value, dir: &Path) -> Result<Route, String> {
+    let entry = entry.as_table().ok_or("must be a table")?;
+    for key in entry.keys() {
+        if !matches!(
+            key.as_str(),
+            "method" | "path" | "status" | "body" | "body_file" | "delay_ms" | "headers" | "expect_headers"
+        ) {
+            return Err(format!("unknown key `{}`", key));
+        }
+    }
+
+    let string = |key: &str| -> Result<Option<String>, String> {
+        match entry.get(key) {
+            Some(toml::Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(format!("`{}` must be a string", key)),
+            None => Ok(None),
+        }
+    };
+    let integer = |key: &str| -> Result<Option<i64>, String> {
+        match entry.get(key) {
+            Some(toml::Value::Integer(value)) => Ok(Some(*value)),
+            Some(_) => Err(format!("`{}` must be an integer", key)),
+            None => Ok(None),
+        }
+    };
+    let headers = |key: &str| -> Result<Vec<(String, String)>, String> {
+        match entry.get(key) {
+            Some(toml::Value::Table(table)) => table
+                .iter()
+                .map(|(name, value)| match value {
+                    toml::Value::String(value) => Ok((name.clone(), value.clone())),
+                    _ => Err(format!("`{}.{}` must be a string", key, name)),
+                })
+                .collect(),
+            Some(_) => Err(format!("`{}` must be a table", key)),
+            None => Ok(Vec::new()),
+        }
+    };
+
+    let path = string("path")?.ok_or("missing `path`")?;
+    let status = match integer("status")? {
+        Some(status) => u16::try_from(status)
+            .ok()
+            .filter(|status| (100..=599).contains(status))
+            .ok_or_else(|| format!("invalid status {}", status))?,
+        None => 200,
+    };
+    let delay = match integer("delay_ms")? {
+        Some(delay) => Duration::from_millis(u64::try_from(delay).map_err(|_| format!("invalid delay_ms {}", delay))?),
+        None => Duration::ZERO,
+    };
+    let body = match (string("body")?, string("body_file")?) {
+        (Some(_), Some(_)) => return Err("set either `body` or `body_file`, not both".to_string()),
+        (Some(body), None) => body.into_bytes(),
+        (None, Some(file)) => fs::read(dir.join(&file)).map_err(|e| format!("body_file {}: {}", file, e))?,
+        (None, None) => Vec::new(),
+    };
+
+    Ok(Route {
+        method: string("method")?.map(|method| method.to_ascii_uppercase()),
+        path,
+        status,
+        headers: headers("headers")?,
+        body,
+        delay,
+        expect_headers: headers("expect_headers")?,
+    })
+}
+
+/// Reads and drops the request body, so closing the connection doesn't reset it before the client has the response
+///
+/// `head` is what was read with the request head, which may include the start of the body.
+pub fn discard_body(stream: &mut impl Read, head: &[u8], headers: &HeaderMap) -> io::Result<()> {
+    let Some(length) = headers.content_length() else {
+        return Ok(());
+    };
+    let received = head
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(0, |end| head.len() - end - 4) as u64;
+    let remaining = length.saturating_sub(received).min(MAX_DISCARDED_BODY);
+    io::copy(&mut stream.take(remaining), &mut io::sink())?;
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use crate::units;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::{Duration, SystemTime};
+use tracing::debug_span;
+
+/// Largest object that is served
+const MAX_OBJECT: u64 = 512 << 20;
+
+/// SHA-256 of an empty payload, which every GET sends
+const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Characters left alone in the path of a signed request; everything else is escaped as SigV4 expects
+const KEY: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~').remove(b'/');
+
+/// Objects of an S3 bucket, or of any storage speaking its API, served in place of a directory, from --s3
+///
+/// `--s3 my-bucket/site` serves the object `site/docs/index.html` at
+/// `/docs/`. Requests are signed with AWS Signature Version 4 when
+/// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are set (and
+/// AWS_SESSION_TOKEN, for temporary credentials), and sent anonymously for
+/// public buckets otherwise. Objects go through the file cache like files do,
+/// and are fetched again once --s3-ttl has passed.
+pub struct Bucket {
+    /// The endpoint, without a trailing slash
+    endpoint: String,
+    host: String,
+    bucket: String,
+    /// Prepended to every key, ending in a slash unless empty
+    prefix: String,
+    region: String,
+    credentials: Option<Credentials>,
+    agent: ureq::Agent,
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Bucket {
+    /// The bucket named by `BUCKET[/PREFIX]`, at `endpoint` or AWS, with credentials from the environment
+    pub fn new(location: &str, endpoint: Option<&str>, region: Option<&str>) -> Result<Bucket, String> {
+        let (bucket, prefix) = location.trim_matches('/').split_once('/').unwrap_or((location.trim_matches('/'), ""));
+        if bucket.is_empty() {
+            return Err(format!("invalid bucket '{}' (expected BUCKET or BUCKET/PREFIX)", location));
+        }
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let region = region.map(str::to_string).or_else(|| env("AWS_REGION")).or_else(|| env("AWS_DEFAULT_REGION"));
+        let region = region.unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://s3.{}.amazonaws.com", region),
+        };
+        let host = endpoint
+            .split_once("://")
+            .map(|(_, rest)| rest.split('/').next().unwrap_or(rest).to_string())
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| format!("invalid endpoint '{}' (expected e.g. http://localhost:9000)", endpoint))?;
+        let credentials = match (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key), Some(secret_key)) => Some(Credentials {
+                access_key,
+                secret_key,
+                session_token: env("AWS_SESSION_TOKEN"),
+            }),
+            _ => None,
+        };
+        Ok(Bucket {
+            endpoint,
+            host,
+            bucket: bucket.to_string(),
+            prefix: match prefix.is_empty() {
+                true => String::new(),
+                false => format!("{}/", prefix.trim_end_matches('/')),
+            },
+            region,
+            credentials,
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(30)).build(),
+        })
+    }
+
+    /// Where objects come from, for the banner
+    pub fn describe(&self) -> String {
+        let signed = if self.credentials.is_some() { "signed" } else { "anonymous" };
+        format!("s3://{}/{} at {} ({}, {})", self.bucket, self.prefix, self.endpoint, self.region, signed)
+    }
+
+    /// The key and contents of the object served for a URL path, `None` if there is none
+    ///
+    /// Directories are served as their index.html.
+    pub fn fetch(&self, url_path: &str) -> Result<Option<(String, Vec<u8>)>, String> {
+        let key = url_path.trim_start_matches('/');
+        let keys = match key.is_empty() || key.ends_with('/') {
+            true => vec![format!("{}index.html", key)],
+            false => vec![key.to_string(), format!("{}/index.html", key)],
+        };
+        for key in keys {
+            if let Some(contents) = self.get(&key)? {
+                return Ok(Some((key, contents)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let _fetch = debug_span!("s3_fetch").entered();
+        let path = format!("/{}/{}", self.bucket, utf8_percent_encode(&format!("{}{}", self.prefix, key), KEY));
+        let mut request = self.agent.get(&format!("{}{}", self.endpoint, path));
+        if let Some(credentials) = &self.credentials {
+            let now = units::iso_basic(SystemTime::now());
+            let mut headers = vec![
+                ("host", self.host.as_str()),
+                ("x-amz-content-sha256", EMPTY_PAYLOAD),
+                ("x-amz-date", now.as_str()),
+            ];
+            if let Some(token) = &credentials.session_token {
+                headers.push(("x-amz-security-token", token));
+            }
+            let authorization = self.authorization(credentials, &path, &headers, &now);
+            for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+                request = request.set(name, value);
+            }
+            request = request.set("Authorization", &authorization);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            // A missing key is a 403 without permission to list the bucket
+            Err(ureq::Error::Status(403 | 404, _)) => return Ok(None),
+            Err(e) => return Err(format!("request for {} failed: {}", key, e)),
+        };
+        let mut contents = Vec::new();
+        (response.into_reader().take(MAX_OBJECT).read_to_end(&mut contents))
+            .map_err(|e| format!("reading {} failed: {}", key, e))?;
+        Ok(Some(contents))
+    }
+
+    /// The `Authorization` header of a GET of `path` with `headers`, sorted by name, at `now`
+    fn authorization(&self, credentials: &Credentials, path: &str, headers: &[(&str, &str)], now: &str) -> String {
+        let date = &now[..8];
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let canonical_request = format!("GET\n{}\n\n{}\n{}\n{}", path, canonical_headers, signed_headers, EMPTY_PAYLOAD);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign =
+            format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", now, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+        let key = [date, &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", credentials.secret_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key,
+            scope,
+            signed_headers,
+            hex(&hmac(&key, string_to_sign.as_bytes()))
+        )
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,158 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// How the process ended up confined
+pub enum Sandbox {
+    /// Landlock rules allow reading the roots and writing a few extra paths
+    Landlock,
+    /// The process is chrooted into the served directory, which is now `/`
+    Chroot,
+}
+
+/// Paths the server still needs once sandboxed
+#[derive(Default)]
+pub struct Allowed {
+    /// Served directories and other files that are only read
+    pub read: Vec<PathBuf>,
+    /// Directories the server writes into (snapshots, reports)
+    pub write: Vec<PathBuf>,
+}
+
+/// Restricts filesystem access to `allowed`, falling back to chroot(root)
+///
+/// Must run before any thread is spawned: Landlock only confines the calling
+/// thread and the threads it starts afterwards. The chroot fallback needs root
+/// and only remaps `root` itself to `/`, so it is refused when `allowed` names
+/// any other path, even one inside `root`, as that would be left at a path
+/// that no longer exists.
+pub fn enter(root: &Path, allowed: &Allowed) -> io::Result<Sandbox> {
+    #[cfg(target_os = "linux")]
+    match landlock::restrict(allowed) {
+        Ok(()) => return Ok(Sandbox::Landlock),
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) || e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+        Err(e) => return Err(e),
+    }
+
+    if let Some(path) = allowed.read.iter().chain(&allowed.write).find(|path| path.as_path() != root) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Landlock is unavailable and chroot can only serve {} on its own, without {}", root.display(), path.display()),
+        ));
+    }
+    let c_root = CString::new(root.as_os_str().as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    unsafe {
+        if libc::chroot(c_root.as_ptr()) != 0 || libc::chdir(c"/".as_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(Sandbox::Chroot)
+}
+
+/// Raw Landlock system calls, see landlock(7)
+#[cfg(target_os = "linux")]
+mod landlock {
+    use super::Allowed;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::Path;
+
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REMOVE_FILE: u64 = 1 << 5;
+    const MAKE_DIR: u64 = 1 << 7;
+    const MAKE_REG: u64 = 1 << 8;
+    /// Every right of ABI version 1
+    const ALL_V1: u64 = (1 << 13) - 1;
+    /// Linking and renaming across directories (ABI version 2)
+    const REFER: u64 = 1 << 13;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub fn restrict(allowed: &Allowed) -> io::Result<()> {
+        let abi = unsafe {
+            libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, CREATE_RULESET_VERSION)
+        };
+        if abi < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Without REFER in the handled set, hard links across directories are always denied
+        let refer = if abi >= 2 { REFER } else { 0 };
+
+        let attr = RulesetAttr {
+            handled_access_fs: ALL_V1 | refer,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        for path in &allowed.read {
+            add_rule(&ruleset, path, READ_FILE | READ_DIR | refer)?;
+        }
+        for path in &allowed.write {
+            add_rule(&ruleset, path, READ_FILE | READ_DIR | WRITE_FILE | MAKE_REG | MAKE_DIR | REMOVE_FILE | refer)?;
+        }
+
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+        let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        // Directory-only rights are rejected on files
+        let access = if file.metadata()?.is_dir() {
+            access
+        } else {
+            access & (READ_FILE | WRITE_FILE | EXECUTE)
+        };
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, PoisonError};
+use tracing::debug;
+
+/// Extensions of stylesheet sources, in the order they are looked for
+const EXTENSIONS: [&str; 2] = ["scss", "sass"];
+
+/// Compiles Sass stylesheets for requests of the missing .css file next to them, from --sass
+///
+/// A request for `style.css` without a file of its own runs `style.scss` (or
+/// `style.sass`) through the compiler, dart-sass's `sass` command unless
+/// --sass-command names another. The result is cached like a file. A change
+/// to any stylesheet source drops every compiled one, since partials can be
+/// imported from anywhere.
+pub struct Sass {
+    command: String,
+    /// The .css paths compiled since the sources last changed
+    compiled: Mutex<HashSet<PathBuf>>,
+}
+
+impl Sass {
+    pub fn new(command: &str) -> Sass {
+        Sass {
+            command: command.to_string(),
+            compiled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Checks that the compiler can be run, returning its version
+    pub fn version(&self) -> Result<String, String> {
+        let output = Command::new(&self.command)
+            .arg("--version")
+            .output()
+            .map_err(|e| format!("cannot run `{}`: {}", self.command, e))?;
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+            false => Err(format!("`{} --version` failed ({})", self.command, output.status)),
+        }
+    }
+
+    /// The source a missing .css file is compiled from, if there is one
+    pub fn source(&self, css: &Path) -> Option<PathBuf> {
+        if css.extension().is_none_or(|extension| extension != "css") {
+            return None;
+        }
+        EXTENSIONS.iter().map(|extension| css.with_extension(extension)).find(|source| source.is_file())
+    }
+
+    /// Whether a changed file may be imported into compiled stylesheets
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn is_source(path: &Path) -> bool {
+        path.extension().is_some_and(|extension| EXTENSIONS.iter().any(|ext| extension == *ext))
+    }
+
+    /// Compiles `source` into the stylesheet served as `css`, or returns the compiler's complaint
+    pub fn compile(&self, css: &Path, source: &Path) -> Result<Vec<u8>, String> {
+        debug!("Compiling {} with `{}`", source.display(), self.command);
+        let output = Command::new(&self.command)
+            .arg("--no-source-map")
+            .arg(source)
+            .output()
+            .map_err(|e| format!("cannot run `{}`: {}", self.command, e))?;
+        if !output.status.success() {
+            let complaint = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(match complaint.is_empty() {
+                true => format!("`{}` failed ({})", self.command, output.status),
+                false => complaint,
+            });
+        }
+        self.compiled.lock().unwrap_or_else(PoisonError::into_inner).insert(css.to_path_buf());
+        Ok(output.stdout)
+    }
+
+    /// The stylesheets compiled so far, to drop from the cache once a source changes
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn take_compiled(&self) -> Vec<PathBuf> {
+        self.compiled.lock().unwrap_or_else(PoisonError::into_inner).drain().collect()
+    }
+}
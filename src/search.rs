@@ -0,0 +1,258 @@
+use crate::walk;
+use rshttp::response::Response;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// URL that answers searches, with the words to look for in `q`
+pub const PATH: &str = "/_rshttps/search";
+
+/// Extensions of the files that are indexed
+const EXTENSIONS: [&str; 5] = ["txt", "md", "markdown", "html", "htm"];
+
+/// Largest file that is indexed
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Most results a search answers with
+const MAX_RESULTS: usize = 50;
+
+/// How much text a snippet shows before and after the first match
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 140;
+
+/// Full-text search over the served text, HTML and Markdown files, from --search
+///
+/// The files below the roots are read into an inverted index at startup, and
+/// the watcher updates it as they change. `GET /_rshttps/search?q=some+words`
+/// answers with JSON listing the files that contain every word, the ones that
+/// mention them most first, each with its title and a snippet around the first
+/// match. Hidden files and files over a megabyte are left out, and so are files
+/// the access rules wouldn't let the client read.
+pub struct Search {
+    roots: Vec<PathBuf>,
+    index: RwLock<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    documents: HashMap<PathBuf, Document>,
+    /// For every word, the files it appears in and how often
+    words: HashMap<String, HashMap<PathBuf, u32>>,
+}
+
+struct Document {
+    title: Option<String>,
+    text: String,
+}
+
+impl Search {
+    pub fn new(roots: Vec<PathBuf>) -> Search {
+        let search = Search {
+            roots,
+            index: RwLock::new(Index::default()),
+        };
+        for root in &search.roots {
+            search.update(root);
+        }
+        search
+    }
+
+    /// How many files are indexed
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().documents.len()
+    }
+
+    /// Indexes `path` again, a file or a directory with everything below it, dropping it if it is gone
+    pub fn update(&self, path: &Path) {
+        let files = match path.is_dir() {
+            true => walk::files(path),
+            false => vec![path.to_path_buf()],
+        };
+        let documents: Vec<(PathBuf, Document)> =
+            files.into_iter().filter_map(|file| self.read(&file).map(|document| (file, document))).collect();
+        let mut index = self.index.write().unwrap();
+        let stale: Vec<PathBuf> = index.documents.keys().filter(|file| file.starts_with(path)).cloned().collect();
+        for file in stale {
+            index.remove(&file);
+        }
+        for (file, document) in documents {
+            index.insert(file, document);
+        }
+    }
+
+    /// Answers a search of the files below `root` with the query of a request
+    pub fn respond(&self, root: &Path, query: &str, readable: &dyn Fn(&str) -> bool) -> Response<'static> {
+        let Some((_, q)) = url::form_urlencoded::parse(query.as_bytes()).find(|(name, _)| name == "q") else {
+            return Response::error(400);
+        };
+        let terms: Vec<String> = words(&q).map(|(_, word)| word).collect();
+        let index = self.index.read().unwrap();
+        let mut results: Vec<(u32, String, &Document)> = index
+            .matches(&terms)
+            .into_iter()
+            .filter_map(|(file, score)| {
+                let relative = file.strip_prefix(root).ok()?.to_string_lossy().replace('\\', "/");
+                let url = format!("/{}", relative);
+                readable(&url).then(|| (score, url, &index.documents[file]))
+            })
+            .collect();
+        results.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let total = results.len();
+        let results: Vec<_> = results
+            .into_iter()
+            .take(MAX_RESULTS)
+            .map(|(_, url, document)| {
+                json!({
+                    "path": url,
+                    "title": document.title,
+                    "snippet": snippet(&document.text, &terms),
+                })
+            })
+            .collect();
+        let body = json!({ "query": q, "total": total, "results": results });
+        Response::new(200)
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(body.to_string().into_bytes())
+    }
+
+    /// The title and text of a file that is indexed
+    fn read(&self, file: &Path) -> Option<Document> {
+        let root = self.roots.iter().find(|root| file.starts_with(root))?;
+        let relative = file.strip_prefix(root).ok()?;
+        if relative.iter().any(|segment| segment.to_string_lossy().starts_with('.')) {
+            return None;
+        }
+        let extension = file.extension()?.to_str()?.to_ascii_lowercase();
+        if !EXTENSIONS.contains(&extension.as_str()) || fs::metadata(file).ok()?.len() > MAX_FILE_SIZE {
+            return None;
+        }
+        let contents = fs::read_to_string(file).ok()?;
+        Some(match extension.as_str() {
+            "html" | "htm" => Document {
+                title: html_title(&contents),
+                text: collapse(&html_text(&contents)),
+            },
+            "md" | "markdown" => Document {
+                title: contents.lines().find_map(|line| line.strip_prefix("# ")).map(|title| title.trim().to_string()),
+                text: collapse(&contents),
+            },
+            _ => Document {
+                title: None,
+                text: collapse(&contents),
+            },
+        })
+    }
+}
+
+impl Index {
+    fn insert(&mut self, file: PathBuf, document: Document) {
+        for (_, word) in words(&document.text) {
+            *self.words.entry(word).or_default().entry(file.clone()).or_default() += 1;
+        }
+        self.documents.insert(file, document);
+    }
+
+    fn remove(&mut self, file: &Path) {
+        let Some(document) = self.documents.remove(file) else {
+            return;
+        };
+        for (_, word) in words(&document.text) {
+            if let Some(files) = self.words.get_mut(&word) {
+                files.remove(file);
+                if files.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// The files containing every one of `terms`, with how often they appear in them
+    fn matches(&self, terms: &[String]) -> Vec<(&PathBuf, u32)> {
+        let Some((first, rest)) = terms.split_first() else {
+            return Vec::new();
+        };
+        let Some(files) = self.words.get(first) else {
+            return Vec::new();
+        };
+        files
+            .iter()
+            .filter_map(|(file, &count)| {
+                rest.iter().try_fold(count, |score, term| Some(score + self.words.get(term)?.get(file)?))
+                    .map(|score| (file, score))
+            })
+            .collect()
+    }
+}
+
+/// The words of `text`, lowercased, with where they start
+fn words(text: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().nth(1).is_some() && word.len() <= 64)
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word.to_lowercase()))
+}
+
+/// The text around the first of `terms` found in `text`
+fn snippet(text: &str, terms: &[String]) -> String {
+    let at = words(text).find(|(_, word)| terms.contains(word)).map_or(0, |(at, _)| at);
+    let start = boundary(text, at.saturating_sub(SNIPPET_BEFORE));
+    let end = boundary(text, (at + SNIPPET_AFTER).min(text.len()));
+    let before = if start > 0 { "…" } else { "" };
+    let after = if end < text.len() { "…" } else { "" };
+    format!("{}{}{}", before, text[start..end].trim(), after)
+}
+
+/// The nearest character boundary at or after `at`
+fn boundary(text: &str, mut at: usize) -> usize {
+    while !text.is_char_boundary(at) {
+        at += 1;
+    }
+    at
+}
+
+/// Text with every run of whitespace made a single space
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn html_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    Some(collapse(&decode_entities(&html[start..end]))).filter(|title| !title.is_empty())
+}
+
+/// The text of an HTML page, without its tags, scripts and styles
+fn html_text(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let mut text = String::new();
+    let mut rest = 0;
+    while let Some(open) = lower[rest..].find('<').map(|open| rest + open) {
+        text.push_str(&decode_entities(&html[rest..open]));
+        text.push(' ');
+        let Some(close) = lower[open..].find('>').map(|close| open + close + 1) else {
+            return text;
+        };
+        rest = close;
+        for element in ["script", "style"] {
+            if lower[open + 1..].starts_with(element) {
+                let end = format!("</{}", element);
+                rest = lower[close..].find(&end).map_or(html.len(), |end| close + end);
+            }
+        }
+    }
+    text.push_str(&decode_entities(&html[rest..]));
+    text
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
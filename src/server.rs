@@ -0,0 +1,278 @@
+//! A static file server to embed in other programs
+//!
+//! ```no_run
+//! let server = rshttp::Server::builder()
+//!     .root("public")
+//!     .port(0)
+//!     .on_request(|event| println!("{} {} {}", event.method, event.path, event.status))
+//!     .build()?;
+//! println!("Serving at http://{}/", server.local_addr()?);
+//! server.serve()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! It answers GET and HEAD with the files below the root, through
+//! [`respond`](crate::respond), on a fixed number of worker threads. The
+//! caching and other features of the rshttp binary are not part of it;
+//! logging, authentication, extra headers and compression are available as
+//! [middlewares](crate::middleware), next to ones of your own. Authentication
+//! runs the same [`AuthProvider`](crate::auth::AuthProvider)s as the binary,
+//! or your own:
+//!
+//! ```no_run
+//! use rshttp::auth::JwtAuth;
+//! use rshttp::middleware::Authenticate;
+//!
+//! let server = rshttp::Server::builder().middleware(Authenticate::new(JwtAuth::new("secret"))).build()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::middleware::{self, Incoming, Middleware};
+use crate::request;
+use crate::response::Response;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Hook = Arc<dyn Fn(&RequestEvent) + Send + Sync>;
+
+/// How long a client may take to send its request, or to take the response
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One answered request, passed to the [`ServerBuilder::on_request`] hooks
+#[derive(Debug, Clone)]
+pub struct RequestEvent {
+    pub peer: Option<SocketAddr>,
+    /// Empty when the request head could not be parsed
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration: Duration,
+}
+
+/// Configures a [`Server`], see [`Server::builder`]
+pub struct ServerBuilder {
+    root: PathBuf,
+    host: String,
+    port: u16,
+    threads: usize,
+    hooks: Vec<Hook>,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl ServerBuilder {
+    /// Directory to serve, the working directory by default
+    pub fn root(mut self, root: impl AsRef<Path>) -> ServerBuilder {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Address to bind to, 127.0.0.1 by default
+    pub fn host(mut self, host: impl Into<String>) -> ServerBuilder {
+        self.host = host.into();
+        self
+    }
+
+    /// Port to bind to, 8000 by default; 0 lets the OS pick one, see [`Server::local_addr`]
+    pub fn port(mut self, port: u16) -> ServerBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Number of worker threads handling connections, 4 by default
+    pub fn threads(mut self, threads: usize) -> ServerBuilder {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Calls `hook` after every answered request, on the worker that answered it
+    pub fn on_request(mut self, hook: impl Fn(&RequestEvent) + Send + Sync + 'static) -> ServerBuilder {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Adds `middleware` to the end of the chain every request goes through, see [`middleware`](crate::middleware)
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> ServerBuilder {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Binds the listening socket; requests are answered once [`Server::serve`] runs
+    pub fn build(self) -> io::Result<Server> {
+        let listener = match self.host.contains(':') {
+            true => TcpListener::bind(format!("[{}]:{}", self.host, self.port))?,
+            false => TcpListener::bind(format!("{}:{}", self.host, self.port))?,
+        };
+        Ok(Server {
+            listener,
+            root: self.root,
+            threads: self.threads,
+            hooks: self.hooks,
+            middlewares: self.middlewares,
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// A bound static file server
+pub struct Server {
+    listener: TcpListener,
+    root: PathBuf,
+    threads: usize,
+    hooks: Vec<Hook>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Stops a [`Server`] from another thread, see [`Server::stop_handle`]
+#[derive(Clone)]
+pub struct StopHandle {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopHandle {
+    /// Makes [`Server::serve`] return once the requests being answered are done
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the acceptor blocked in accept()
+        let _ = TcpStream::connect_timeout(&self.address, Duration::from_secs(1));
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            root: PathBuf::from("."),
+            host: "127.0.0.1".to_string(),
+            port: 8000,
+            threads: 4,
+            hooks: Vec::new(),
+            middlewares: Vec::new(),
+        }
+    }
+
+    /// The address the server listens on, with the port the OS picked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn stop_handle(&self) -> io::Result<StopHandle> {
+        let mut address = self.local_addr()?;
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(StopHandle {
+            address,
+            stopped: Arc::clone(&self.stopped),
+        })
+    }
+
+    /// Answers requests until stopped through a [`StopHandle`]
+    pub fn serve(self) -> io::Result<()> {
+        let root = Arc::new(self.root);
+        let hooks = Arc::new(self.hooks);
+        let middlewares = Arc::new(self.middlewares);
+        let (connections, receiver) = sync_channel::<TcpStream>(self.threads * 16);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers: Vec<_> = (0..self.threads)
+            .map(|id| {
+                let (receiver, root) = (Arc::clone(&receiver), Arc::clone(&root));
+                let (hooks, middlewares) = (Arc::clone(&hooks), Arc::clone(&middlewares));
+                thread::Builder::new()
+                    .name(format!("rshttp-{}", id))
+                    .spawn(move || work(&receiver, &root, &hooks, &middlewares))
+            })
+            .collect::<io::Result<_>>()?;
+
+        for stream in self.listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    // Workers only exit once the sender is gone
+                    let _ = connections.send(stream);
+                }
+                // e.g. the client gave up before it was accepted
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        drop(connections);
+        for worker in workers {
+            let _ = worker.join();
+        }
+        Ok(())
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<TcpStream>>, root: &Path, hooks: &[Hook], middlewares: &[Box<dyn Middleware>]) {
+    loop {
+        let next = receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv();
+        let Ok(mut stream) = next else {
+            return;
+        };
+        let started = Instant::now();
+        let peer = stream.peer_addr().ok();
+        let _ = stream.set_read_timeout(Some(TIMEOUT));
+        let _ = stream.set_write_timeout(Some(TIMEOUT));
+        let Ok((method, path, status)) = answer(&mut stream, peer, root, middlewares) else {
+            continue;
+        };
+        let event = RequestEvent {
+            peer,
+            method,
+            path,
+            status,
+            duration: started.elapsed(),
+        };
+        for hook in hooks {
+            hook(&event);
+        }
+    }
+}
+
+/// Reads one request from `stream` and answers it through the middlewares
+fn answer(
+    stream: &mut TcpStream,
+    peer: Option<SocketAddr>,
+    root: &Path,
+    middlewares: &[Box<dyn Middleware>],
+) -> io::Result<(String, String, u16)> {
+    let head = request::read_head(stream)?;
+    let head = String::from_utf8_lossy(&head);
+    let parsed = match request::parse(&head) {
+        Ok(parsed) => parsed,
+        Err(malformed) => {
+            let response = Response::error(malformed.status).header("Connection", "close");
+            response.send(stream, false)?;
+            return Ok((String::new(), String::new(), response.status()));
+        }
+    };
+    let (path, query) = match parsed.target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (parsed.target, None),
+    };
+    let mut incoming = Incoming {
+        peer,
+        method: parsed.method.to_string(),
+        path: request::normalize_path(path),
+        query,
+        headers: parsed.headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        principal: None,
+    };
+    let response = middleware::run(middlewares, &mut incoming, |incoming| {
+        crate::respond(root, &incoming.method, &incoming.path, incoming.header("Range"))
+    });
+    response.send(stream, incoming.method == "HEAD")?;
+    Ok((incoming.method, incoming.path, response.status()))
+}
@@ -0,0 +1,274 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use tracing::{error, info};
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW,
+    RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW, SC_HANDLE,
+    SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP,
+    SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP,
+    SERVICE_DEMAND_START, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_STATUS,
+    SERVICE_STATUS_CURRENT_STATE, SERVICE_STATUS_HANDLE, SERVICE_STOP, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+    SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+};
+
+/// Standard access right to delete an object, here the service
+const DELETE: u32 = 0x0001_0000;
+
+/// Arguments of the `service` subcommand
+#[derive(clap::Args, Debug)]
+pub struct ServiceArgs {
+    #[command(subcommand)]
+    action: ServiceAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ServiceAction {
+    /// Register a service that serves with the options after `--`, started with Windows
+    /// (e.g. `rshttp service install -- -d C:\site --port 80`); run it from an elevated prompt
+    Install {
+        /// Name of the service
+        #[arg(long, default_value = "rshttp")]
+        name: String,
+        /// Leave the service to be started by hand, e.g. with `sc start rshttp`
+        #[arg(long)]
+        manual: bool,
+        /// Server options, with absolute paths as services start in the system directory
+        #[arg(last = true)]
+        options: Vec<String>,
+    },
+    /// Stop and remove the service
+    Uninstall {
+        /// Name of the service
+        #[arg(long, default_value = "rshttp")]
+        name: String,
+    },
+}
+
+/// Installs or uninstalls the service, returning the process exit code
+pub fn run(args: &ServiceArgs) -> i32 {
+    let result = match &args.action {
+        ServiceAction::Install { name, manual, options } => install(name, *manual, options),
+        ServiceAction::Uninstall { name } => uninstall(name),
+    };
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn install(name: &str, manual: bool, options: &[String]) -> io::Result<String> {
+    let exe = std::env::current_exe()?;
+    let mut command = quote(&exe.to_string_lossy());
+    // The server finds out it runs as a service from the hidden --as-service flag
+    for arg in ["--as-service", name].iter().copied().chain(options.iter().map(String::as_str)) {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+    let manager = Handle::manager(SC_MANAGER_CREATE_SERVICE)?;
+    let start = if manual { SERVICE_DEMAND_START } else { SERVICE_AUTO_START };
+    let service = unsafe {
+        CreateServiceW(
+            manager.0,
+            wide(name).as_ptr(),
+            wide(&format!("rshttp ({})", name)).as_ptr(),
+            SERVICE_QUERY_STATUS,
+            SERVICE_WIN32_OWN_PROCESS,
+            start,
+            SERVICE_ERROR_NORMAL,
+            wide(&command).as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        )
+    };
+    Handle::new(service)?;
+    let when = match manual {
+        true => format!("start it with `sc start {}`", name),
+        false => format!("it starts with Windows, or now with `sc start {}`", name),
+    };
+    Ok(format!("Installed service {}: {}", name, when))
+}
+
+fn uninstall(name: &str) -> io::Result<String> {
+    let manager = Handle::manager(SC_MANAGER_CONNECT)?;
+    let service = Handle::new(unsafe { OpenServiceW(manager.0, wide(name).as_ptr(), SERVICE_STOP | DELETE) })?;
+    let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+    // Fails when the service isn't running, which is fine
+    unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) };
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(format!("Removed service {}", name))
+}
+
+/// A Service Control Manager handle, closed on drop
+struct Handle(SC_HANDLE);
+
+impl Handle {
+    fn new(handle: SC_HANDLE) -> io::Result<Handle> {
+        match handle.is_null() {
+            true => Err(io::Error::last_os_error()),
+            false => Ok(Handle(handle)),
+        }
+    }
+
+    fn manager(access: u32) -> io::Result<Handle> {
+        Handle::new(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) })
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/// What the control handler needs once the Service Control Manager has started the service
+struct Service {
+    name: Vec<u16>,
+    status: Mutex<Option<SERVICE_STATUS_HANDLE>>,
+    on_stop: Box<dyn Fn() + Send + Sync>,
+}
+
+// The status handle is only ever passed back to SetServiceStatus
+unsafe impl Send for Service {}
+unsafe impl Sync for Service {}
+
+static SERVICE: OnceLock<Service> = OnceLock::new();
+
+/// Reports to the Service Control Manager that the service runs, calling `on_stop` when it is told to stop
+///
+/// The dispatcher gets its own thread, as the server keeps the main one.
+pub fn start(name: &str, on_stop: impl Fn() + Send + Sync + 'static) {
+    let service = Service {
+        name: wide(name),
+        status: Mutex::new(None),
+        on_stop: Box::new(on_stop),
+    };
+    if SERVICE.set(service).is_err() {
+        return;
+    }
+    thread::spawn(|| {
+        let service = SERVICE.get().expect("set above");
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: service.name.as_ptr() as *mut u16,
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // Returns once the service has stopped
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            error!("Not started as a service: {}", io::Error::last_os_error());
+        }
+    });
+}
+
+/// Tells the Service Control Manager that the service has stopped, before the process exits
+pub fn stopped() {
+    report(SERVICE_STOPPED);
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let Some(service) = SERVICE.get() else {
+        return;
+    };
+    let handle = RegisterServiceCtrlHandlerExW(service.name.as_ptr(), Some(control), ptr::null());
+    if handle.is_null() {
+        error!("Failed to register the service control handler: {}", io::Error::last_os_error());
+        return;
+    }
+    *service.status.lock().unwrap() = Some(handle);
+    report(SERVICE_RUNNING);
+    info!("Running as service {}", String::from_utf16_lossy(&service.name[..service.name.len() - 1]));
+}
+
+unsafe extern "system" fn control(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut core::ffi::c_void,
+    _context: *mut core::ffi::c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            report(SERVICE_STOP_PENDING);
+            if let Some(service) = SERVICE.get() {
+                (service.on_stop)();
+            }
+            0
+        }
+        SERVICE_CONTROL_INTERROGATE => 0,
+        // ERROR_CALL_NOT_IMPLEMENTED
+        _ => 120,
+    }
+}
+
+fn report(state: SERVICE_STATUS_CURRENT_STATE) {
+    let Some(handle) = SERVICE.get().and_then(|service| *service.status.lock().unwrap()) else {
+        return;
+    };
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: match state {
+            SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        dwWin32ExitCode: 0,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        // The drain may take up to --drain-timeout
+        dwWaitHint: if state == SERVICE_STOP_PENDING { 30_000 } else { 0 },
+    };
+    unsafe { SetServiceStatus(handle, &status) };
+}
+
+/// A NUL-terminated UTF-16 copy of `text`
+fn wide(text: &str) -> Vec<u16> {
+    OsStr::new(text).encode_wide().chain([0]).collect()
+}
+
+/// Quotes an argument so the C runtime splits the service's command line back into the same arguments
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Backslashes before a quote are escaped, and so is the quote
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            quoted.push(c);
+        }
+    }
+    // Backslashes before the closing quote are escaped too
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
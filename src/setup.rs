@@ -0,0 +1,891 @@
+use crate::access::{AccessPolicy, Cidr, IpFilter, TrustedProxies};
+use crate::accesslog::AccessLog;
+use crate::banner::Banner;
+use crate::cache::{Loads, MemoryCache, NoCache, RedisCache, Tiered};
+use crate::cgi::Cgi;
+use crate::events::ChangeEvents;
+#[cfg(feature = "fallback")]
+use crate::fallback::{CachePolicy, FallbackOrigin};
+use crate::gitref::GitTree;
+use crate::har::Har;
+use crate::htpasswd::HtpasswdFile;
+use crate::ignore::{self, IgnoreRules};
+use crate::images::Images;
+use crate::inspector::Inspector;
+use crate::language::Languages;
+use crate::listing::Listing;
+use crate::livereload::LiveReload;
+use crate::lua::LuaHooks;
+use crate::manifest::Manifest;
+use crate::markdown::Markdown;
+use crate::metrics::Metrics;
+use crate::mime::MimeTypes;
+use crate::mock::Mocks;
+use crate::oidc::OidcClient;
+use crate::placeholders::Robots;
+use crate::plugins::Plugins;
+use crate::recording::{Recorder, Replay};
+use crate::requestdb::RequestDb;
+use crate::routes::Routes;
+use crate::sass::Sass;
+use crate::search::Search;
+use crate::shares::Shares;
+use crate::shutdown::{PeerConnections, Shutdown};
+use crate::signing::UrlSigner;
+use crate::singlefile::SingleFile;
+use crate::sitearchive::SiteArchive;
+use crate::sitemap::Sitemap;
+use crate::snapshots::Snapshots;
+use crate::startup::Problems;
+use crate::template::Templates;
+use crate::throttle::Shaping;
+use crate::listener::Timeouts;
+use crate::upload::{self, Uploads};
+use crate::webdav::WebDav;
+#[cfg(feature = "webhook")]
+use crate::webhook::Webhook;
+use crate::{archive, bundle, events, geoip, inject, inspector, manifest, metrics, s3, search, shares, sitemap, snapshots};
+use crate::{canonical_root, preload, seconds, units, Cli, Command, Context, FileCache, Roots, Security};
+use crate::{CACHE_ADMIN_PATH, DEBUG_ECHO_PATH};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Makes the [`Context`] the options describe
+///
+/// [`ContextBuilder::load`] reads everything the options name before the process is sandboxed, so all
+/// problems are reported together, and [`ContextBuilder::build`] puts it together once it is.
+pub struct ContextBuilder<'a> {
+    cli: &'a Cli,
+    roots: Roots,
+    cache: FileCache,
+    /// Follows changes from other instances, on a thread that has to wait for the sandbox
+    shared_tier: Option<Arc<Tiered<RedisCache>>>,
+    snapshots: Option<Snapshots>,
+    exec_watch: Vec<PathBuf>,
+    request_db: Option<RequestDb>,
+    har: Option<Har>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    access_policy: Option<AccessPolicy>,
+    ignore_rules: Option<IgnoreRules>,
+    routes: Option<Routes>,
+    mocks: Option<Mocks>,
+    markdown: Option<Markdown>,
+    sass: Option<Sass>,
+    templates: Option<Templates>,
+    listing: Option<Listing>,
+    images: Option<Images>,
+    site_archive: Option<SiteArchive>,
+    git_tree: Option<GitTree>,
+    bucket: Option<s3::Bucket>,
+    plugins: Option<Plugins>,
+    lua: Option<LuaHooks>,
+    geo_filter: Option<geoip::GeoFilter>,
+    single_file: Option<SingleFile>,
+    htpasswd: Option<HtpasswdFile>,
+    oidc: Option<OidcClient>,
+}
+
+impl<'a> ContextBuilder<'a> {
+    /// Reads the roots, files and programs the options name, adding what keeps them from being used to
+    /// `problems` and what they enable to `banner`
+    pub fn load(cli: &'a Cli, problems: &mut Problems, banner: &mut Banner) -> ContextBuilder<'a> {
+        let roots = Roots {
+            default: canonical_root(PathBuf::from(&cli.directory)),
+            vhosts: cli
+                .vhosts
+                .iter()
+                .map(|(host, dir)| (host.clone(), canonical_root(dir.clone())))
+                .collect(),
+            mountpoints: HashSet::new(),
+        };
+        if !roots.default.is_dir() {
+            problems.push(
+                "E100",
+                format!("{} is not a directory", roots.default.display()),
+                Some("pass the directory to serve with --directory"),
+            );
+        }
+        banner.root(None, &roots.default);
+        for (host, dir) in &roots.vhosts {
+            if dir.is_dir() {
+                banner.root(Some(host), dir);
+            } else {
+                problems.push(
+                    "E101",
+                    format!("root of virtual host {} ({}) is not a directory", host, dir.display()),
+                    Some("check the DIR part of --vhost HOST=DIR"),
+                );
+            }
+        }
+        let mut shared_tier = None;
+        let cache: FileCache = match &cli.shared_cache {
+            _ if cli.no_cache => {
+                banner.feature("Cache", "off, every request reads from disk");
+                Arc::new(NoCache)
+            }
+            Some(url) => match RedisCache::new(url) {
+                Ok(shared) => {
+                    banner.feature(
+                        "Cache",
+                        format!("{} in memory, and in Redis at {}", units::size(cli.cache_size), shared.address()),
+                    );
+                    let tiered = Arc::new(Tiered::new(MemoryCache::new(cli.cache_size), shared));
+                    shared_tier = Some(Arc::clone(&tiered));
+                    tiered
+                }
+                Err(e) => {
+                    problems.push(
+                        "E302",
+                        format!("invalid --shared-cache {}: {}", url, e),
+                        Some("e.g. redis://127.0.0.1:6379/0"),
+                    );
+                    Arc::new(MemoryCache::new(cli.cache_size))
+                }
+            },
+            None => {
+                banner.feature("Cache", format!("{} in memory", units::size(cli.cache_size)));
+                Arc::new(MemoryCache::new(cli.cache_size))
+            }
+        };
+
+        let snapshots = match &cli.snapshots {
+            Some(dir) => match Snapshots::new(dir, &roots.default) {
+                Ok(snapshots) => Some(snapshots),
+                Err(e) => {
+                    problems.push("E102", format!("cannot keep snapshots in {}: {}", dir.display(), e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut exec_watch = Vec::new();
+        for dir in &cli.exec_watch {
+            match dir.is_dir() {
+                true => exec_watch.push(canonical_root(dir.clone())),
+                false => problems.push(
+                    "E103",
+                    format!("--exec-watch {} is not a directory", dir.display()),
+                    Some("pass the directory holding the sources --exec builds from"),
+                ),
+            }
+        }
+
+        #[cfg(not(feature = "sqlite"))]
+        let request_db = None;
+        #[cfg(feature = "sqlite")]
+        let request_db = match &cli.request_db {
+            Some(path) => match RequestDb::open(path) {
+                Ok(request_db) => {
+                    banner.feature("Requests", format!("recorded in {}", path.display()));
+                    Some(request_db)
+                }
+                Err(e) => {
+                    problems.push("E106", format!("cannot open request database {}: {}", path.display(), e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+        let har = match &cli.har {
+            Some(path) => match Har::create(path, cli.har_bodies) {
+                Ok(har) => {
+                    let bodies = if cli.har_bodies { ", with bodies" } else { "" };
+                    banner.feature("HAR", format!("requests written to {} on shutdown{}", path.display(), bodies));
+                    Some(har)
+                }
+                Err(e) => {
+                    problems.push("E107", format!("cannot create HAR file {}: {}", path.display(), e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let recorder = match &cli.record {
+            Some(dir) => match Recorder::create(dir) {
+                Ok(recorder) => {
+                    banner.feature("Record", format!("responses kept in {}", dir.display()));
+                    Some(recorder)
+                }
+                Err(e) => {
+                    problems.push("E108", format!("cannot record into {}: {}", dir.display(), e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(dir) = &cli.fallback_store {
+            match fs::create_dir_all(dir) {
+                Ok(()) => banner.feature("Store", format!("files from the fallback origin saved in {}", dir.display())),
+                Err(e) => problems.push("E110", format!("cannot save fetched files in {}: {}", dir.display(), e), None),
+            }
+        }
+        let replay = match &cli.replay {
+            Some(dir) => match Replay::load(dir) {
+                Ok(replay) => {
+                    banner.feature("Replay", format!("{} recorded response(s) from {}", replay.len(), dir.display()));
+                    Some(replay)
+                }
+                Err(e) => {
+                    problems.push(
+                        "E109",
+                        format!("cannot load the recording in {}: {}", dir.display(), e),
+                        Some("record one first with --record DIR"),
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let access_policy = match &cli.access_rules {
+            Some(path) => match AccessPolicy::load(path) {
+                Ok(policy) => {
+                    banner.feature("Access", format!("rules from {}", path.display()));
+                    Some(policy)
+                }
+                Err(e) => {
+                    problems.push("E300", format!("invalid access rules: {}", e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let ignore_rules = match IgnoreRules::load(&roots.default) {
+            Ok(Some(rules)) => {
+                banner.feature("Ignored", format!("{} pattern(s) from {}", rules.len(), ignore::FILE_NAME));
+                Some(rules)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                problems.push("E122", format!("cannot read {}: {}", ignore::FILE_NAME, e), None);
+                None
+            }
+        };
+
+        let routes = match &cli.routes {
+            Some(path) => match Routes::load(path) {
+                Ok(routes) => {
+                    banner.feature("Routes", format!("{} stub route(s) from {}", routes.len(), path.display()));
+                    Some(routes)
+                }
+                Err(e) => {
+                    problems.push("E303", format!("invalid routes: {}", e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+        let mocks = match &cli.mock {
+            Some(dir) if dir.is_dir() => {
+                let mocks = Mocks::new(dir, &cli.mock_prefix, cli.mock_latency.unwrap_or_default());
+                let resources = format!("{} resource(s) from {}", mocks.len(), dir.display());
+                banner.feature("Mock API", format!("{} at {}", resources, mocks.prefix()));
+                Some(mocks)
+            }
+            Some(dir) => {
+                problems.push("E111", format!("mock directory {} does not exist", dir.display()), None);
+                None
+            }
+            None => None,
+        };
+        let markdown = match Markdown::new(cli.markdown_template.as_deref()) {
+            Ok(markdown) if cli.render_markdown => {
+                let template = cli.markdown_template.as_ref().map(|path| format!(" into {}", path.display()));
+                banner.feature("Markdown", format!("rendered to HTML{}", template.unwrap_or_default()));
+                Some(markdown)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                let path = cli.markdown_template.as_deref().unwrap_or(Path::new("")).display();
+                problems.push("E112", format!("cannot use markdown template {}: {}", path, e), None);
+                None
+            }
+        };
+        let sass = match cli.sass.then(|| Sass::new(&cli.sass_command)) {
+            Some(sass) => match sass.version() {
+                Ok(version) => {
+                    banner.feature("Sass", format!("compiled with `{}` {}", sass.command(), version));
+                    Some(sass)
+                }
+                Err(e) => {
+                    let hint = "install dart-sass, or name the compiler with --sass-command";
+                    problems.push("E113", format!("cannot compile Sass: {}", e), Some(hint));
+                    None
+                }
+            },
+            None => None,
+        };
+        let templates = match cli.templates.then(|| Templates::new(cli.template_data.as_deref())) {
+            Some(Ok(templates)) => {
+                let data = cli.template_data.as_ref().map(|path| format!(" with data from {}", path.display()));
+                banner.feature("Templates", format!(".hbs files rendered{}", data.unwrap_or_default()));
+                Some(templates)
+            }
+            Some(Err(e)) => {
+                problems.push("E114", e, None);
+                None
+            }
+            None => None,
+        };
+        let listing = match cli.listing_template.as_deref().map(|template| Listing::new(template, cli.listing_thumbnails)) {
+            Some(Ok(listing)) => {
+                let template = cli.listing_template.as_deref().unwrap_or(Path::new("")).display();
+                let thumbnails = if cli.listing_thumbnails { ", with image thumbnails" } else { "" };
+                banner.feature("Listings", format!("directories without an index page, through {}{}", template, thumbnails));
+                Some(listing)
+            }
+            Some(Err(e)) => {
+                problems.push("E126", format!("cannot use listing template: {}", e), None);
+                None
+            }
+            None => None,
+        };
+        let images = match cli.resize_images.then(|| Images::new(&cli.image_command)) {
+            Some(images) => match images.version() {
+                Ok(version) => {
+                    banner.feature("Images", format!("resized by query with `{}` ({})", images.command(), version));
+                    Some(images)
+                }
+                Err(e) => {
+                    let hint = "install ImageMagick, or name its program with --image-command";
+                    problems.push("E115", format!("cannot resize images: {}", e), Some(hint));
+                    None
+                }
+            },
+            None => None,
+        };
+        let site_archive = match &cli.site_archive {
+            Some(path) => Some(SiteArchive::open(path).map(|site| (site, path.display().to_string()))),
+            None => bundle::embedded().map(|site| site.map(|site| (site, "this executable".to_string()))),
+        };
+        let site_archive = match site_archive {
+            Some(Ok((site, source))) => {
+                banner.feature("Archive", format!("{} files served from {}", site.len(), source));
+                Some(site)
+            }
+            Some(Err(e)) => {
+                problems.push("E116", e, None);
+                None
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "git"))]
+        let git_tree = None;
+        #[cfg(feature = "git")]
+        let git_tree = match &cli.git_ref {
+            Some(revision) => match GitTree::open(&roots.default, revision) {
+                Ok(git_tree) => {
+                    let commit = git_tree.commit().unwrap_or_default();
+                    banner.feature("Git ref", format!("{} ({}) instead of the working tree", revision, commit));
+                    Some(git_tree)
+                }
+                Err(e) => {
+                    problems.push("E120", e, Some("pass a commit, tag or branch of the repository the directory is in"));
+                    None
+                }
+            },
+            None => None,
+        };
+        #[cfg(not(feature = "s3"))]
+        let bucket = None;
+        #[cfg(feature = "s3")]
+        let bucket = match &cli.s3 {
+            Some(location) => match s3::Bucket::new(location, cli.s3_endpoint.as_deref(), cli.s3_region.as_deref()) {
+                Ok(bucket) => {
+                    banner.feature("S3", format!("{}, cached for {}s", bucket.describe(), cli.s3_ttl));
+                    Some(bucket)
+                }
+                Err(e) => {
+                    problems.push("E121", e, Some("pass a bucket, e.g. --s3 my-bucket or --s3 my-bucket/site"));
+                    None
+                }
+            },
+            None => None,
+        };
+        #[cfg(not(feature = "plugins"))]
+        let plugins: Option<Plugins> = None;
+        #[cfg(feature = "plugins")]
+        let plugins = match &cli.plugins {
+            Some(dir) => match Plugins::load(dir) {
+                Ok(plugins) if plugins.names().is_empty() => {
+                    problems.push("E124", format!("{} holds no .wasm plugins", dir.display()), None);
+                    None
+                }
+                Ok(plugins) => {
+                    banner.feature("Plugins", plugins.names().join(", "));
+                    Some(plugins)
+                }
+                Err(e) => {
+                    problems.push("E124", format!("cannot load plugins: {}", e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+        #[cfg(not(feature = "lua"))]
+        let lua: Option<LuaHooks> = None;
+        #[cfg(feature = "lua")]
+        let lua = match cli.lua.as_deref().map(LuaHooks::load) {
+            Some(Ok(lua)) => {
+                banner.feature("Lua", lua.path().display().to_string());
+                Some(lua)
+            }
+            Some(Err(e)) => {
+                problems.push("E125", format!("cannot load the Lua script: {}", e), None);
+                None
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "geoip"))]
+        let geo_filter = None;
+        #[cfg(feature = "geoip")]
+        let geo_filter = match &cli.geoip_db {
+            Some(path) => match geoip::GeoFilter::open(path, &cli.geo_allow, &cli.geo_deny) {
+                Ok(geo_filter) => {
+                    let mut rules = Vec::new();
+                    if !cli.geo_allow.is_empty() {
+                        rules.push(format!("allow {}", cli.geo_allow.join(", ")));
+                    }
+                    if !cli.geo_deny.is_empty() {
+                        rules.push(format!("deny {}", cli.geo_deny.join(", ")));
+                    }
+                    let rules = if rules.is_empty() { "no rules".to_string() } else { rules.join("; ") };
+                    banner.feature("Countries", format!("{} ({})", rules, geo_filter.describe()));
+                    Some(geo_filter)
+                }
+                Err(e) => {
+                    problems.push("E127", format!("cannot open GeoIP database: {}", e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+        let stdin_type =
+            cli.mime_overrides.iter().find(|(extension, _)| extension.is_empty()).map(|(_, mime_type)| mime_type.as_str());
+        if stdin_type.is_some() && !cli.stdin {
+            let hint = Some("name the extension it is for, e.g. --mime ts=text/typescript");
+            problems.push("E118", "--mime without an extension only applies to --stdin", hint);
+        }
+        let single_file = match &cli.command {
+            Some(Command::File(_)) if cli.upload || cli.webdav || cli.stdin => {
+                problems.push("E117", "`file` serves its file read-only, without --upload, --webdav or --stdin", None);
+                None
+            }
+            Some(Command::File(args)) => Some(SingleFile::open(args).map_err(|e| ("E117", e))),
+            _ if cli.stdin => Some(SingleFile::stdin(stdin_type).map_err(|e| ("E119", e))),
+            _ => None,
+        };
+        let single_file = match single_file {
+            Some(Ok(single_file)) => {
+                banner.feature("File", format!("only {} is served", single_file.describe()));
+                banner.share(single_file.url_path());
+                Some(single_file)
+            }
+            Some(Err((code, e))) => {
+                problems.push(code, e, None);
+                None
+            }
+            None => None,
+        };
+        let htpasswd = match &cli.auth_file {
+            Some(path) => match HtpasswdFile::load(path) {
+                Ok(htpasswd) => Some(htpasswd),
+                Err(e) => {
+                    problems.push("E305", format!("invalid auth file: {}", e), None);
+                    None
+                }
+            },
+            None => None,
+        };
+        let authenticates = !cli.basic_auth.is_empty()
+            || cli.auth_file.is_some()
+            || cli.jwt_secret.is_some()
+            || cli.url_secret.is_some();
+        if !cli.protect.is_empty() && !authenticates && cli.oidc_issuer.is_none() {
+            problems.push(
+                "E306",
+                "--protect needs a way to authenticate",
+                Some("add --auth, --auth-file, --jwt-secret, --url-secret or --oidc-issuer"),
+            );
+        }
+        if cli.shares && !authenticates && cli.oidc_issuer.is_none() {
+            problems.push(
+                "E307",
+                "--shares needs a way to authenticate, anyone could mint links otherwise",
+                Some("add --auth, --auth-file, --jwt-secret, --url-secret or --oidc-issuer"),
+            );
+        }
+
+        #[cfg(not(feature = "oidc"))]
+        let oidc = None;
+        #[cfg(feature = "oidc")]
+        let oidc = match &cli.oidc_issuer {
+            Some(issuer) => match OidcClient::discover(
+                issuer,
+                cli.oidc_client_id.as_deref().unwrap_or_default(),
+                cli.oidc_client_secret.as_deref().unwrap_or_default(),
+                cli.oidc_redirect_url.as_deref().unwrap_or_default(),
+            ) {
+                Ok(client) => {
+                    banner.feature("Login", format!("OpenID Connect via {}", issuer));
+                    Some(client)
+                }
+                Err(e) => {
+                    problems.push(
+                        "E301",
+                        format!("OpenID Connect discovery failed: {}", e),
+                        Some("check --oidc-issuer, --oidc-redirect-url and that the provider is reachable"),
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        ContextBuilder {
+            cli,
+            roots,
+            cache,
+            shared_tier,
+            snapshots,
+            exec_watch,
+            request_db,
+            har,
+            recorder,
+            replay,
+            access_policy,
+            ignore_rules,
+            routes,
+            mocks,
+            markdown,
+            sass,
+            templates,
+            listing,
+            images,
+            site_archive,
+            git_tree,
+            bucket,
+            plugins,
+            lua,
+            geo_filter,
+            single_file,
+            htpasswd,
+            oidc,
+        }
+    }
+
+    /// The roots served, which entering the sandbox may move
+    pub fn roots(&mut self) -> &mut Roots {
+        &mut self.roots
+    }
+
+    /// Puts the context together, along with the file cache it is served from, and describes the rest of
+    /// what the options enable in `banner`
+    ///
+    /// The command line and the configuration file are kept to read the credentials and rules again on
+    /// reload.
+    pub fn build(
+        self,
+        command_line: Vec<OsString>,
+        config_file: Option<crate::config::Config>,
+        access_log: Option<AccessLog>,
+        banner: &mut Banner,
+    ) -> (Context, FileCache) {
+        let ContextBuilder { cli, mut roots, cache, .. } = self;
+        let watch_extensions: Vec<String> =
+            cli.watch_extensions.iter().map(|ext| ext.trim_start_matches('.').to_string()).collect();
+        #[cfg(feature = "watch")]
+        if !cli.no_cache {
+            let files = match watch_extensions.is_empty() {
+                true => "changed files".to_string(),
+                false => format!("changed .{} files", watch_extensions.join(", .")),
+            };
+            banner.feature(
+                "Watch",
+                format!("{} root(s), {} are reloaded after {} ms", roots.all().len(), files, cli.watch_debounce),
+            );
+            if !cli.watch_ignore.is_empty() {
+                banner.feature("Unwatched", cli.watch_ignore.join(", "));
+            }
+        }
+        if let Some(command) = &cli.exec {
+            let sources = match cli.exec_watch.is_empty() {
+                true => "the roots".to_string(),
+                false => cli.exec_watch.iter().map(|dir| dir.display().to_string()).collect::<Vec<_>>().join(", "),
+            };
+            banner.feature("Build", format!("`{}` on changes in {}", command, sources));
+        }
+        if let Some(tiered) = &self.shared_tier {
+            tiered.follow_removals();
+        }
+
+        if let (Some(htpasswd), Some(path)) = (&self.htpasswd, &cli.auth_file) {
+            banner.feature("Users", format!("{} from {}", htpasswd.len(), path.display()));
+        }
+        let security = Security::new(cli, self.htpasswd, self.access_policy);
+        let scope = match cli.protect.is_empty() {
+            true => "required".to_string(),
+            false => format!("required for {}", cli.protect.join(", ")),
+        };
+        if !security.auth_providers.is_empty() {
+            banner.feature("Auth", format!("{}, {} provider(s)", scope, security.auth_providers.len()));
+        }
+        let url_signer = cli.url_secret.as_deref().map(UrlSigner::new);
+        if url_signer.is_some() {
+            banner.feature("Signed links", format!("accepted, credentials {}", scope));
+        }
+        if !cli.allow.is_empty() || !cli.deny.is_empty() {
+            let list = |networks: &[Cidr]| networks.iter().map(Cidr::to_string).collect::<Vec<_>>().join(", ");
+            let filter = match (cli.allow.is_empty(), cli.deny.is_empty()) {
+                (false, false) => format!("allow {}; deny {}", list(&cli.allow), list(&cli.deny)),
+                (false, true) => format!("allow {}", list(&cli.allow)),
+                _ => format!("deny {}", list(&cli.deny)),
+            };
+            banner.feature("Networks", filter);
+        }
+        if !cli.trusted_proxies.is_empty() {
+            let proxies = cli.trusted_proxies.iter().map(Cidr::to_string).collect::<Vec<_>>().join(", ");
+            banner.feature("Proxies", format!("client addresses forwarded by {}", proxies));
+        }
+
+        let charset = Some(cli.charset.as_str()).filter(|&charset| charset != "none");
+        roots.record_mountpoints();
+        let search = cli.search.then(|| Search::new(roots.all()));
+        let sitemap = cli.sitemap.then(|| Sitemap::new(roots.all()));
+        let context = Context {
+            roots,
+            command_line,
+            config_file,
+            security: RwLock::new(Arc::new(security)),
+            oidc: self.oidc,
+            url_signer,
+            ip_filter: IpFilter {
+                allow: cli.allow.clone(),
+                deny: cli.deny.clone(),
+                geo: self.geo_filter,
+            },
+            trusted_proxies: TrustedProxies::new(cli.trusted_proxies.clone()),
+            ignore_rules: self.ignore_rules,
+            access_log,
+            request_db: self.request_db,
+            har: self.har,
+            recorder: self.recorder,
+            replay: self.replay,
+            debug_echo: cli.debug_echo,
+            cache_admin: cli.cache_admin,
+            metrics_endpoint: cli.metrics,
+            manifest: cli.manifest.then(Manifest::default),
+            inspector: cli.inspect.then(Inspector::new),
+            shares: cli.shares.then(Shares::new),
+            routes: self.routes,
+            canonical_hosts: cli.canonical_hosts.clone(),
+            redirects: cli.redirects.clone(),
+            rewrites: cli.rewrites.clone(),
+            mocks: self.mocks,
+            languages: cli.default_language.as_deref().map(Languages::new),
+            markdown: self.markdown,
+            sass: self.sass,
+            templates: self.templates,
+            listing: self.listing,
+            images: self.images,
+            ssi: cli.ssi,
+            archives: cli.archives,
+            site_archive: self.site_archive,
+            git_tree: self.git_tree,
+            bucket: self.bucket,
+            s3_ttl: Duration::from_secs(cli.s3_ttl),
+            plugins: self.plugins,
+            lua: self.lua,
+            single_file: self.single_file,
+            search,
+            sitemap,
+            uploads: cli.upload.then(|| Uploads::new(cli.max_upload_size)),
+            webdav: cli.webdav.then(|| WebDav::new(cli.upload)),
+            mime_types: MimeTypes::new(&cli.mime_overrides, charset),
+            downloads: cli.downloads.clone(),
+            compress: cli.compress,
+            robots: cli.robots,
+            favicon: cli.favicon,
+            cgi: cli.cgi.as_deref().map(Cgi::new),
+            fastcgi: cli.fastcgi.clone(),
+            proxies: cli.proxies.clone(),
+            proxy_credentials: cli.proxy_credentials,
+            #[cfg(not(feature = "fallback"))]
+            fallback: None,
+            #[cfg(feature = "fallback")]
+            fallback: cli.fallback_origin.as_deref().map(|origin| {
+                let policy = CachePolicy {
+                    min_ttl: cli.fallback_min_ttl.map(Duration::from_secs),
+                    max_ttl: cli.fallback_max_ttl.map(Duration::from_secs),
+                    no_store_html: cli.fallback_no_store_html,
+                };
+                FallbackOrigin::new(origin, policy, cli.fallback_store.as_deref())
+            }),
+            fallback_cache: cli.fallback_cache,
+            snapshots: self.snapshots,
+            // Held polls and event streams each pin a worker; between them they may take at most half
+            live_reload: cli.live_reload.then(|| LiveReload::new(cli.threads / 4)),
+            preview_banner: cli.preview_banner.as_deref().map(inject::banner),
+            change_events: cli.change_events.then(|| ChangeEvents::new(cli.threads / 4)),
+            #[cfg(not(feature = "webhook"))]
+            webhook: None,
+            #[cfg(feature = "webhook")]
+            webhook: cli.webhook.as_deref().map(Webhook::new),
+            #[cfg(unix)]
+            mmap_threshold: cli.mmap_threshold,
+            loads: Loads::default(),
+            // Without the watcher nothing else notices changed files
+            cache_ttl: cli.cache_ttl.map(Duration::from_secs).or((!cfg!(feature = "watch")).then_some(Duration::ZERO)),
+            watch_extensions,
+            watch_ignore: cli.watch_ignore.iter().map(|pattern| pattern.trim_matches('/').to_string()).collect(),
+            watch_debounce: Duration::from_millis(cli.watch_debounce),
+            exec: cli.exec.clone(),
+            exec_watch: self.exec_watch,
+            watching: AtomicBool::new(!cfg!(feature = "watch")),
+            metrics: Metrics::new(),
+            shutdown: Arc::new(Shutdown::new()),
+            max_connections: cli.max_connections,
+            peer_connections: cli.max_connections_per_ip.map(|limit| Arc::new(PeerConnections::new(limit))),
+            chaos: cli.chaos,
+            shaping: Shaping {
+                rate: cli.throttle,
+                latency: cli.latency.unwrap_or_default(),
+            },
+            timeouts: Timeouts {
+                header: seconds(cli.header_timeout),
+                body: seconds(cli.body_timeout),
+                write: seconds(cli.write_timeout),
+            },
+            keep_alive: seconds(cli.keep_alive),
+            tcp_nodelay: cli.tcp_nodelay,
+        };
+
+        if cli.preload {
+            let (files, bytes) = preload(&context, &cache, cli.cache_size);
+            banner.feature("Preload", format!("{} file(s), {}", files, units::size(bytes)));
+        }
+        describe(cli, &context, banner);
+        (context, cache)
+    }
+}
+
+/// Adds what the options enable besides the files loaded up front to `banner`
+fn describe(cli: &Cli, context: &Context, banner: &mut Banner) {
+    if let Some(dir) = &cli.snapshots {
+        banner.feature("Snapshots", format!("kept in {}, browsable under {}/", dir.display(), snapshots::PREFIX));
+    }
+    if let Some(ttl) = cli.cache_ttl {
+        banner.feature("Revalidate", format!("cached files against the disk every {}s", ttl));
+    }
+    if let Some(origin) = &cli.fallback_origin {
+        banner.feature("Fallback", origin.clone());
+    }
+    if !cli.canonical_hosts.is_empty() {
+        banner.feature("Canonical", format!("{} host rule(s)", cli.canonical_hosts.len()));
+    }
+    if !cli.redirects.is_empty() {
+        banner.feature("Redirects", format!("{} path(s)", cli.redirects.len()));
+    }
+    if !cli.rewrites.is_empty() {
+        banner.feature("Rewrites", format!("{} rule(s)", cli.rewrites.len()));
+    }
+    if let Some(language) = &cli.default_language {
+        banner.feature("Languages", format!("translations negotiated, {} by default", language));
+    }
+    if cli.ssi {
+        banner.feature("SSI", "include directives expanded in HTML pages");
+    }
+    if cli.archives {
+        banner.feature("Archives", format!("directories downloadable with ?{}", archive::QUERY));
+    }
+    if let Some(sitemap) = &context.sitemap {
+        let kept = if cfg!(feature = "watch") { ", kept current by the watcher" } else { "" };
+        banner.feature("Sitemap", format!("{} pages{}, at {}", sitemap.len(), kept, sitemap::PATH));
+    }
+    if let Some(search) = &context.search {
+        let kept = if cfg!(feature = "watch") { ", kept current by the watcher" } else { "" };
+        banner.feature("Search", format!("{} files indexed{}, at {}?q=", search.len(), kept, search::PATH));
+    }
+    if cli.upload {
+        let open = match context.security.read().unwrap().auth_providers.is_empty() && context.oidc.is_none() {
+            true => ", by anyone who can connect",
+            false => "",
+        };
+        let limit = units::size(cli.max_upload_size);
+        let detail = format!("PUT, multipart POST up to {} and DELETE{}, page at {}", limit, open, upload::PAGE_PATH);
+        banner.feature("Uploads", detail);
+    }
+    if cli.webdav {
+        banner.feature("WebDAV", if cli.upload { "mountable as a network drive" } else { "mountable read-only" });
+    }
+    if !cli.mime_overrides.is_empty() {
+        banner.feature("MIME types", format!("{} override(s)", cli.mime_overrides.len()));
+    }
+    if cli.compress {
+        banner.feature("Compression", "brotli and gzip, cached with the files");
+    }
+    let placeholders: Vec<&str> = [
+        cli.robots.map(|robots| if robots == Robots::Allow { "robots.txt (allow)" } else { "robots.txt (deny)" }),
+        cli.favicon.then_some("favicon.ico"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if !placeholders.is_empty() {
+        banner.feature("Stand-ins", format!("{} when missing", placeholders.join(", ")));
+    }
+    if let Some(prefix) = &cli.cgi {
+        banner.feature("CGI", format!("scripts under {}", prefix));
+    }
+    for fastcgi in &cli.fastcgi {
+        banner.feature("FastCGI", format!("{} to {}", fastcgi.pattern(), fastcgi.address()));
+    }
+    for proxy in &cli.proxies {
+        banner.feature("Proxy", format!("{} to {}", proxy.prefix(), proxy.upstream()));
+    }
+    if cli.debug_echo {
+        banner.feature("Debug", format!("request echo at {}", DEBUG_ECHO_PATH));
+    }
+    if cli.cache_admin {
+        banner.feature("Admin", format!("cache at {}", CACHE_ADMIN_PATH));
+    }
+    if cli.metrics {
+        banner.feature("Metrics", format!("Prometheus at {}", metrics::PATH));
+    }
+    if cli.manifest {
+        banner.feature("Manifest", format!("file sizes and hashes at {}", manifest::PATH));
+    }
+    if cli.inspect {
+        banner.feature("Inspector", format!("latest requests at {}", inspector::PATH));
+    }
+    if cli.shares {
+        banner.feature("Share links", format!("minted at {}", shares::PATH));
+    }
+    if cli.live_reload {
+        banner.feature("Reload", "pages in the browser when their files change");
+    }
+    if cli.change_events {
+        banner.feature("Events", format!("file changes streamed at {}", events::PATH));
+    }
+    if let Some(url) = &cli.webhook {
+        banner.feature("Webhook", format!("file changes posted to {}", url));
+    }
+    if let Some(text) = &cli.preview_banner {
+        banner.feature("Preview", format!("\"{}\" shown on every page", text));
+    }
+    #[cfg(unix)]
+    if let Some(threshold) = cli.mmap_threshold {
+        banner.feature("Mmap", format!("files of {} and more", units::size(threshold)));
+    }
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    banner.feature("io_uring", "file reads");
+}
@@ -0,0 +1,181 @@
+use crate::units;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// URL where share links are minted (POST), listed (GET) and revoked (DELETE), enabled by --shares
+pub const PATH: &str = "/_rshttps/shares";
+
+/// Share links are this prefix followed by their token
+pub const LINK_PREFIX: &str = "/_rshttps/s/";
+
+/// How long a share link stays valid when no expiry is given
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Short random links to single files, each valid for a time window and optionally a number of downloads
+///
+/// A link lets anyone who has it download its file, even from a protected
+/// path, like a signed link would; access rules that deny the path still
+/// apply. Links are kept in memory only, so a restart revokes them all.
+pub struct Shares {
+    links: Mutex<HashMap<String, Share>>,
+}
+
+struct Share {
+    path: String,
+    expires: SystemTime,
+    /// Downloads left before the link stops working, unlimited when `None`
+    downloads_left: Option<u32>,
+}
+
+/// What a share link leads to
+pub enum Redeemed {
+    /// The path of the shared file
+    Path(String),
+    /// The link expired or ran out of downloads
+    Gone,
+    Unknown,
+}
+
+impl Shares {
+    pub fn new() -> Shares {
+        Shares {
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a link to `path` and returns its token, or `None` if `ttl` ends past what a time can hold
+    pub fn create(&self, path: &str, ttl: Duration, downloads: Option<u32>) -> Option<String> {
+        let token = random_token();
+        let share = Share {
+            path: path.to_string(),
+            expires: SystemTime::now().checked_add(ttl)?,
+            downloads_left: downloads,
+        };
+        let mut links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        links.retain(|_, share| share.is_valid());
+        links.insert(token.clone(), share);
+        Some(token)
+    }
+
+    /// The path a link leads to, without counting a download, see [`Shares::spend`]
+    pub fn redeem(&self, token: &str) -> Redeemed {
+        let mut links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(share) = links.get(token) else {
+            return Redeemed::Unknown;
+        };
+        if !share.is_valid() {
+            links.remove(token);
+            return Redeemed::Gone;
+        }
+        Redeemed::Path(share.path.clone())
+    }
+
+    /// Counts a download of a link, returning false if it has none left or is gone
+    pub fn spend(&self, token: &str) -> bool {
+        let mut links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        match links.get_mut(token) {
+            Some(share) if share.is_valid() => {
+                if let Some(left) = share.downloads_left.as_mut() {
+                    *left -= 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Gives back a download that was spent on a request that didn't get the file
+    pub fn refund(&self, token: &str) {
+        let mut links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(left) = links.get_mut(token).and_then(|share| share.downloads_left.as_mut()) {
+            *left += 1;
+        }
+    }
+
+    /// Revokes a link, returning whether it existed
+    pub fn revoke(&self, token: &str) -> bool {
+        self.links.lock().unwrap_or_else(PoisonError::into_inner).remove(token).is_some()
+    }
+
+    /// The links still valid, as JSON
+    pub fn list(&self) -> Value {
+        let mut links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        links.retain(|_, share| share.is_valid());
+        links.iter().map(|(token, share)| share.to_json(token)).collect()
+    }
+
+    /// One link as JSON
+    pub fn describe(&self, token: &str) -> Option<Value> {
+        let links = self.links.lock().unwrap_or_else(PoisonError::into_inner);
+        links.get(token).map(|share| share.to_json(token))
+    }
+}
+
+impl Share {
+    fn is_valid(&self) -> bool {
+        SystemTime::now() < self.expires && self.downloads_left != Some(0)
+    }
+
+    fn to_json(&self, token: &str) -> Value {
+        json!({
+            "link": format!("{}{}", LINK_PREFIX, token),
+            "path": self.path,
+            "expires": units::iso_timestamp(self.expires),
+            "downloads_left": self.downloads_left,
+        })
+    }
+}
+
+/// A random URL-safe token, short enough to paste but impossible to guess
+fn random_token() -> String {
+    let mut bytes = [0u8; 12];
+    getrandom::getrandom(&mut bytes).expect("Failed to read random bytes");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(redeemed: Redeemed) -> Option<String> {
+        match redeemed {
+            Redeemed::Path(path) => Some(path),
+            Redeemed::Gone | Redeemed::Unknown => None,
+        }
+    }
+
+    #[test]
+    fn links_run_out_of_downloads() {
+        let shares = Shares::new();
+        let token = shares.create("/report.pdf", DEFAULT_TTL, Some(2)).unwrap();
+        // Looking without downloading doesn't count
+        assert_eq!(path(shares.redeem(&token)).as_deref(), Some("/report.pdf"));
+        assert!(shares.spend(&token));
+        // A download the request didn't get is given back
+        assert!(shares.spend(&token));
+        shares.refund(&token);
+        assert!(shares.spend(&token));
+        assert!(!shares.spend(&token));
+        assert!(matches!(shares.redeem(&token), Redeemed::Gone));
+        assert!(matches!(shares.redeem(&token), Redeemed::Unknown));
+        assert!(!shares.spend(&token));
+    }
+
+    #[test]
+    fn links_expire_and_can_be_revoked() {
+        let shares = Shares::new();
+        let expired = shares.create("/a", Duration::ZERO, None).unwrap();
+        assert!(matches!(shares.redeem(&expired), Redeemed::Gone));
+        let token = shares.create("/b", DEFAULT_TTL, None).unwrap();
+        assert_eq!(shares.list().as_array().map(Vec::len), Some(1));
+        assert!(shares.revoke(&token));
+        assert!(!shares.revoke(&token));
+        assert!(matches!(shares.redeem(&token), Redeemed::Unknown));
+        assert!(matches!(shares.redeem("guess"), Redeemed::Unknown));
+        assert!(shares.create("/c", Duration::MAX, None).is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Tracks in-flight connections so shutdown can wait for them to finish
+pub struct Shutdown {
+    draining: AtomicBool,
+    active: AtomicUsize,
+}
+
+/// Marks one connection as in flight until dropped
+///
+/// Owns a reference so it can travel with a connection waiting in the worker queue.
+pub struct ActiveConnection(Arc<Shutdown>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            draining: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Stops new connections from being served
+    pub fn begin(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Connections in flight, including those waiting for a worker
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Counts a connection as in flight, unless `limit` connections are already
+    pub fn track(self: &Arc<Self>, limit: Option<usize>) -> Option<ActiveConnection> {
+        let limit = limit.unwrap_or(usize::MAX);
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| (active < limit).then_some(active + 1))
+            .ok()?;
+        Some(ActiveConnection(Arc::clone(self)))
+    }
+
+    /// Waits for in-flight connections to finish, returning how many were still open at the deadline
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let active = self.active.load(Ordering::SeqCst);
+            if active == 0 || Instant::now() >= deadline {
+                return active;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Connections in flight per client address, for --max-connections-per-ip
+pub struct PeerConnections {
+    limit: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Marks one connection from a client address as in flight until dropped
+pub struct PeerConnection(Arc<PeerConnections>, IpAddr);
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        let mut counts = self.0.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get_mut(&self.1) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.1);
+            }
+        }
+    }
+}
+
+impl PeerConnections {
+    pub fn new(limit: usize) -> PeerConnections {
+        PeerConnections {
+            limit,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a connection from `ip` as in flight, unless it has the most allowed already
+    pub fn track(self: &Arc<Self>, ip: IpAddr) -> Option<PeerConnection> {
+        // Dual-stack sockets report IPv4 clients as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(PeerConnection(Arc::clone(self), ip))
+    }
+}
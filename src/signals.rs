@@ -0,0 +1,56 @@
+use std::io;
+
+/// The signals the server handles itself
+const HANDLED_SIGNALS: [libc::c_int; 5] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2];
+
+/// A signal that changes the server's lifecycle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// SIGINT or SIGTERM: drain and exit
+    Terminate(&'static str),
+    /// SIGUSR2: hand the listeners to a fresh copy of the binary, then drain and exit
+    Restart,
+    /// SIGHUP: re-read the configuration files
+    Reload,
+    /// SIGUSR1: print how full the cache is
+    Report,
+}
+
+/// Blocks the handled signals in the calling thread and every thread it spawns afterwards
+///
+/// Must run before any other thread is started so that only the thread calling
+/// [`wait`] ever receives them.
+pub fn block_handled_signals() -> io::Result<()> {
+    unsafe {
+        let set = signal_set();
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) {
+            0 => Ok(()),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// Waits until one of the handled signals arrives
+pub fn wait() -> Signal {
+    let mut signal: libc::c_int = 0;
+    unsafe {
+        let set = signal_set();
+        while libc::sigwait(&set, &mut signal) != 0 {}
+    }
+    match signal {
+        libc::SIGUSR2 => Signal::Restart,
+        libc::SIGHUP => Signal::Reload,
+        libc::SIGUSR1 => Signal::Report,
+        libc::SIGINT => Signal::Terminate("SIGINT"),
+        _ => Signal::Terminate("SIGTERM"),
+    }
+}
+
+unsafe fn signal_set() -> libc::sigset_t {
+    let mut set: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut set);
+    for signal in HANDLED_SIGNALS {
+        libc::sigaddset(&mut set, signal);
+    }
+    set
+}
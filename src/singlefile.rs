@@ -0,0 +1,102 @@
+use crate::mime::MimeTypes;
+use rshttp::request;
+use rshttp::response::Response;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::PathBuf;
+
+/// Type of what is piped to --stdin when no bare --mime TYPE names one
+const PIPED_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Arguments of the `file` subcommand
+#[derive(clap::Args, Debug)]
+pub struct FileArgs {
+    /// The file to serve
+    file: PathBuf,
+}
+
+/// Exactly one file, served at `/` and at its name, from the `file` subcommand or --stdin
+///
+/// Every other path is answered with 404, so nothing else next to the file can
+/// be reached. A file is read again for each request and keeps its name when
+/// it is saved from `/`. What is piped to --stdin is read once at startup and
+/// only served at `/`.
+pub struct SingleFile {
+    source: Source,
+    /// The name the file is also served at, empty for stdin
+    name: String,
+}
+
+enum Source {
+    File(PathBuf),
+    Piped { contents: Vec<u8>, mime_type: String },
+}
+
+impl SingleFile {
+    pub fn open(args: &FileArgs) -> Result<SingleFile, String> {
+        if !args.file.is_file() {
+            return Err(format!("{} is not a file", args.file.display()));
+        }
+        let name = args.file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(SingleFile {
+            source: Source::File(args.file.clone()),
+            name,
+        })
+    }
+
+    /// Reads stdin until it ends, to be served as `mime_type`
+    pub fn stdin(mime_type: Option<&str>) -> Result<SingleFile, String> {
+        let mut stdin = io::stdin().lock();
+        if stdin.is_terminal() {
+            return Err("nothing is piped to stdin".to_string());
+        }
+        let mut contents = Vec::new();
+        stdin.read_to_end(&mut contents).map_err(|e| format!("cannot read stdin: {}", e))?;
+        Ok(SingleFile {
+            source: Source::Piped {
+                contents,
+                mime_type: mime_type.unwrap_or(PIPED_TYPE).to_string(),
+            },
+            name: String::new(),
+        })
+    }
+
+    /// What is served, for the banner
+    pub fn describe(&self) -> String {
+        match &self.source {
+            Source::File(file) => file.display().to_string(),
+            Source::Piped { contents, mime_type } => format!("{} bytes of {} from stdin", contents.len(), mime_type),
+        }
+    }
+
+    /// The URL path that names the file
+    pub fn url_path(&self) -> String {
+        request::encode_path(&format!("/{}", self.name))
+    }
+
+    /// Answers a request for `url_path`, decoded, with the file if it asks for it
+    pub fn respond(&self, url_path: &str, mime_types: &MimeTypes, range: Option<&str>) -> Response<'static> {
+        let named = url_path.strip_prefix('/') == Some(self.name.as_str());
+        if url_path != "/" && (self.name.is_empty() || !named) {
+            return Response::error(404);
+        }
+        let (contents, mime_type) = match &self.source {
+            Source::File(file) => match fs::read(file) {
+                Ok(contents) => (contents, mime_types.of(file)),
+                Err(e) => {
+                    tracing::error!("Failed to read {}: {}", file.display(), e);
+                    return Response::error(404);
+                }
+            },
+            Source::Piped { contents, mime_type } => (contents.clone(), mime_type.clone()),
+        };
+        let response = Response::file(&contents, &mime_type, range).into_owned();
+        match self.name.is_empty() {
+            true => response,
+            false => {
+                let disposition = format!("inline; filename=\"{}\"", self.name.replace(['"', '\\'], "_"));
+                response.header("Content-Disposition", disposition)
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use crate::mime::MimeTypes;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use rshttp::response::Response;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const TAR_BLOCK: usize = 512;
+
+/// How far from its end a zip file's end of central directory record can be
+const MAX_ZIP_TRAILER: u64 = 22 + 65535;
+
+/// A site served straight out of a zip or tar archive, from --archive
+///
+/// Only the archive's index is read at startup: the central directory of a
+/// zip, or the headers of a tar. Files are read out of the archive, and
+/// inflated if they have to be, when they are requested. A gzip-compressed tar
+/// can't be read from the middle, so it is unpacked into memory as a whole.
+/// When everything sits in a single top-level directory without an index.html
+/// beside it, as when a site's folder is zipped, that directory is served.
+pub struct SiteArchive {
+    path: PathBuf,
+    /// The unpacked contents of a gzip-compressed tar
+    unpacked: Option<Vec<u8>>,
+    entries: HashMap<String, Entry>,
+    dirs: HashSet<String>,
+    /// The top-level directory that is served, ending in a slash, or empty
+    prefix: String,
+}
+
+struct Entry {
+    offset: u64,
+    /// Size in the archive, which differs from the file's for deflated zip entries
+    stored: u64,
+    deflated: bool,
+}
+
+impl SiteArchive {
+    /// Reads the index of a .zip, .tar, .tar.gz or .tgz file
+    pub fn open(path: &Path) -> Result<SiteArchive, String> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+        let failed = |e: io::Error| format!("cannot read {}: {}", path.display(), e);
+        let mut file = File::open(path).map_err(failed)?;
+        let entries = if name.ends_with(".zip") {
+            zip_entries(&mut file).map_err(failed)?
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            return SiteArchive::unpack(path, file);
+        } else if name.ends_with(".tar") {
+            tar_entries(&mut file).map_err(failed)?
+        } else {
+            return Err(format!("{} is not a .zip, .tar, .tar.gz or .tgz file", path.display()));
+        };
+        Ok(SiteArchive::index(path, None, entries))
+    }
+
+    /// Unpacks a gzip-compressed tar read from `tar_gz`, which comes from `path`
+    pub fn unpack(path: &Path, tar_gz: impl Read) -> Result<SiteArchive, String> {
+        let failed = |e: io::Error| format!("cannot read {}: {}", path.display(), e);
+        let mut unpacked = Vec::new();
+        GzDecoder::new(tar_gz).read_to_end(&mut unpacked).map_err(failed)?;
+        let entries = tar_entries(&mut io::Cursor::new(&unpacked)).map_err(failed)?;
+        Ok(SiteArchive::index(path, Some(unpacked), entries))
+    }
+
+    fn index(path: &Path, unpacked: Option<Vec<u8>>, entries: Vec<(String, Option<Entry>)>) -> SiteArchive {
+        let mut site = SiteArchive {
+            path: path.to_path_buf(),
+            unpacked,
+            entries: HashMap::new(),
+            dirs: HashSet::new(),
+            prefix: String::new(),
+        };
+        for (name, entry) in entries {
+            let name = name.trim_start_matches("./").trim_start_matches('/').to_string();
+            if name.is_empty() || name.split('/').any(|segment| segment == "..") {
+                continue;
+            }
+            match entry {
+                Some(entry) => {
+                    site.entries.insert(name, entry);
+                }
+                None => {
+                    site.dirs.insert(dir_name(name.trim_end_matches('/')));
+                }
+            }
+        }
+        // Every directory that holds a file, also when the archive has no entry for it
+        let parents: Vec<String> = site.entries.keys().chain(site.dirs.iter()).flat_map(|name| parents(name)).collect();
+        site.dirs.extend(parents);
+        site.dirs.insert(String::new());
+        let top: HashSet<&str> = site.entries.keys().map(|name| name.split('/').next().unwrap_or("")).collect();
+        if let [only] = top.into_iter().collect::<Vec<_>>()[..] {
+            if site.dirs.contains(&dir_name(only)) && !site.entries.contains_key("index.html") {
+                site.prefix = dir_name(only);
+            }
+        }
+        site
+    }
+
+    /// How many files are served
+    pub fn len(&self) -> usize {
+        self.entries.keys().filter(|name| name.starts_with(&self.prefix)).count()
+    }
+
+    /// Answers a request for `url_path` with the file of the archive it names
+    pub fn respond(&self, url_path: &str, mime_types: &MimeTypes, range: Option<&str>) -> Response<'static> {
+        let name = format!("{}{}", self.prefix, url_path.trim_start_matches('/'));
+        let name = match self.dirs.contains(&dir_name(name.trim_end_matches('/'))) {
+            true => format!("{}index.html", dir_name(name.trim_end_matches('/'))),
+            false => name,
+        };
+        let Some(entry) = self.entries.get(&name) else {
+            return Response::error(404);
+        };
+        match self.read(entry) {
+            Ok(contents) => {
+                Response::file(&contents, &mime_types.of(Path::new(&name)), range).into_owned()
+            }
+            Err(e) => {
+                tracing::error!("Failed to read {} from {}: {}", name, self.path.display(), e);
+                Response::error(500)
+            }
+        }
+    }
+
+    fn read(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let stored = match &self.unpacked {
+            Some(unpacked) => {
+                let start = entry.offset as usize;
+                unpacked.get(start..start + entry.stored as usize).ok_or(io::ErrorKind::UnexpectedEof)?.to_vec()
+            }
+            None => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(entry.offset))?;
+                let mut stored = Vec::new();
+                file.take(entry.stored).read_to_end(&mut stored)?;
+                stored
+            }
+        };
+        match entry.deflated {
+            true => {
+                let mut contents = Vec::new();
+                DeflateDecoder::new(stored.as_slice()).read_to_end(&mut contents)?;
+                Ok(contents)
+            }
+            false => Ok(stored),
+        }
+    }
+}
+
+/// `name` as a directory, ending in a slash unless it is the top
+fn dir_name(name: &str) -> String {
+    match name.is_empty() {
+        true => String::new(),
+        false => format!("{}/", name),
+    }
+}
+
+/// The directories `name` lies in, as [`dir_name`]s
+fn parents(name: &str) -> Vec<String> {
+    let segments: Vec<&str> = name.trim_end_matches('/').split('/').collect();
+    (1..segments.len()).map(|depth| dir_name(&segments[..depth].join("/"))).collect()
+}
+
+/// The names in a zip's central directory, with where their data is
+fn zip_entries(file: &mut File) -> io::Result<Vec<(String, Option<Entry>)>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let len = file.seek(SeekFrom::End(0))?;
+    let tail_start = len.saturating_sub(MAX_ZIP_TRAILER);
+    file.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&at| tail[at..at + 4] == [0x50, 0x4b, 0x05, 0x06])
+        .ok_or_else(|| invalid("not a zip file"))?;
+    let record = &tail[end..];
+    let size = u32::from_le_bytes(record[12..16].try_into().unwrap());
+    let offset = u32::from_le_bytes(record[16..20].try_into().unwrap());
+    if size == u32::MAX || offset == u32::MAX {
+        return Err(invalid("zip64 archives are not supported"));
+    }
+    file.seek(SeekFrom::Start(offset as u64))?;
+    let mut directory = vec![0; size as usize];
+    file.read_exact(&mut directory)?;
+
+    let mut entries = Vec::new();
+    let mut at = 0;
+    while directory.get(at..at + 4) == Some(&[0x50, 0x4b, 0x01, 0x02]) {
+        let header = directory.get(at..at + 46).ok_or_else(|| invalid("truncated central directory"))?;
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as usize;
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap()) as u64;
+        let (name_len, extra_len, comment_len) = (u16_at(28), u16_at(30), u16_at(32));
+        let name = directory.get(at + 46..at + 46 + name_len).ok_or_else(|| invalid("truncated central directory"))?;
+        let name = String::from_utf8_lossy(name).replace('\\', "/");
+        let entry = match u16_at(10) {
+            0 | 8 if !name.ends_with('/') => Some(Entry {
+                offset: zip_data_offset(file, u32_at(42))?,
+                stored: u32_at(20),
+                deflated: u16_at(10) == 8,
+            }),
+            0 | 8 => None,
+            method => {
+                tracing::warn!("Leaving out {}, compressed with unsupported method {}", name, method);
+                at += 46 + name_len + extra_len + comment_len;
+                continue;
+            }
+        };
+        entries.push((name, entry));
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// Where the data of the zip entry whose local header is at `offset` starts
+fn zip_data_offset(file: &mut File, offset: u64) -> io::Result<u64> {
+    let mut header = [0u8; 30];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header)?;
+    if header[..4] != [0x50, 0x4b, 0x03, 0x04] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "missing local file header"));
+    }
+    let name_len = u16::from_le_bytes([header[26], header[27]]) as u64;
+    let extra_len = u16::from_le_bytes([header[28], header[29]]) as u64;
+    Ok(offset + 30 + name_len + extra_len)
+}
+
+/// The names in a tar, with where their data is; directories have none
+fn tar_entries(tar: &mut (impl Read + Seek)) -> io::Result<Vec<(String, Option<Entry>)>> {
+    let mut entries = Vec::new();
+    // A name given by a GNU long name entry or a pax header for the entry after it
+    let mut long_name: Option<String> = None;
+    let mut offset = 0u64;
+    loop {
+        let mut header = [0u8; TAR_BLOCK];
+        if tar.read_exact(&mut header).is_err() || header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        offset += TAR_BLOCK as u64;
+        let size = tar_size(&header[124..136]);
+        let padded = size.div_ceil(TAR_BLOCK as u64) * TAR_BLOCK as u64;
+        match header[156] {
+            b'L' | b'x' => {
+                let mut data = Vec::new();
+                tar.take(size).read_to_end(&mut data)?;
+                long_name = match header[156] {
+                    b'L' => Some(String::from_utf8_lossy(&data).trim_end_matches('\0').to_string()),
+                    _ => pax_path(&data).or(long_name),
+                };
+            }
+            kind @ (b'0' | 0 | b'5') => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let field = |bytes: &[u8]| {
+                        String::from_utf8_lossy(&bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())])
+                            .into_owned()
+                    };
+                    match (&header[257..262] == b"ustar", field(&header[345..500])) {
+                        (true, prefix) if !prefix.is_empty() => format!("{}/{}", prefix, field(&header[..100])),
+                        _ => field(&header[..100]),
+                    }
+                });
+                let entry = (kind != b'5' && !name.ends_with('/')).then_some(Entry {
+                    offset,
+                    stored: size,
+                    deflated: false,
+                });
+                entries.push((name, entry));
+            }
+            _ => long_name = None,
+        }
+        offset += padded;
+        tar.seek(SeekFrom::Start(offset))?;
+    }
+    Ok(entries)
+}
+
+/// A tar header's size field, in octal or, for large files, base-256
+fn tar_size(field: &[u8]) -> u64 {
+    match field[0] & 0x80 != 0 {
+        true => field[4..].iter().fold(0, |size, &byte| size << 8 | byte as u64),
+        false => {
+            let digits = String::from_utf8_lossy(field);
+            u64::from_str_radix(digits.trim_matches(|c: char| c == '\0' || c == ' '), 8).unwrap_or(0)
+        }
+    }
+}
+
+/// The `path` record of a pax extended header
+fn pax_path(data: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .find_map(|record| record.split_once(' ')?.1.strip_prefix("path=").map(str::to_string))
+}
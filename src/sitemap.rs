@@ -0,0 +1,94 @@
+use crate::inject::escape;
+use crate::{units, walk};
+use rshttp::response::Response;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// URL of the generated sitemap
+pub const PATH: &str = "/sitemap.xml";
+
+/// Most URLs one sitemap may list
+const MAX_URLS: usize = 50_000;
+
+/// A sitemap of the served HTML pages, from --sitemap
+///
+/// The pages below the roots are listed at startup with their modification
+/// times, and the watcher updates the list as they change. `/sitemap.xml`
+/// answers with the pages of the requested root, unless that root has a
+/// sitemap.xml of its own. An `index.html` is listed as its directory, and
+/// hidden files are left out, as are pages the ignore and access rules hide.
+pub struct Sitemap {
+    roots: Vec<PathBuf>,
+    pages: RwLock<BTreeMap<PathBuf, Option<SystemTime>>>,
+}
+
+impl Sitemap {
+    pub fn new(roots: Vec<PathBuf>) -> Sitemap {
+        let sitemap = Sitemap {
+            roots,
+            pages: RwLock::new(BTreeMap::new()),
+        };
+        for root in &sitemap.roots {
+            sitemap.update(root);
+        }
+        sitemap
+    }
+
+    /// How many pages are listed
+    pub fn len(&self) -> usize {
+        self.pages.read().unwrap().len()
+    }
+
+    /// Lists `path` again, a file or a directory with everything below it, dropping it if it is gone
+    pub fn update(&self, path: &Path) {
+        let files = match path.is_dir() {
+            true => walk::files(path),
+            false => vec![path.to_path_buf()],
+        };
+        let pages: Vec<(PathBuf, Option<SystemTime>)> = (files.into_iter())
+            .filter(|file| self.lists(file))
+            .filter_map(|file| Some((file.metadata().ok()?.modified().ok(), file)))
+            .map(|(modified, file)| (file, modified))
+            .collect();
+        let mut listed = self.pages.write().unwrap();
+        listed.retain(|file, _| !file.starts_with(path));
+        listed.extend(pages);
+    }
+
+    /// Answers with the sitemap of the pages below `root`, as served for `host`
+    pub fn respond(&self, root: &Path, host: Option<&str>, shown: &dyn Fn(&str) -> bool) -> Response<'static> {
+        let pages = self.pages.read().unwrap();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+        let urls = pages.range(root.to_path_buf()..).take_while(|(file, _)| file.starts_with(root)).filter_map(
+            |(file, modified)| {
+                let relative = file.strip_prefix(root).ok()?.to_string_lossy().replace('\\', "/");
+                let path = format!("/{}", relative);
+                shown(&path).then_some((path, modified))
+            },
+        );
+        for (path, modified) in urls.take(MAX_URLS) {
+            let url_path = path.strip_suffix("index.html").unwrap_or(&path);
+            let location = format!("http://{}{}", host.unwrap_or("localhost"), url_path);
+            xml.push_str(&format!("  <url>\n    <loc>{}</loc>\n", escape(&location)));
+            if let Some(modified) = modified {
+                xml.push_str(&format!("    <lastmod>{}</lastmod>\n", units::iso_timestamp(*modified)));
+            }
+            xml.push_str("  </url>\n");
+        }
+        xml.push_str("</urlset>\n");
+        Response::new(200).header("Content-Type", "application/xml; charset=utf-8").body(xml.into_bytes())
+    }
+
+    /// Whether `file` is a page that goes into the sitemap
+    fn lists(&self, file: &Path) -> bool {
+        let Some(relative) = self.roots.iter().find_map(|root| file.strip_prefix(root).ok()) else {
+            return false;
+        };
+        let hidden = relative.iter().any(|segment| segment.to_string_lossy().starts_with('.'));
+        let extension = file.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+        !hidden && matches!(extension.as_deref(), Some("html" | "htm"))
+    }
+}
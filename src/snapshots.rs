@@ -0,0 +1,135 @@
+use crate::{units, walk};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// URL prefix under which past snapshots are served
+pub const PREFIX: &str = "/__snapshots";
+
+/// How long the tree has to stay unchanged before a snapshot is taken
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Point-in-time copies of the served root, made of hard links
+///
+/// Each snapshot is a directory named after the Unix time it was taken at.
+/// Hard links keep unchanged files from using extra space; editors that save by
+/// writing a new file and renaming it leave the old content in the snapshot.
+pub struct Snapshots {
+    dir: PathBuf,
+    root: PathBuf,
+}
+
+impl Snapshots {
+    pub fn new(dir: &Path, root: &Path) -> io::Result<Snapshots> {
+        fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        if dir.starts_with(root) {
+            // Every snapshot would trigger the watcher again
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the snapshot directory must be outside the served directory",
+            ));
+        }
+        Ok(Snapshots {
+            dir,
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Takes a snapshot whenever the tree settles after a change notification
+    pub fn record_changes(&self, changes: Receiver<()>) {
+        self.record();
+        while changes.recv().is_ok() {
+            loop {
+                match changes.recv_timeout(SETTLE_TIME) {
+                    Ok(()) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            self.record();
+        }
+    }
+
+    fn record(&self) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let target = self.dir.join(timestamp.to_string());
+        if target.exists() {
+            return;
+        }
+
+        let mut linked = 0;
+        for file in walk::files(&self.root) {
+            let Ok(relative) = file.strip_prefix(&self.root) else {
+                continue;
+            };
+            let destination = target.join(relative);
+            if let Some(parent) = destination.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            // Copy when the snapshot directory is on another filesystem
+            let result = fs::hard_link(&file, &destination).or_else(|_| fs::copy(&file, &destination).map(|_| ()));
+            match result {
+                Ok(()) => linked += 1,
+                Err(e) => warn!("Snapshot {}: failed to store {}: {}", timestamp, relative.display(), e),
+            }
+        }
+        info!("Snapshot {} taken ({} file(s))", timestamp, linked);
+    }
+
+    /// Timestamps of all snapshots, oldest first
+    pub fn list(&self) -> Vec<String> {
+        let mut timestamps: Vec<String> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| name.bytes().all(|b| b.is_ascii_digit()))
+            .collect();
+        timestamps.sort_by_key(|name| name.parse::<u64>().unwrap_or(0));
+        timestamps
+    }
+
+    /// An HTML page linking to every snapshot, newest first
+    pub fn index_page(&self) -> String {
+        let now = SystemTime::now();
+        let mut page = String::from("<h1>Snapshots</h1>\n<ul>\n");
+        for timestamp in self.list().iter().rev() {
+            let taken = UNIX_EPOCH + Duration::from_secs(timestamp.parse().unwrap_or(0));
+            page.push_str(&format!(
+                "<li><a href=\"{}/{}/\">{}</a> ({} ago)</li>\n",
+                PREFIX,
+                timestamp,
+                units::timestamp(taken),
+                units::duration(now.duration_since(taken).unwrap_or_default())
+            ));
+        }
+        page.push_str("</ul>\n");
+        page
+    }
+
+    /// Maps `<timestamp>/<path>` to the file in that snapshot
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let (timestamp, rest) = path.split_once('/').unwrap_or((path, ""));
+        if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let relative = Path::new(rest);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        let resolved = self.dir.join(timestamp).join(relative);
+        if resolved.is_dir() {
+            Some(resolved.join("index.html"))
+        } else {
+            Some(resolved)
+        }
+    }
+}
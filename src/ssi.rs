@@ -0,0 +1,95 @@
+use crate::cache::CachedFile;
+use rshttp::request;
+use std::sync::Arc;
+use tracing::warn;
+
+/// What a directive is replaced by when it cannot be carried out, as in Apache
+const ERROR: &[u8] = b"[an error occurred while processing this directive]";
+
+/// How deep included pages may include others, which also ends include loops
+const MAX_DEPTH: usize = 8;
+
+/// Expands `<!--#include virtual="/header.html" -->` directives in HTML pages, from --ssi
+///
+/// `virtual` names a URL path, absolute or relative to the page; `file` a
+/// path relative to the page's directory that stays below it. Included files
+/// are fetched like requests for them, through the access rules and the file
+/// cache, and included HTML has its own directives expanded.
+pub struct Includes<'a> {
+    /// The URL path of the page being served
+    page: &'a str,
+    fetch: &'a dyn Fn(&str) -> Option<Arc<CachedFile>>,
+}
+
+impl<'a> Includes<'a> {
+    pub fn new(page: &'a str, fetch: &'a dyn Fn(&str) -> Option<Arc<CachedFile>>) -> Includes<'a> {
+        Includes { page, fetch }
+    }
+
+    /// The page with its directives replaced by what they include
+    pub fn expand(&self, html: &[u8]) -> Vec<u8> {
+        self.expand_at(html, self.page, 0)
+    }
+
+    fn expand_at(&self, html: &[u8], page: &str, depth: usize) -> Vec<u8> {
+        let mut expanded = Vec::with_capacity(html.len());
+        let mut rest = html;
+        while let Some(start) = find(rest, b"<!--#") {
+            expanded.extend_from_slice(&rest[..start]);
+            let directive = &rest[start + 5..];
+            let Some(end) = find(directive, b"-->") else {
+                rest = &rest[start..];
+                break;
+            };
+            let text = String::from_utf8_lossy(&directive[..end]);
+            expanded.extend_from_slice(&self.include(&text, page, depth));
+            rest = &directive[end + 3..];
+        }
+        expanded.extend_from_slice(rest);
+        expanded
+    }
+
+    fn include(&self, directive: &str, page: &str, depth: usize) -> Vec<u8> {
+        let Some(target) = include_target(directive, page) else {
+            warn!("Cannot carry out <!--#{}--> in {}", directive.trim(), page);
+            return ERROR.to_vec();
+        };
+        if depth >= MAX_DEPTH {
+            warn!("Not including {} in {}: includes are nested more than {} deep", target, page, MAX_DEPTH);
+            return ERROR.to_vec();
+        }
+        match (self.fetch)(&target) {
+            Some(file) if file.mime_type.split(';').next() == Some("text/html") => {
+                self.expand_at(&file.contents, &target, depth + 1)
+            }
+            Some(file) => file.contents.clone(),
+            None => {
+                warn!("Cannot include {} in {}", target, page);
+                ERROR.to_vec()
+            }
+        }
+    }
+}
+
+/// The URL path an include directive such as `include virtual="/footer.html"` names
+fn include_target(directive: &str, page: &str) -> Option<String> {
+    let (kind, value) = directive.trim().strip_prefix("include")?.split_once('=')?;
+    let value = value.trim();
+    let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"'))?;
+    let value = value.split('?').next().unwrap_or(value);
+    let dir = &page[..page.rfind('/').map_or(0, |slash| slash + 1)];
+    let target = match kind.trim() {
+        "virtual" if value.starts_with('/') => value.to_string(),
+        "virtual" => format!("{}{}", dir, value),
+        // Only files in or below the page's directory
+        "file" if !value.starts_with('/') && !value.split('/').any(|segment| segment == "..") => {
+            format!("{}{}", dir, value)
+        }
+        _ => return None,
+    };
+    Some(request::normalize_path(&target))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
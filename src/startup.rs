@@ -0,0 +1,50 @@
+/// Something that keeps the server from starting
+pub struct Problem {
+    /// Stable identifier, e.g. `E200` (E1xx files, E2xx listeners, E3xx configuration, E4xx process setup)
+    code: &'static str,
+    message: String,
+    hint: Option<&'static str>,
+}
+
+/// Collects startup problems so all of them can be reported in one pass
+#[derive(Default)]
+pub struct Problems(Vec<Problem>);
+
+impl Problems {
+    pub fn push(&mut self, code: &'static str, message: impl Into<String>, hint: Option<&'static str>) {
+        self.0.push(Problem {
+            code,
+            message: message.into(),
+            hint,
+        });
+    }
+
+    /// Reports every problem on stderr and exits if there are any
+    ///
+    /// With `json`, each problem is written as one JSON object per line instead.
+    pub fn exit_if_any(&self, json: bool) {
+        if self.0.is_empty() {
+            return;
+        }
+        if json {
+            for problem in &self.0 {
+                let event = serde_json::json!({
+                    "event": "startup_problem",
+                    "code": problem.code,
+                    "message": problem.message,
+                    "hint": problem.hint,
+                });
+                eprintln!("{}", event);
+            }
+        } else {
+            eprintln!("Error: cannot start, found {} problem(s):", self.0.len());
+            for problem in &self.0 {
+                eprintln!("  [{}] {}", problem.code, problem.message);
+                if let Some(hint) = problem.hint {
+                    eprintln!("         hint: {}", hint);
+                }
+            }
+        }
+        std::process::exit(1);
+    }
+}
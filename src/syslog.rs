@@ -0,0 +1,117 @@
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Sockets the local syslog daemon (or journald) listens on, in the order they are tried
+const SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+/// Facility of the server's diagnostics
+pub const DAEMON: u8 = 3;
+
+/// Facility of access log lines, the one Apache's syslog logging defaults to
+pub const LOCAL7: u8 = 23;
+
+/// Severity of access log lines
+pub const INFO: u8 = 6;
+
+/// The local syslog socket, for --log-target syslog
+///
+/// Messages are sent in the traditional BSD format, tagged `rshttp[pid]`;
+/// the daemon adds the time and host. If the daemon restarts, the socket is
+/// connected again on the next message.
+pub struct Syslog {
+    socket: Mutex<UnixDatagram>,
+    tag: String,
+}
+
+impl Syslog {
+    pub fn connect() -> io::Result<Syslog> {
+        Ok(Syslog {
+            socket: Mutex::new(connect()?),
+            tag: format!("rshttp[{}]", std::process::id()),
+        })
+    }
+
+    /// Sends one message, dropping it if the daemon can't be reached
+    pub fn send(&self, facility: u8, severity: u8, message: &str) {
+        let datagram = format!("<{}>{}: {}", facility * 8 + severity, self.tag, message.trim_end());
+        let mut socket = self.socket.lock().unwrap_or_else(PoisonError::into_inner);
+        if socket.send(datagram.as_bytes()).is_err() {
+            if let Ok(reconnected) = connect() {
+                *socket = reconnected;
+                let _ = socket.send(datagram.as_bytes());
+            }
+        }
+    }
+}
+
+fn connect() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    let mut last_error = io::Error::from(io::ErrorKind::NotFound);
+    for path in SOCKETS {
+        match socket.connect(path) {
+            Ok(()) => return Ok(socket),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Hands every diagnostic event to syslog under [`DAEMON`], at the severity matching its level
+pub struct Diagnostics(pub Arc<Syslog>);
+
+impl<'a> MakeWriter<'a> for Diagnostics {
+    type Writer = Message;
+
+    fn make_writer(&'a self) -> Message {
+        self.message(INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Message {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        self.message(severity)
+    }
+}
+
+impl Diagnostics {
+    fn message(&self, severity: u8) -> Message {
+        Message {
+            syslog: Arc::clone(&self.0),
+            severity,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// One formatted event, sent when the formatter is done with it
+pub struct Message {
+    syslog: Arc<Syslog>,
+    severity: u8,
+    buffer: Vec<u8>,
+}
+
+impl Write for Message {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.syslog.send(DAEMON, self.severity, &String::from_utf8_lossy(&self.buffer));
+        }
+    }
+}
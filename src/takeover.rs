@@ -0,0 +1,167 @@
+use crate::listener::{self, Listener};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Most listening sockets passed in one handoff
+const MAX_FDS: usize = 32;
+
+/// How long the running instance waits for the new one to start serving
+const READY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Binds the control socket through which a new instance can take over the listeners
+pub fn listen(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A previous instance's socket is replaced, so the next takeover reaches us
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Answers takeover requests on the control socket until one completes
+///
+/// The listeners are sent to the new instance right away, but `on_handoff` only
+/// runs once it reports that it is serving. If it exits before that, this
+/// instance keeps going as if nothing happened.
+pub fn serve(control: UnixListener, listener_fds: &[RawFd], on_handoff: impl FnOnce()) {
+    for stream in control.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Control socket error: {}", e);
+                continue;
+            }
+        };
+        match hand_over(stream, listener_fds) {
+            Ok(()) => {
+                info!("Listeners taken over by a new instance");
+                on_handoff();
+                return;
+            }
+            Err(e) => warn!("Takeover aborted, continuing to serve: {}", e),
+        }
+    }
+}
+
+fn hand_over(stream: UnixStream, listener_fds: &[RawFd]) -> io::Result<()> {
+    stream.set_read_timeout(Some(READY_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    expect_line(&mut reader, "TAKEOVER")?;
+    send_fds(&stream, listener_fds)?;
+    expect_line(&mut reader, "READY")
+}
+
+/// A takeover in progress on the new instance's side
+pub struct Takeover {
+    stream: UnixStream,
+}
+
+impl Takeover {
+    /// Asks the instance behind the control socket at `path` for its listeners
+    pub fn request(path: &Path) -> io::Result<(Takeover, Vec<Listener>)> {
+        let mut stream = UnixStream::connect(path)?;
+        stream.write_all(b"TAKEOVER\n")?;
+        let listeners: Vec<_> = receive_fds(&stream)?
+            .into_iter()
+            .filter_map(|fd| listener::adopt_fd(fd, "taken over"))
+            .collect();
+        if listeners.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no listening sockets received"));
+        }
+        Ok((Takeover { stream }, listeners))
+    }
+
+    /// Tells the old instance that we are serving, so it starts draining
+    pub fn ready(mut self) -> io::Result<()> {
+        self.stream.write_all(b"READY\n")
+    }
+}
+
+fn expect_line(reader: &mut impl BufRead, expected: &str) -> io::Result<()> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() == expected {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {}, got {:?}", expected, line.trim_end()),
+        ))
+    }
+}
+
+/// Sends file descriptors as SCM_RIGHTS ancillary data along with one byte
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let fds = &fds[..fds.len().min(MAX_FDS)];
+    let payload = std::mem::size_of_val(fds) as u32;
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(payload) } as usize];
+
+    unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len() as _;
+
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(payload) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(header) as *mut RawFd, fds.len());
+
+        if libc::sendmsg(stream.as_raw_fd(), &message, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn receive_fds(stream: &UnixStream) -> io::Result<Vec<RawFd>> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut fds = Vec::new();
+
+    unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.len() as _;
+
+        match libc::recvmsg(stream.as_raw_fd(), &mut message, 0) {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "control socket closed")),
+            _ => {}
+        }
+
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header) as *const RawFd;
+                let count = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(std::ptr::read_unaligned(data.add(i)));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    Ok(fds)
+}
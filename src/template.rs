@@ -0,0 +1,425 @@
+use crate::inject::escape;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Renders Handlebars templates into pages, from --templates
+///
+/// A request for `page.hbs` (or `page.html.hbs`) renders the template with the
+/// --template-data file (JSON or TOML) as the context, plus the request's
+/// query parameters under `query` and its path under `path`. The data file is
+/// read for every page, so edits show up on the next request.
+///
+/// The supported syntax is the core of Handlebars: `{{name}}` and `{{a.b}}`
+/// (HTML-escaped), `{{{raw}}}`, the `if`, `unless`, `each` and `with` blocks
+/// with `{{else}}`, `this`, `../`, `@root`, `@index`, `@key`, `@first`,
+/// `@last`, `{{! comments }}` and `~` to trim whitespace.
+pub struct Templates {
+    data: Option<PathBuf>,
+}
+
+/// A parsed template
+enum Node {
+    Text(String),
+    Value { path: String, raw: bool },
+    Block { helper: String, path: String, body: Vec<Node>, inverse: Vec<Node> },
+}
+
+enum Token {
+    Text(String),
+    Value { path: String, raw: bool },
+    Open { helper: String, path: String },
+    Else,
+    Close(String),
+}
+
+/// One level of the context stack, with the data variables of `each`
+struct Frame<'v> {
+    value: &'v Value,
+    index: Option<usize>,
+    key: Option<String>,
+    last: bool,
+}
+
+impl Templates {
+    /// Checks that the data file, if any, can be read
+    pub fn new(data: Option<&Path>) -> Result<Templates, String> {
+        let templates = Templates {
+            data: data.map(Path::to_path_buf),
+        };
+        templates.data()?;
+        Ok(templates)
+    }
+
+    /// Whether `file` is rendered rather than sent as is
+    pub fn renders(file: &Path) -> bool {
+        file.extension().is_some_and(|extension| extension == "hbs")
+    }
+
+    /// The path whose extension gives the content type of a rendered page: `page.html` for both
+    /// `page.hbs` and `page.html.hbs`
+    pub fn output_path(file: &Path) -> PathBuf {
+        let without = file.with_extension("");
+        match without.extension() {
+            Some(_) => without,
+            None => without.with_extension("html"),
+        }
+    }
+
+    /// Renders the template in `file` for a request of `path` with `query`
+    pub fn render(&self, file: &Path, path: &str, query: &str) -> Result<String, String> {
+        let mut context = self.data()?;
+        context.insert("query".to_string(), query_params(query));
+        context.insert("path".to_string(), Value::String(path.to_string()));
+        render_file(file, &Value::Object(context))
+    }
+
+    fn data(&self) -> Result<Map<String, Value>, String> {
+        let Some(path) = &self.data else {
+            return Ok(Map::new());
+        };
+        let source = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let value = match path.extension().is_some_and(|extension| extension == "toml") {
+            true => source
+                .parse::<toml::Table>()
+                .map_err(|e| e.message().to_string())
+                .and_then(|table| serde_json::to_value(table).map_err(|e| e.to_string())),
+            false => serde_json::from_str(&source).map_err(|e| e.to_string()),
+        };
+        match value {
+            Ok(Value::Object(data)) => Ok(data),
+            Ok(_) => Err(format!("the template data in {} is not an object", path.display())),
+            Err(e) => Err(format!("invalid template data in {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Renders the template in `file` with `context`
+pub fn render_file(file: &Path, context: &Value) -> Result<String, String> {
+    let source = fs::read_to_string(file).map_err(|e| format!("cannot read {}: {}", file.display(), e))?;
+    let nodes = parse(&source).map_err(|e| format!("{}: {}", file.display(), e))?;
+    let mut output = String::new();
+    let mut stack = vec![Frame::new(context)];
+    render(&nodes, &mut stack, &mut output)?;
+    Ok(output)
+}
+
+/// Checks that the template in `file` can be read and parsed
+pub fn check_file(file: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(file).map_err(|e| format!("cannot read {}: {}", file.display(), e))?;
+    parse(&source).map(drop).map_err(|e| format!("{}: {}", file.display(), e))
+}
+
+/// The parameters of `query` as an object, the later of repeated ones winning
+pub fn query_params(query: &str) -> Value {
+    let params: Map<String, Value> = url::form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| (name.into_owned(), Value::String(value.into_owned())))
+        .collect();
+    Value::Object(params)
+}
+
+impl<'v> Frame<'v> {
+    fn new(value: &'v Value) -> Frame<'v> {
+        Frame {
+            value,
+            index: None,
+            key: None,
+            last: false,
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Node>, String> {
+    let mut tokens = tokenize(source)?.into_iter();
+    let (nodes, _, closing) = build(&mut tokens)?;
+    match closing {
+        Some(helper) => Err(format!("{{{{/{}}}}} without a matching {{{{#{}}}}}", helper, helper)),
+        None => Ok(nodes),
+    }
+}
+
+/// Splits a template into text and tags, applying `~` whitespace control
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    let mut trim_next = false;
+    while let Some(start) = rest.find("{{") {
+        let mut text = &rest[..start];
+        if trim_next {
+            text = text.trim_start();
+        }
+        let tag = &rest[start..];
+        let raw = tag.starts_with("{{{");
+        let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+        let comment = tag[open.len()..].trim_start_matches('~').starts_with("!--");
+        let end = match comment {
+            true => tag.find("--}}").map(|end| end + 2).or_else(|| tag.find("--~}}").map(|end| end + 3)),
+            false => tag.find(close),
+        }
+        .ok_or_else(|| format!("unclosed tag at line {}", line_of(source, source.len() - tag.len())))?;
+        let mut inner = &tag[open.len()..end];
+        if let Some(stripped) = inner.strip_prefix('~') {
+            text = text.trim_end();
+            inner = stripped;
+        }
+        trim_next = inner.ends_with('~');
+        let inner = inner.trim_end_matches('~').trim();
+        if !text.is_empty() {
+            tokens.push(Token::Text(text.to_string()));
+        }
+        rest = &tag[end + close.len()..];
+
+        if inner.starts_with('!') {
+            continue;
+        }
+        tokens.push(match inner.chars().next() {
+            _ if raw => Token::Value {
+                path: inner.to_string(),
+                raw: true,
+            },
+            _ if inner == "else" || inner == "^" => Token::Else,
+            Some('#') => {
+                let mut words = inner[1..].split_whitespace();
+                let helper = words.next().unwrap_or("").to_string();
+                Token::Open {
+                    helper,
+                    path: words.collect::<Vec<_>>().join(" "),
+                }
+            }
+            Some('/') => Token::Close(inner[1..].trim().to_string()),
+            Some('&') => Token::Value {
+                path: inner[1..].trim().to_string(),
+                raw: true,
+            },
+            _ => Token::Value {
+                path: inner.to_string(),
+                raw: false,
+            },
+        });
+    }
+    let text = if trim_next { rest.trim_start() } else { rest };
+    if !text.is_empty() {
+        tokens.push(Token::Text(text.to_string()));
+    }
+    Ok(tokens)
+}
+
+/// Builds nodes up to an `{{else}}` or a closing tag, returning the nodes, whether the else was
+/// reached and the helper a closing tag names
+fn build(tokens: &mut impl Iterator<Item = Token>) -> Result<(Vec<Node>, bool, Option<String>), String> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Text(text) => nodes.push(Node::Text(text)),
+            Token::Value { path, raw } => nodes.push(Node::Value { path, raw }),
+            Token::Else => return Ok((nodes, true, None)),
+            Token::Close(helper) => return Ok((nodes, false, Some(helper))),
+            Token::Open { helper, path } => {
+                if !matches!(helper.as_str(), "if" | "unless" | "each" | "with") {
+                    return Err(format!("unknown block helper '{}'", helper));
+                }
+                let (body, has_else, mut closing) = build(tokens)?;
+                let mut inverse = Vec::new();
+                if has_else {
+                    let (nodes, _, close) = build(tokens)?;
+                    (inverse, closing) = (nodes, close);
+                }
+                if closing.as_deref() != Some(helper.as_str()) {
+                    return Err(format!("{{{{#{}}}}} is not closed by {{{{/{}}}}}", helper, helper));
+                }
+                nodes.push(Node::Block {
+                    helper,
+                    path,
+                    body,
+                    inverse,
+                });
+            }
+        }
+    }
+    Ok((nodes, false, None))
+}
+
+fn render<'v>(nodes: &'v [Node], stack: &mut Vec<Frame<'v>>, output: &mut String) -> Result<(), String> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::Value { path, raw } => {
+                let text = match lookup(stack, path) {
+                    Some(value) => display(&value),
+                    None => String::new(),
+                };
+                // The characters Handlebars escapes, which covers unquoted attributes too
+                let text = match raw {
+                    true => text,
+                    false => escape(&text).replace('\'', "&#x27;").replace('`', "&#x60;").replace('=', "&#x3D;"),
+                };
+                output.push_str(&text);
+            }
+            Node::Block {
+                helper,
+                path,
+                body,
+                inverse,
+            } => {
+                let value = lookup(stack, path).unwrap_or(Cow::Owned(Value::Null));
+                if matches!(helper.as_str(), "if" | "unless") {
+                    let branch = if is_truthy(&value) == (helper == "if") { body } else { inverse };
+                    render(branch, stack, output)?;
+                    continue;
+                }
+                // Data variables such as @index have no context to enter
+                let value = match value {
+                    Cow::Borrowed(value) => value,
+                    Cow::Owned(_) => &Value::Null,
+                };
+                match helper.as_str() {
+                    "with" if is_truthy(value) => {
+                        stack.push(Frame::new(value));
+                        let rendered = render(body, stack, output);
+                        stack.pop();
+                        rendered?;
+                    }
+                    "each" => {
+                        let items: Vec<(Option<String>, &Value)> = match value {
+                            Value::Array(items) => items.iter().map(|item| (None, item)).collect(),
+                            Value::Object(entries) => {
+                                entries.iter().map(|(key, item)| (Some(key.clone()), item)).collect()
+                            }
+                            _ => Vec::new(),
+                        };
+                        if items.is_empty() {
+                            render(inverse, stack, output)?;
+                        }
+                        let count = items.len();
+                        for (index, (key, item)) in items.into_iter().enumerate() {
+                            stack.push(Frame {
+                                value: item,
+                                index: Some(index),
+                                key,
+                                last: index + 1 == count,
+                            });
+                            let rendered = render(body, stack, output);
+                            stack.pop();
+                            rendered?;
+                        }
+                    }
+                    _ => render(inverse, stack, output)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The value a path such as `name`, `this.items`, `../title`, `@root.query.page` or `@index` stands for
+fn lookup<'v>(stack: &[Frame<'v>], path: &str) -> Option<Cow<'v, Value>> {
+    let frame = stack.last()?;
+    let data = match path {
+        "@index" => frame.index.map(Value::from),
+        "@key" => frame.key.clone().map(Value::String),
+        "@first" => frame.index.map(|index| Value::Bool(index == 0)),
+        "@last" => Some(Value::Bool(frame.last)),
+        _ => None,
+    };
+    if let Some(data) = data {
+        return Some(Cow::Owned(data));
+    }
+    let mut depth = stack.len() - 1;
+    let mut rest = path;
+    if let Some(after) = rest.strip_prefix("@root") {
+        depth = 0;
+        rest = after.trim_start_matches(['.', '/']);
+    }
+    while let Some(after) = rest.strip_prefix("../") {
+        depth = depth.checked_sub(1)?;
+        rest = after;
+    }
+    let rest = rest.strip_prefix("this").map_or(rest, |after| after.trim_start_matches(['.', '/']));
+    let rest = rest.strip_prefix("./").unwrap_or(rest);
+    let mut value = stack[depth].value;
+    for segment in rest.split(['.', '/']).filter(|segment| !segment.is_empty() && *segment != ".") {
+        value = match value {
+            Value::Object(entries) => entries.get(segment)?,
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(Cow::Borrowed(value))
+}
+
+/// Falsy like in Handlebars: false, null, "", 0 and empty lists
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(_) => true,
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render_str(source: &str, context: Value) -> Result<String, String> {
+        let nodes = parse(source)?;
+        let mut output = String::new();
+        render(&nodes, &mut vec![Frame::new(&context)], &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn values_are_escaped_unless_triple_stashed() {
+        let context = json!({"name": "<a href=\"x\">Tom & 'Jerry'</a>", "attr": "x onclick=`y`", "count": 3, "user": {"role": "admin"}});
+        assert_eq!(
+            render_str("{{name}}|{{{name}}}|{{attr}}|{{count}}|{{user.role}}", context).unwrap(),
+            "&lt;a href&#x3D;&quot;x&quot;&gt;Tom &amp; &#x27;Jerry&#x27;&lt;/a&gt;|<a href=\"x\">Tom & 'Jerry'</a>\
+             |x onclick&#x3D;&#x60;y&#x60;|3|admin"
+        );
+    }
+
+    #[test]
+    fn missing_values_render_empty() {
+        let context = json!({"user": {}});
+        assert_eq!(render_str("[{{nope}}][{{user.name}}][{{a.b.c}}]", context.clone()).unwrap(), "[][][]");
+        assert_eq!(render_str("{{#if nope}}yes{{else}}no{{/if}}", context).unwrap(), "no");
+    }
+
+    #[test]
+    fn conditionals() {
+        let context = json!({"yes": true, "empty": [], "zero": 0, "text": "x"});
+        let source = "{{#if yes}}a{{/if}}{{#if empty}}b{{else}}c{{/if}}{{#unless zero}}d{{/unless}}{{#if text}}e{{/if}}";
+        assert_eq!(render_str(source, context).unwrap(), "acde");
+    }
+
+    #[test]
+    fn loops() {
+        let context = json!({"title": "T", "items": ["a", "<b>"], "map": {"k": 1}, "none": []});
+        let source = "{{#each items}}{{@index}}:{{this}}{{#if @first}}(first){{/if}}{{#unless @last}},{{/unless}} \
+                      {{../title}}{{/each}}|{{#each map}}{{@key}}={{this}}{{/each}}|{{#each none}}x{{else}}empty{{/each}}";
+        assert_eq!(render_str(source, context).unwrap(), "0:a(first), T1:&lt;b&gt; T|k=1|empty");
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_errors() {
+        assert!(render_str("{{#if a}}open", json!({})).is_err());
+        assert!(render_str("{{/each}}", json!({})).is_err());
+    }
+}
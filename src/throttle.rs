@@ -0,0 +1,137 @@
+use crate::listener::Connection;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Pauses between chunks are at most this long, so the rate holds at any time scale
+const CHUNK_TIME: Duration = Duration::from_millis(50);
+
+/// A slow network to simulate, from --throttle and --latency
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Shaping {
+    /// Bytes per second, unlimited when `None`
+    pub rate: Option<u64>,
+    /// Added before the first byte of each response
+    pub latency: Duration,
+}
+
+impl Shaping {
+    pub fn is_active(&self) -> bool {
+        self.rate.is_some() || !self.latency.is_zero()
+    }
+}
+
+/// Spaces out the bytes of one response, telling the writer how long to wait before each chunk
+pub struct Pacer {
+    shaping: Shaping,
+    /// When the first byte may go out, set by the first chunk
+    start: Option<Instant>,
+    sent: u64,
+}
+
+impl Pacer {
+    pub fn new(shaping: Shaping) -> Pacer {
+        Pacer {
+            shaping,
+            start: None,
+            sent: 0,
+        }
+    }
+
+    /// Most bytes to write at once
+    pub fn chunk_size(&self) -> usize {
+        match self.shaping.rate {
+            Some(rate) => (rate as f64 * CHUNK_TIME.as_secs_f64()).max(1.0) as usize,
+            None => usize::MAX,
+        }
+    }
+
+    /// How long to wait before writing the next `len` bytes, counting them as sent
+    pub fn wait(&mut self, len: usize) -> Duration {
+        let start = *self.start.get_or_insert_with(|| Instant::now() + self.shaping.latency);
+        let due = match self.shaping.rate {
+            Some(rate) => start + Duration::from_secs_f64(self.sent as f64 / rate as f64),
+            None => start,
+        };
+        self.sent += len as u64;
+        due.saturating_duration_since(Instant::now())
+    }
+}
+
+/// A connection whose writes are paced by a [`Pacer`], for the thread pool
+///
+/// Without shaping it passes everything through, sendfile included.
+pub struct Throttled<S> {
+    inner: S,
+    pacer: Option<Pacer>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, shaping: Shaping) -> Throttled<S> {
+        Throttled {
+            inner,
+            pacer: shaping.is_active().then(|| Pacer::new(shaping)),
+        }
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(pacer) = &mut self.pacer else {
+            return self.inner.write(buf);
+        };
+        let chunk = &buf[..buf.len().min(pacer.chunk_size())];
+        thread::sleep(pacer.wait(chunk.len()));
+        self.inner.write(chunk)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Connection> Connection for Throttled<S> {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.inner.peer_ip()
+    }
+
+    fn streams(&self) -> bool {
+        self.inner.streams()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn cut_short(&mut self) {
+        self.inner.cut_short()
+    }
+
+    fn wait_for_request(&self, idle: Duration) -> bool {
+        self.inner.wait_for_request(idle)
+    }
+
+    fn write_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        if self.pacer.is_none() {
+            return self.inner.write_file(file, offset, len);
+        }
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        if io::copy(&mut file.take(len), self)? < len {
+            return Err(io::ErrorKind::UnexpectedEof.into()); // The file shrank
+        }
+        Ok(())
+    }
+}
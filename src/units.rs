@@ -0,0 +1,278 @@
+use rshttp::date::{self, civil_from_days, offset_text, MONTHS};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The formats chosen at startup, see [`configure`]
+static FORMATS: OnceLock<Formats> = OnceLock::new();
+
+/// How sizes are shown, chosen with --size-units
+#[derive(Clone, Copy, Debug, Default)]
+pub enum SizeUnits {
+    /// Multiples of 1024: KiB, MiB, GiB, TiB
+    #[default]
+    Binary,
+    /// Multiples of 1000: kB, MB, GB, TB
+    Si,
+    /// Exact byte counts
+    Bytes,
+}
+
+impl SizeUnits {
+    pub fn parse(value: &str) -> Result<SizeUnits, String> {
+        match value {
+            "binary" => Ok(SizeUnits::Binary),
+            "si" => Ok(SizeUnits::Si),
+            "bytes" => Ok(SizeUnits::Bytes),
+            _ => Err("expected binary, si or bytes".to_string()),
+        }
+    }
+}
+
+/// How points in time are shown, chosen with --time-style
+#[derive(Clone, Copy, Debug, Default)]
+pub enum TimeStyle {
+    /// ISO 8601 in UTC, e.g. `2024-05-01T13:55:36Z`
+    #[default]
+    Iso,
+    /// The server's local time with its UTC offset, e.g. `2024-05-01 15:55:36 +0200`
+    Local,
+}
+
+impl TimeStyle {
+    pub fn parse(value: &str) -> Result<TimeStyle, String> {
+        match value {
+            "iso" => Ok(TimeStyle::Iso),
+            "local" => Ok(TimeStyle::Local),
+            _ => Err("expected iso or local".to_string()),
+        }
+    }
+}
+
+/// How sizes, durations and times are shown in logs, reports and generated pages
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Formats {
+    pub sizes: SizeUnits,
+    pub times: TimeStyle,
+}
+
+/// Sets the formats for the rest of the process; only the first call counts
+pub fn configure(formats: Formats) {
+    if let TimeStyle::Local = formats.times {
+        // Loads the time zone now, before a sandbox could hide it
+        utc_offset(0);
+    }
+    let _ = FORMATS.set(formats);
+}
+
+fn formats() -> Formats {
+    FORMATS.get().copied().unwrap_or_default()
+}
+
+/// Parses a byte size such as `512`, `200K`, `1.5M` or `2G` (binary multiples)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let upper = value.to_ascii_uppercase();
+    let number = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (number, multiplier) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}' (expected e.g. 512K, 256M, 1G)", value))?;
+    if number < 0.0 || !number.is_finite() {
+        return Err(format!("invalid size '{}'", value));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a transfer rate into bytes per second: bits as in `56kbps`, `1Mbps` or `1.5Gbps`
+/// (decimal multiples), or a size per second as in `200K/s`
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if let Some(size) = value.strip_suffix("/s") {
+        return parse_size(size).and_then(|rate| positive(rate, value));
+    }
+    let invalid = || format!("invalid rate '{}' (expected e.g. 56kbps, 1Mbps or 200K/s)", value);
+    let number = value.strip_suffix("bps").ok_or_else(invalid)?;
+    let (number, multiplier) = match number.chars().last() {
+        Some('k' | 'K') => (&number[..number.len() - 1], 1e3),
+        Some('M') => (&number[..number.len() - 1], 1e6),
+        Some('G') => (&number[..number.len() - 1], 1e9),
+        _ => (number, 1.0),
+    };
+    let bits: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if bits < 0.0 || !bits.is_finite() {
+        return Err(invalid());
+    }
+    positive((bits * multiplier / 8.0) as u64, value)
+}
+
+fn positive(rate: u64, value: &str) -> Result<u64, String> {
+    match rate {
+        0 => Err(format!("rate '{}' is below one byte per second", value)),
+        rate => Ok(rate),
+    }
+}
+
+/// Parses a span of time such as `200ms`, `1.5s` or `2m`; a bare number is seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (value, 1.0)
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration '{}' (expected e.g. 200ms, 1.5s or 2m)", value))?;
+    Duration::try_from_secs_f64(number * unit).map_err(|_| format!("invalid duration '{}'", value))
+}
+
+/// Formats a byte count in the configured units, e.g. `1.5 MiB`
+pub fn size(bytes: u64) -> String {
+    let (base, prefixes) = match formats().sizes {
+        SizeUnits::Binary => (1024.0, ["KiB", "MiB", "GiB", "TiB"]),
+        SizeUnits::Si => (1000.0, ["kB", "MB", "GB", "TB"]),
+        SizeUnits::Bytes => return format!("{} bytes", bytes),
+    };
+    let mut value = bytes as f64;
+    let mut unit = None;
+    for prefix in prefixes {
+        if value < base {
+            break;
+        }
+        value /= base;
+        unit = Some(prefix);
+    }
+    match unit {
+        Some(unit) => format!("{:.1} {}", value, unit),
+        None => format!("{} bytes", bytes),
+    }
+}
+
+/// Formats a span of time with its two largest units, e.g. `4.2s`, `3m 07s` or `2h 05m 00s`
+pub fn duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+        _ => format!("{}d {:02}h {:02}m", secs / 86400, secs / 3600 % 24, secs / 60 % 60),
+    }
+}
+
+/// Formats a point in time in the configured style
+pub fn timestamp(time: SystemTime) -> String {
+    let (year, month, day, secs, offset) = broken_down(time);
+    let (hour, minute, second) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match formats().times {
+        TimeStyle::Iso => iso_timestamp(time),
+        TimeStyle::Local => format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            offset_text(offset)
+        ),
+    }
+}
+
+/// Formats a point in time as ISO 8601 in UTC with milliseconds, whatever style was configured, for machine-read output
+pub fn iso_timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+/// Formats a point in time as an HTTP date, e.g. `Wed, 01 May 2024 13:55:36 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let days = secs.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let secs = secs.rem_euclid(86400);
+    // The epoch was a Thursday
+    let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][days.rem_euclid(7) as usize];
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Formats a point in time in the basic ISO 8601 form, e.g. `20240501T135536Z`
+#[cfg(feature = "s3")]
+pub fn iso_basic(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Formats a point in time the way Apache's `%t` does, e.g. `01/May/2024:13:55:36 +0000`
+///
+/// The time is in UTC unless local times were configured.
+pub fn clf_timestamp(time: SystemTime) -> String {
+    let (.., offset) = broken_down(time);
+    date::clf_timestamp(time, offset)
+}
+
+/// Year, month, day, seconds into the day and UTC offset in seconds of `time`, in the configured style
+fn broken_down(time: SystemTime) -> (i64, u32, u32, i64, i64) {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let offset = match formats().times {
+        TimeStyle::Iso => 0,
+        TimeStyle::Local => utc_offset(secs),
+    };
+    let local = secs + offset;
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    (year, month, day, local.rem_euclid(86400), offset)
+}
+
+/// Seconds the local time zone is ahead of UTC at `secs` after the epoch
+#[cfg(unix)]
+fn utc_offset(secs: i64) -> i64 {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    match unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        true => 0,
+        false => tm.tm_gmtoff as i64,
+    }
+}
+
+#[cfg(not(unix))]
+fn utc_offset(_secs: i64) -> i64 {
+    0
+}
+
@@ -0,0 +1,649 @@
+use crate::cgi::Invocation;
+use crate::{ignore, units};
+use rshttp::{request, resolve};
+use rshttp::response::Response;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{debug, info, warn};
+
+/// URL of the upload page served with --upload
+pub const PAGE_PATH: &str = "/_rshttps/upload";
+
+/// Most bytes of headers a part of a multipart body may have
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+/// Numbers the temporary files uploads are written to
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes files sent with PUT, or with a multipart POST to a directory, below the served root, from --upload
+/// (or --writable), and deletes them with DELETE
+///
+/// `PUT /dir/file` stores the body as that file, creating missing directories,
+/// and answers 201 for a new file or 204 for a replaced one. A multipart POST
+/// to a directory stores each file field in it under the field's filename.
+/// Files are written to a temporary name next to them and renamed into place
+/// once complete, so a failed upload leaves nothing behind. Nothing is
+/// written through a symlink leading out of the root, and uploads larger than
+/// --max-upload-size are refused with 413. DELETE removes a file, or a
+/// directory with everything in it when asked with `?recursive=1`.
+///
+/// Large files can be sent in parts that survive a dropped connection: a PUT
+/// with `Content-Range: bytes START-END/TOTAL` adds its part to a hidden
+/// `.NAME.partial` file next to the target, answering 202 with a `Range`
+/// header of what has been received, until the last part moves the file into
+/// place. `Content-Range: bytes */TOTAL` with an empty body asks where to
+/// resume, and a DELETE of the target abandons the upload.
+pub struct Uploads {
+    max_size: u64,
+}
+
+/// The upload page: files dropped on it or picked are POSTed one by one to the chosen directory
+const PAGE: &str = r#"<!DOCTYPE html>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width">
+<title>Upload files</title>
+<style>
+body{font:15px/1.5 system-ui,sans-serif;max-width:40em;margin:2em auto;padding:0 1em}
+#drop{border:2px dashed #999;border-radius:8px;padding:2.5em 1em;text-align:center;cursor:pointer}
+#drop.over{border-color:#2a7ae2;background:#eef5ff}
+input[type=text]{width:100%;box-sizing:border-box;font:inherit;padding:.3em}
+li{list-style:none;margin:.6em 0}progress{width:100%}.failed{color:#c00}small{color:#666}
+</style>
+<h1>Upload files</h1>
+<p><label>Into the directory<br><input type="text" id="dir" value="/"></label>
+<div id="drop">Drop files here, or click to pick them<br><small>Up to {max} each</small></div>
+<input type="file" id="files" multiple hidden>
+<ul id="list"></ul>
+<script>
+const dir = document.getElementById('dir'), drop = document.getElementById('drop');
+const picker = document.getElementById('files'), list = document.getElementById('list');
+dir.value = new URLSearchParams(location.search).get('dir') || '/';
+drop.onclick = () => picker.click();
+picker.onchange = () => { [...picker.files].forEach(upload); picker.value = ''; };
+drop.ondragover = e => { e.preventDefault(); drop.classList.add('over'); };
+drop.ondragleave = () => drop.classList.remove('over');
+drop.ondrop = e => { e.preventDefault(); drop.classList.remove('over'); [...e.dataTransfer.files].forEach(upload); };
+function upload(file) {
+  const item = document.createElement('li'), bar = document.createElement('progress');
+  item.textContent = file.name + ' ';
+  item.append(bar);
+  list.prepend(item);
+  let target = dir.value.trim() || '/';
+  if (!target.startsWith('/')) target = '/' + target;
+  if (!target.endsWith('/')) target += '/';
+  const form = new FormData();
+  form.append('file', file);
+  const request = new XMLHttpRequest();
+  request.open('POST', target);
+  request.upload.onprogress = e => { if (e.lengthComputable) { bar.max = e.total; bar.value = e.loaded; } };
+  request.onload = () => {
+    if (request.status == 201) {
+      const link = document.createElement('a');
+      link.href = JSON.parse(request.responseText).files[0];
+      link.textContent = link.getAttribute('href');
+      item.replaceChildren(link);
+    } else {
+      item.className = 'failed';
+      item.textContent = file.name + ': ' + (request.responseText.trim() || request.status);
+    }
+  };
+  request.onerror = () => { item.className = 'failed'; item.textContent = file.name + ': the upload failed'; };
+  request.send(form);
+}
+</script>
+"#;
+
+/// What a write request did
+pub struct Written {
+    pub response: Response<'static>,
+    /// The files and directories written or deleted, whose cached contents are stale
+    pub changed: Vec<PathBuf>,
+}
+
+/// Why an upload was refused, and the status to answer it with
+struct Refused(u16, String);
+
+/// The headers of a part of a multipart body that matter here
+struct PartHead {
+    filename: Option<String>,
+}
+
+/// Decodes a chunked body, failing once it grows past the upload limit
+struct Chunked<R> {
+    body: R,
+    /// What is left of the current chunk
+    left: u64,
+    decoded: u64,
+    max_size: u64,
+    done: bool,
+}
+
+/// Reads a multipart/form-data body part by part, without holding a whole part in memory
+struct Multipart<R> {
+    body: R,
+    /// `\r\n--` and the boundary, which ends every part
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    /// Whether the buffer is inside a part (or the preamble) rather than after a delimiter
+    in_part: bool,
+}
+
+impl Uploads {
+    pub fn new(max_size: u64) -> Uploads {
+        Uploads { max_size }
+    }
+
+    /// The upload page, telling how large uploads may be
+    pub fn page(&self) -> String {
+        PAGE.replace("{max}", &units::size(self.max_size))
+    }
+
+    /// Stores the body of a PUT or the files of a multipart POST, or carries out a DELETE, and tells what to answer
+    pub fn handle(&self, client: &mut (impl Read + Write), invocation: &Invocation) -> Written {
+        let mut changed = Vec::new();
+        let done = match invocation.method {
+            "PUT" => self.put(client, invocation, &mut changed),
+            "DELETE" => delete(invocation, &mut changed),
+            _ => self.post(client, invocation, &mut changed),
+        };
+        let response = match done {
+            Ok(response) => response,
+            Err(Refused(status, reason)) => {
+                debug!("Refused to {} {}: {}", invocation.method, invocation.path, reason);
+                // The body may not have been read, so the connection can't be reused
+                Response::new(status)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .header("Connection", "close")
+                    .body(format!("{}\n", reason).into_bytes())
+            }
+        };
+        Written { response, changed }
+    }
+
+    fn put(
+        &self,
+        client: &mut (impl Read + Write),
+        invocation: &Invocation,
+        changed: &mut Vec<PathBuf>,
+    ) -> Result<Response<'static>, Refused> {
+        let file = resolve::file_path(invocation.root, invocation.path);
+        let (Some(dir), Some(name)) = (file.parent(), file.file_name()) else {
+            return Err(Refused(409, "the root directory cannot be replaced".to_string()));
+        };
+        if invocation.path.ends_with('/') || file.is_dir() {
+            return Err(Refused(409, format!("{} is a directory", invocation.path)));
+        }
+        if let Some(range) = invocation.headers.get("Content-Range") {
+            return self.put_part(client, invocation, range, &file, changed);
+        }
+        let length = self.body_length(invocation)?;
+        prepare_dir(invocation.root, dir)?;
+        let existed = file.exists();
+        continue_if_expected(client, invocation)?;
+        let size = store(&mut self.body(client, invocation, length), &file, &name.to_string_lossy(), length)?;
+        info!("Stored {} ({})", invocation.path, units::size(size));
+        changed.push(file);
+        Ok(match existed {
+            true => Response::new(204),
+            false => Response::new(201).header("Location", request::encode_path(invocation.path)),
+        })
+    }
+
+    /// Adds a part of a file to what was received of it before, moving it into place once complete
+    fn put_part(
+        &self,
+        client: &mut (impl Read + Write),
+        invocation: &Invocation,
+        range: &str,
+        file: &Path,
+        changed: &mut Vec<PathBuf>,
+    ) -> Result<Response<'static>, Refused> {
+        let invalid = || Refused(400, format!("invalid Content-Range '{}'", range));
+        let (part, total) = content_range(range).ok_or_else(invalid)?;
+        if total > self.max_size {
+            return Err(Refused(413, format!("uploads are limited to {}", units::size(self.max_size))));
+        }
+        let partial = partial_path(file);
+        let received = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+        let Some((start, end)) = part else {
+            return Ok(progress(202, received));
+        };
+        if start > end || end >= total {
+            return Err(invalid());
+        }
+        if start > received {
+            // The client has to go back to where the received part ends
+            return Ok(progress(416, received).header("Connection", "close"));
+        }
+        if self.body_length(invocation)? != Some(end - start + 1) {
+            return Err(Refused(400, "the body must be as long as its Content-Range".to_string()));
+        }
+        prepare_dir(invocation.root, file.parent().unwrap_or(invocation.root))?;
+        continue_if_expected(client, invocation)?;
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&partial)
+            .map_err(|e| unwritable(&partial, e))?;
+        // A part sent again replaces what was received of it
+        (out.set_len(start).and_then(|()| out.seek(SeekFrom::Start(start)))).map_err(|e| unwritable(&partial, e))?;
+        let mut body = invocation.received_body().chain(client.take(end - start + 1)).take(end - start + 1);
+        // What arrives is kept even if the connection drops, so the upload can resume from there
+        let copied = write_all_from(&mut body, &mut out, &partial)?;
+        if start + copied < end + 1 {
+            return Err(unreadable(io::ErrorKind::UnexpectedEof.into()));
+        }
+        if end + 1 < total {
+            return Ok(progress(202, end + 1));
+        }
+        let existed = file.exists();
+        fs::rename(&partial, file).map_err(|e| unwritable(file, e))?;
+        info!("Stored {} ({}, sent in parts)", invocation.path, units::size(total));
+        changed.push(file.to_path_buf());
+        Ok(match existed {
+            true => Response::new(204),
+            false => Response::new(201).header("Location", request::encode_path(invocation.path)),
+        })
+    }
+
+    fn post(
+        &self,
+        client: &mut (impl Read + Write),
+        invocation: &Invocation,
+        changed: &mut Vec<PathBuf>,
+    ) -> Result<Response<'static>, Refused> {
+        let dir = resolve::file_path(invocation.root, invocation.path);
+        if !dir.is_dir() {
+            return Err(match dir.exists() {
+                true => Refused(409, format!("{} is not a directory", invocation.path)),
+                false => Refused(404, format!("{} does not exist", invocation.path)),
+            });
+        }
+        let content_type = invocation.headers.get("Content-Type").unwrap_or("");
+        let Some(boundary) = boundary(content_type) else {
+            let reason = "uploads are POSTed as multipart/form-data, or sent with PUT".to_string();
+            return Err(Refused(415, reason));
+        };
+        let length = self.body_length(invocation)?;
+        prepare_dir(invocation.root, &dir)?;
+        continue_if_expected(client, invocation)?;
+        let mut parts = Multipart::new(self.body(client, invocation, length), &boundary);
+        let mut stored = Vec::new();
+        while let Some(head) = parts.next_part().map_err(unreadable)? {
+            // Form fields that are not files are skipped, as are file inputs left empty
+            let Some(filename) = head.filename.filter(|filename| !filename.is_empty()) else {
+                continue;
+            };
+            // Browsers send bare names, but some clients send the path on their side
+            let name = filename.rsplit(['/', '\\']).next().unwrap_or("");
+            if name.is_empty() || name == "." || name == ".." || ignore::is_reserved(name) {
+                return Err(Refused(400, format!("invalid filename '{}'", filename)));
+            }
+            let file = dir.join(name);
+            if file.is_dir() {
+                return Err(Refused(409, format!("{} is a directory", name)));
+            }
+            let size = store(&mut parts, &file, name, None)?;
+            let path = format!("{}/{}", invocation.path.trim_end_matches('/'), name);
+            info!("Stored {} ({})", path, units::size(size));
+            changed.push(file);
+            stored.push(path);
+        }
+        if stored.is_empty() {
+            return Err(Refused(400, "the upload has no files".to_string()));
+        }
+        Ok(Response::new(201)
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "files": stored }).to_string().into_bytes()))
+    }
+
+    /// The announced length of the body, refused if over the limit, or `None` for a chunked body
+    fn body_length(&self, invocation: &Invocation) -> Result<Option<u64>, Refused> {
+        match invocation.headers.get("Transfer-Encoding") {
+            Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => return Ok(None),
+            Some(encoding) => return Err(Refused(501, format!("unsupported transfer encoding '{}'", encoding))),
+            None => {}
+        }
+        let Some(length) = invocation.headers.content_length() else {
+            return Err(Refused(411, "uploads need a Content-Length or a chunked body".to_string()));
+        };
+        if length > self.max_size {
+            return Err(Refused(413, format!("uploads are limited to {}", units::size(self.max_size))));
+        }
+        Ok(Some(length))
+    }
+
+    /// The body, of the announced length or chunked, which a chunked body fails to read past the limit
+    fn body<'c>(
+        &self,
+        client: &'c mut impl Read,
+        invocation: &'c Invocation,
+        length: Option<u64>,
+    ) -> Box<dyn Read + 'c> {
+        match length {
+            Some(length) => Box::new(invocation.received_body().chain(client.take(length)).take(length)),
+            None => Box::new(Chunked {
+                body: BufReader::new(invocation.received().chain(client)),
+                left: 0,
+                decoded: 0,
+                max_size: self.max_size,
+                done: false,
+            }),
+        }
+    }
+}
+
+/// Deletes a file, or a directory if the query asks for it to go with its contents
+fn delete(invocation: &Invocation, changed: &mut Vec<PathBuf>) -> Result<Response<'static>, Refused> {
+    let target = resolve::file_path(invocation.root, invocation.path);
+    let Some(dir) = target.parent().filter(|_| invocation.path != "/") else {
+        return Err(Refused(403, "the served directory itself cannot be deleted".to_string()));
+    };
+    let partial = partial_path(&target);
+    if partial.is_file() && fs::remove_file(&partial).is_ok() {
+        info!("Abandoned the upload of {}", invocation.path);
+        if fs::symlink_metadata(&target).is_err() {
+            return Ok(Response::new(204));
+        }
+    }
+    let Ok(metadata) = fs::symlink_metadata(&target) else {
+        return Err(Refused(404, format!("{} does not exist", invocation.path)));
+    };
+    if !inside(invocation.root, dir) {
+        return Err(Refused(403, "files outside the served directory cannot be deleted".to_string()));
+    }
+    let recursive = url::form_urlencoded::parse(invocation.query.as_bytes())
+        .any(|(name, value)| name == "recursive" && value == "1");
+    let removed = match metadata.is_dir() {
+        true if !recursive => {
+            let reason = format!("{} is a directory, delete it with ?recursive=1", invocation.path);
+            return Err(Refused(409, reason));
+        }
+        true => fs::remove_dir_all(&target),
+        // Symlinks are removed themselves, not what they point to
+        false => fs::remove_file(&target),
+    };
+    if let Err(e) = removed {
+        warn!("Failed to delete {}: {}", target.display(), e);
+        return Err(Refused(500, format!("{} could not be deleted", invocation.path)));
+    }
+    info!("Deleted {}", invocation.path);
+    changed.push(target);
+    Ok(Response::new(204))
+}
+
+/// Creates `dir` if needed, refusing when a file is in the way or the nearest existing directory is outside `root`
+fn prepare_dir(root: &Path, dir: &Path) -> Result<(), Refused> {
+    let existing = dir.ancestors().find(|ancestor| ancestor.exists()).unwrap_or(root);
+    if !existing.is_dir() {
+        return Err(Refused(409, "a file is in the way of the directories to create".to_string()));
+    }
+    if !inside(root, existing) {
+        return Err(Refused(403, "uploads cannot be written outside the served directory".to_string()));
+    }
+    fs::create_dir_all(dir).map_err(|e| unwritable(dir, e))
+}
+
+/// Whether `dir` is `root` or below it once symlinks are resolved
+pub fn inside(root: &Path, dir: &Path) -> bool {
+    match (dir.canonicalize(), root.canonicalize()) {
+        (Ok(dir), Ok(root)) => dir.starts_with(root),
+        _ => false,
+    }
+}
+
+/// Tells a client waiting with `Expect: 100-continue` to send the body
+fn continue_if_expected(client: &mut impl Write, invocation: &Invocation) -> Result<(), Refused> {
+    request::send_continue(client, invocation.headers, invocation.received()).map_err(unreadable)
+}
+
+/// Writes `body` to a temporary file next to `file` and renames it into place, returning its size
+///
+/// With an `expected` length, a body that ends early is refused rather than stored.
+fn store(body: &mut impl Read, file: &Path, name: &str, expected: Option<u64>) -> Result<u64, Refused> {
+    let temporary = file.with_file_name(format!(
+        ".{}.upload-{}-{}",
+        name,
+        process::id(),
+        UPLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let copied = copy_to(body, &temporary).and_then(|copied| match expected {
+        Some(expected) if copied < expected => Err(unreadable(io::ErrorKind::UnexpectedEof.into())),
+        _ => fs::rename(&temporary, file).map(|_| copied).map_err(|e| unwritable(file, e)),
+    });
+    if copied.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    copied
+}
+
+fn copy_to(body: &mut impl Read, temporary: &Path) -> Result<u64, Refused> {
+    let mut out = File::create(temporary).map_err(|e| unwritable(temporary, e))?;
+    write_all_from(body, &mut out, temporary)
+}
+
+/// Writes everything `body` has to `out` and syncs it, returning how much that was
+///
+/// What was written stays written if reading the body fails.
+fn write_all_from(body: &mut impl Read, out: &mut File, path: &Path) -> Result<u64, Refused> {
+    let mut buffer = [0u8; 16 * 1024];
+    let mut copied = 0;
+    let read_error = loop {
+        let read = match body.read(&mut buffer) {
+            Ok(0) => break None,
+            Ok(read) => read,
+            Err(e) => break Some(e),
+        };
+        out.write_all(&buffer[..read]).map_err(|e| unwritable(path, e))?;
+        copied += read as u64;
+    };
+    out.sync_all().map_err(|e| unwritable(path, e))?;
+    match read_error {
+        Some(e) => Err(unreadable(e)),
+        None => Ok(copied),
+    }
+}
+
+/// Where the parts of `file` received so far are kept
+fn partial_path(file: &Path) -> PathBuf {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    file.with_file_name(format!(".{}.partial", name))
+}
+
+/// Whether a file name is that of the parts of an upload received so far, see [`partial_path`]
+pub fn is_partial(name: &str) -> bool {
+    let file = name.strip_prefix('.').and_then(|rest| rest.strip_suffix(".partial"));
+    file.is_some_and(|file| !file.is_empty())
+}
+
+/// An answer telling how much of a file sent in parts has been received
+fn progress(status: u16, received: u64) -> Response<'static> {
+    match received {
+        0 => Response::new(status),
+        received => Response::new(status).header("Range", format!("bytes=0-{}", received - 1)),
+    }
+}
+
+/// The part and total length of a `Content-Range: bytes START-END/TOTAL`, without a part for `bytes */TOTAL`
+fn content_range(value: &str) -> Option<(Option<(u64, u64)>, u64)> {
+    let (part, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let total = total.trim().parse().ok()?;
+    if part.trim() == "*" {
+        return Some((None, total));
+    }
+    let (start, end) = part.trim().split_once('-')?;
+    Some((Some((start.parse().ok()?, end.parse().ok()?)), total))
+}
+
+fn unreadable(e: io::Error) -> Refused {
+    match e.kind() {
+        io::ErrorKind::FileTooLarge => Refused(413, e.to_string()),
+        _ => Refused(400, format!("the upload could not be read: {}", e)),
+    }
+}
+
+fn unwritable(path: &Path, e: io::Error) -> Refused {
+    warn!("Failed to store an upload as {}: {}", path.display(), e);
+    Refused(500, "the upload could not be stored".to_string())
+}
+
+/// The boundary of a multipart/form-data content type
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"').to_string())
+    })?;
+    Some(boundary).filter(|boundary| !boundary.is_empty())
+}
+
+impl PartHead {
+    fn parse(head: &str) -> PartHead {
+        let disposition = (head.lines().filter_map(|line| line.split_once(':')))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Disposition"))
+            .map_or("", |(_, value)| value);
+        let filename = disposition.split(';').skip(1).find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            (name.trim() == "filename").then(|| value.trim().trim_matches('"').to_string())
+        });
+        PartHead { filename }
+    }
+}
+
+impl<R: BufRead> Chunked<R> {
+    /// A line of the chunk framing, without its line ending
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        (&mut self.body).take(1024).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(malformed("the chunked body is cut short or its framing is invalid"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            let line = self.line()?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = u64::from_str_radix(size, 16).map_err(|_| malformed("invalid chunk size"))?;
+            if size == 0 {
+                // Trailer fields, up to the empty line ending the body
+                while !self.line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+            self.decoded += size;
+            if self.decoded > self.max_size {
+                let limit = format!("uploads are limited to {}", units::size(self.max_size));
+                return Err(io::Error::new(io::ErrorKind::FileTooLarge, limit));
+            }
+            self.left = size;
+        }
+        let wanted = out.len().min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        let read = self.body.read(&mut out[..wanted])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.left -= read as u64;
+        if self.left == 0 && !self.line()?.is_empty() {
+            return Err(malformed("a chunk is longer than its size"));
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(body: R, boundary: &str) -> Multipart<R> {
+        Multipart {
+            body,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // So the first delimiter is found like the others, though nothing comes before it
+            buffer: b"\r\n".to_vec(),
+            in_part: true,
+        }
+    }
+
+    /// Skips the rest of the current part and reads the headers of the next, `None` after the last
+    fn next_part(&mut self) -> io::Result<Option<PartHead>> {
+        io::copy(self, &mut io::sink())?;
+        // The closing delimiter is followed by `--`, the others by the part's headers
+        while self.buffer.len() < 2 {
+            if !self.fill()? {
+                return Err(malformed("the multipart body ends without its closing boundary"));
+            }
+        }
+        if self.buffer.starts_with(b"--") {
+            return Ok(None);
+        }
+        let end = loop {
+            if let Some(end) = find(&self.buffer, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buffer.len() > MAX_PART_HEAD || !self.fill()? {
+                return Err(malformed("a part of the multipart body has no end to its headers"));
+            }
+        };
+        let head = PartHead::parse(&String::from_utf8_lossy(&self.buffer[..end]));
+        self.buffer.drain(..end + 4);
+        self.in_part = true;
+        Ok(Some(head))
+    }
+
+    /// Reads more of the body into the buffer, returning false at its end
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; 16 * 1024];
+        let read = self.body.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+}
+
+/// Reads the current part, up to the delimiter that ends it
+impl<R: Read> Read for Multipart<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.in_part {
+                return Ok(0);
+            }
+            // Everything before the delimiter, or before what may turn out to be its start
+            let available = match find(&self.buffer, &self.delimiter) {
+                Some(0) => {
+                    self.buffer.drain(..self.delimiter.len());
+                    self.in_part = false;
+                    return Ok(0);
+                }
+                Some(at) => at,
+                None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available > 0 {
+                let read = available.min(out.len());
+                out[..read].copy_from_slice(&self.buffer[..read]);
+                self.buffer.drain(..read);
+                return Ok(read);
+            }
+            if !self.fill()? {
+                return Err(malformed("the multipart body ends inside a part"));
+            }
+        }
+    }
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
@@ -0,0 +1,99 @@
+use io_uring::{opcode, types, IoUring};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use tracing::warn;
+
+/// Reads kept in flight at once for one file
+const QUEUE_DEPTH: u32 = 8;
+
+/// Bytes requested by a single read
+const CHUNK: usize = 1024 * 1024;
+
+thread_local! {
+    /// One ring per worker thread, `None` if the kernel refused to create one
+    static RING: RefCell<Option<Option<IoUring>>> = const { RefCell::new(None) };
+}
+
+/// Reads a whole file, keeping up to [`QUEUE_DEPTH`] chunk reads in flight per submission
+///
+/// Falls back to [`fs::read`] where io_uring is unavailable, e.g. on old
+/// kernels or when it is disabled through sysctl or seccomp.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        let ring = ring.get_or_insert_with(|| match IoUring::new(QUEUE_DEPTH) {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                warn!("io_uring unavailable, reading files the usual way: {}", e);
+                None
+            }
+        });
+        match ring {
+            Some(ring) => read_with(ring, path),
+            None => fs::read(path),
+        }
+    })
+}
+
+fn read_with(ring: &mut IoUring, path: &Path) -> io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    let mut contents = vec![0u8; file.metadata()?.len() as usize];
+    let fd = types::Fd(file.as_raw_fd());
+
+    // Byte ranges still to read, and the end of each read in flight keyed by its start
+    let mut remaining: Vec<(usize, usize)> = (0..contents.len())
+        .step_by(CHUNK)
+        .map(|start| (start, (start + CHUNK).min(contents.len())))
+        .rev()
+        .collect();
+    let mut in_flight: HashMap<u64, usize> = HashMap::new();
+    let mut end_of_file = contents.len();
+    let mut error = None;
+
+    while error.is_none() && !remaining.is_empty() || !in_flight.is_empty() {
+        while error.is_none() && in_flight.len() < QUEUE_DEPTH as usize {
+            let Some((start, end)) = remaining.pop() else {
+                break;
+            };
+            let entry = opcode::Read::new(fd, contents[start..].as_mut_ptr(), (end - start) as u32)
+                .offset(start as u64)
+                .build()
+                .user_data(start as u64);
+            // The queue is as deep as the number of reads in flight, so there is always room;
+            // `contents` outlives the read because every submission is waited for below
+            unsafe { ring.submission().push(&entry) }.expect("submission queue full");
+            in_flight.insert(start as u64, end);
+        }
+
+        ring.submit_and_wait(1)?;
+        for completion in ring.completion() {
+            let start = completion.user_data();
+            let Some(end) = in_flight.remove(&start) else {
+                continue;
+            };
+            let start = start as usize;
+            match completion.result() {
+                // The file shrank while we were reading it
+                0 => end_of_file = end_of_file.min(start),
+                read if read > 0 && start + (read as usize) < end => remaining.push((start + read as usize, end)),
+                read if read > 0 => {}
+                code if -code == libc::EINTR || -code == libc::EAGAIN => remaining.push((start, end)),
+                code => {
+                    error.get_or_insert(io::Error::from_raw_os_error(-code));
+                }
+            }
+        }
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => {
+            contents.truncate(end_of_file);
+            Ok(contents)
+        }
+    }
+}
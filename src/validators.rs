@@ -0,0 +1,54 @@
+use crate::units;
+use rshttp::response::Response;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The ETag and Last-Modified of a served file, which `If-Range` is checked against
+pub struct Validators {
+    etag: String,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Validators for a body of `len` bytes from a file last modified at `modified`
+    ///
+    /// The ETag changes with either, like the ones nginx and Apache send.
+    pub fn new(len: usize, modified: Option<SystemTime>) -> Validators {
+        let since = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", since.as_secs(), len),
+            last_modified: modified.map(units::http_date),
+        }
+    }
+
+    /// Validators for a body made from files rather than read from one, tagged `etag` and dated `modified`
+    pub fn generated(etag: String, modified: Option<SystemTime>) -> Validators {
+        Validators {
+            etag,
+            last_modified: modified.map(units::http_date),
+        }
+    }
+
+    /// The `Range` to honour: the requested one, unless `if_range` names an older version of the file
+    ///
+    /// A date only matches if it is exactly the Last-Modified sent, and an
+    /// ETag only if it is the current one and not weak.
+    pub fn range<'r>(&self, range: Option<&'r str>, if_range: Option<&str>) -> Option<&'r str> {
+        let Some(if_range) = if_range.map(str::trim) else {
+            return range;
+        };
+        let holds = match if_range.starts_with('"') {
+            true => if_range == self.etag,
+            false => self.last_modified.as_deref() == Some(if_range),
+        };
+        range.filter(|_| holds)
+    }
+
+    /// `response` with the ETag and Last-Modified headers
+    pub fn label<'a>(&self, response: Response<'a>) -> Response<'a> {
+        let response = response.header("ETag", self.etag.clone());
+        match &self.last_modified {
+            Some(last_modified) => response.header("Last-Modified", last_modified.clone()),
+            None => response,
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use crate::mime::{self, MimeTypes};
+use crate::{units, walk};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Arguments of the `verify` subcommand
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Directory to check
+    #[arg(default_value = ".")]
+    directory: PathBuf,
+    /// Size budget for assets, either global (`1M`) or per extension (`js=200K`); repeatable
+    #[arg(long = "budget", value_name = "[EXT=]SIZE", value_parser = parse_budget)]
+    budgets: Vec<(Option<String>, u64)>,
+    /// The server's --mime overrides, to check files by the type they will be served as
+    #[arg(long = "mime", value_name = "EXT=TYPE", value_parser = mime::parse_override)]
+    mime_overrides: Vec<(String, String)>,
+}
+
+fn parse_budget(value: &str) -> Result<(Option<String>, u64), String> {
+    match value.split_once('=') {
+        Some((ext, size)) => Ok((Some(ext.trim_start_matches('.').to_ascii_lowercase()), units::parse_size(size)?)),
+        None => Ok((None, units::parse_size(value)?)),
+    }
+}
+
+/// One problem found in the served tree
+struct Problem {
+    path: PathBuf,
+    message: String,
+}
+
+/// Checks a directory the way the server would serve it and returns the process exit code
+pub fn run(args: &VerifyArgs) -> i32 {
+    let root = &args.directory;
+    if !root.is_dir() {
+        eprintln!("Error: {} is not a directory", root.display());
+        return 2;
+    }
+
+    let mime_types = MimeTypes::new(&args.mime_overrides, None);
+    let files = walk::files(root);
+
+    let global_budget = args.budgets.iter().find(|(ext, _)| ext.is_none()).map(|(_, size)| *size);
+    let budgets: HashMap<&str, u64> = args
+        .budgets
+        .iter()
+        .filter_map(|(ext, size)| ext.as_deref().map(|ext| (ext, *size)))
+        .collect();
+
+    let mut problems = Vec::new();
+    if !root.join("index.html").is_file() {
+        problems.push(Problem {
+            path: root.to_path_buf(),
+            message: "missing index.html at the root".to_string(),
+        });
+    }
+
+    for file in &files {
+        let extension = file
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let Ok(metadata) = fs::metadata(file) else {
+            continue;
+        };
+
+        let budget = budgets.get(extension.as_str()).copied().or(global_budget);
+        if let Some(budget) = budget {
+            if metadata.len() > budget {
+                problems.push(Problem {
+                    path: file.clone(),
+                    message: format!("{} exceeds the budget of {}", units::size(metadata.len()), units::size(budget)),
+                });
+            }
+        }
+
+        let guessed = mime_types.of(file);
+        let Ok(contents) = fs::read(file) else {
+            problems.push(Problem {
+                path: file.clone(),
+                message: "unreadable".to_string(),
+            });
+            continue;
+        };
+        if guessed == "application/octet-stream" {
+            problems.push(Problem {
+                path: file.clone(),
+                message: "unknown MIME type, served as application/octet-stream (map it with --mime)".to_string(),
+            });
+        } else if let Some(sniffed) = sniff(&contents) {
+            // Office documents, jars, epubs etc. are zip containers under another name
+            let container = sniffed == "application/zip" && !guessed.starts_with("text/") && !guessed.starts_with("image/");
+            if sniffed != guessed && !container {
+                problems.push(Problem {
+                    path: file.clone(),
+                    message: format!("content looks like {} but is served as {}", sniffed, guessed),
+                });
+            }
+        } else if expects_binary(&guessed) && looks_like_text(&contents) {
+            problems.push(Problem {
+                path: file.clone(),
+                message: format!("content looks like text but is served as {}", guessed),
+            });
+        }
+
+        if guessed == "text/html" {
+            let html = String::from_utf8_lossy(&contents);
+            for link in links(&html) {
+                if let Some(message) = check_link(root, file, &link) {
+                    problems.push(Problem {
+                        path: file.clone(),
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    for problem in &problems {
+        let path = problem.path.strip_prefix(root).unwrap_or(&problem.path);
+        println!("{}: {}", path.display(), problem.message);
+    }
+    println!("Checked {} file(s), found {} problem(s)", files.len(), problems.len());
+
+    if problems.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Guesses a MIME type from well-known magic numbers
+fn sniff(contents: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x00asm", "application/wasm"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if contents.len() >= 12 && &contents[..4] == b"RIFF" && &contents[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| contents.starts_with(magic))
+        .map(|(_, mime)| *mime)
+}
+
+/// Types whose files are never plain text
+fn expects_binary(mime: &str) -> bool {
+    (mime.starts_with("image/") && mime != "image/svg+xml")
+        || mime.starts_with("font/")
+        || matches!(mime, "application/wasm" | "application/pdf" | "application/zip" | "application/gzip")
+}
+
+fn looks_like_text(contents: &[u8]) -> bool {
+    let sample = &contents[..contents.len().min(512)];
+    !sample.is_empty() && !sample.contains(&0) && std::str::from_utf8(sample).is_ok()
+}
+
+/// Extracts the values of href and src attributes
+fn links(html: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    let lower = html.to_ascii_lowercase();
+    for attribute in ["href=", "src="] {
+        let mut offset = 0;
+        while let Some(found) = lower[offset..].find(attribute) {
+            let start = offset + found + attribute.len();
+            offset = start;
+            // Skip matches inside longer attribute names such as data-src=
+            let preceding = lower[..start - attribute.len()].chars().last();
+            if preceding.is_some_and(|c| c.is_ascii_alphanumeric() || c == '-') {
+                continue;
+            }
+            let rest = &html[start..];
+            let value = match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => rest[1..].split(quote).next(),
+                Some(_) => rest.split(|c: char| c.is_whitespace() || c == '>').next(),
+                None => None,
+            };
+            if let Some(value) = value {
+                links.push(value.trim().to_string());
+            }
+        }
+    }
+    links
+}
+
+/// Resolves an internal link and describes it if nothing would be served for it
+fn check_link(root: &Path, from: &Path, link: &str) -> Option<String> {
+    let target = link.split(['#', '?']).next().unwrap_or("");
+    let is_external = target.starts_with("//") || target.contains(':');
+    if target.is_empty() || is_external || target.starts_with("{{") {
+        return None;
+    }
+
+    let decoded = percent_decode(target);
+    let resolved = if let Some(absolute) = decoded.strip_prefix('/') {
+        root.join(absolute)
+    } else {
+        from.parent().unwrap_or(root).join(&decoded)
+    };
+
+    if resolved.is_file() {
+        None
+    } else if resolved.is_dir() {
+        if resolved.join("index.html").is_file() {
+            None
+        } else {
+            Some(format!("link to {} points at a directory without index.html", link))
+        }
+    } else {
+        Some(format!("broken link to {}", link))
+    }
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones untouched
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
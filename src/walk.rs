@@ -0,0 +1,50 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// All regular files below `dir`, recursively, in sorted order
+///
+/// Symlinked directories are followed, but each directory is only walked
+/// once, so a link back up the tree doesn't send the walk around forever.
+pub fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    collect(dir, &mut files, &mut HashSet::new());
+    files.sort();
+    files
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>, visited: &mut HashSet<PathBuf>) {
+    let Ok(canonical) = dir.canonicalize() else {
+        return;
+    };
+    if !visited.insert(canonical) {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect(&path, files, visited);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlink_loops_are_walked_once() {
+        let dir = std::env::temp_dir().join(format!("rshttp-walk-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::write(dir.join("a/file.txt"), "x").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("a/loop")).unwrap();
+        let found = files(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, vec![dir.join("a/file.txt")]);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::cgi::Invocation;
+use crate::ignore;
+use crate::inject::escape;
+use crate::mime::MimeTypes;
+use crate::units;
+use crate::upload::{self, Written};
+use crate::walk;
+use rshttp::request::decode_path;
+use rshttp::request::encode_path;
+use rshttp::response::Response;
+use rshttp::{request, resolve};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Methods answered with --webdav, besides GET, HEAD and those of the uploads
+pub const METHODS: [&str; 8] = ["OPTIONS", "PROPFIND", "PROPPATCH", "MKCOL", "COPY", "MOVE", "LOCK", "UNLOCK"];
+
+/// The methods among them that change the served directory, only answered with --upload as well
+pub const WRITES: [&str; 6] = ["PROPPATCH", "MKCOL", "COPY", "MOVE", "LOCK", "UNLOCK"];
+
+/// Most bytes of an XML request body that are read
+const MAX_BODY: u64 = 64 * 1024;
+
+/// How long the locks handed out claim to last
+const LOCK_TIMEOUT: &str = "Second-3600";
+
+const MULTISTATUS_START: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n";
+
+/// Answers WebDAV requests, from --webdav, so the served directory can be mounted as a network drive
+///
+/// PROPFIND lists files and directories with their size, type and times, to a
+/// depth of 0 or 1. With --upload as well, MKCOL creates directories, COPY and
+/// MOVE copy and rename, PUT and DELETE are the uploads', and LOCK hands out
+/// locks that lock nothing, which Finder and Windows ask for before writing.
+/// Property changes are acknowledged but not kept.
+pub struct WebDav {
+    writable: bool,
+}
+
+/// A WebDAV request and what it needs from the request handling around it
+pub struct DavRequest<'a> {
+    pub invocation: &'a Invocation<'a>,
+    /// Whether a request with a method for another path would be let through
+    pub permitted: &'a dyn Fn(&str, &str) -> bool,
+    pub mime_types: &'a MimeTypes,
+}
+
+impl WebDav {
+    pub fn new(writable: bool) -> WebDav {
+        WebDav { writable }
+    }
+
+    /// The methods to list in `Allow`
+    pub fn allow(&self) -> &'static str {
+        match self.writable {
+            true => "OPTIONS, GET, HEAD, PROPFIND, PUT, POST, DELETE, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK",
+            false => "OPTIONS, GET, HEAD, PROPFIND",
+        }
+    }
+
+    /// Answers one of [`METHODS`], telling what changed on disk
+    pub fn handle(&self, client: &mut (impl Read + Write), request: &DavRequest) -> Written {
+        let invocation = request.invocation;
+        let mut changed = Vec::new();
+        let response = match invocation.method {
+            "OPTIONS" => Response::new(200)
+                .header("DAV", if self.writable { "1, 2" } else { "1" })
+                .header("Allow", self.allow())
+                .header("MS-Author-Via", "DAV"),
+            method if WRITES.contains(&method) && !self.writable => {
+                Response::error(405).header("Allow", self.allow()).header("Connection", "close")
+            }
+            "PROPFIND" => self.propfind(client, request),
+            "PROPPATCH" => proppatch(client, invocation),
+            "MKCOL" => mkcol(invocation, &mut changed),
+            "COPY" | "MOVE" => transfer(request, &mut changed),
+            "LOCK" => lock(client, invocation, &mut changed),
+            _ => Response::new(204),
+        };
+        Written { response, changed }
+    }
+
+    fn propfind(&self, client: &mut (impl Read + Write), request: &DavRequest) -> Response<'static> {
+        let invocation = request.invocation;
+        // Every property is sent whichever were asked for, which clients accept
+        if let Err(status) = read_body(client, invocation) {
+            return Response::error(status).header("Connection", "close");
+        }
+        let target = resolve::file_path(invocation.root, invocation.path);
+        let Ok(metadata) = fs::metadata(&target) else {
+            return Response::error(404);
+        };
+        let mut xml = String::from(MULTISTATUS_START);
+        self.describe(&mut xml, invocation.path, &target, &metadata, request.mime_types);
+        // Depth infinity is answered like 1, clients walk the tree themselves
+        if metadata.is_dir() && invocation.headers.get("Depth") != Some("0") {
+            let entries = fs::read_dir(&target).map(|entries| entries.flatten().collect());
+            let mut entries: Vec<_> = entries.unwrap_or_default();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let child = format!("{}/{}", invocation.path.trim_end_matches('/'), entry.file_name().to_string_lossy());
+                if ignore::is_reserved(&child) || !(request.permitted)("GET", &child) {
+                    continue;
+                }
+                // e.g. a broken symlink
+                let Ok(metadata) = fs::metadata(entry.path()) else {
+                    continue;
+                };
+                self.describe(&mut xml, &child, &entry.path(), &metadata, request.mime_types);
+            }
+        }
+        xml.push_str("</D:multistatus>\n");
+        multistatus(xml)
+    }
+
+    /// Adds the properties of a file or directory to a multistatus
+    fn describe(&self, xml: &mut String, path: &str, file: &Path, metadata: &Metadata, mime_types: &MimeTypes) {
+        let mut href = encode_path(path);
+        if metadata.is_dir() && !href.ends_with('/') {
+            href.push('/');
+        }
+        let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+        let _ = write!(
+            xml,
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>",
+            escape(&href),
+            escape(name)
+        );
+        match metadata.is_dir() {
+            true => xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>"),
+            false => {
+                let _ = write!(
+                    xml,
+                    "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                     <D:getcontenttype>{}</D:getcontenttype>",
+                    metadata.len(),
+                    escape(&mime_types.of(file))
+                );
+            }
+        }
+        if let Ok(modified) = metadata.modified() {
+            let created = metadata.created().unwrap_or(modified);
+            let _ = write!(
+                xml,
+                "<D:getlastmodified>{}</D:getlastmodified><D:creationdate>{}</D:creationdate>",
+                units::http_date(modified),
+                units::iso_timestamp(created)
+            );
+        }
+        if self.writable {
+            xml.push_str(
+                "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+                 <D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock>",
+            );
+        }
+        xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+    }
+}
+
+fn multistatus(xml: String) -> Response<'static> {
+    Response::new(207)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(xml.into_bytes())
+}
+
+/// Reads a small XML request body
+fn read_body(client: &mut (impl Read + Write), invocation: &Invocation) -> Result<Vec<u8>, u16> {
+    if invocation.headers.get("Transfer-Encoding").is_some() {
+        return Err(411);
+    }
+    let length = invocation.headers.content_length().unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(413);
+    }
+    request::send_continue(client, invocation.headers, invocation.received_body()).map_err(|_| 400u16)?;
+    let mut body = invocation.received_body().to_vec();
+    let remaining = length - body.len() as u64;
+    match client.take(remaining).read_to_end(&mut body) {
+        Ok(read) if read as u64 == remaining => Ok(body),
+        _ => Err(400),
+    }
+}
+
+/// Acknowledges property changes, such as the file times Windows sets, without keeping them
+fn proppatch(client: &mut (impl Read + Write), invocation: &Invocation) -> Response<'static> {
+    let body = match read_body(client, invocation) {
+        Ok(body) => body,
+        Err(status) => return Response::error(status).header("Connection", "close"),
+    };
+    if fs::symlink_metadata(resolve::file_path(invocation.root, invocation.path)).is_err() {
+        return Response::error(404);
+    }
+    let mut xml = String::from(MULTISTATUS_START);
+    let _ = write!(xml, "<D:response><D:href>{}</D:href><D:propstat><D:prop>", escape(&encode_path(invocation.path)));
+    for (namespace, name) in property_names(&String::from_utf8_lossy(&body)) {
+        let _ = write!(xml, "<{} xmlns=\"{}\"/>", name, escape(&namespace));
+    }
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n</D:multistatus>\n");
+    multistatus(xml)
+}
+
+/// The namespaces and names of the properties a PROPPATCH body sets or removes
+fn property_names(body: &str) -> Vec<(String, String)> {
+    let mut namespaces = HashMap::new();
+    let mut open: Vec<String> = Vec::new();
+    let mut names = Vec::new();
+    for tag in body.split('<').skip(1).filter_map(|rest| rest.split_once('>').map(|(tag, _)| tag)) {
+        if tag.starts_with(['?', '!']) {
+            continue;
+        }
+        if let Some(closing) = tag.strip_prefix('/') {
+            let closing = closing.trim();
+            if let Some(position) = open.iter().rposition(|name| name == closing) {
+                open.truncate(position);
+            }
+            continue;
+        }
+        let name = tag.split([' ', '\t', '\r', '\n', '/']).next().unwrap_or("").to_string();
+        // Declarations are taken to hold for the whole body, which is how clients write them
+        for declaration in tag.split_whitespace().skip(1) {
+            if let Some((attribute, value)) = declaration.split_once('=') {
+                let value = value.trim_end_matches('/').trim_matches(['"', '\'']).to_string();
+                let prefix = match attribute {
+                    "xmlns" => Some(""),
+                    _ => attribute.strip_prefix("xmlns:"),
+                };
+                if let Some(prefix) = prefix {
+                    namespaces.insert(prefix.to_string(), value);
+                }
+            }
+        }
+        let parent = open.last().map(|parent| parent.rsplit(':').next().unwrap_or(parent));
+        if parent == Some("prop") {
+            let (prefix, local) = name.split_once(':').unwrap_or(("", &name));
+            // Written back as an element name, so anything else is left out
+            if is_xml_name(local) {
+                names.push((prefix.to_string(), local.to_string()));
+            }
+        }
+        if !tag.ends_with('/') {
+            open.push(name);
+        }
+    }
+    names
+        .into_iter()
+        .map(|(prefix, local)| (namespaces.get(&prefix).cloned().unwrap_or_default(), local))
+        .collect()
+}
+
+/// Whether `name` can stand as an XML element name without a prefix
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|first| first.is_alphabetic() || first == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Creates a directory
+fn mkcol(invocation: &Invocation, changed: &mut Vec<PathBuf>) -> Response<'static> {
+    if invocation.headers.content_length().unwrap_or(0) > 0 || invocation.headers.get("Transfer-Encoding").is_some() {
+        return Response::error(415).header("Connection", "close");
+    }
+    let dir = resolve::file_path(invocation.root, invocation.path);
+    if fs::symlink_metadata(&dir).is_ok() {
+        return Response::error(405);
+    }
+    let Some(parent) = dir.parent().filter(|parent| parent.is_dir()) else {
+        return Response::error(409);
+    };
+    if !upload::inside(invocation.root, parent) {
+        return Response::error(403);
+    }
+    if let Err(e) = fs::create_dir(&dir) {
+        warn!("Failed to create {}: {}", dir.display(), e);
+        return Response::error(500);
+    }
+    info!("Created the directory {}", invocation.path);
+    changed.push(dir);
+    Response::new(201)
+}
+
+/// Copies or moves a file or directory to the path in the Destination header
+fn transfer(request: &DavRequest, changed: &mut Vec<PathBuf>) -> Response<'static> {
+    let invocation = request.invocation;
+    let Some(destination) = invocation.headers.get("Destination").map(destination_path) else {
+        return Response::error(400);
+    };
+    let (source, target) = (
+        resolve::file_path(invocation.root, invocation.path),
+        resolve::file_path(invocation.root, &destination),
+    );
+    let Ok(metadata) = fs::symlink_metadata(&source) else {
+        return Response::error(404);
+    };
+    if invocation.path == "/" || destination == "/" || target.starts_with(&source) || ignore::is_reserved(&destination) {
+        return Response::error(403);
+    }
+    let Some(parent) = target.parent().filter(|parent| parent.is_dir()) else {
+        return Response::error(409);
+    };
+    let inside = |path: &Path| upload::inside(invocation.root, path);
+    let source_inside = source.parent().is_some_and(inside);
+    if !(request.permitted)(invocation.method, &destination) || !source_inside || !inside(parent) {
+        return Response::error(403);
+    }
+    let moving = invocation.method == "MOVE";
+    // Only files the client may read are copied or moved, or they could be read from the destination
+    if !metadata.is_dir() && !(request.permitted)("GET", invocation.path) {
+        return Response::error(403);
+    }
+    // Whatever is moved stays as reachable as it was, so all of it has to be readable
+    if moving && metadata.is_dir() {
+        let readable = walk::files(&source).iter().all(|file| {
+            let relative = file.strip_prefix(&source).unwrap_or(file).to_string_lossy().replace('\\', "/");
+            (request.permitted)("GET", &format!("{}/{}", invocation.path.trim_end_matches('/'), relative))
+        });
+        if !readable {
+            return Response::error(403);
+        }
+    }
+    let existed = fs::symlink_metadata(&target).is_ok();
+    if existed && invocation.headers.get("Overwrite").is_some_and(|overwrite| overwrite.eq_ignore_ascii_case("F")) {
+        return Response::error(412);
+    }
+    let replaced = match fs::symlink_metadata(&target) {
+        Ok(existing) if existing.is_dir() => fs::remove_dir_all(&target),
+        Ok(_) => fs::remove_file(&target),
+        Err(_) => Ok(()),
+    };
+    let done = replaced.and_then(|()| match moving {
+        true => fs::rename(&source, &target),
+        false => {
+            let recursive = invocation.headers.get("Depth") != Some("0");
+            copy(&source, &target, invocation.path, recursive, request.permitted)
+        }
+    });
+    if let Err(e) = done {
+        warn!("Failed to {} {} to {}: {}", invocation.method, source.display(), target.display(), e);
+        return Response::error(500);
+    }
+    info!("{} {} to {}", if moving { "Moved" } else { "Copied" }, invocation.path, destination);
+    if moving {
+        changed.push(source);
+    }
+    changed.push(target);
+    Response::new(if existed { 204 } else { 201 })
+}
+
+/// Copies a file, or a directory with the files below it that are readable
+fn copy(
+    source: &Path,
+    target: &Path,
+    path: &str,
+    recursive: bool,
+    permitted: &dyn Fn(&str, &str) -> bool,
+) -> io::Result<()> {
+    if !source.is_dir() {
+        return fs::copy(source, target).map(|_| ());
+    }
+    fs::create_dir(target)?;
+    if !recursive {
+        return Ok(());
+    }
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let child = format!("{}/{}", path.trim_end_matches('/'), name.to_string_lossy());
+        if permitted("GET", &child) {
+            copy(&entry.path(), &target.join(&name), &child, true, permitted)?;
+        }
+    }
+    Ok(())
+}
+
+/// The path of a Destination header, which is usually a full URL
+fn destination_path(destination: &str) -> String {
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+        None => destination,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    request::normalize_path(&decode_path(path))
+}
+
+/// Hands out a lock that locks nothing, creating the file if it doesn't exist yet
+fn lock(client: &mut (impl Read + Write), invocation: &Invocation, changed: &mut Vec<PathBuf>) -> Response<'static> {
+    let body = match read_body(client, invocation) {
+        Ok(body) => body,
+        Err(status) => return Response::error(status).header("Connection", "close"),
+    };
+    let file = resolve::file_path(invocation.root, invocation.path);
+    let mut status = 200;
+    if fs::symlink_metadata(&file).is_err() {
+        let Some(parent) = file.parent().filter(|parent| parent.is_dir()) else {
+            return Response::error(409);
+        };
+        if !upload::inside(invocation.root, parent) {
+            return Response::error(403);
+        }
+        if let Err(e) = File::create(&file) {
+            warn!("Failed to create {}: {}", file.display(), e);
+            return Response::error(500);
+        }
+        changed.push(file);
+        status = 201;
+    }
+    // A refresh names its lock in the If header and has no body
+    let refreshed = invocation.headers.get("If").filter(|_| body.is_empty()).and_then(|condition| {
+        let start = condition.find("opaquelocktoken:")?;
+        condition[start..].split('>').next().map(str::to_string)
+    });
+    let token = refreshed.unwrap_or_else(lock_token);
+    let depth = match invocation.headers.get("Depth") {
+        Some("0") => "0",
+        _ => "infinity",
+    };
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>\
+         <D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope>\
+         <D:depth>{}</D:depth><D:timeout>{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock></D:lockdiscovery></D:prop>\n",
+        depth,
+        LOCK_TIMEOUT,
+        escape(&token),
+        escape(&encode_path(invocation.path))
+    );
+    Response::new(status)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .header("Lock-Token", format!("<{}>", token))
+        .header("Timeout", LOCK_TIMEOUT)
+        .body(xml.into_bytes())
+}
+
+/// A random lock token in the URN form WebDAV clients expect
+fn lock_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("Failed to read random bytes");
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("opaquelocktoken:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proppatch_names_are_checked() {
+        let body = r#"<?xml version="1.0"?>
+<D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:schemas-microsoft-com:">
+  <D:set><D:prop>
+    <Z:Win32LastModifiedTime>Wed, 01 Jan 2025 00:00:00 GMT</Z:Win32LastModifiedTime>
+    <Z:a"b/><Z:a&amp;b/>
+  </D:prop></D:set>
+</D:propertyupdate>"#;
+        let names = property_names(body);
+        assert_eq!(names, vec![("urn:schemas-microsoft-com:".to_string(), "Win32LastModifiedTime".to_string())]);
+    }
+
+    #[test]
+    fn xml_names() {
+        assert!(is_xml_name("getlastmodified"));
+        assert!(is_xml_name("_a-b.c1"));
+        assert!(!is_xml_name(""));
+        assert!(!is_xml_name("1st"));
+        assert!(!is_xml_name("a\"b"));
+        assert!(!is_xml_name("a><b"));
+    }
+}
@@ -0,0 +1,60 @@
+use crate::events::Change;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// How long one delivery may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts every batch of changes the watcher handled to --webhook
+///
+/// The body is a JSON object with the URL paths that changed and were
+/// removed, and when the first change of the batch came in and when the
+/// batch was handled, both in seconds since the epoch:
+/// `{"changed": ["/index.html"], "removed": [], "first_change": 1700000000, "handled": 1700000001}`.
+/// Deliveries run one at a time on a thread of their own so a slow receiver
+/// never holds up the cache; a failed one is logged and not retried.
+pub struct Webhook {
+    batches: Sender<serde_json::Value>,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Webhook {
+        let (batches, rx) = channel::<serde_json::Value>();
+        let url = url.to_string();
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        thread::spawn(move || {
+            for batch in rx {
+                if let Err(e) = agent.post(&url).set("Content-Type", "application/json").send_string(&batch.to_string()) {
+                    warn!("Webhook {} failed: {}", url, e);
+                }
+            }
+        });
+        Webhook { batches }
+    }
+
+    /// Queues a batch for delivery, unless nothing in it is served
+    pub fn send(&self, changes: &[Change], first_change: SystemTime) {
+        if changes.is_empty() {
+            return;
+        }
+        let (mut changed, mut removed) = (Vec::new(), Vec::new());
+        for change in changes {
+            match change {
+                Change::Changed(path) => changed.push(path),
+                Change::Removed(path) => removed.push(path),
+            }
+        }
+        let _ = self.batches.send(serde_json::json!({
+            "changed": changed,
+            "removed": removed,
+            "first_change": seconds(first_change),
+            "handled": seconds(SystemTime::now()),
+        }));
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}